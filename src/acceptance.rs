@@ -0,0 +1,251 @@
+//! An end-to-end acceptance suite for the whole toolchain on the course's
+//! OS, which is too large to check in. Small driver programs are each
+//! translated, linked with a local copy of the OS `.vm` files, assembled,
+//! and run on the CPU until the OS halts; then what each left in its
+//! statics is checked against the answers written at its top. Run it
+//! with `HACK_OS_DIR=<dir> cargo test --features os-suite`.
+
+use crate::asm::{self, BootstrapMode, CodegenOptions};
+use crate::assembler::{assemble, Cpu, SymbolKind};
+use crate::link::{self, Fragment};
+use crate::memory::MemoryMap;
+use crate::provenance::TRANSLATOR_VERSION;
+use crate::translator::Translator;
+use crate::{sha256, vm};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Names the directory holding the OS's `.vm` files.
+pub const OS_DIR_VAR: &str = "HACK_OS_DIR";
+
+/// Names a directory to keep translations of the OS in between runs; a
+/// directory under the system's temporary one when unset.
+pub const CACHE_DIR_VAR: &str = "HACK_OS_CACHE";
+
+/// How many instructions a driver may run, the OS's start-up included,
+/// before it's taken to be stuck.
+pub const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
+/// The drivers, as `(name, Main.vm)`.
+pub const DRIVERS: [(&str, &str); 3] = [
+    ("MathTable", include_str!("../tests/os-suite/MathTable/Main.vm")),
+    ("MemoryStress", include_str!("../tests/os-suite/MemoryStress/Main.vm")),
+    ("StringConversion", include_str!("../tests/os-suite/StringConversion/Main.vm")),
+];
+
+/// Part of the cache key, as code generation changes far more often than
+/// the version number does.
+const CODEGEN_SOURCE: &str = include_str!("asm.rs");
+
+/// A `Main.vm` to run on top of the OS, and what it should leave in its
+/// statics, from its `// expect static <i> = <value>` comments.
+#[derive(Debug, Clone)]
+pub struct Driver {
+    pub name: String,
+    pub source: String,
+    pub expected: Vec<(u16, i16)>,
+}
+
+impl Driver {
+    pub fn parse(name: &str, source: &str) -> Result<Driver, String> {
+        let mut expected = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let Some(rest) = line.trim().strip_prefix("// expect static ") else { continue };
+            let parsed = rest.split_once('=').and_then(|(index, value)| {
+                let value = value.split_whitespace().next()?;
+                Some((index.trim().parse().ok()?, value.parse().ok()?))
+            });
+            expected.push(parsed.ok_or(format!("{name} line {}: expected '// expect static <i> = <value>'", i + 1))?);
+        }
+        if expected.is_empty() {
+            return Err(format!("{name} expects nothing"));
+        }
+        Ok(Driver { name: name.to_string(), source: source.to_string(), expected })
+    }
+
+    /// `DRIVERS`, parsed.
+    pub fn all() -> Vec<Driver> {
+        DRIVERS.iter().map(|(name, source)| Driver::parse(name, source).expect("the drivers are checked in")).collect()
+    }
+}
+
+/// How one driver went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverResult {
+    pub name: String,
+    /// Why it failed, if it did.
+    pub failure: Option<String>,
+    /// The linked program's size in ROM words, if it got as far as
+    /// assembling.
+    pub rom_words: usize,
+    pub instructions: u64,
+}
+
+impl DriverResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for DriverResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.failure {
+            None => write!(f, "{}: ok ({} ROM words, {} instructions)", self.name, self.rom_words, self.instructions),
+            Some(failure) => write!(f, "{}: FAILED: {failure}", self.name),
+        }
+    }
+}
+
+/// What `run_from_env` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Suite {
+    /// Why nothing ran.
+    Skipped(String),
+    Ran(Vec<DriverResult>),
+}
+
+/// Runs `DRIVERS` on the OS in `HACK_OS_DIR`, or skips them if it isn't
+/// set. An OS that can't be read or translated is an error rather than a
+/// failed driver.
+///
+#[cfg_attr(feature = "os-suite", doc = "```")]
+#[cfg_attr(not(feature = "os-suite"), doc = "```ignore")]
+/// use hack_vmtranslator::acceptance::{run_from_env, Suite};
+///
+/// match run_from_env().unwrap() {
+///     Suite::Skipped(reason) => eprintln!("{reason}"),
+///     Suite::Ran(results) => {
+///         let report: Vec<String> = results.iter().map(ToString::to_string).collect();
+///         assert!(results.iter().all(|r| r.passed()), "\n{}", report.join("\n"));
+///     }
+/// }
+/// ```
+pub fn run_from_env() -> Result<Suite, String> {
+    let Some(os_dir) = std::env::var_os(OS_DIR_VAR) else {
+        return Ok(Suite::Skipped(format!("{OS_DIR_VAR} isn't set, so the OS suite is skipped")));
+    };
+    let cache_dir = std::env::var_os(CACHE_DIR_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("hack_os_suite"));
+    run(Path::new(&os_dir), &cache_dir, &Driver::all()).map(Suite::Ran)
+}
+
+/// Translates the OS in `os_dir`, or takes its translation from
+/// `cache_dir`, and runs each driver on it.
+///
+/// ```
+/// use hack_vmtranslator::acceptance::{run, Driver};
+/// use std::path::Path;
+///
+/// // A stand-in for the OS with just what the drivers use.
+/// let os = Path::new("tests/os-suite/stub-os");
+/// let cache = std::env::temp_dir().join(format!("acceptance_{}", std::process::id()));
+/// let _ = std::fs::remove_dir_all(&cache);
+/// let results = run(os, &cache, &Driver::all()).unwrap();
+/// for result in &results {
+///     assert!(result.passed(), "{result}");
+/// }
+/// assert_eq!(results.len(), 3);
+/// assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+///
+/// let wrong = Driver::parse("Wrong", "// expect static 0 = 2\nfunction Main.main 0\npush constant 1\npop static 0\npush constant 0\nreturn\n");
+/// let error = Driver::parse("Error", "// expect static 0 = 0\nfunction Main.main 0\npush constant 7\ncall Sys.error 1\nreturn\n");
+/// let results = run(os, &cache, &[wrong.unwrap(), error.unwrap()]).unwrap();
+/// assert_eq!(results[0].failure.as_deref(), Some("static 0 is 1, expected 2"));
+/// assert_eq!(results[1].to_string(), "Error: FAILED: the OS called Sys.error(7)");
+/// assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+///
+/// assert_eq!(Driver::parse("Bad", "// expect static 0 = two\n").unwrap_err(), "Bad line 1: expected '// expect static <i> = <value>'");
+/// assert!(run(Path::new("tests/os-suite/nowhere"), &cache, &[]).is_err());
+/// std::fs::remove_dir_all(&cache).unwrap();
+/// ```
+pub fn run(os_dir: &Path, cache_dir: &Path, drivers: &[Driver]) -> Result<Vec<DriverResult>, String> {
+    let os = translate_os(os_dir, cache_dir)?;
+    Ok(drivers.iter().map(|driver| run_driver(&os, driver)).collect())
+}
+
+/// The OS translated without a bootstrap, for linking under each driver.
+/// It's kept in `cache_dir` under a hash of its files and the translator.
+fn translate_os(os_dir: &Path, cache_dir: &Path) -> Result<String, String> {
+    let files = vm::discover_sources(os_dir).map_err(|e| format!("Error reading {}: {e}", os_dir.display()))?;
+    if files.is_empty() {
+        return Err(format!("{} has no .vm files", os_dir.display()));
+    }
+    let mut translator = Translator::new();
+    let mut key = format!("{TRANSLATOR_VERSION}\n{CODEGEN_SOURCE}").into_bytes();
+    for file in &files {
+        let text = fs::read_to_string(file).map_err(|e| format!("Error reading {}: {e}", file.display()))?;
+        key.extend(file.file_name().unwrap_or_default().as_encoded_bytes());
+        key.extend(text.as_bytes());
+        translator.add_source(file, text).map_err(|errors| errors[0].to_string())?;
+    }
+    let cached = cache_dir.join(format!("os-{}.asm", &sha256::hex_digest(&key)[..16]));
+    if let Ok(asm) = fs::read_to_string(&cached) {
+        return Ok(asm);
+    }
+
+    let mut options = CodegenOptions::default();
+    options.bootstrap.mode = BootstrapMode::Never;
+    let asm = translator.finish(&options).map_err(|errors| errors[0].to_string())?.asm;
+    // Without a cache it's only slower.
+    let _ = fs::create_dir_all(cache_dir).and_then(|()| fs::write(&cached, &asm));
+    Ok(asm)
+}
+
+fn run_driver(os: &str, driver: &Driver) -> DriverResult {
+    let mut result = DriverResult { name: driver.name.clone(), failure: None, rom_words: 0, instructions: 0 };
+    if let Err(failure) = run_linked(os, driver, &mut result) {
+        result.failure = Some(failure);
+    }
+    result
+}
+
+fn run_linked(os: &str, driver: &Driver, result: &mut DriverResult) -> Result<(), String> {
+    let mut translator = Translator::new();
+    translator.add_source("Main.vm", driver.source.as_str()).map_err(|errors| errors[0].to_string())?;
+    let main = translator.finish(&CodegenOptions::default()).map_err(|errors| errors[0].to_string())?;
+    let fragments = vec![
+        Fragment { name: "OS".to_string(), text: os.to_string() },
+        Fragment { name: "Main.asm".to_string(), text: main.asm },
+    ];
+    let program = link::link(fragments).map_err(|errors| errors.join("; "))?;
+
+    let stack_base = MemoryMap::default().stack_base;
+    let variables = asm::variable_allocation(&program);
+    if let Some((first, _)) = variables.iter().find(|(_, address)| *address >= stack_base) {
+        return Err(format!("{} variables don't fit below the stack (first: {first})", variables.len()));
+    }
+    let rom = assemble(&program).map_err(|errors| errors[0].to_string())?;
+    result.rom_words = rom.words.len();
+    if rom.words.len() > asm::ROM_SIZE {
+        return Err(format!("{} instructions don't fit in {} ROM words", rom.words.len(), asm::ROM_SIZE));
+    }
+
+    let symbol = |name: &str, kind: SymbolKind| rom.symbols.iter().find(|s| s.name == name && s.kind == kind).map(|s| s.address as usize);
+    let halt = symbol("Sys.halt", SymbolKind::Label).ok_or("the OS has no Sys.halt")?;
+    let error = symbol("Sys.error", SymbolKind::Label);
+    let mut cpu = Cpu::new();
+    while cpu.pc != halt {
+        if Some(cpu.pc) == error {
+            let argument = cpu.ram[cpu.ram[2] as u16 as usize];
+            return Err(format!("the OS called Sys.error({argument})"));
+        }
+        if result.instructions == MAX_INSTRUCTIONS {
+            return Err(format!("still running after {MAX_INSTRUCTIONS} instructions"));
+        }
+        if !cpu.step(&rom) {
+            return Err(format!("ran off the end of the program at ROM[{}]", cpu.pc));
+        }
+        result.instructions += 1;
+    }
+
+    for &(index, expected) in &driver.expected {
+        let address = symbol(&format!("Main.{index}"), SymbolKind::Variable).ok_or(format!("static {index} is never used"))?;
+        let value = cpu.ram[address];
+        if value != expected {
+            return Err(format!("static {index} is {value}, expected {expected}"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// How deeply response files may name further response files.
+const MAX_DEPTH: usize = 8;
+
+/// Replaces every `@file` argument with the arguments listed in `file`,
+/// so long input lists don't have to fit on the command line. Tokens are
+/// separated by whitespace, `#` starts a comment running to the end of the
+/// line, and single or double quotes keep spaces inside a path. The
+/// program name in `args[0]` and everything after `--` are left alone.
+///
+/// ```
+/// use hack_vmtranslator::argfile::expand;
+/// use std::fs;
+///
+/// let dir = std::env::temp_dir().join(format!("hack_argfile_{}", std::process::id()));
+/// fs::create_dir_all(&dir).unwrap();
+/// let file = |name: &str, text: &str| {
+///     let path = dir.join(name);
+///     fs::write(&path, text).unwrap();
+///     format!("@{}", path.display())
+/// };
+/// let args = |list: &[&str]| -> Vec<String> { list.iter().map(|s| s.to_string()).collect() };
+///
+/// // Spliced in where it stands, with comments dropped and quotes kept together.
+/// let flags = file("flags.txt", "--summary # quiet\n--prelude 'my pre.asm'\n\"Main Dir\"\n");
+/// assert_eq!(
+///     expand(args(&["hack", "-W", "all", &flags, "-D", "x"])).unwrap(),
+///     ["hack", "-W", "all", "--summary", "--prelude", "my pre.asm", "Main Dir", "-D", "x"]
+/// );
+///
+/// // Response files can name further response files.
+/// let outer = file("outer.txt", &format!("--summary {flags} Sys.vm"));
+/// assert_eq!(
+///     expand(args(&["hack", &outer])).unwrap(),
+///     ["hack", "--summary", "--summary", "--prelude", "my pre.asm", "Main Dir", "Sys.vm"]
+/// );
+///
+/// // ...but not themselves, however indirectly.
+/// let a = dir.join("a.txt");
+/// let b = file("b.txt", &format!("@{}", a.display()));
+/// let a = file("a.txt", &format!("-W all {b}"));
+/// assert!(expand(args(&["hack", &a])).unwrap_err().ends_with("includes itself"));
+///
+/// // Neither the program name nor what follows `--` is a response file,
+/// // even when `--` comes from one.
+/// let rest = file("rest.txt", "--summary -- @literal");
+/// assert_eq!(expand(args(&["@hack", "--", &flags])).unwrap(), ["@hack", "--", flags.as_str()]);
+/// assert_eq!(
+///     expand(args(&["hack", &rest, &flags])).unwrap(),
+///     ["hack", "--summary", "--", "@literal", flags.as_str()]
+/// );
+/// assert_eq!(
+///     expand(args(&["hack", "@missing.txt"])).unwrap_err().split(':').next(),
+///     Some("Error reading response file missing.txt")
+/// );
+/// fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn expand(args: Vec<String>) -> Result<Vec<String>, String> {
+    let mut args = args.into_iter();
+    let mut expanded: Vec<String> = args.next().into_iter().collect();
+    expand_into(args.collect(), &mut expanded, &mut Vec::new(), &mut false)?;
+    Ok(expanded)
+}
+
+fn expand_into(
+    args: Vec<String>,
+    expanded: &mut Vec<String>,
+    open: &mut Vec<PathBuf>,
+    literal: &mut bool,
+) -> Result<(), String> {
+    for arg in args {
+        let file = match arg.strip_prefix('@') {
+            Some(file) if !*literal => file,
+            _ => {
+                *literal |= arg == "--";
+                expanded.push(arg);
+                continue;
+            }
+        };
+
+        let path = fs::canonicalize(file)
+            .map_err(|e| format!("Error reading response file {file}: {e}"))?;
+        if open.contains(&path) {
+            return Err(format!("Response file {file} includes itself"));
+        }
+        if open.len() == MAX_DEPTH {
+            return Err(format!(
+                "Response files nested more than {MAX_DEPTH} deep at {file}"
+            ));
+        }
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Error reading response file {file}: {e}"))?;
+        let tokens = tokenize(&text).map_err(|e| format!("In response file {file}: {e}"))?;
+        open.push(path);
+        expand_into(tokens, expanded, open, literal)?;
+        open.pop();
+    }
+    Ok(())
+}
+
+/// Splits `text` the way a response file is split.
+///
+/// ```
+/// use hack_vmtranslator::argfile::tokenize;
+///
+/// assert_eq!(
+///     tokenize("a  'b c'\td\"e f\"g # h i\n'#j'").unwrap(),
+///     ["a", "b c", "de fg", "#j"]
+/// );
+/// assert_eq!(tokenize("''").unwrap(), [""]);
+/// assert_eq!(tokenize("\"a b").unwrap_err(), "unterminated \" quote");
+/// ```
+pub fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            chars.by_ref().find(|&c| c == '\n');
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                chars.next();
+                if c == '"' || c == '\'' {
+                    let quote = c;
+                    loop {
+                        match chars.next() {
+                            Some(c) if c == quote => break,
+                            Some(c) => token.push(c),
+                            None => return Err(format!("unterminated {quote} quote")),
+                        }
+                    }
+                } else {
+                    token.push(c);
+                }
+            }
+            tokens.push(token);
+        }
+    }
+
+    Ok(tokens)
+}
@@ -1,122 +1,2133 @@
-use crate::vm::{Command, Segment, SourceCommand};
+use crate::callgraph::function_bodies;
+use crate::diagnostic::{excerpt, Diagnostic};
+use crate::filter;
+use crate::json::Json;
+use crate::memory::MemoryMap;
+use crate::vm::{Command, Segment, Source, SourceCommand, SourceItem, Trivia};
+use snippets::{pop_d, push_d, push_symbol};
 use indoc::formatdoc;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::str::FromStr;
 
-pub fn generate_code(commands: Vec<SourceCommand>) -> Result<Vec<String>, String> {
-    let mut scope: Vec<String> = Vec::new();
-    let mut should_bootstrap = false;
+pub mod snippets;
 
-    let mut instructions =
-        commands
-        .iter()
-        .map(|source_command|{
-            if let Command::Function {name: function, nvars: _} = source_command.command() {
-                should_bootstrap = should_bootstrap || *function == "Sys.init";
-                scope.push(format!("{function}"));
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// Prepended to every symbol the translator defines, so separately
+    /// translated programs can be concatenated without collisions.
+    /// Setting a prefix suppresses the bootstrap.
+    ///
+    #[cfg_attr(feature = "assembler", doc = "```")]
+    #[cfg_attr(not(feature = "assembler"), doc = "```ignore")]
+    /// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+    /// use hack_vmtranslator::assembler::{assemble, SymbolKind};
+    /// use hack_vmtranslator::link::check_duplicate_labels;
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// // Two programs with the same function, file and label names, the
+    /// // second also defining `Lib.f`, which the first calls.
+    /// let main = "function Main.main 0\npush constant 1\npush constant 2\neq\npop static 0\n\
+    ///             label LOOP\ncall Lib.f 0\ngoto LOOP\n";
+    /// let lib = "function Lib.f 0\npush static 0\nreturn\n";
+    /// let translate = |sources: &[Source], prefix: Option<&str>, externs: &[&str]| {
+    ///     let options = CodegenOptions {
+    ///         symbol_prefix: prefix.map(str::to_string),
+    ///         externs: externs.iter().map(|e| e.to_string()).collect(),
+    ///         ..CodegenOptions::default()
+    ///     };
+    ///     let commands = parse_sources(sources).into_iter().map(Result::unwrap).collect();
+    ///     generate_code(commands, &options).unwrap().join("\n")
+    /// };
+    /// let first = [Source::new("Main.vm", main)];
+    /// let second = [Source::new("Main.vm", main), Source::new("Lib.vm", lib)];
+    ///
+    /// // Unprefixed, the two collide.
+    /// let (a, b) = (translate(&first, None, &[]), translate(&second, None, &[]));
+    /// assert!(check_duplicate_labels([("a.asm", a.as_str()), ("b.asm", b.as_str())].into_iter()).is_err());
+    ///
+    /// // Prefixed, they don't. `Lib.f` is extern to the second program, so
+    /// // its label keeps the name the first program calls, and the first
+    /// // program leaves its call alone as it doesn't define `Lib.f`.
+    /// let a = translate(&first, Some("a_"), &[]);
+    /// let b = translate(&second, Some("b_"), &["Lib.*"]);
+    /// assert!(a.contains("@Lib.f\n") && !a.contains("a_Lib.f"));
+    /// assert!(b.contains("(Lib.f)\n") && b.contains("(b_Main.main)\n"));
+    /// check_duplicate_labels([("a.asm", a.as_str()), ("b.asm", b.as_str())].into_iter()).unwrap();
+    ///
+    /// let program = assemble(&format!("{a}\n{b}\n")).unwrap();
+    /// let label = |name: &str| program.symbols.iter().any(|s| s.name == name && s.kind == SymbolKind::Label);
+    /// let variable = |name: &str| program.symbols.iter().any(|s| s.name == name && s.kind == SymbolKind::Variable);
+    /// assert!(label("a_Main.main") && label("b_Main.main") && label("Lib.f"));
+    /// assert!(label("a_Main.main$LOOP") && label("b_Main.main$LOOP"));
+    /// // Each program keeps its own statics.
+    /// assert!(variable("a_Main.0") && variable("b_Main.0") && !variable("Lib.f"));
+    /// ```
+    pub symbol_prefix: Option<String>,
+    /// Function name patterns (see `filter`) that are never prefixed,
+    /// whether they are defined here or in another program.
+    pub externs: Vec<String>,
+    pub bootstrap: BootstrapOptions,
+    /// Where the `pointer` and `temp` segments live.
+    pub memory: MemoryMap,
+    /// Used by `generate_code_with_trivia` to copy comments that stand on
+    /// their own line in the VM source into the output.
+    pub keep_comments: bool,
+    /// How return and comparison labels are numbered.
+    pub label_scheme: LabelScheme,
+    /// Makes `gt` and `lt` compare the operands' signs before
+    /// subtracting. The short form jumps on the sign of `x - y`, which is
+    /// wrong when the subtraction overflows, as `20000 gt -20000` does;
+    /// the course's reference translator has the same bug, so the short
+    /// form stays the default. The interpreter always compares correctly,
+    /// so running a program both ways shows the difference only when this
+    /// is off.
+    ///
+    #[cfg_attr(all(feature = "assembler", feature = "interp"), doc = "```")]
+    #[cfg_attr(not(all(feature = "assembler", feature = "interp")), doc = "```ignore")]
+    /// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+    /// use hack_vmtranslator::assembler::{assemble, Cpu};
+    /// use hack_vmtranslator::interp::{Config, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let values: [i16; 9] = [0, 1, -1, 12, 20000, -20000, 32767, -32767, -32768];
+    /// let push = |v: i16| match v {
+    ///     -32768 => "push constant 32767\nneg\npush constant 1\nsub\n".to_string(),
+    ///     v if v < 0 => format!("push constant {}\nneg\n", -v),
+    ///     v => format!("push constant {v}\n"),
+    /// };
+    /// for safe_compare in [false, true] {
+    ///     let options = CodegenOptions { safe_compare, ..CodegenOptions::default() };
+    ///     let mut wrong = Vec::new();
+    ///     for x in values {
+    ///         for y in values {
+    ///             for (op, holds) in [("eq", x == y), ("gt", x > y), ("lt", x < y)] {
+    ///                 let sources = [Source::new("Main.vm", format!("{}{}{op}\n", push(x), push(y)))];
+    ///                 let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    ///                 let expected = -(holds as i16);
+    ///
+    ///                 let mut vm = Vm::new(&program, Config::default());
+    ///                 vm.run(&Limits::default()).unwrap();
+    ///                 assert_eq!(vm.ram()[256], expected, "the interpreter: {x} {op} {y}");
+    ///
+    ///                 let asm = generate_code(program.clone(), &options).unwrap().join("\n");
+    ///                 let mut cpu = Cpu::new();
+    ///                 cpu.ram[0] = 256;
+    ///                 cpu.run(&assemble(&asm).unwrap(), 1000);
+    ///                 assert_eq!(cpu.ram[0], 257);
+    ///                 if cpu.ram[256] != expected {
+    ///                     wrong.push((x, op, y));
+    ///                 }
+    ///             }
+    ///         }
+    ///     }
+    ///     if safe_compare {
+    ///         assert_eq!(wrong, []);
+    ///     } else {
+    ///         assert!(wrong.contains(&(20000, "gt", -20000)));
+    ///         assert!(wrong.contains(&(-32768, "lt", 1)));
+    ///         // Only where the signs differ and the difference overflows.
+    ///         for (x, _, y) in wrong {
+    ///             assert!((x as i32 - y as i32) != (x.wrapping_sub(y)) as i32, "{x} {y}");
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub safe_compare: bool,
+    /// How much each command's header comment says; see `Comments`.
+    pub comments: Comments,
+    /// Makes every function check, once its locals are pushed, that the
+    /// stack hasn't grown past `memory.stack_limit` into the heap, and
+    /// every `return` that its function left a value above its locals.
+    /// A failed check jumps to a halt loop, `STACK_OVERFLOW` or
+    /// `STACK_UNDERFLOW`, instead of running on with a corrupt stack.
+    ///
+    #[cfg_attr(feature = "assembler", doc = "```")]
+    #[cfg_attr(not(feature = "assembler"), doc = "```ignore")]
+    /// use hack_vmtranslator::asm::{generate_code, label_addresses, CodegenOptions};
+    /// use hack_vmtranslator::assembler::{assemble, Cpu};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let run = |main: &str, stack_checks: bool| {
+    ///     let sources = [
+    ///         Source::new("Sys.vm", "function Sys.init 0\ncall Main.f 0\nlabel END\ngoto END\n"),
+    ///         Source::new("Main.vm", main),
+    ///     ];
+    ///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    ///     let options = CodegenOptions { stack_checks, ..CodegenOptions::default() };
+    ///     let asm = generate_code(commands, &options).unwrap().join("\n");
+    ///     let mut cpu = Cpu::new();
+    ///     cpu.run(&assemble(&asm).unwrap(), 100_000);
+    ///     // Each halt loop is a label and a jump back to it.
+    ///     let halted_at = label_addresses(&asm).into_iter().rev().find(|(_, at)| (*at..*at + 2).contains(&cpu.pc));
+    ///     halted_at.map(|(label, _)| label.to_string())
+    /// };
+    ///
+    /// // Recursion with no way out, and a return with nothing to return.
+    /// let forever = "function Main.f 1\ncall Main.f 0\nreturn\n";
+    /// let empty = "function Main.f 2\nreturn\n";
+    /// assert_eq!(run(forever, true).as_deref(), Some("STACK_OVERFLOW"));
+    /// assert_eq!(run(empty, true).as_deref(), Some("STACK_UNDERFLOW"));
+    /// // Unchecked, the second returns its last local as if it were the
+    /// // value.
+    /// assert_eq!(run(empty, false).as_deref(), Some("Sys.init$END"));
+    /// assert_eq!(run("function Main.f 2\npush constant 1\nreturn\n", true).as_deref(), Some("Sys.init$END"));
+    /// ```
+    pub stack_checks: bool,
+}
+
+impl CodegenOptions {
+    /// The options as an object, with the bootstrap and the memory map
+    /// nested in their own members. `from_json` reads it back.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{BootstrapMode, BootstrapStyle, CodegenOptions, Comments, LabelScheme};
+    /// use hack_vmtranslator::json::Json;
+    ///
+    /// let mut options = CodegenOptions {
+    ///     symbol_prefix: Some("lib_".to_string()),
+    ///     externs: vec!["Sys.*".to_string()],
+    ///     keep_comments: true,
+    ///     label_scheme: LabelScheme::Lines,
+    ///     safe_compare: true,
+    ///     comments: Comments::Annotated,
+    ///     stack_checks: true,
+    ///     ..CodegenOptions::default()
+    /// };
+    /// options.bootstrap.sentinels = false;
+    /// options.bootstrap.mode = BootstrapMode::Never;
+    /// options.bootstrap.style = BootstrapStyle::Minimal;
+    /// options.memory.temp_base = 6;
+    /// options.memory.temp_size = 7;
+    /// for options in [CodegenOptions::default(), options] {
+    ///     let text = options.to_json().pretty();
+    ///     assert_eq!(CodegenOptions::from_json(&Json::parse(&text).unwrap()).unwrap(), options);
+    /// }
+    ///
+    /// // Members left out keep their default.
+    /// let json = Json::parse("{\"label_scheme\": \"lines\", \"bootstrap\": {\"entry\": \"Main.main\"}}").unwrap();
+    /// let options = CodegenOptions::from_json(&json).unwrap();
+    /// assert_eq!((options.label_scheme, options.bootstrap.entry.as_str()), (LabelScheme::Lines, "Main.main"));
+    /// assert!(options.bootstrap.sentinels);
+    ///
+    /// let error = |text: &str| CodegenOptions::from_json(&Json::parse(text).unwrap()).unwrap_err();
+    /// assert_eq!(error("{\"label_sheme\": \"lines\"}"), "Unknown option 'label_sheme'");
+    /// assert_eq!(error("{\"keep_comments\": 1}"), "keep_comments must be true or false");
+    /// assert_eq!(error("{\"memory\": {\"temp_base\": 70000}}"), "temp_base must be an address from 0 to 65535, got 70000");
+    /// ```
+    pub fn to_json(&self) -> Json {
+        let mut members = Vec::new();
+        if let Some(prefix) = &self.symbol_prefix {
+            members.push(("symbol_prefix", Json::from(prefix.as_str())));
+        }
+        members.extend([
+            ("externs", Json::Array(self.externs.iter().map(|e| Json::from(e.as_str())).collect())),
+            (
+                "bootstrap",
+                Json::object(vec![
+                    ("stack_base", Json::from(self.bootstrap.stack_base as usize)),
+                    ("entry", Json::from(self.bootstrap.entry.as_str())),
+                    ("extern_entry", Json::from(self.bootstrap.extern_entry)),
+                    ("sentinels", Json::from(self.bootstrap.sentinels)),
+                    ("mode", Json::from(self.bootstrap.mode.to_string())),
+                    ("style", Json::from(self.bootstrap.style.to_string())),
+                ]),
+            ),
+            ("memory", self.memory.to_json()),
+            ("keep_comments", Json::from(self.keep_comments)),
+            ("label_scheme", Json::from(self.label_scheme.to_string())),
+            ("safe_compare", Json::from(self.safe_compare)),
+            ("comments", Json::from(self.comments.to_string())),
+            ("stack_checks", Json::from(self.stack_checks)),
+        ]);
+        Json::object(members)
+    }
+
+    pub fn from_json(json: &Json) -> Result<CodegenOptions, String> {
+        let members = |json: &Json, what: &str| match json {
+            Json::Object(members) => Ok(members.clone()),
+            _ => Err(format!("{what} must be an object")),
+        };
+        let string = |key: &str, value: &Json| value.as_str().map(str::to_string).ok_or(format!("{key} must be a string"));
+        let flag = |key: &str, value: &Json| match value {
+            Json::Bool(b) => Ok(*b),
+            _ => Err(format!("{key} must be true or false")),
+        };
+
+        let mut options = CodegenOptions::default();
+        for (key, value) in members(json, "the options")? {
+            match key.as_str() {
+                "symbol_prefix" => options.symbol_prefix = Some(string(&key, &value)?),
+                "externs" => {
+                    options.externs = value
+                        .as_array()
+                        .ok_or(format!("{key} must be an array"))?
+                        .iter()
+                        .map(|e| string(&key, e))
+                        .collect::<Result<_, _>>()?;
+                }
+                "bootstrap" => {
+                    for (key, value) in members(&value, "bootstrap")? {
+                        let bootstrap = &mut options.bootstrap;
+                        match key.as_str() {
+                            "stack_base" => {
+                                bootstrap.stack_base = match value {
+                                    Json::Number(n) => u16::try_from(n)
+                                        .map_err(|_| format!("{key} must be an address from 0 to 65535, got {n}"))?,
+                                    _ => return Err(format!("{key} must be a number")),
+                                }
+                            }
+                            "entry" => bootstrap.entry = string(&key, &value)?,
+                            "extern_entry" => bootstrap.extern_entry = flag(&key, &value)?,
+                            "sentinels" => bootstrap.sentinels = flag(&key, &value)?,
+                            "mode" => bootstrap.mode = string(&key, &value)?.parse()?,
+                            "style" => bootstrap.style = string(&key, &value)?.parse()?,
+                            _ => return Err(format!("Unknown bootstrap option '{key}'")),
+                        }
+                    }
+                }
+                "memory" => options.memory = MemoryMap::from_json(&value)?,
+                "keep_comments" => options.keep_comments = flag(&key, &value)?,
+                "label_scheme" => options.label_scheme = string(&key, &value)?.parse()?,
+                "safe_compare" => options.safe_compare = flag(&key, &value)?,
+                "comments" => options.comments = string(&key, &value)?.parse()?,
+                "stack_checks" => options.stack_checks = flag(&key, &value)?,
+                _ => return Err(format!("Unknown option '{key}'")),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Checks that the options make sense together. Each field is valid on
+    /// its own by construction; these are the combinations that translate
+    /// without complaint but not to what was meant.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{BootstrapMode, CodegenOptions};
+    /// use hack_vmtranslator::diagnostic::Severity;
+    ///
+    /// assert!(CodegenOptions::default().validate().is_empty());
+    ///
+    /// let mut options = CodegenOptions::default();
+    /// options.memory.temp_size = 12;
+    /// options.bootstrap.stack_base = 300;
+    /// options.bootstrap.entry = String::new();
+    /// options.symbol_prefix = Some("1st".to_string());
+    /// let found: Vec<_> = options.validate().into_iter().map(|d| (d.severity, d.message)).collect();
+    /// assert_eq!(
+    ///     found,
+    ///     [
+    ///         (Severity::Error, "Invalid memory map: temp (5-16) overlaps R13-R15 (13-15)".to_string()),
+    ///         (Severity::Warning, "The bootstrap starts the stack at 300, but the memory map puts it at 256".to_string()),
+    ///         (Severity::Error, "The entry function can't be empty".to_string()),
+    ///         (Severity::Error, "The symbol prefix '1st' isn't a valid start of a symbol".to_string()),
+    ///     ]
+    /// );
+    ///
+    /// options = CodegenOptions { symbol_prefix: Some("lib_".to_string()), ..CodegenOptions::default() };
+    /// options.bootstrap.extern_entry = true;
+    /// assert_eq!(
+    ///     options.validate()[0].message,
+    ///     "A symbol prefix leaves out the bootstrap, so extern_entry has no effect"
+    /// );
+    /// options.bootstrap.mode = BootstrapMode::Always;
+    /// assert_eq!(options.validate()[1].severity, Severity::Error);
+    /// ```
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if let Err(e) = self.memory.validate() {
+            diagnostics.push(Diagnostic::error("Error", format!("Invalid memory map: {e}")));
+        }
+        if self.bootstrap.stack_base != self.memory.stack_base {
+            diagnostics.push(Diagnostic::warning(
+                "Warning",
+                format!(
+                    "The bootstrap starts the stack at {}, but the memory map puts it at {}",
+                    self.bootstrap.stack_base, self.memory.stack_base
+                ),
+            ));
+        }
+        if self.bootstrap.entry.is_empty() {
+            diagnostics.push(Diagnostic::error("Error", "The entry function can't be empty"));
+        }
+        if let Some(prefix) = &self.symbol_prefix {
+            let valid = !prefix.starts_with(|c: char| c.is_ascii_digit())
+                && prefix.chars().all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c));
+            if !valid {
+                diagnostics.push(Diagnostic::error(
+                    "Error",
+                    format!("The symbol prefix '{prefix}' isn't a valid start of a symbol"),
+                ));
+            }
+            if self.bootstrap.extern_entry {
+                diagnostics.push(Diagnostic::warning(
+                    "Warning",
+                    "A symbol prefix leaves out the bootstrap, so extern_entry has no effect",
+                ));
+            }
+            if self.bootstrap.mode == BootstrapMode::Always {
+                diagnostics.push(Diagnostic::error(
+                    "Error",
+                    "A symbol prefix leaves out the bootstrap, so it can't also be always emitted",
+                ));
+            }
+        } else if self.bootstrap.mode == BootstrapMode::Never && self.bootstrap.extern_entry {
+            diagnostics.push(Diagnostic::warning(
+                "Warning",
+                "The bootstrap is never emitted, so extern_entry has no effect",
+            ));
+        }
+        diagnostics
+    }
+}
+
+/// How ROM addresses are written by `annotate_addresses`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFormat {
+    Hex,
+    Decimal,
+}
+
+impl FromStr for AddressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AddressFormat, String> {
+        match s {
+            "hex" => Ok(AddressFormat::Hex),
+            "dec" => Ok(AddressFormat::Decimal),
+            _ => Err(format!("Unknown address format: '{}' (expected hex or dec)", s)),
+        }
+    }
+}
+
+impl fmt::Display for AddressFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressFormat::Hex => write!(f, "hex"),
+            AddressFormat::Decimal => write!(f, "dec"),
+        }
+    }
+}
+
+/// How the labels the translator makes up for return addresses and
+/// comparisons are numbered.
+///
+/// `Counters` follows the course's reference translator: the return
+/// address of a function's i-th call is `function$ret.i`, counting from 0,
+/// and its comparisons are numbered the same way on their own. Only an
+/// edit to a function can rename its labels. `Lines` is the scheme this
+/// translator used before, numbering both by source line, so an edit near
+/// the top of a file renames every such label below it.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, label_addresses, CodegenOptions, LabelScheme};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let labels = |text: &str, label_scheme: LabelScheme| {
+///     let sources = [Source::new("Main.vm", text)];
+///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     let options = CodegenOptions { label_scheme, ..CodegenOptions::default() };
+///     let asm = generate_code(commands, &options).unwrap().join("\n");
+///     label_addresses(&asm).into_iter().map(|(label, _)| label.to_string()).collect::<Vec<_>>()
+/// };
+/// let text = "function Main.f 0\npush constant 1\npush constant 2\nlt\ncall Main.g 0\nreturn\n\
+///             function Main.g 0\ncall Main.f 0\neq\ncall Main.f 0\ncall Main.f 0\nreturn\n";
+/// let edited = format!("// a new comment\n{text}");
+///
+/// assert_eq!(
+///     labels(text, LabelScheme::Counters),
+///     [
+///         "Main.f", "Main.f$cmp.0", "Main.f$cmp.0.end", "Main.f$ret.0",
+///         "Main.g", "Main.g$ret.0", "Main.g$cmp.0", "Main.g$cmp.0.end", "Main.g$ret.1", "Main.g$ret.2",
+///     ]
+/// );
+/// assert_eq!(
+///     labels(text, LabelScheme::Lines),
+///     [
+///         "Main.f", "COMP_TRUE_Main.3", "COMP_END_Main.3", "Main.f$ret.4",
+///         "Main.g", "Main.g$ret.7", "COMP_TRUE_Main.8", "COMP_END_Main.8", "Main.g$ret.9", "Main.g$ret.10",
+///     ]
+/// );
+/// assert_eq!(labels(&edited, LabelScheme::Counters), labels(text, LabelScheme::Counters));
+/// assert_ne!(labels(&edited, LabelScheme::Lines), labels(text, LabelScheme::Lines));
+/// assert_eq!(LabelScheme::default(), LabelScheme::Counters);
+///
+/// // A VM label can't take the name of one the translator makes up in
+/// // the same function.
+/// let sources = [Source::new("Main.vm", "function Main.f 0\nlabel cmp.0\npush constant 1\npush constant 2\neq\ngoto cmp.0\n")];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// assert_eq!(
+///     generate_code(commands.clone(), &CodegenOptions::default()).unwrap_err(),
+///     "The label name 'cmp.0' is reserved for the translator's own labels"
+/// );
+/// let options = CodegenOptions { label_scheme: LabelScheme::Lines, ..CodegenOptions::default() };
+/// assert!(generate_code(commands, &options).is_ok());
+/// assert_eq!(labels("function Main.f 0\nlabel cmp.0a\nlabel ret.x\n", LabelScheme::Counters), ["Main.f", "Main.f$cmp.0a", "Main.f$ret.x"]);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelScheme {
+    /// Numbered by source line.
+    Lines,
+    /// Numbered per function in order of appearance, calls and
+    /// comparisons separately.
+    #[default]
+    Counters,
+}
+
+impl FromStr for LabelScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LabelScheme, String> {
+        match s {
+            "lines" => Ok(LabelScheme::Lines),
+            "counters" => Ok(LabelScheme::Counters),
+            _ => Err(format!("Unknown label scheme: '{s}' (expected lines or counters)")),
+        }
+    }
+}
+
+impl fmt::Display for LabelScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelScheme::Lines => write!(f, "lines"),
+            LabelScheme::Counters => write!(f, "counters"),
+        }
+    }
+}
+
+/// What goes in the comment ahead of each command's code.
+///
+/// `Headers` names the file, line and command, which is what `lift`,
+/// `DebugInfo::from_asm` and `lockstep` read. `Annotated` adds the
+/// function the command is in (its file, outside functions) and how many
+/// words its function's operand stack holds after it, not counting
+/// locals. `Off` leaves the code bare.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions, Comments};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let comments = |comments: Comments| {
+///     let sources = [Source::new("Main.vm", "function Main.f 1\npush constant 1\npush local 0\nadd\nreturn\n")];
+///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     let options = CodegenOptions { comments, ..CodegenOptions::default() };
+///     let asm = generate_code(commands, &options).unwrap().join("\n");
+///     asm.lines().filter(|line| line.starts_with("//")).map(str::to_string).collect::<Vec<_>>()
+/// };
+/// assert_eq!(comments(Comments::Off), Vec::<String>::new());
+/// assert_eq!(
+///     comments(Comments::Headers),
+///     ["// Main[0]: function Main.f 1", "// Main[1]: push constant 1", "// Main[2]: push local 0", "// Main[3]: add", "// Main[4]: return"]
+/// );
+/// assert_eq!(
+///     comments(Comments::Annotated),
+///     [
+///         "// Main[0]: function Main.f 1 // Main.f, stack 0",
+///         "// Main[1]: push constant 1 // Main.f, stack 1",
+///         "// Main[2]: push local 0 // Main.f, stack 2",
+///         "// Main[3]: add // Main.f, stack 1",
+///         "// Main[4]: return // Main.f, stack 1",
+///     ]
+/// );
+/// assert_eq!("annotated".parse::<Comments>().unwrap().to_string(), "annotated");
+/// assert_eq!(Comments::default(), Comments::Headers);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Comments {
+    Off,
+    #[default]
+    Headers,
+    Annotated,
+}
+
+impl FromStr for Comments {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Comments, String> {
+        match s {
+            "off" => Ok(Comments::Off),
+            "headers" => Ok(Comments::Headers),
+            "annotated" => Ok(Comments::Annotated),
+            _ => Err(format!("Unknown comment level: '{s}' (expected off, headers or annotated)")),
+        }
+    }
+}
+
+impl fmt::Display for Comments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Comments::Off => write!(f, "off"),
+            Comments::Headers => write!(f, "headers"),
+            Comments::Annotated => write!(f, "annotated"),
+        }
+    }
+}
+
+pub fn generate_code(commands: Vec<SourceCommand>, options: &CodegenOptions) -> Result<Vec<String>, String> {
+    let annotations = Annotations::new(commands.len());
+    generate_program(commands, annotations, options)
+}
+
+/// `generate_code`, written to `out` a command at a time rather than
+/// collected, so the program is never held whole. The bytes are those of
+/// `generate_code(..)?.join("\n")`. An error leaves `out` with the code
+/// before it.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, generate_code_to, CodegenOptions};
+/// use hack_vmtranslator::vm::{parse_sources, Source, SourceCommand};
+///
+/// let sources = [
+///     Source::new("Main.vm", "function Main.f 0\npush constant 1\npush constant 2\nlt\ncall Main.g 0\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.f 0\npush local 9 9\nlabel END\ngoto END\n"),
+/// ];
+/// // A line that failed to parse becomes the trap, which goes at the end.
+/// let commands: Vec<SourceCommand> = parse_sources(&sources)
+///     .into_iter()
+///     .map(|command| command.unwrap_or_else(|e| SourceCommand::trap(&e)))
+///     .collect();
+/// let options = CodegenOptions::default();
+/// let mut out = Vec::new();
+/// generate_code_to(&commands, &options, &mut out).unwrap();
+/// assert_eq!(String::from_utf8(out).unwrap(), generate_code(commands.clone(), &options).unwrap().join("\n"));
+///
+/// let mut full = [0u8; 64];
+/// assert_eq!(
+///     generate_code_to(&commands, &options, &mut &mut full[..]).unwrap_err(),
+///     "Error writing the assembly: failed to write whole buffer"
+/// );
+/// assert!(full.starts_with(b"@256\nD=A\n"));
+/// ```
+pub fn generate_code_to<W: Write>(commands: &[SourceCommand], options: &CodegenOptions, out: &mut W) -> Result<(), String> {
+    let mut separator: &[u8] = b"";
+    emit_program(commands, &Annotations::new(commands.len()), options, |_, code| {
+        out.write_all(separator)
+            .and_then(|()| out.write_all(code.as_bytes()))
+            .map_err(|e| format!("Error writing the assembly: {e}"))?;
+        separator = b"\n";
+        Ok(())
+    })
+}
+
+/// Like `generate_code_with_trivia`, but for commands that may have been
+/// reordered, filtered or spliced together since they were parsed from
+/// `sources`. With `keep_comments` set, each comment in `sources` is
+/// copied into the output once. A comment on its own line goes ahead of
+/// the next command of its file that has code in the output, or after the
+/// file's last one; a comment trailing a command is appended to that
+/// command's `// file[line]` header. Comments of a command that produces
+/// nothing (one filtered out, or interpreter-only) move to the nearest
+/// command that does, and those of a file with no code at all are
+/// dropped.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code_with_comments, CodegenOptions};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new(
+///     "Main.vm",
+///     "// Sum two numbers\n\
+///      push constant 2 // first\n\
+///      push constant 3\n\
+///      // Shown before add, as debug.print has no code\n\
+///      debug.print // and so is this\n\
+///      // neg is dropped, so this is shown before add too\n\
+///      neg // negated\n\
+///      add\n\
+///      pop temp 0\n\
+///      // The end\n",
+/// )];
+/// let mut commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// commands.remove(3);
+/// let options = CodegenOptions { keep_comments: true, ..CodegenOptions::default() };
+/// let asm = generate_code_with_comments(commands, &sources, &options).unwrap().join("\n");
+/// let comments: Vec<&str> = asm.lines().filter(|line| line.starts_with("//")).collect();
+///
+/// assert_eq!(
+///     comments,
+///     [
+///         "// Sum two numbers",
+///         "// Main[1]: push constant 2 // first",
+///         "// Main[2]: push constant 3",
+///         "// Shown before add, as debug.print has no code",
+///         "// and so is this",
+///         "// neg is dropped, so this is shown before add too",
+///         "// negated",
+///         "// Main[7]: add",
+///         "// Main[8]: pop temp 0",
+///         "// The end",
+///     ]
+/// );
+/// ```
+pub fn generate_code_with_comments(commands: Vec<SourceCommand>, sources: &[Source], options: &CodegenOptions) -> Result<Vec<String>, String> {
+    let annotations = source_annotations(&commands, sources, options);
+    generate_program(commands, annotations, options)
+}
+
+// Where `generate_code_with_comments` puts each comment of `sources`.
+fn source_annotations<'s>(commands: &[SourceCommand], sources: &'s [Source], options: &CodegenOptions) -> Annotations<'s> {
+    let mut annotations = Annotations::new(commands.len());
+    if !options.keep_comments {
+        return annotations;
+    }
+
+    for source in sources {
+        // The file's commands that have code, by line.
+        let mut survivors: Vec<(usize, usize)> = commands
+            .iter()
+            .enumerate()
+            .filter(|(_, sc)| sc.path() == source.path && !sc.command().is_interpreter_only())
+            .map(|(i, sc)| (sc.line(), i))
+            .collect();
+        survivors.sort();
+        let Some(&(_, last)) = survivors.last() else { continue };
+
+        for (line, text) in source.text.lines().enumerate() {
+            let Some(start) = text.find("//") else { continue };
+            let comment = &text[start + 2..];
+            let next = survivors.partition_point(|(l, _)| *l < line);
+            match survivors.get(next) {
+                Some(&(l, i)) if l == line && !text[..start].trim().is_empty() => annotations.trailing[i].push(comment),
+                Some(&(_, i)) => annotations.before[i].push(comment),
+                None => annotations.after[last].push(comment),
+            }
+        }
+    }
+    annotations
+}
+
+/// Where the code for one command is in the output of
+/// `generate_with_spans`: its `// file[line]` header and instructions,
+/// without the source comments around them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpan {
+    /// The command's index in the commands translated.
+    pub index: usize,
+    pub bytes: Range<usize>,
+    /// Counting from 0.
+    pub lines: Range<usize>,
+    pub instructions: usize,
+}
+
+/// `generate_code(..)?.join("\n")`, with a span for each command that
+/// has code, in order, for tools that show what each costs. Commands with
+/// no code, the bootstrap and the parse error trap have none.
+///
+/// ```
+/// use hack_vmtranslator::asm::{expansion_size, generate_code, generate_with_spans, generate_with_spans_and_comments, is_instruction, CodegenOptions, LabelScheme};
+/// use hack_vmtranslator::vm::{parse_sources, Command, Source};
+///
+/// let sources = [
+///     Source::new("Main.vm", "// Doubles its argument\nfunction Main.double 0\npush argument 0 // x\npush argument 0\nadd\ndebug.print\nreturn\n// The end\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\npush constant 4\ncall Main.double 1\npush constant 8\neq\nlabel END\ngoto END\n"),
+/// ];
+/// let commands = || parse_sources(&sources).into_iter().map(Result::unwrap).collect::<Vec<_>>();
+/// let plain = CodegenOptions::default();
+/// let commented = CodegenOptions { keep_comments: true, label_scheme: LabelScheme::Counters, safe_compare: true, ..CodegenOptions::default() };
+/// for options in [plain, commented] {
+///     let (asm, spans) = generate_with_spans_and_comments(commands(), &sources, &options).unwrap();
+///     if !options.keep_comments {
+///         assert_eq!(asm, generate_code(commands(), &options).unwrap().join("\n"));
+///         assert_eq!(generate_with_spans(commands(), &options).unwrap(), (asm.clone(), spans.clone()));
+///     }
+///     // Everything but debug.print, in order.
+///     let indices: Vec<usize> = spans.iter().map(|span| span.index).collect();
+///     assert_eq!(indices, [0, 1, 2, 3, 5, 6, 7, 8, 9, 10, 11, 12]);
+///
+///     let lines: Vec<&str> = asm.lines().collect();
+///     let all = commands();
+///     for (span, command) in spans.iter().zip(indices.iter().map(|&i| &all[i])) {
+///         let text = &asm[span.bytes.clone()];
+///         assert_eq!(text, lines[span.lines.clone()].join("\n"));
+///         assert!(text.starts_with(&format!("// {}[{}]: {}", command.file_base(), command.line(), command.command())));
+///         assert_eq!(text.lines().filter(|line| is_instruction(line)).count(), span.instructions);
+///     }
+///     assert_eq!(spans[0].instructions, 0);
+///     assert_eq!(spans[3].instructions, expansion_size(Command::Add, &options));
+///     assert!(spans.windows(2).all(|pair| pair[0].bytes.end < pair[1].bytes.start));
+///     // The bootstrap and the comments about the code are left out.
+///     assert!(spans[0].bytes.start > asm.find("(Bootstrap$ret.0)").unwrap());
+///     if options.keep_comments {
+///         assert!(asm.contains("// Doubles its argument"));
+///         assert_eq!(lines[spans[1].lines.start], "// Main[2]: push argument 0 // x");
+///         assert!(asm[spans[4].bytes.end..].starts_with("\n// The end\n"));
+///     }
+/// }
+/// ```
+pub fn generate_with_spans(commands: Vec<SourceCommand>, options: &CodegenOptions) -> Result<(String, Vec<CommandSpan>), String> {
+    let annotations = Annotations::new(commands.len());
+    generate_spanned(&commands, &annotations, options)
+}
+
+/// `generate_with_spans`, with the comments of `sources` kept as
+/// `generate_code_with_comments` keeps them.
+pub fn generate_with_spans_and_comments(commands: Vec<SourceCommand>, sources: &[Source], options: &CodegenOptions) -> Result<(String, Vec<CommandSpan>), String> {
+    let annotations = source_annotations(&commands, sources, options);
+    generate_spanned(&commands, &annotations, options)
+}
+
+fn generate_spanned(commands: &[SourceCommand], annotations: &Annotations, options: &CodegenOptions) -> Result<(String, Vec<CommandSpan>), String> {
+    let mut asm = String::new();
+    let mut lines = 0;
+    let mut spans = Vec::new();
+    emit_program(commands, annotations, options, |command, code| {
+        if !asm.is_empty() {
+            asm.push('\n');
+            lines += 1;
+        }
+        if let Some((index, own)) = command {
+            let text = &code[own.clone()];
+            let first = lines + code[..own.start].matches('\n').count();
+            spans.push(CommandSpan {
+                index,
+                bytes: asm.len() + own.start..asm.len() + own.end,
+                lines: first..first + text.lines().count(),
+                instructions: count_instructions(text),
+            });
+        }
+        lines += code.matches('\n').count();
+        asm.push_str(&code);
+        Ok(())
+    })?;
+    Ok((asm, spans))
+}
+
+/// Like `generate_code`, but takes the output of `parse_source_with_trivia`.
+/// With `keep_comments` set, each comment that stands on its own line is
+/// emitted as an asm comment ahead of the code for the command following
+/// it, and a comment trailing a command is appended to its header.
+/// Commands must have parsed successfully.
+pub fn generate_code_with_trivia(items: Vec<SourceItem>, options: &CodegenOptions) -> Result<Vec<String>, String> {
+    let mut commands: Vec<SourceCommand> = Vec::new();
+    let mut annotations = Annotations::new(0);
+    let mut pending: Vec<&str> = Vec::new();
+    let mut last_command_line: Option<usize> = None;
+
+    for item in items {
+        match item {
+            SourceItem::Command(Ok(sc)) => {
+                last_command_line = Some(sc.line());
+                annotations.before.insert(commands.len(), std::mem::take(&mut pending));
+                annotations.after.push(Vec::new());
+                annotations.trailing.push(Vec::new());
+                commands.push(sc);
+            }
+            SourceItem::Command(Err(e)) => return Err(e.to_string()),
+            SourceItem::Trivia(Trivia::Comment(line, text)) => {
+                if options.keep_comments {
+                    if last_command_line == Some(line) {
+                        annotations.trailing[commands.len() - 1].push(text);
+                    } else {
+                        pending.push(text);
+                    }
+                }
+                last_command_line = None;
             }
+            SourceItem::Trivia(Trivia::Blank(_)) => last_command_line = None,
+        }
+    }
+    annotations.before[commands.len()] = pending;
+
+    generate_program(commands, annotations, options)
+}
 
-            generate_code_for_command(&source_command, scope.last())
-        }).collect::<Result<Vec<String>, String>>()?;
+// The source comments `generate_program` copies into the output, by
+// command index: `before[i]` go ahead of command `i`'s code, with any
+// left over at `before[commands.len()]`, `after[i]` follow it, and
+// `trailing[i]` are appended to its header.
+struct Annotations<'s> {
+    before: Vec<Vec<&'s str>>,
+    after: Vec<Vec<&'s str>>,
+    trailing: Vec<Vec<&'s str>>,
+}
 
-    if should_bootstrap {
-        instructions.insert(0, bootstrap());
+impl Annotations<'_> {
+    fn new(commands: usize) -> Self {
+        Annotations {
+            before: vec![Vec::new(); commands + 1],
+            after: vec![Vec::new(); commands],
+            trailing: vec![Vec::new(); commands],
+        }
     }
+}
 
+fn generate_program(commands: Vec<SourceCommand>, annotations: Annotations, options: &CodegenOptions) -> Result<Vec<String>, String> {
+    let mut instructions = Vec::new();
+    emit_program(&commands, &annotations, options, |_, code| {
+        instructions.push(code);
+        Ok(())
+    })?;
     Ok(instructions)
 }
 
-fn bootstrap() -> String {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(formatdoc!(
-        "@256
-        D=A
-        @SP
-        M=D
-        @LCL
-        M=-1
-        @2
-        D=-A
-        @ARG
-        M=D
-        @3
-        D=-A
-        @THIS
-        M=D
-        @4
-        D=-A
-        @THAT
-        M=D"
-    ));
+// Hands the program to `emit` a piece at a time, in order: the bootstrap,
+// which the entry function decides before any code is made, each command
+// with code or comments, comments after the last command, and the parse
+// error trap. A command's piece comes with its index and where its own
+// code is in the piece.
+fn emit_program(commands: &[SourceCommand], annotations: &Annotations, options: &CodegenOptions, mut emit: impl FnMut(Option<(usize, Range<usize>)>, String) -> Result<(), String>) -> Result<(), String> {
+    let symbols = Symbols::new(commands, options);
+    if should_bootstrap(commands, options) {
+        emit(None, generate_bootstrap(&options.bootstrap))?;
+    }
+    generate_commands(commands, annotations, &symbols, options, |i, own, code| if code.is_empty() { Ok(()) } else { emit(Some((i, own)), code) })?;
 
-    let command = Command::Call { name: "Bootstrap", nargs: 0 };
-    let sc = SourceCommand::bootstrap(command);
-    asm.push(generate_call(&sc, "Sys.init", 0, Some(&"Bootstrap".to_string())).unwrap());
+    if let Some(remaining) = annotations.before.last().filter(|c| !c.is_empty()) {
+        emit(None, source_comments(remaining).trim_end().to_string())?;
+    }
 
-    asm.join("\n")
+    if commands.iter().any(|sc| matches!(sc.command(), Command::Trap)) {
+        emit(None, parse_error_trap(&symbols))?;
+    }
+    if has_stack_checks(commands, options) {
+        emit(None, stack_check_traps(&symbols))?;
+    }
+    Ok(())
 }
 
-fn generate_code_for_command(source_command: &SourceCommand, scope: Option<&String>) -> Result<String, String> {
-    let code = match source_command.command() {
-        Command::Add => generate_add(),
-        Command::And => generate_and(),
-        Command::Eq => generate_eq(source_command),
-        Command::Gt => generate_gt(source_command),
-        Command::Lt => generate_lt(source_command),
-        Command::Neg => generate_neg(),
-        Command::Not => generate_not(),
-        Command::Or => generate_or(),
-        Command::Pop { segment, index } => generate_pop(source_command, segment, *index),
-        Command::Push { segment, index } => generate_push(source_command, segment, *index),
-        Command::Sub => generate_sub(),
-        Command::Goto(label) => generate_goto(source_command, label, scope),
-        Command::IfGoto(label) => generate_if_goto(source_command, label, scope),
-        Command::Label(label) => generate_label(source_command, label, scope),
-        Command::Call {name, nargs } => generate_call(source_command, name, *nargs, scope),
-        Command::Function { name, nvars } => generate_function(name, *nvars),
-        Command::Return => generate_return(),
+// Hands `emit` the code for each of `commands`, with its comments, in
+// order; empty for a command that has neither. Along with it go the
+// command's index and the range of the piece that is its header and
+// code, without the comments around them.
+fn generate_commands(commands: &[SourceCommand], annotations: &Annotations, symbols: &Symbols, options: &CodegenOptions, mut emit: impl FnMut(usize, Range<usize>, String) -> Result<(), String>) -> Result<(), String> {
+    // The function (or file, outside functions) being numbered under
+    // `LabelScheme::Counters`, and the next call and comparison numbers
+    // in it.
+    let mut counter: (String, usize, usize) = (String::new(), 0, 0);
+    // How deep the operand stack is, for `Comments::Annotated`.
+    let mut depth: isize = 0;
+
+    for (i, (function, source_command)) in function_bodies(commands).enumerate() {
+        let scope = function.map(str::to_string);
+        let owner = function.unwrap_or(source_command.file_base());
+        if counter.0 != owner {
+            counter = (owner.to_string(), 0, 0);
+            depth = 0;
+        }
+        if matches!(source_command.command(), Command::Function { .. }) {
+            depth = 0;
+        }
+        depth += crate::stack::stack_effect(source_command.command());
+
+        let site = match options.label_scheme {
+            LabelScheme::Lines => source_command.line(),
+            LabelScheme::Counters => {
+                let next = match source_command.command() {
+                    Command::Call { .. } => &mut counter.1,
+                    _ => &mut counter.2,
+                };
+                let site = *next;
+                if matches!(source_command.command(), Command::Call { .. } | Command::Eq | Command::Gt | Command::Lt) {
+                    *next += 1;
+                }
+                site
+            }
+        };
+        let mut code = generate_code_for_command(source_command, scope.as_ref(), site, symbols, options)?;
+        let mut before = source_comments(&annotations.before[i]);
+        if code.is_empty() || options.comments == Comments::Off {
+            // No header to append to.
+            before += &source_comments(&annotations.trailing[i]);
+        } else {
+            if options.comments == Comments::Annotated {
+                let header_end = code.find('\n').unwrap_or(code.len());
+                code.insert_str(header_end, &format!(" // {owner}, stack {depth}"));
+            }
+            for trailing in &annotations.trailing[i] {
+                let header_end = code.find('\n').unwrap_or(code.len());
+                code.insert_str(header_end, &format!(" //{}", trailing.trim_end()));
+            }
+        }
+        let own = before.len()..before.len() + code.len();
+        if !annotations.after[i].is_empty() {
+            if !code.is_empty() {
+                code.push('\n');
+            }
+            code.push_str(source_comments(&annotations.after[i]).trim_end());
+        }
+        emit(i, own, before + &code)?;
+    }
+    Ok(())
+}
+
+// Whether any code checks the stack, and so jumps to the halt loops.
+fn has_stack_checks(commands: &[SourceCommand], options: &CodegenOptions) -> bool {
+    options.stack_checks && commands.iter().any(|sc| matches!(sc.command(), Command::Function { .. } | Command::Return))
+}
+
+/// The bootstrap is only emitted for whole programs, which we recognise
+/// by the presence of the entry function (`Sys.init` unless configured
+/// otherwise), or by its being declared extern. `BootstrapMode` can say
+/// so instead, either way.
+///
+#[cfg_attr(feature = "interp", doc = "```")]
+#[cfg_attr(not(feature = "interp"), doc = "```ignore")]
+/// use hack_vmtranslator::asm::{generate_code, should_bootstrap, BootstrapMode, BootstrapOptions, CodegenOptions};
+/// use hack_vmtranslator::interp::{Config, Limits, Vm};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "function Main.start 0\npush constant 42\npop static 0\npush constant 0\nreturn\n")];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let options = CodegenOptions {
+///     bootstrap: BootstrapOptions { entry: "Main.start".to_string(), ..BootstrapOptions::default() },
+///     ..CodegenOptions::default()
+/// };
+/// assert!(should_bootstrap(&program, &options));
+/// assert!(!should_bootstrap(&program, &CodegenOptions::default()));
+///
+/// let mut vm = Vm::new(&program, Config { entry: Some("Main.start".to_string()), ..Config::default() });
+/// vm.run(&Limits::default()).unwrap();
+/// assert_eq!(vm.ram()[16], 42);
+///
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let asm = generate_code(program, &options).unwrap().join("\n");
+/// assert!(asm.contains("@Main.start\n0;JMP\n(Bootstrap$ret.0)"));
+///
+/// let fragment: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let mut options = CodegenOptions::default();
+/// options.bootstrap.mode = BootstrapMode::Always;
+/// assert!(should_bootstrap(&fragment, &options));
+/// let sources = [Source::new("Sys.vm", "function Sys.init 0\nlabel END\ngoto END\n")];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// options.bootstrap.mode = BootstrapMode::Never;
+/// assert!(!should_bootstrap(&program, &options));
+/// assert!(!generate_code(program, &options).unwrap().join("\n").contains("Bootstrap"));
+/// ```
+pub fn should_bootstrap(commands: &[SourceCommand], options: &CodegenOptions) -> bool {
+    let entry = options.bootstrap.entry.as_str();
+    options.symbol_prefix.is_none()
+        && match options.bootstrap.mode {
+            BootstrapMode::Always => true,
+            BootstrapMode::Never => false,
+            BootstrapMode::Auto => {
+                options.bootstrap.extern_entry
+                    || commands.iter().any(|sc| {
+                        matches!(sc.command(), Command::Function { name, .. } if *name == entry)
+                    })
+            }
+        }
+}
+
+/// How many ROM words `command` translates to on its own, without the
+/// bootstrap or anything shared between commands. An `include` has no
+/// code until it is spliced in, so it counts as 0.
+///
+/// The table below is the size of every command kind; a change to it is
+/// a change in the size of every program.
+///
+/// ```
+/// use hack_vmtranslator::asm::{expansion_size, CodegenOptions};
+/// use hack_vmtranslator::vm::{Command, Segment};
+///
+/// let segments = [
+///     Segment::Argument, Segment::Local, Segment::This, Segment::That,
+///     Segment::Pointer, Segment::Temp, Segment::Static, Segment::Reg,
+/// ];
+/// let mut commands = vec![Command::Push { segment: Segment::Constant, index: 7 }];
+/// commands.extend(segments.map(|segment| Command::Push { segment, index: 1 }));
+/// commands.extend(segments.map(|segment| Command::Pop { segment, index: 1 }));
+/// commands.extend([
+///     Command::Add, Command::Sub, Command::Neg, Command::And, Command::Or, Command::Not,
+///     Command::Eq, Command::Gt, Command::Lt,
+///     Command::Label("L"), Command::Goto("L"), Command::IfGoto("L"),
+/// ]);
+/// commands.extend((0..3).map(|nargs| Command::Call { name: "Main.f", nargs }));
+/// commands.extend((0..3).map(|nvars| Command::Function { name: "Main.f", nvars }));
+/// commands.push(Command::Return);
+///
+/// let options = CodegenOptions::default();
+/// let table: String = commands
+///     .into_iter()
+///     .map(|command| format!("{:<18}{:>3}\n", command.to_string(), expansion_size(command, &options)))
+///     .collect();
+/// assert_eq!(
+///     table,
+///     "\
+/// push constant 7     7
+/// push argument 1    10
+/// push local 1       10
+/// push this 1        10
+/// push that 1        10
+/// push pointer 1      7
+/// push temp 1         7
+/// push static 1       7
+/// push reg 1          7
+/// pop argument 1      9
+/// pop local 1         9
+/// pop this 1          9
+/// pop that 1          9
+/// pop pointer 1       5
+/// pop temp 1          5
+/// pop static 1        5
+/// pop reg 1           5
+/// add                11
+/// sub                11
+/// neg                 9
+/// and                11
+/// or                 11
+/// not                 9
+/// eq                 19
+/// gt                 19
+/// lt                 19
+/// label L             0
+/// goto L              2
+/// if-goto L           5
+/// call Main.f 0      47
+/// call Main.f 1      47
+/// call Main.f 2      47
+/// function Main.f 0   0
+/// function Main.f 1   7
+/// function Main.f 2  14
+/// return             44
+/// "
+/// );
+/// ```
+pub fn expansion_size(command: Command, options: &CodegenOptions) -> usize {
+    let symbols = Symbols::new(&[], options);
+    let sc = SourceCommand::new("Bootstrap", 0, "Bootstrap", command);
+    generate_code_for_command(&sc, None, 0, &symbols, options)
+        .map_or(0, |code| code.lines().filter(|line| is_instruction(line)).count())
+}
+
+/// How many instructions a program takes, and where they go.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    pub total: usize,
+    pub bootstrap: usize,
+    /// Code shared by the whole program: the halt loops that commands
+    /// which failed to parse and failed stack checks jump to.
+    pub helpers: usize,
+    /// Each function's code, in program order.
+    pub functions: Vec<(String, usize)>,
+    /// Code ahead of its file's first function.
+    pub outside_functions: usize,
+}
+
+impl SizeEstimate {
+    fn new(commands: &[SourceCommand], sizes: impl Iterator<Item = usize>, options: &CodegenOptions) -> SizeEstimate {
+        let mut estimate = SizeEstimate::default();
+        if should_bootstrap(commands, options) {
+            estimate.bootstrap = count_instructions(&generate_bootstrap(&options.bootstrap));
+        }
+        if commands.iter().any(|sc| matches!(sc.command(), Command::Trap)) {
+            estimate.helpers = count_instructions(&parse_error_trap(&Symbols::new(commands, options)));
+        }
+        if has_stack_checks(commands, options) {
+            estimate.helpers += count_instructions(&stack_check_traps(&Symbols::new(commands, options)));
+        }
+        for ((function, sc), size) in function_bodies(commands).zip(sizes) {
+            match function {
+                Some(name) if matches!(sc.command(), Command::Function { .. }) => estimate.functions.push((name.to_string(), size)),
+                Some(_) => estimate.functions.last_mut().unwrap().1 += size,
+                None => estimate.outside_functions += size,
+            }
+        }
+        estimate.total = estimate.bootstrap
+            + estimate.helpers
+            + estimate.outside_functions
+            + estimate.functions.iter().map(|(_, size)| size).sum::<usize>();
+        estimate
+    }
+
+    /// The `size` object of the JSON report.
+    pub fn to_json(&self) -> Json {
+        let functions = self
+            .functions
+            .iter()
+            .map(|(name, size)| Json::object(vec![("name", Json::from(name.as_str())), ("instructions", Json::from(*size))]))
+            .collect();
+        Json::object(vec![
+            ("total", Json::from(self.total)),
+            ("bootstrap", Json::from(self.bootstrap)),
+            ("helpers", Json::from(self.helpers)),
+            ("outside_functions", Json::from(self.outside_functions)),
+            ("functions", Json::Array(functions)),
+        ])
+    }
+}
+
+/// The size of `commands` translated with `options`, from each command's
+/// `expansion_size`, without generating any code. `measure_program_size`
+/// gets the same answer by generating it.
+///
+/// ```
+/// use hack_vmtranslator::asm::{estimate_program_size, generate_code, measure_program_size, CodegenOptions, LabelScheme};
+/// use hack_vmtranslator::fuzz::Generator;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new("Main.vm", "push constant 1\nfunction Main.main 0\npush constant 2\nadd\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let options = CodegenOptions::default();
+/// let estimate = estimate_program_size(&commands, &options);
+/// assert_eq!(estimate.functions.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["Main.main", "Sys.init"]);
+/// assert_eq!((estimate.bootstrap, estimate.helpers, estimate.outside_functions), (65, 0, 7));
+/// let asm = generate_code(commands.clone(), &options).unwrap().join("\n");
+/// assert_eq!(estimate.total, asm.lines().filter(|line| hack_vmtranslator::asm::is_instruction(line)).count());
+/// assert_eq!(
+///     estimate.to_json().to_string(),
+///     "{\"total\":183,\"bootstrap\":65,\"helpers\":0,\"outside_functions\":7,\"functions\":[\
+///      {\"name\":\"Main.main\",\"instructions\":62},{\"name\":\"Sys.init\",\"instructions\":49}]}"
+/// );
+///
+/// for label_scheme in [LabelScheme::Lines, LabelScheme::Counters] {
+///     let options = CodegenOptions { label_scheme, ..CodegenOptions::default() };
+///     for seed in 0..50 {
+///         let sources = Generator::new(seed).sources();
+///         let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///         assert_eq!(estimate_program_size(&commands, &options), measure_program_size(&commands, &options).unwrap(), "seed {seed}");
+///     }
+/// }
+/// ```
+pub fn estimate_program_size(commands: &[SourceCommand], options: &CodegenOptions) -> SizeEstimate {
+    let sizes = commands.iter().map(|sc| expansion_size(sc.command().clone(), options));
+    SizeEstimate::new(commands, sizes, options)
+}
+
+/// The size of `commands` translated with `options`, counted in the code
+/// generated for each. Fails where translation would.
+pub fn measure_program_size(commands: &[SourceCommand], options: &CodegenOptions) -> Result<SizeEstimate, String> {
+    let symbols = Symbols::new(commands, options);
+    let mut codes = Vec::new();
+    generate_commands(commands, &Annotations::new(commands.len()), &symbols, options, |_, _, code| {
+        codes.push(code);
+        Ok(())
+    })?;
+    Ok(SizeEstimate::new(commands, codes.iter().map(|code| count_instructions(code)), options))
+}
+
+fn count_instructions(code: &str) -> usize {
+    code.lines().filter(|line| is_instruction(line)).count()
+}
+
+/// What one command translates to, for tools that show a command at a
+/// time. See `explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandExplanation {
+    /// The code, one line each, labels included but without the
+    /// `// file[line]` header.
+    pub instructions: Vec<String>,
+    /// How many of `instructions` take ROM, as counted by `expansion_size`.
+    pub size: usize,
+    /// How far the command moves SP, where that is fixed; `return` and raw
+    /// assembly leave SP wherever the frame or the code puts it.
+    pub sp_delta: Option<i32>,
+    /// What isn't evident from the code.
+    pub notes: Vec<String>,
+}
+
+/// Translates `command` on its own, as if it were outside any function,
+/// and says what the code does. Fails for commands that don't translate:
+/// `pop constant`, or an `include` that hasn't been spliced in.
+///
+/// ```
+/// use hack_vmtranslator::asm::{explain, CodegenOptions};
+/// use hack_vmtranslator::vm::parse_command;
+///
+/// let options = CodegenOptions::default();
+/// let explain = |text: &str| explain(&parse_command("Main", 4, text).unwrap(), &options);
+///
+/// let push = explain("push static 3").unwrap();
+/// assert_eq!(push.instructions, ["@Main.3", "D=M", "@SP", "A=M", "M=D", "@SP", "M=M+1"]);
+/// assert_eq!((push.size, push.sp_delta), (7, Some(1)));
+/// assert_eq!(push.notes, ["allocates static symbol Main.3"]);
+///
+/// let call = explain("call Math.max 2").unwrap();
+/// assert_eq!(call.sp_delta, Some(-1));
+/// assert_eq!(call.notes, [
+///     "defines label Main$ret.0",
+///     "requires enclosing function for label scoping; shown as outside any function, in Main",
+///     "calls Math.max with the 2 arguments on top of the stack",
+/// ]);
+///
+/// let table: Vec<String> = [
+///     "push constant 7", "push argument 1", "push local 1", "push this 1", "push that 1",
+///     "push pointer 1", "push temp 1", "push reg 13", "pop static 0", "pop temp 6",
+///     "add", "sub", "neg", "and", "or", "not", "eq", "gt", "lt",
+///     "label LOOP", "goto LOOP", "if-goto LOOP", "function Main.f 2", "return",
+///     "asm { @7 / D=A }", "debug.print",
+/// ]
+/// .iter()
+/// .map(|text| {
+///     let e = explain(text).unwrap();
+///     let delta = e.sp_delta.map_or("?".to_string(), |d| format!("{d:+}"));
+///     format!("{text:<17} {:>2} {delta:>2}  {}", e.size, e.notes.join("; ")).trim_end().to_string()
+/// })
+/// .collect();
+/// assert_eq!(table.join("\n"), "\
+/// push constant 7    7 +1\n\
+/// push argument 1   10 +1\n\
+/// push local 1      10 +1\n\
+/// push this 1       10 +1\n\
+/// push that 1       10 +1\n\
+/// push pointer 1     7 +1  pointer 1 is RAM[4]\n\
+/// push temp 1        7 +1  temp 1 is RAM[6]\n\
+/// push reg 13        7 +1  reg 13 is R13\n\
+/// pop static 0       5 -1  allocates static symbol Main.0\n\
+/// pop temp 6         5 -1  temp 6 is RAM[11]\n\
+/// add               11 -1\n\
+/// sub               11 -1\n\
+/// neg                9 +0\n\
+/// and               11 -1\n\
+/// or                11 -1\n\
+/// not                9 +0\n\
+/// eq                19 -1  defines label Main$cmp.0; defines label Main$cmp.0.end; requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// gt                19 -1  defines label Main$cmp.0; defines label Main$cmp.0.end; requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// lt                19 -1  defines label Main$cmp.0; defines label Main$cmp.0.end; requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// label LOOP         0 +0  defines label Main$LOOP; requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// goto LOOP          2 +0  requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// if-goto LOOP       5 -1  requires enclosing function for label scoping; shown as outside any function, in Main\n\
+/// function Main.f 2 14 +2  defines label Main.f; initialises 2 locals to 0\n\
+/// return            44  ?  returns to the caller, with SP just past the return value it left at ARG 0\n\
+/// asm { @7 / D=A }   2  ?  raw assembly, copied as written\n\
+/// debug.print        0 +0  interpreter-only; no code is generated");
+///
+/// assert_eq!(explain("pop constant 1").unwrap_err(), "Unable to address segment for pop: Constant");
+/// assert!(explain("include \"Lib.vm\"").unwrap_err().contains("wasn't spliced in"));
+/// ```
+pub fn explain(command: &SourceCommand, options: &CodegenOptions) -> Result<CommandExplanation, String> {
+    let symbols = Symbols::new(std::slice::from_ref(command), options);
+    let site = match options.label_scheme {
+        LabelScheme::Lines => command.line(),
+        LabelScheme::Counters => 0,
     };
+    let code = generate_code_for_command(command, None, site, &symbols, options)?;
+    let instructions: Vec<String> = code
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .map(str::to_string)
+        .collect();
 
-    if let Ok(code) = code {
-        let mut result = String::new();
-        result.push_str(&comment(source_command));
-        result.push_str(&code);
-        Ok(result)
-    } else {
-        code
+    let mut notes = Vec::new();
+    if !matches!(command.command(), Command::RawAsm(_)) {
+        for label in instructions.iter().filter_map(|line| line.strip_prefix('(')?.strip_suffix(')')) {
+            notes.push(format!("defines label {label}"));
+        }
     }
+    let file = command.file_base();
+    let memory = &options.memory;
+    let scoping = || format!("requires enclosing function for label scoping; shown as outside any function, in {file}");
+    let sp_delta = match command.command() {
+        Command::Push { segment, index } | Command::Pop { segment, index } => {
+            match segment {
+                Segment::Static => notes.push(format!("allocates static symbol {}", symbols.static_variable(file, *index))),
+                Segment::Pointer => notes.push(format!("pointer {index} is RAM[{}]", memory.pointer_base + index)),
+                Segment::Temp => notes.push(format!("temp {index} is RAM[{}]", memory.temp_base + index)),
+                Segment::Reg => notes.push(format!("reg {index} is R{index}")),
+                _ => (),
+            }
+            Some(if matches!(command.command(), Command::Push { .. }) { 1 } else { -1 })
+        }
+        Command::Add | Command::Sub | Command::And | Command::Or => Some(-1),
+        Command::Eq | Command::Gt | Command::Lt => {
+            if options.label_scheme == LabelScheme::Counters {
+                notes.push(scoping());
+            }
+            Some(-1)
+        }
+        Command::Neg | Command::Not | Command::DebugPrint => Some(0),
+        Command::Label(_) | Command::Goto(_) | Command::IfGoto(_) | Command::Call { .. } => {
+            notes.push(scoping());
+            match command.command() {
+                Command::IfGoto(_) => Some(-1),
+                Command::Call { name, nargs } => {
+                    notes.push(format!("calls {name} with the {nargs} arguments on top of the stack"));
+                    Some(1 - i32::from(*nargs))
+                }
+                _ => Some(0),
+            }
+        }
+        Command::Function { nvars, .. } => {
+            if *nvars > 0 {
+                notes.push(format!("initialises {nvars} locals to 0"));
+            }
+            Some(i32::from(*nvars))
+        }
+        Command::Return => {
+            notes.push("returns to the caller, with SP just past the return value it left at ARG 0".to_string());
+            None
+        }
+        Command::RawAsm(_) => {
+            notes.push("raw assembly, copied as written".to_string());
+            None
+        }
+        Command::Trap => {
+            notes.push(format!("stands in for a line that didn't parse; halts at {}", symbols.parse_error_trap()));
+            None
+        }
+        Command::Include(_) => unreachable!("include has no code"),
+    };
+    if command.command().is_interpreter_only() {
+        notes.push("interpreter-only; no code is generated".to_string());
+    }
+
+    Ok(CommandExplanation {
+        size: instructions.iter().filter(|line| is_instruction(line)).count(),
+        instructions,
+        sp_delta,
+        notes,
+    })
 }
 
-fn generate_if_goto(source_command: &SourceCommand, label: &str, scope: Option<&String>) -> Result<String, String> {
-    let file = source_command.file_base().to_string();
-    let label_scope = scope.unwrap_or(&file);
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(pop_d());
-    asm.push(formatdoc!(
-            "@{label_scope}${label}
-            D;JNE"));
-    Ok(asm.join("\n"))
+/// The code `command` alone generates inside `scope` (a function, or
+/// `None` for the file), at call or comparison number `site`. For `lift`,
+/// which checks each command it recovers by generating it again.
+pub(crate) fn command_code(command: &SourceCommand, scope: Option<&str>, site: usize, options: &CodegenOptions) -> Result<String, String> {
+    let symbols = Symbols::new(std::slice::from_ref(command), options);
+    let scope = scope.map(str::to_string);
+    generate_code_for_command(command, scope.as_ref(), site, &symbols, options)
 }
 
-fn generate_goto(source_command: &SourceCommand, label: &str, scope: Option<&String>) -> Result<String, String> {
-    let file = source_command.file_base().to_string();
-    let label_scope = scope.unwrap_or(&file);
+// Every symbol the translator defines is named here, so that prefixing
+// applies consistently to definitions and references.
+struct Symbols<'a> {
+    prefix: &'a str,
+    externs: &'a [String],
+    /// Each function defined here, with how many locals it has.
+    defined: HashMap<&'a str, u16>,
+    scheme: LabelScheme,
+}
 
-    Ok(formatdoc!(
-            "@{label_scope}${label}
-             0;JMP"))
+impl<'a> Symbols<'a> {
+    fn new(commands: &[SourceCommand<'a>], options: &'a CodegenOptions) -> Symbols<'a> {
+        let defined = commands
+            .iter()
+            .filter_map(|sc| match sc.command() {
+                Command::Function { name, nvars } => Some((*name, *nvars)),
+                _ => None,
+            })
+            .collect();
+
+        Symbols {
+            prefix: options.symbol_prefix.as_deref().unwrap_or(""),
+            externs: &options.externs,
+            defined,
+            scheme: options.label_scheme,
+        }
+    }
+
+    // Functions defined elsewhere keep their names so calls link against
+    // the program that defines them.
+    fn function(&self, name: &str) -> String {
+        let is_extern = !self.defined.contains_key(name)
+            || self.externs.iter().any(|pattern| filter::matches(pattern, name));
+
+        if is_extern {
+            name.to_string()
+        } else {
+            format!("{}{name}", self.prefix)
+        }
+    }
+
+    // A VM label shares its scope's `$` namespace with the return and
+    // comparison labels, so the names those take after the `$` are
+    // refused rather than left to clash in the assembler.
+    fn label(&self, scope: &str, label: &str) -> Result<String, String> {
+        let digits = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+        let reserved = match label.split_once('.') {
+            Some(("ret", n)) => digits(n),
+            Some(("cmp", rest)) if self.scheme == LabelScheme::Counters => match rest.split_once('.') {
+                Some((n, outcome)) => digits(n) && ["false", "end"].contains(&outcome),
+                None => digits(rest),
+            },
+            _ => false,
+        };
+        if reserved {
+            return Err(format!("The label name '{label}' is reserved for the translator's own labels"));
+        }
+        Ok(format!("{}{scope}${label}", self.prefix))
+    }
+
+    // `site` is the source line or, under `LabelScheme::Counters`, the
+    // label's number within its scope.
+    fn return_label(&self, scope: &str, site: usize) -> String {
+        format!("{}{scope}$ret.{site}", self.prefix)
+    }
+
+    fn comparison(&self, outcome: &str, file: &str, scope: &str, site: usize) -> String {
+        match (self.scheme, outcome) {
+            (LabelScheme::Lines, _) => format!("{}COMP_{outcome}_{file}.{site}", self.prefix),
+            (LabelScheme::Counters, "TRUE") => format!("{}{scope}$cmp.{site}", self.prefix),
+            (LabelScheme::Counters, _) => format!("{}{scope}$cmp.{site}.{}", self.prefix, outcome.to_lowercase()),
+        }
+    }
+
+    fn static_variable(&self, file: &str, index: u16) -> String {
+        format!("{}{file}.{index}", self.prefix)
+    }
+
+    fn parse_error_trap(&self) -> String {
+        format!("{}PARSE_ERROR_TRAP", self.prefix)
+    }
+
+    fn stack_overflow(&self) -> String {
+        format!("{}STACK_OVERFLOW", self.prefix)
+    }
+
+    fn stack_underflow(&self) -> String {
+        format!("{}STACK_UNDERFLOW", self.prefix)
+    }
 }
 
-fn generate_label(source_command: &SourceCommand, label: &str, scope: Option<&String>) -> Result<String, String> {
-    let file = source_command.file_base().to_string();
-    let label_scope = scope.unwrap_or(&file);
+/// Appends the ROM address of every instruction in `asm` as a trailing
+/// comment, e.g. `@SP             // 0x01A3`. Labels, comments and blank
+/// lines don't occupy ROM, so they are left untouched and don't advance
+/// the address.
+///
+#[cfg_attr(feature = "assembler", doc = "```")]
+#[cfg_attr(not(feature = "assembler"), doc = "```ignore")]
+/// use hack_vmtranslator::asm::{annotate_addresses, label_addresses, AddressFormat};
+/// use hack_vmtranslator::assembler::assemble;
+/// use std::fs;
+/// use std::path::Path;
+///
+/// let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat/programs/Fib");
+/// let sources: Vec<_> = ["Main.vm", "Sys.vm"]
+///     .iter()
+///     .map(|name| (name.to_string(), fs::read_to_string(dir.join(name)).unwrap()))
+///     .collect();
+/// let asm = hack_vmtranslator::translate(&sources).unwrap();
+/// let annotated = annotate_addresses(&asm, AddressFormat::Hex);
+/// let lines: Vec<&str> = annotated.lines().collect();
+/// assert_eq!(lines.len(), asm.lines().count());
+///
+/// // The bootstrap starts at 0, and each function's first instruction
+/// // sits at the address of its label.
+/// assert_eq!(lines[0], "@256            // 0x0000");
+/// let labels = label_addresses(&asm);
+/// for (function, address) in [("Main.fibonacci", 0x41), ("Sys.init", 0x16F)] {
+///     let at = lines.iter().position(|line| *line == format!("({function})")).unwrap();
+///     let first = lines[at + 1..].iter().find(|line| line.contains("// 0x")).unwrap();
+///     assert!(first.ends_with(&format!("// 0x{address:04X}")), "{first}");
+///     assert!(labels.contains(&(function, address)));
+/// }
+///
+/// // Every annotated address is the one the assembler gives that line,
+/// // and the listing starts the line with it.
+/// let program = assemble(&asm).unwrap();
+/// let listing = program.listing(&asm);
+/// let mut annotated_lines = 0;
+/// for ((line, address), listed) in lines.iter().zip(&program.addresses).zip(listing.lines()) {
+///     let comment = line.rsplit_once("// 0x").map(|(_, hex)| usize::from_str_radix(hex, 16).unwrap());
+///     assert_eq!(comment, *address, "{line}");
+///     if let Some(address) = address {
+///         assert!(listed.starts_with(&format!("{address:05}  ")), "{listed}");
+///         annotated_lines += 1;
+///     }
+/// }
+/// assert_eq!(annotated_lines, program.words.len());
+///
+/// let decimal = annotate_addresses(&asm, AddressFormat::Decimal);
+/// let last = decimal.lines().filter(|line| line.contains("// 0")).last().unwrap();
+/// assert!(last.ends_with(&format!("// {:05}", program.words.len() - 1)), "{last}");
+/// ```
+pub fn annotate_addresses(asm: &str, format: AddressFormat) -> String {
+    let mut address = 0;
 
-    Ok(format!("({label_scope}${label})"))
+    asm.lines()
+        .map(|line| {
+            if is_instruction(line) {
+                let annotated = match format {
+                    AddressFormat::Hex => format!("{line:<15} // 0x{address:04X}"),
+                    AddressFormat::Decimal => format!("{line:<15} // {address:05}"),
+                };
+                address += 1;
+                annotated
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
 }
 
-fn generate_call(source_command: &SourceCommand, name: &str, nargs: u16, scope: Option<&String>) -> Result<String, String> {
+/// The ROM address of every label in `asm`, in the order they appear,
+/// counted the same way as `annotate_addresses`.
+///
+/// ```
+/// use hack_vmtranslator::asm::{annotate_addresses, label_addresses, AddressFormat};
+///
+/// let asm = "@1\nD=A\n(Main.f)\n// body\n@2\n(Main.f$END)\n0;JMP";
+/// assert_eq!(label_addresses(asm), vec![("Main.f", 2), ("Main.f$END", 3)]);
+/// assert!(annotate_addresses(asm, AddressFormat::Decimal).contains("@2              // 00002"));
+/// ```
+pub fn label_addresses(asm: &str) -> Vec<(&str, usize)> {
+    let mut address = 0;
+    let mut labels = Vec::new();
+
+    for line in asm.lines() {
+        if let Some(label) = line.trim().strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            labels.push((label, address));
+        } else if is_instruction(line) {
+            address += 1;
+        }
+    }
+
+    labels
+}
+
+/// Returns true if `line` is an A- or C-instruction, i.e. something the
+/// assembler will place in ROM.
+pub fn is_instruction(line: &str) -> bool {
+    let line = line.trim();
+    !(line.is_empty() || line.starts_with("//") || line.starts_with('('))
+}
+
+/// Encodes a C-instruction such as `D=M-1` or `0;JMP`. The computation
+/// must be one the ALU has, written as the course writes it (or with the
+/// operands of a commutative operator swapped); the destination is any
+/// set of `A`, `D` and `M`, each at most once.
+///
+/// ```
+/// use hack_vmtranslator::asm::c_instruction;
+///
+/// assert_eq!(c_instruction("D=M-1"), Ok(0b1111110010010000));
+/// assert_eq!(c_instruction("0;JMP"), Ok(0b1110101010000111));
+/// for legal in ["AM=M-1", "D=D|A", "M=A|D", "MD=D+1", "D;JNE", "A=-1", "AMD=!M"] {
+///     assert!(c_instruction(legal).is_ok(), "{legal}");
+/// }
+/// for illegal in ["D=A-M", "M=D+D", "D=M+1+1", "DD=A", "X=D", "D;JNO", "D=1+D", "0;jmp", ""] {
+///     assert!(c_instruction(illegal).is_err(), "{illegal}");
+/// }
+/// assert_eq!(c_instruction("M=D^A"), Err("Invalid computation: 'D^A'".to_string()));
+/// ```
+pub fn c_instruction(code: &str) -> Result<u16, String> {
+    let (dest, rest) = match code.split_once('=') {
+        Some((dest, rest)) => (dest, rest),
+        None => ("", code),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp, jump),
+        None => (rest, ""),
+    };
+
+    let comp = comp_bits(comp).ok_or_else(|| format!("Invalid computation: '{comp}'"))?;
+    let dest = dest_bits(dest).ok_or_else(|| format!("Invalid destination: '{dest}'"))?;
+    let jump = jump_bits(jump).ok_or_else(|| format!("Invalid jump: '{jump}'"))?;
+    Ok(0b111 << 13 | comp << 6 | dest << 3 | jump)
+}
+
+/// Checks every C-instruction in `asm`, saying which line is wrong. The
+/// code generator's own output is checked like this in debug builds.
+///
+/// ```
+/// use hack_vmtranslator::asm::check_instructions;
+///
+/// assert_eq!(check_instructions("@SP\nAM=M-1 // pop\n(L)\nD;JGT"), Ok(()));
+/// assert_eq!(
+///     check_instructions("@SP\nA=M\nM=D-D"),
+///     Err("line 3 (M=D-D): Invalid computation: 'D-D'".to_string())
+/// );
+/// ```
+pub fn check_instructions(asm: &str) -> Result<(), String> {
+    for (i, line) in asm.lines().enumerate() {
+        let code = line.split("//").next().unwrap_or_default().trim();
+        if !is_instruction(code) || code.starts_with('@') {
+            continue;
+        }
+        c_instruction(code).map_err(|e| format!("line {} ({code}): {e}", i + 1))?;
+    }
+    Ok(())
+}
+
+/// The `a` bit and six `c` bits of a computation.
+fn comp_bits(comp: &str) -> Option<u16> {
+    let bits = match comp {
+        "0" => 0b0101010,
+        "1" => 0b0111111,
+        "-1" => 0b0111010,
+        "D" => 0b0001100,
+        "A" => 0b0110000,
+        "M" => 0b1110000,
+        "!D" => 0b0001101,
+        "!A" => 0b0110001,
+        "!M" => 0b1110001,
+        "-D" => 0b0001111,
+        "-A" => 0b0110011,
+        "-M" => 0b1110011,
+        "D+1" => 0b0011111,
+        "A+1" => 0b0110111,
+        "M+1" => 0b1110111,
+        "D-1" => 0b0001110,
+        "A-1" => 0b0110010,
+        "M-1" => 0b1110010,
+        "D+A" | "A+D" => 0b0000010,
+        "D+M" | "M+D" => 0b1000010,
+        "D-A" => 0b0010011,
+        "D-M" => 0b1010011,
+        "A-D" => 0b0000111,
+        "M-D" => 0b1000111,
+        "D&A" | "A&D" => 0b0000000,
+        "D&M" | "M&D" => 0b1000000,
+        "D|A" | "A|D" => 0b0010101,
+        "D|M" | "M|D" => 0b1010101,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+fn dest_bits(dest: &str) -> Option<u16> {
+    let mut bits = 0;
+    for c in dest.chars() {
+        let bit = match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return None,
+        };
+        if bits & bit != 0 {
+            return None;
+        }
+        bits |= bit;
+    }
+    Some(bits)
+}
+
+fn jump_bits(jump: &str) -> Option<u16> {
+    let bits = match jump {
+        "" => 0b000,
+        "JGT" => 0b001,
+        "JEQ" => 0b010,
+        "JGE" => 0b011,
+        "JLT" => 0b100,
+        "JNE" => 0b101,
+        "JLE" => 0b110,
+        "JMP" => 0b111,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+/// Registers and I/O maps every Hack program can refer to by name.
+pub const PREDEFINED_SYMBOLS: [(&str, u16); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// Words of instruction memory in the standard Hack computer.
+pub const ROM_SIZE: usize = 32768;
+
+/// The first RAM address the assembler hands out to variables.
+pub const VARIABLE_BASE: u16 = 16;
+
+/// The RAM address the assembler will give each variable in `asm`: every
+/// `@symbol` that isn't a label or predefined, numbered from RAM[16] in
+/// order of first use, just as the assembler does.
+///
+/// ```
+/// use hack_vmtranslator::asm::variable_allocation;
+///
+/// let asm = "@Main.0\nM=D\n(LOOP)\n@frame\n@LOOP\n@Main.0\n@SP";
+/// assert_eq!(
+///     variable_allocation(asm),
+///     vec![("Main.0".to_string(), 16), ("frame".to_string(), 17)]
+/// );
+/// ```
+pub fn variable_allocation(asm: &str) -> Vec<(String, u16)> {
+    let mut scan = VariableScan::default();
+    for line in asm.lines() {
+        scan.line(line);
+    }
+    scan.finish()
+}
+
+/// `variable_allocation` for output seen a line at a time, holding only
+/// the symbols.
+#[derive(Debug, Clone, Default)]
+pub struct VariableScan {
+    labels: HashSet<String>,
+    /// Every other symbol, in order of first use.
+    symbols: Vec<String>,
+    seen: HashSet<String>,
+}
+
+impl VariableScan {
+    pub fn line(&mut self, line: &str) {
+        if let Some(label) = line.trim().strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            self.labels.insert(label.to_string());
+        }
+        let code = line.split("//").next().unwrap_or_default().trim();
+        let Some(symbol) = code.strip_prefix('@') else { return };
+        if symbol.starts_with(|c: char| c.is_ascii_digit())
+            || PREDEFINED_SYMBOLS.iter().any(|(name, _)| *name == symbol)
+            || self.seen.contains(symbol)
+        {
+            return;
+        }
+        self.seen.insert(symbol.to_string());
+        self.symbols.push(symbol.to_string());
+    }
+
+    /// The variables, which are the symbols that never turned out to be
+    /// labels, with their addresses.
+    pub fn finish(self) -> Vec<(String, u16)> {
+        let labels = self.labels;
+        self.symbols
+            .into_iter()
+            .filter(|symbol| !labels.contains(symbol))
+            .enumerate()
+            .map(|(i, symbol)| (symbol, VARIABLE_BASE + i as u16))
+            .collect()
+    }
+}
+
+const METADATA_START: &str = "// == hack_vm_translator metadata ==";
+const METADATA_END: &str = "// == end metadata ==";
+
+/// What a translated file holds, written at the bottom of the output so
+/// that a fragment describes itself: each static with the file and index
+/// it belongs to, the function entry labels, and whether the bootstrap is
+/// in it. Its `Display` is that comment block, and `read_metadata` parses
+/// it back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// `(symbol, file name, index)`, in order of first use. The name
+    /// leaves out the directory, so output doesn't depend on where it
+    /// was translated.
+    pub statics: Vec<(String, String, u16)>,
+    pub functions: Vec<String>,
+    pub bootstrap: bool,
+}
+
+impl Manifest {
+    /// The manifest for `commands` translated with `options`, under the
+    /// names the output uses for them.
+    pub fn new(commands: &[SourceCommand], options: &CodegenOptions) -> Manifest {
+        let symbols = Symbols::new(commands, options);
+        let mut manifest = Manifest {
+            bootstrap: should_bootstrap(commands, options),
+            ..Manifest::default()
+        };
+        for sc in commands {
+            match sc.command() {
+                Command::Function { name, .. } => manifest.functions.push(symbols.function(name)),
+                Command::Push { segment: Segment::Static, index } | Command::Pop { segment: Segment::Static, index } => {
+                    let symbol = symbols.static_variable(sc.file_base(), *index);
+                    if !manifest.statics.iter().any(|(s, _, _)| *s == symbol) {
+                        let file = sc.path().file_name().unwrap_or_default().to_string_lossy().to_string();
+                        manifest.statics.push((symbol, file, *index));
+                    }
+                }
+                _ => (),
+            }
+        }
+        manifest
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{METADATA_START}")?;
+        writeln!(f, "// bootstrap {}", if self.bootstrap { "yes" } else { "no" })?;
+        for function in &self.functions {
+            writeln!(f, "// function {function}")?;
+        }
+        // The file goes last, so it may contain spaces.
+        for (symbol, file, index) in &self.statics {
+            writeln!(f, "// static {symbol} {index} {file}")?;
+        }
+        write!(f, "{METADATA_END}")
+    }
+}
+
+/// The manifest at the bottom of translated `text`, or `None` if it has
+/// none or it is damaged. With several, as in linked output, the last is
+/// read.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, read_metadata, variable_allocation, CodegenOptions, Manifest};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new("Main.vm", "function Main.main 0\npush static 3\npop static 0\npush constant 0\nreturn\n"),
+///     Source::new("lib/My Util.vm", "function My_Util.id 0\npush static 0\nreturn\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let options = CodegenOptions::default();
+/// let manifest = Manifest::new(&commands, &options);
+/// let fragment = format!("{}\n{manifest}", generate_code(commands, &options).unwrap().join("\n"));
+///
+/// assert!(fragment.ends_with(
+///     "// == hack_vm_translator metadata ==\n\
+///      // bootstrap no\n\
+///      // function Main.main\n\
+///      // function My_Util.id\n\
+///      // static Main.3 3 Main.vm\n\
+///      // static Main.0 0 Main.vm\n\
+///      // static My_Util.0 0 My Util.vm\n\
+///      // == end metadata =="
+/// ));
+/// let read = read_metadata(&fragment).unwrap();
+/// assert_eq!(read, manifest);
+///
+/// // The statics are what the assembler will allocate, besides the
+/// // translator's own variables.
+/// let variables: Vec<String> = variable_allocation(&fragment)
+///     .into_iter()
+///     .map(|(name, _)| name)
+///     .filter(|name| !["frame", "retaddr"].contains(&name.as_str()))
+///     .collect();
+/// let statics: Vec<String> = read.statics.iter().map(|(symbol, _, _)| symbol.clone()).collect();
+/// assert_eq!(variables, statics);
+///
+/// assert_eq!(read_metadata("@SP\nM=1\n"), None);
+/// assert_eq!(read_metadata(&fragment.replace("// bootstrap no", "// bootstrap maybe")), None);
+/// ```
+pub fn read_metadata(text: &str) -> Option<Manifest> {
+    let start = text.rfind(METADATA_START)?;
+    let mut manifest = Manifest::default();
+    let mut bootstrap = None;
+    for line in text[start..].lines().skip(1) {
+        if line == METADATA_END {
+            manifest.bootstrap = bootstrap?;
+            return Some(manifest);
+        }
+        match line.strip_prefix("// ")?.split_once(' ')? {
+            ("bootstrap", "yes") => bootstrap = Some(true),
+            ("bootstrap", "no") => bootstrap = Some(false),
+            ("function", name) => manifest.functions.push(name.to_string()),
+            ("static", rest) => {
+                let mut fields = rest.splitn(3, ' ');
+                let (symbol, index, file) = (fields.next()?, fields.next()?, fields.next()?);
+                manifest.statics.push((symbol.to_string(), file.to_string(), index.parse().ok()?));
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The label that marks a bootstrap block in generated code; the return
+/// point of its call to the entry function.
+pub const BOOTSTRAP_LABEL: &str = "Bootstrap$ret.0";
+
+/// Settings for `generate_bootstrap`. The default matches the course
+/// convention that the supplied test scripts expect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapOptions {
+    /// The address the stack starts at.
+    pub stack_base: u16,
+    /// The function called once the stack is set up.
+    pub entry: String,
+    /// The entry function is defined by a program linked in later, so
+    /// the bootstrap is emitted without it being defined here.
+    pub extern_entry: bool,
+    /// Whether to fill LCL, ARG, THIS and THAT with the sentinel values
+    /// -1 to -4 before the call, as the course test scripts expect.
+    pub sentinels: bool,
+    /// Whether to emit it at all.
+    pub mode: BootstrapMode,
+    /// What it does before handing over to the entry function.
+    pub style: BootstrapStyle,
+}
+
+/// When the bootstrap is emitted, from `--bootstrap` and `--no-bootstrap`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BootstrapMode {
+    /// When the program defines the entry function, or it's extern.
+    #[default]
+    Auto,
+    /// Always, first thing, so a partial directory can still be run.
+    Always,
+    /// Never, for output that will be joined to a program that has one.
+    Never,
+}
+
+impl FromStr for BootstrapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BootstrapMode, String> {
+        match s {
+            "auto" => Ok(BootstrapMode::Auto),
+            "always" => Ok(BootstrapMode::Always),
+            "never" => Ok(BootstrapMode::Never),
+            _ => Err(format!("Unknown bootstrap mode: '{s}' (expected auto, always or never)")),
+        }
+    }
+}
+
+impl fmt::Display for BootstrapMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootstrapMode::Auto => write!(f, "auto"),
+            BootstrapMode::Always => write!(f, "always"),
+            BootstrapMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// How the bootstrap starts the entry function, from `--bootstrap-mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BootstrapStyle {
+    /// A full `call`, with a frame and the sentinels, as the course's
+    /// test scripts expect.
+    #[default]
+    Full,
+    /// A plain jump, 40-odd instructions shorter, for an entry function
+    /// that never returns. SP is still left where the call would have
+    /// left it, so the stack is laid out the same.
+    Minimal,
+}
+
+impl FromStr for BootstrapStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BootstrapStyle, String> {
+        match s {
+            "full" => Ok(BootstrapStyle::Full),
+            "minimal" => Ok(BootstrapStyle::Minimal),
+            _ => Err(format!("Unknown bootstrap style: '{s}' (expected full or minimal)")),
+        }
+    }
+}
+
+impl fmt::Display for BootstrapStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootstrapStyle::Full => write!(f, "full"),
+            BootstrapStyle::Minimal => write!(f, "minimal"),
+        }
+    }
+}
+
+impl Default for BootstrapOptions {
+    fn default() -> BootstrapOptions {
+        BootstrapOptions {
+            stack_base: MemoryMap::default().stack_base,
+            entry: "Sys.init".to_string(),
+            extern_entry: false,
+            sentinels: true,
+            mode: BootstrapMode::Auto,
+            style: BootstrapStyle::Full,
+        }
+    }
+}
+
+/// Generates the start-up code that initialises the stack pointer and
+/// calls the entry function. `generate_code` places this at the start of
+/// any program that defines it.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_bootstrap, BootstrapOptions};
+///
+/// let options = BootstrapOptions {
+///     entry: "Main.main".to_string(),
+///     ..BootstrapOptions::default()
+/// };
+/// let asm = generate_bootstrap(&options);
+///
+/// assert!(asm.starts_with("@256\nD=A\n@SP\nM=D"));
+/// assert!(asm.contains("@Main.main\n0;JMP"));
+/// assert!(asm.ends_with("(Bootstrap$ret.0)"));
+/// ```
+///
+/// Either style runs the course's programs to the same end:
+///
+#[cfg_attr(feature = "assembler", doc = "```")]
+#[cfg_attr(not(feature = "assembler"), doc = "```ignore")]
+/// use hack_vmtranslator::asm::{BootstrapStyle, CodegenOptions};
+/// use hack_vmtranslator::assembler::{assemble, Cpu};
+/// use hack_vmtranslator::translator::Translator;
+///
+/// let programs = [
+///     ("Fib", vec!["Main.vm", "Sys.vm"], vec![(0, 262), (261, 3)]),
+///     ("Statics", vec!["Class1.vm", "Sys.vm"], vec![(0, 262), (261, -2)]),
+/// ];
+/// for (name, files, expected) in programs {
+///     let mut sizes = Vec::new();
+///     for style in [BootstrapStyle::Full, BootstrapStyle::Minimal] {
+///         let mut translator = Translator::new();
+///         for file in &files {
+///             let path = format!("tests/compat/programs/{name}/{file}");
+///             translator.add_source(&path, std::fs::read_to_string(&path).unwrap()).unwrap();
+///         }
+///         let mut options = CodegenOptions::default();
+///         options.bootstrap.style = style;
+///         let rom = assemble(&translator.finish(&options).unwrap().asm).unwrap();
+///         let mut cpu = Cpu::new();
+///         cpu.run(&rom, 5000);
+///         for &(address, value) in &expected {
+///             assert_eq!(cpu.ram[address], value, "{name} {style}: RAM[{address}]");
+///         }
+///         sizes.push(rom.words.len());
+///     }
+///     assert!(sizes[1] + 40 < sizes[0], "{sizes:?}");
+/// }
+/// ```
+pub fn generate_bootstrap(options: &BootstrapOptions) -> String {
+    if options.style == BootstrapStyle::Minimal {
+        // Past the five words a call would have saved; the label still
+        // marks where the bootstrap ends for the linker.
+        let sp = options.stack_base.wrapping_add(5);
+        return format!("{}\n@{}\n0;JMP\n({BOOTSTRAP_LABEL})", snippets::set_segment_pointer("SP", sp), options.entry);
+    }
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(snippets::set_segment_pointer("SP", options.stack_base));
+    if options.sentinels {
+        asm.push(formatdoc!(
+            "@LCL
+            M=-1
+            @2
+            D=-A
+            @ARG
+            M=D
+            @3
+            D=-A
+            @THIS
+            M=D
+            @4
+            D=-A
+            @THAT
+            M=D"
+        ));
+    }
+    asm.push(generate_call_sequence(&options.entry, 0, BOOTSTRAP_LABEL));
+
+    asm.join("\n")
+}
+
+/// Generates the calling sequence for `call name nargs`: it saves the
+/// caller's frame, repositions ARG and LCL, jumps to `name` and defines
+/// `return_label` as the point execution resumes at. The `nargs`
+/// arguments must already be on the stack.
+///
+/// ```
+/// use hack_vmtranslator::asm::generate_call_sequence;
+///
+/// let asm = generate_call_sequence("Math.multiply", 2, "Main.main$ret.0");
+///
+/// assert!(asm.starts_with("@Main.main$ret.0\nD=A"));
+/// assert!(asm.contains("@7\nD=D-A\n@ARG\nM=D"));
+/// assert!(asm.ends_with("@Math.multiply\n0;JMP\n(Main.main$ret.0)"));
+/// ```
+pub fn generate_call_sequence(name: &str, nargs: u16, return_label: &str) -> String {
     let arg_offset = nargs + 5;
-    let file = source_command.file_base().to_string();
-    let label_scope = scope.unwrap_or(&file);
-    let return_label = format!("{label_scope}$ret.{}", source_command.line());
     let mut asm: Vec<String> = Vec::new();
     asm.push(formatdoc!(
         "@{return_label}
@@ -148,22 +2159,169 @@ fn generate_call(source_command: &SourceCommand, name: &str, nargs: u16, scope:
     ));
     asm.push(format!("({return_label})"));
 
+    asm.join("\n")
+}
+
+fn generate_code_for_command(source_command: &SourceCommand, scope: Option<&String>, site: usize, symbols: &Symbols, options: &CodegenOptions) -> Result<String, String> {
+    if source_command.command().is_interpreter_only() {
+        return Ok(String::new());
+    }
+    let memory = &options.memory;
+    let code = match source_command.command() {
+        Command::Add => generate_add(),
+        Command::And => generate_and(),
+        Command::Eq => generate_eq(source_command, scope, site, symbols),
+        Command::Gt if options.safe_compare => generate_safe_comparison(source_command, "JGT", scope, site, symbols),
+        Command::Lt if options.safe_compare => generate_safe_comparison(source_command, "JLT", scope, site, symbols),
+        Command::Gt => generate_gt(source_command, scope, site, symbols),
+        Command::Lt => generate_lt(source_command, scope, site, symbols),
+        Command::Neg => generate_neg(),
+        Command::Not => generate_not(),
+        Command::Or => generate_or(),
+        Command::Pop { segment, index } => generate_pop(source_command, segment, *index, symbols, memory),
+        Command::Push { segment, index } => generate_push(source_command, segment, *index, symbols, memory),
+        Command::Sub => generate_sub(),
+        Command::Goto(label) => generate_goto(source_command, label, scope, symbols),
+        Command::IfGoto(label) => generate_if_goto(source_command, label, scope, symbols),
+        Command::Label(label) => generate_label(source_command, label, scope, symbols),
+        Command::Call {name, nargs } => generate_call(source_command, name, *nargs, scope, site, symbols),
+        Command::Function { name, nvars } => generate_function(name, *nvars, symbols, options),
+        Command::Return => generate_return(scope, symbols, options),
+        Command::Trap => generate_trap(symbols),
+        Command::Include(name) => Err(format!("include \"{name}\" wasn't spliced in before code generation")),
+        Command::RawAsm(body) => Ok(crate::vm::raw_asm_lines(body).collect::<Vec<_>>().join("\n")),
+        Command::DebugPrint => unreachable!("interpreter-only commands have no code"),
+    };
+
+    if let Ok(code) = code {
+        // A typo in a generator is a bug here, not in the program; say
+        // which command it was generating.
+        #[cfg(debug_assertions)]
+        if !matches!(source_command.command(), Command::RawAsm(_)) {
+            if let Err(e) = check_instructions(&code) {
+                panic!("illegal instruction generated for `{}`: {e}", source_command.command());
+            }
+        }
+        let mut result = String::new();
+        if options.comments != Comments::Off {
+            result.push_str(&comment(source_command));
+        }
+        result.push_str(&code);
+        Ok(result)
+    } else {
+        code
+    }
+}
+
+// Commands that failed to parse jump here, so a program translated with
+// `--keep-going` halts instead of running past the missing code.
+fn parse_error_trap(symbols: &Symbols) -> String {
+    let trap = symbols.parse_error_trap();
+    formatdoc!(
+        "// Halt loop for commands that failed to parse
+        ({trap})
+        @{trap}
+        0;JMP"
+    )
+}
+
+// Where `CodegenOptions::stack_checks` jump when a check fails.
+fn stack_check_traps(symbols: &Symbols) -> String {
+    let (overflow, underflow) = (symbols.stack_overflow(), symbols.stack_underflow());
+    formatdoc!(
+        "// Halt loops for failed stack checks
+        ({overflow})
+        @{overflow}
+        0;JMP
+        ({underflow})
+        @{underflow}
+        0;JMP"
+    )
+}
+
+fn generate_trap(symbols: &Symbols) -> Result<String, String> {
+    let trap = symbols.parse_error_trap();
+    Ok(formatdoc!(
+        "@{trap}
+        0;JMP"
+    ))
+}
+
+fn generate_if_goto(source_command: &SourceCommand, label: &str, scope: Option<&String>, symbols: &Symbols) -> Result<String, String> {
+    let file = source_command.file_base().to_string();
+    let label = symbols.label(scope.unwrap_or(&file), label)?;
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(pop_d());
+    asm.push(formatdoc!(
+            "@{label}
+            D;JNE"));
     Ok(asm.join("\n"))
 }
 
-fn generate_function(name: &str, nvars: u16) -> Result<String, String> {
+fn generate_goto(source_command: &SourceCommand, label: &str, scope: Option<&String>, symbols: &Symbols) -> Result<String, String> {
+    let file = source_command.file_base().to_string();
+    let label = symbols.label(scope.unwrap_or(&file), label)?;
+
+    Ok(formatdoc!(
+            "@{label}
+             0;JMP"))
+}
+
+fn generate_label(source_command: &SourceCommand, label: &str, scope: Option<&String>, symbols: &Symbols) -> Result<String, String> {
+    let file = source_command.file_base().to_string();
+    let label = symbols.label(scope.unwrap_or(&file), label)?;
+
+    Ok(format!("({label})"))
+}
+
+fn generate_call(source_command: &SourceCommand, name: &str, nargs: u16, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    let file = source_command.file_base().to_string();
+    let return_label = symbols.return_label(scope.unwrap_or(&file), site);
+
+    Ok(generate_call_sequence(&symbols.function(name), nargs, &return_label))
+}
+
+fn generate_function(name: &str, nvars: u16, symbols: &Symbols, options: &CodegenOptions) -> Result<String, String> {
     let mut asm: Vec<String> = Vec::new();
-    asm.push(format!("({name})"));
+    asm.push(format!("({})", symbols.function(name)));
 
     for _ in 0..nvars {
-        asm.push(push_constant(0)?);
+        asm.push(snippets::push_constant(0));
+    }
+    if options.stack_checks {
+        // if SP > stack_limit goto STACK_OVERFLOW
+        asm.push(formatdoc!(
+            "@SP
+            D=M
+            @{}
+            D=D-A
+            @{}
+            D;JGT",
+            options.memory.stack_limit,
+            symbols.stack_overflow()
+        ));
     }
 
     Ok(asm.join("\n"))
 }
 
-fn generate_return() -> Result<String, String> {
+fn generate_return(scope: Option<&String>, symbols: &Symbols, options: &CodegenOptions) -> Result<String, String> {
     let mut asm: Vec<String> = Vec::new();
+    if options.stack_checks {
+        // if SP <= LCL + nvars goto STACK_UNDERFLOW
+        let nvars = scope.and_then(|function| symbols.defined.get(function.as_str())).copied().unwrap_or(0);
+        asm.push(formatdoc!(
+            "@LCL
+            D=M
+            @{nvars}
+            D=D+A
+            @SP
+            D=M-D
+            @{}
+            D;JLE",
+            symbols.stack_underflow()
+        ));
+    }
     // frame = LCL
     asm.push(formatdoc!(
         "@LCL
@@ -255,102 +2413,44 @@ fn generate_not() -> Result<String, String> {
     generate_unary("!D")
 }
 
-fn generate_pop(sc: &SourceCommand, segment: &Segment, index: u16) -> Result<String, String> {
+fn generate_pop(sc: &SourceCommand, segment: &Segment, index: u16, symbols: &Symbols, memory: &MemoryMap) -> Result<String, String> {
     match segment {
-        Segment::Argument => pop_to_segment("ARG", index),
-        Segment::Local => pop_to_segment("LCL", index),
-        Segment::Pointer => pop_to_address(index + 3),
-        Segment::Static => pop_to_variable(&format!("{}.{index}", sc.file_base())),
-        Segment::Temp => pop_to_address(index + 5),
-        Segment::That => pop_to_segment("THAT", index),
-        Segment::This => pop_to_segment("THIS", index),
+        Segment::Argument => Ok(snippets::pop_to_segment("ARG", index)),
+        Segment::Local => Ok(snippets::pop_to_segment("LCL", index)),
+        Segment::Pointer => Ok(snippets::pop_to_address(fixed_address(segment, index, memory.pointer_base, memory.pointer_size)?)),
+        Segment::Static => Ok(snippets::pop_to_variable(&symbols.static_variable(sc.file_base(), index))),
+        Segment::Temp => Ok(snippets::pop_to_address(fixed_address(segment, index, memory.temp_base, memory.temp_size)?)),
+        Segment::That => Ok(snippets::pop_to_segment("THAT", index)),
+        Segment::This => Ok(snippets::pop_to_segment("THIS", index)),
+        Segment::Reg => Ok(snippets::pop_to_variable(&format!("R{index}"))),
         _ => Err(format!("Unable to address segment for pop: {segment:?}")),
     }
 }
 
-fn pop_to_address(address: u16) -> Result<String, String> {
-    pop_to_variable(&address.to_string())
-}
-
-fn pop_to_segment(segment_name: &str, index: u16) -> Result<String, String> {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(formatdoc!(
-        "@{segment_name}
-        D=M
-        @{index}
-        D=D+A
-        @SP
-        AM=M-1
-        D=D+M
-        A=D-M
-        M=D-A"
-    ));
-
-    Ok(asm.join("\n"))
-}
-
-fn pop_to_variable(variable: &str) -> Result<String, String> {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(pop_d());
-    asm.push(formatdoc!(
-        "@{variable}
-        M=D"
-    ));
-
-    Ok(asm.join("\n"))
-}
-
-fn generate_push(sc: &SourceCommand, segment: &Segment, index: u16) -> Result<String, String> {
+fn generate_push(sc: &SourceCommand, segment: &Segment, index: u16, symbols: &Symbols, memory: &MemoryMap) -> Result<String, String> {
     let file = sc.file_base();
 
-    match segment {
-        Segment::Argument => push_from_segment("ARG", index),
-        Segment::Constant => push_constant(index),
-        Segment::Local => push_from_segment("LCL", index),
-        Segment::Pointer => push_from_address(index + 3),
-        Segment::Static => push_from_variable(&format!("{file}.{index}")),
-        Segment::Temp => push_from_address(index + 5),
-        Segment::That => push_from_segment("THAT", index),
-        Segment::This => push_from_segment("THIS", index),
-    }
-}
-
-fn push_from_variable(variable: &str) -> Result<String, String> {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(formatdoc!(
-        "@{variable}
-        D=M
-        "
-    ));
-    asm.push(push_d());
-
-    Ok(asm.join("\n"))
-}
-
-fn push_from_address(index: u16) -> Result<String, String> {
-    push_from_variable(&index.to_string())
+    Ok(match segment {
+        Segment::Argument => snippets::push_from_segment("ARG", index),
+        Segment::Constant => snippets::push_constant(index),
+        Segment::Local => snippets::push_from_segment("LCL", index),
+        Segment::Pointer => snippets::push_from_address(fixed_address(segment, index, memory.pointer_base, memory.pointer_size)?),
+        Segment::Static => snippets::push_from_variable(&symbols.static_variable(file, index)),
+        Segment::Temp => snippets::push_from_address(fixed_address(segment, index, memory.temp_base, memory.temp_size)?),
+        Segment::That => snippets::push_from_segment("THAT", index),
+        Segment::This => snippets::push_from_segment("THIS", index),
+        Segment::Reg => snippets::push_from_variable(&format!("R{index}")),
+    })
 }
 
-fn push_from_segment(segment_name: &str, index: u16) -> Result<String, String> {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(formatdoc!(
-        "@{index}
-        D=A
-        @{segment_name}
-        A=D+M
-        D=M"
-    ));
-    asm.push(push_d());
-
-    Ok(asm.join("\n"))
-}
-
-fn push_constant(value: u16) -> Result<String, String> {
-    let mut asm: Vec<String> = Vec::new();
-    asm.push(format!("@{value}"));
-    asm.push(format!("D=A"));
-    asm.push(push_d());
-    Ok(asm.join("\n"))
+// The address of `segment index` for `pointer` and `temp`, which take
+// `size` words from `base`; past them is some other part of RAM.
+fn fixed_address(segment: &Segment, index: u16, base: u16, size: u16) -> Result<u16, String> {
+    match size.checked_sub(1) {
+        Some(max) if index <= max => Ok(base + index),
+        Some(max) => Err(format!("{segment} index must be 0-{max}, got {index}")),
+        None => Err(format!("The memory map has no {segment} segment")),
+    }
 }
 
 fn generate_binary_operation(op: &str) -> Result<String, String> {
@@ -375,16 +2475,16 @@ fn generate_unary(op: &str) -> Result<String, String> {
     Ok(asm.join("\n"))
 }
 
-fn generate_eq(source_command: &SourceCommand) -> Result<String, String> {
-    generate_comparison(source_command, "JEQ")
+fn generate_eq(source_command: &SourceCommand, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    generate_comparison(source_command, "JEQ", scope, site, symbols)
 }
 
-fn generate_gt(source_command: &SourceCommand) -> Result<String, String> {
-    generate_comparison(source_command, "JGT")
+fn generate_gt(source_command: &SourceCommand, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    generate_comparison(source_command, "JGT", scope, site, symbols)
 }
 
-fn generate_lt(source_command: &SourceCommand) -> Result<String, String> {
-    generate_comparison(source_command, "JLT")
+fn generate_lt(source_command: &SourceCommand, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    generate_comparison(source_command, "JLT", scope, site, symbols)
 }
 
 // This generates a comparison process that will
@@ -394,9 +2494,10 @@ fn generate_lt(source_command: &SourceCommand) -> Result<String, String> {
 // Hack jump command, that will jump if the required
 // comparison is true based on the value of D.
 //
-fn generate_comparison(sc: &SourceCommand, comp: &str) -> Result<String, String> {
-    let file = sc.file_base();
-    let line = sc.line();
+fn generate_comparison(sc: &SourceCommand, comp: &str, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    let scope = scope.map_or(sc.file_base(), String::as_str);
+    let comp_true = symbols.comparison("TRUE", sc.file_base(), scope, site);
+    let comp_end = symbols.comparison("END", sc.file_base(), scope, site);
     let mut asm: Vec<String> = Vec::new();
     asm.push(pop_d());
     asm.push(formatdoc!(
@@ -404,55 +2505,94 @@ fn generate_comparison(sc: &SourceCommand, comp: &str) -> Result<String, String>
         @SP
         AM=M-1
         D=M-D
-        @COMP_TRUE_{file}.{line}
+        @{comp_true}
         D;{comp}
         @0
         D=A
-        @COMP_END_{file}.{line}
+        @{comp_end}
         0;JMP
-        (COMP_TRUE_{file}.{line})
+        ({comp_true})
         @1
         D=-A
-        (COMP_END_{file}.{line})"
+        ({comp_end})"
     ));
     asm.push(push_d());
 
     Ok(asm.join("\n"))
 }
 
-fn comment(source_command: &SourceCommand) -> String {
-    format!(
-        "// {}[{}]: {}\n",
-        source_command.file_base(),
-        source_command.line(),
-        source_command.source()
-    )
-}
-
-fn pop_d() -> String {
-    formatdoc!(
+// Like `generate_comparison`, but for `gt` and `lt` with operands of
+// any size. When the signs of X and Y differ, X - Y can overflow, but
+// the answer is then X's sign, so D is set to 1 or -1 instead; only
+// when the signs match is the subtraction used. Y is left in the word
+// above the stack until X has been read.
+//
+fn generate_safe_comparison(sc: &SourceCommand, comp: &str, scope: Option<&String>, site: usize, symbols: &Symbols) -> Result<String, String> {
+    let scope = scope.map_or(sc.file_base(), String::as_str);
+    let [y_negative, same_sign, compare, comp_true, comp_end] =
+        ["NEG", "SAME", "TEST", "TRUE", "END"].map(|outcome| symbols.comparison(outcome, sc.file_base(), scope, site));
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(formatdoc!(
         "
         @SP
         AM=M-1
-        D=M"
-    )
-}
-
-fn push_d() -> String {
-    formatdoc!(
-        "
+        D=M
+        @{y_negative}
+        D;JLT
+        @SP
+        A=M-1
+        D=M
+        @{same_sign}
+        D;JGE
+        D=-1
+        @{compare}
+        0;JMP
+        ({y_negative})
+        @SP
+        A=M-1
+        D=M
+        @{same_sign}
+        D;JLT
+        D=1
+        @{compare}
+        0;JMP
+        ({same_sign})
         @SP
         A=M
-        M=D
+        D=M
+        A=A-1
+        D=M-D
+        ({compare})
         @SP
-        M=M+1"
-    )
+        M=M-1
+        @{comp_true}
+        D;{comp}
+        @0
+        D=A
+        @{comp_end}
+        0;JMP
+        ({comp_true})
+        @1
+        D=-A
+        ({comp_end})"
+    ));
+    asm.push(push_d());
+
+    Ok(asm.join("\n"))
 }
 
-fn push_symbol(symbol: &str) -> String {
-    formatdoc!(
-        "@{symbol}
-        D=M
-        {}", push_d()
+fn source_comments(comments: &[&str]) -> String {
+    comments
+        .iter()
+        .map(|text| format!("//{}\n", text.trim_end()))
+        .collect()
+}
+
+fn comment(source_command: &SourceCommand) -> String {
+    format!(
+        "// {}[{}]: {}\n",
+        source_command.file_base(),
+        source_command.line(),
+        excerpt(source_command.source())
     )
 }
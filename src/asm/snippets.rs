@@ -0,0 +1,235 @@
+//! Building blocks shared by the code generators, for tools that
+//! post-process or extend the translator's output.
+//!
+//! Each snippet is a newline-separated block of Hack assembly. Unless
+//! stated otherwise a snippet assumes nothing about the registers on
+//! entry and leaves A and D clobbered. "SP delta" is the change to the
+//! stack pointer once the snippet has run.
+//!
+//! Each snippet is run below on the assembler's `Cpu`, with two values on
+//! a stack at 256 and every other word of RAM holding something
+//! recognisable, and must change exactly the words its contract names:
+//! SP by its delta, and the cells listed. A new snippet goes in the table
+//! too; the check fails until it does.
+//!
+#![cfg_attr(feature = "assembler", doc = "```")]
+#![cfg_attr(not(feature = "assembler"), doc = "```ignore")]
+//! use hack_vmtranslator::asm::snippets::*;
+//! use hack_vmtranslator::assembler::{assemble, Cpu};
+//!
+//! let pattern = |address: usize| (address * 7 + 3) as i16;
+//! // name, the snippet (after any setup), SP delta, the other words it
+//! // writes, and D and A on exit where the contract says.
+//! let contracts: Vec<(&str, String, i16, Vec<(usize, i16)>, Option<i16>, Option<i16>)> = vec![
+//!     ("pop_d", pop_d(), -1, vec![], Some(22), Some(255)),
+//!     ("push_d", format!("@77\nD=A\n{}", push_d()), 1, vec![(256, 77)], Some(77), None),
+//!     ("push_symbol", push_symbol("LCL"), 1, vec![(256, 300)], Some(300), None),
+//!     ("push_constant", push_constant(7), 1, vec![(256, 7)], Some(7), None),
+//!     ("push_from_variable", push_from_variable("R13"), 1, vec![(256, pattern(13))], Some(pattern(13)), None),
+//!     ("push_from_address", push_from_address(5), 1, vec![(256, pattern(5))], Some(pattern(5)), None),
+//!     ("push_from_segment", push_from_segment("LCL", 2), 1, vec![(256, pattern(302))], Some(pattern(302)), None),
+//!     ("pop_to_variable", pop_to_variable("R14"), -1, vec![(14, 22)], Some(22), None),
+//!     ("pop_to_address", pop_to_address(6), -1, vec![(6, 22)], Some(22), None),
+//!     ("pop_to_segment", pop_to_segment("LCL", 3), -1, vec![(303, 22)], Some(303 + 22), Some(303)),
+//!     ("pop_to_segment_via_r13", pop_to_segment_via_r13("THAT", 1), -1, vec![(13, 4001), (4001, 22)], Some(22), None),
+//!     ("set_segment_pointer", set_segment_pointer("THIS", 3030), 0, vec![(3, 3030)], Some(3030), None),
+//! ];
+//!
+//! let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/asm/snippets.rs")).unwrap();
+//! for name in source.lines().filter_map(|line| line.strip_prefix("pub fn ")).map(|rest| rest.split('(').next().unwrap()) {
+//!     assert!(contracts.iter().any(|(n, ..)| *n == name), "{name} has no contract");
+//! }
+//!
+//! for (name, snippet, sp_delta, writes, d, a) in contracts {
+//!     let mut cpu = Cpu::new();
+//!     for (address, word) in cpu.ram.iter_mut().enumerate() {
+//!         *word = pattern(address);
+//!     }
+//!     for (address, value) in [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 4000), (254, 11), (255, 22)] {
+//!         cpu.ram[address] = value;
+//!     }
+//!     let before = cpu.ram.clone();
+//!     cpu.run(&assemble(&snippet).unwrap(), 1000);
+//!
+//!     let mut expected = before.clone();
+//!     expected[0] += sp_delta;
+//!     for (address, value) in writes {
+//!         expected[address] = value;
+//!     }
+//!     let changed = |ram: &[i16]| -> Vec<(usize, i16)> {
+//!         ram.iter().enumerate().filter(|(i, v)| before[*i] != **v).map(|(i, v)| (i, *v)).collect()
+//!     };
+//!     assert_eq!(changed(&cpu.ram), changed(&expected), "{name} changed RAM outside its contract");
+//!     if let Some(d) = d {
+//!         assert_eq!(cpu.d, d, "{name} left D wrong");
+//!     }
+//!     if let Some(a) = a {
+//!         assert_eq!(cpu.a, a, "{name} left A wrong");
+//!     }
+//! }
+//! ```
+
+use indoc::formatdoc;
+
+/// Pops the top of the stack into D.
+///
+/// SP delta: -1. On exit D holds the popped value and A holds the new SP.
+pub fn pop_d() -> String {
+    formatdoc!(
+        "
+        @SP
+        AM=M-1
+        D=M"
+    )
+}
+
+/// Pushes D onto the stack.
+///
+/// SP delta: +1. Expects the value in D; D is preserved.
+pub fn push_d() -> String {
+    formatdoc!(
+        "
+        @SP
+        A=M
+        M=D
+        @SP
+        M=M+1"
+    )
+}
+
+/// Pushes the value stored at `symbol`, e.g. the LCL pointer itself.
+///
+/// SP delta: +1. On exit D holds the pushed value.
+pub fn push_symbol(symbol: &str) -> String {
+    formatdoc!(
+        "@{symbol}
+        D=M
+        {}", push_d()
+    )
+}
+
+/// Pushes a constant, which must fit in an A-instruction (0..=32767).
+///
+/// SP delta: +1. On exit D holds the constant.
+pub fn push_constant(value: u16) -> String {
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(format!("@{value}"));
+    asm.push("D=A".to_string());
+    asm.push(push_d());
+    asm.join("\n")
+}
+
+/// Pushes the value stored in the variable or register `variable`.
+///
+/// SP delta: +1. On exit D holds the pushed value.
+pub fn push_from_variable(variable: &str) -> String {
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(formatdoc!(
+        "@{variable}
+        D=M
+        "
+    ));
+    asm.push(push_d());
+    asm.join("\n")
+}
+
+/// Pushes RAM[`address`].
+///
+/// SP delta: +1. On exit D holds the pushed value.
+pub fn push_from_address(address: u16) -> String {
+    push_from_variable(&address.to_string())
+}
+
+/// Pushes RAM[`pointer` + `index`], where `pointer` names a segment
+/// pointer such as LCL or THAT.
+///
+/// SP delta: +1. On exit D holds the pushed value.
+pub fn push_from_segment(pointer: &str, index: u16) -> String {
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(formatdoc!(
+        "@{index}
+        D=A
+        @{pointer}
+        A=D+M
+        D=M"
+    ));
+    asm.push(push_d());
+    asm.join("\n")
+}
+
+/// Pops the top of the stack into the variable or register `variable`.
+///
+/// SP delta: -1. On exit D holds the popped value.
+pub fn pop_to_variable(variable: &str) -> String {
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(pop_d());
+    asm.push(formatdoc!(
+        "@{variable}
+        M=D"
+    ));
+    asm.join("\n")
+}
+
+/// Pops the top of the stack into RAM[`address`].
+///
+/// SP delta: -1. On exit D holds the popped value.
+pub fn pop_to_address(address: u16) -> String {
+    pop_to_variable(&address.to_string())
+}
+
+/// Pops the top of the stack into RAM[`pointer` + `index`] without using
+/// any scratch register: D briefly holds address + value, and the value
+/// is recovered by subtracting the address back out.
+///
+/// SP delta: -1. On exit A holds the target address and D holds
+/// address + value.
+pub fn pop_to_segment(pointer: &str, index: u16) -> String {
+    formatdoc!(
+        "@{pointer}
+        D=M
+        @{index}
+        D=D+A
+        @SP
+        AM=M-1
+        D=D+M
+        A=D-M
+        M=D-A"
+    )
+}
+
+/// Pops the top of the stack into RAM[`pointer` + `index`], keeping the
+/// target address in R13. Longer than `pop_to_segment` but simpler to
+/// follow, and D holds the popped value on exit.
+///
+/// SP delta: -1. Clobbers R13.
+pub fn pop_to_segment_via_r13(pointer: &str, index: u16) -> String {
+    let mut asm: Vec<String> = Vec::new();
+    asm.push(formatdoc!(
+        "@{pointer}
+        D=M
+        @{index}
+        D=D+A
+        @R13
+        M=D"
+    ));
+    asm.push(pop_d());
+    asm.push(formatdoc!(
+        "@R13
+        A=M
+        M=D"
+    ));
+    asm.join("\n")
+}
+
+/// Points the segment pointer (or any register) `pointer` at `address`,
+/// e.g. `set_segment_pointer("SP", 256)`.
+///
+/// SP delta: 0, unless `pointer` is SP. On exit D holds `address`.
+pub fn set_segment_pointer(pointer: &str, address: u16) -> String {
+    formatdoc!(
+        "@{address}
+        D=A
+        @{pointer}
+        M=D"
+    )
+}
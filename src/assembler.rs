@@ -0,0 +1,443 @@
+use crate::asm::{c_instruction, PREDEFINED_SYMBOLS, VARIABLE_BASE};
+use crate::json::Json;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A line of assembly that couldn't be assembled. `line` counts from zero.
+#[derive(Debug, Clone)]
+pub struct AssembleError {
+    pub line: usize,
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Assembly error at line {} ({}): {}",
+            self.line, self.source, self.message
+        )
+    }
+}
+
+/// Machine code assembled from Hack assembly.
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// One word per instruction, in ROM order.
+    pub words: Vec<u16>,
+    /// For each line of the source, the ROM address of the instruction on
+    /// it, if it holds one.
+    pub addresses: Vec<Option<usize>>,
+    /// The labels and variables the words were encoded with, labels in
+    /// source order and then variables in the order they were allocated.
+    /// The predefined symbols aren't included.
+    pub symbols: Vec<Symbol>,
+}
+
+/// A symbol defined by the assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// A ROM address for a label, a RAM address for a variable.
+    pub address: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Variable,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Variable => "variable",
+        })
+    }
+}
+
+impl Program {
+    /// The `.hack` text: one 16-digit binary word per line.
+    pub fn to_hack(&self) -> String {
+        self.words.iter().map(|w| format!("{w:016b}\n")).collect()
+    }
+
+    /// `source` with each instruction prefixed by its ROM address and
+    /// machine word. `source` must be the text the program was assembled
+    /// from.
+    pub fn listing(&self, source: &str) -> String {
+        source
+            .lines()
+            .zip(&self.addresses)
+            .map(|(line, address)| match address {
+                Some(address) => {
+                    format!("{address:05}  {:016b}  {line}\n", self.words[*address])
+                }
+                None if line.trim().is_empty() => "\n".to_string(),
+                None => format!("{:23}{line}\n", ""),
+            })
+            .collect()
+    }
+
+    /// The `.symbols` file: `symbol kind address` on each line, in the
+    /// order of `symbols`.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{label_addresses, variable_allocation};
+    /// use hack_vmtranslator::assembler::{assemble, SymbolKind};
+    ///
+    /// let source = "@i\nM=0\n(LOOP)\n@i\nM=M+1\n@sum\nM=D\n@LOOP\n0;JMP\n(END)\n@END\n0;JMP\n";
+    /// let program = assemble(source).unwrap();
+    /// assert_eq!(program.symbol_table(), "LOOP label 2\nEND label 8\ni variable 16\nsum variable 17\n");
+    ///
+    /// // The same addresses as the listing, the `.sym` file's labels and
+    /// // the variables `--dump-symbols` predicts.
+    /// let listing = program.listing(source);
+    /// for symbol in program.symbols.iter().filter(|s| s.kind == SymbolKind::Label) {
+    ///     let at = listing.lines().position(|line| line.ends_with(&format!("({})", symbol.name))).unwrap();
+    ///     assert!(listing.lines().nth(at + 1).unwrap().starts_with(&format!("{:05}", symbol.address)));
+    ///     assert!(label_addresses(source).contains(&(symbol.name.as_str(), symbol.address as usize)));
+    /// }
+    /// for (name, address) in variable_allocation(source) {
+    ///     assert!(program.symbols.iter().any(|s| s.name == name && s.address == address));
+    /// }
+    /// ```
+    pub fn symbol_table(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|symbol| format!("{} {} {}\n", symbol.name, symbol.kind, symbol.address))
+            .collect()
+    }
+
+    /// `symbols` for the JSON report.
+    pub fn symbols_to_json(&self) -> Json {
+        Json::Array(
+            self.symbols
+                .iter()
+                .map(|symbol| {
+                    Json::object(vec![
+                        ("name", Json::from(symbol.name.as_str())),
+                        ("kind", Json::from(symbol.kind.to_string())),
+                        ("address", Json::from(symbol.address as usize)),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The Hack CPU and its RAM, running an assembled `Program` from ROM
+/// address 0 until it runs off the end. Enough to check what a piece of
+/// generated code does without the course's CPU emulator; there is no
+/// screen or keyboard, only RAM.
+///
+/// ```
+/// use hack_vmtranslator::assembler::{assemble, Cpu};
+///
+/// // RAM[2] = max(RAM[0], RAM[1])
+/// let program = assemble(
+///     "@0\nD=M\n@1\nD=D-M\n@FIRST\nD;JGT\n@1\nD=M\n@STORE\n0;JMP\n(FIRST)\n@0\nD=M\n(STORE)\n@2\nM=D\n",
+/// )
+/// .unwrap();
+/// let mut cpu = Cpu::new();
+/// cpu.ram[0] = -7;
+/// cpu.ram[1] = 12;
+/// assert_eq!(cpu.run(&program, 100), 12);
+/// assert_eq!((cpu.ram[2], cpu.d, cpu.a), (12, 12, 2));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cpu {
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+    pub ram: Vec<i16>,
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu {
+            a: 0,
+            d: 0,
+            pc: 0,
+            ram: vec![0; 0x8000],
+        }
+    }
+
+    /// Runs until the program counter leaves `program` or `max_steps`
+    /// instructions have run, and returns how many did.
+    pub fn run(&mut self, program: &Program, max_steps: u64) -> u64 {
+        let mut steps = 0;
+        while steps < max_steps && self.step(program) {
+            steps += 1;
+        }
+        steps
+    }
+
+    /// Runs the instruction at `pc`; false, running nothing, if there
+    /// isn't one.
+    pub fn step(&mut self, program: &Program) -> bool {
+        let Some(&word) = program.words.get(self.pc) else {
+            return false;
+        };
+        self.pc += 1;
+        if word & 0x8000 == 0 {
+            self.a = word as i16;
+            return true;
+        }
+
+        let address = self.a as u16 as usize & 0x7FFF;
+        let bit = |n: u16| word >> n & 1 == 1;
+        let mut x = self.d;
+        let mut y = if bit(12) { self.ram[address] } else { self.a };
+        if bit(11) {
+            x = 0;
+        }
+        if bit(10) {
+            x = !x;
+        }
+        if bit(9) {
+            y = 0;
+        }
+        if bit(8) {
+            y = !y;
+        }
+        let mut out = if bit(7) { x.wrapping_add(y) } else { x & y };
+        if bit(6) {
+            out = !out;
+        }
+
+        if bit(3) {
+            self.ram[address] = out;
+        }
+        let jump = (bit(2) && out < 0) || (bit(1) && out == 0) || (bit(0) && out > 0);
+        if jump {
+            self.pc = self.a as u16 as usize;
+        }
+        if bit(5) {
+            self.a = out;
+        }
+        if bit(4) {
+            self.d = out;
+        }
+        true
+    }
+}
+
+/// Assembles Hack assembly in the usual two passes: labels first, then
+/// instructions, with variables allocated from RAM[16] on first use.
+///
+/// ```
+/// use hack_vmtranslator::assembler::assemble;
+///
+/// let program = assemble("(LOOP)\n@LOOP\n0;JMP // spin\n").unwrap();
+/// assert_eq!(program.to_hack(), "0000000000000000\n1110101010000111\n");
+/// assert_eq!(program.addresses, vec![None, Some(0), Some(1)]);
+/// ```
+pub fn assemble(source: &str) -> Result<Program, Vec<AssembleError>> {
+    let mut words: Vec<u16> = Vec::new();
+    let mut addresses: Vec<Option<usize>> = Vec::new();
+    let (lines, symbols, mut errors) = resolve(source);
+
+    for (i, line) in lines.into_iter().enumerate() {
+        let word = match line {
+            Line::A(value) => Ok(value),
+            Line::C(code) => c_instruction(code),
+            Line::Invalid(message) => Err(message),
+            Line::Label(_) | Line::Other => {
+                addresses.push(None);
+                continue;
+            }
+        };
+        match word {
+            Ok(word) => {
+                addresses.push(Some(words.len()));
+                words.push(word);
+            }
+            Err(message) => {
+                addresses.push(None);
+                errors.push(AssembleError {
+                    line: i,
+                    source: strip(source.lines().nth(i).unwrap_or_default()).to_string(),
+                    message,
+                });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Program {
+            words,
+            addresses,
+            symbols,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+/// `source` with every symbol replaced by its address and the labels
+/// dropped, for assemblers that only take numbers. With `comments` set,
+/// comments are kept and each label is left behind as one.
+///
+/// Uses the same resolution as `assemble`, so the two always agree.
+///
+/// ```
+/// use hack_vmtranslator::assembler::{assemble, resolve_symbols};
+///
+/// let source = "@i\nM=1\n(LOOP)\n@LOOP\n0;JMP // spin\n";
+/// let resolved = resolve_symbols(source, false).unwrap();
+/// assert_eq!(resolved, "@16\nM=1\n@2\n0;JMP\n");
+/// assert!(!resolved.lines().any(|l| l.starts_with('@') && !l[1..].starts_with(|c: char| c.is_ascii_digit())));
+/// assert_eq!(assemble(&resolved).unwrap().words, assemble(source).unwrap().words);
+///
+/// let commented = resolve_symbols(source, true).unwrap();
+/// assert_eq!(commented, "@16\nM=1\n// (LOOP)\n@2\n0;JMP // spin\n");
+/// ```
+pub fn resolve_symbols(source: &str, comments: bool) -> Result<String, Vec<AssembleError>> {
+    let (lines, _, mut errors) = resolve(source);
+    for (i, line) in lines.iter().enumerate() {
+        if let Line::Invalid(message) = line {
+            let text = source.lines().nth(i).unwrap_or_default();
+            errors.push(AssembleError {
+                line: i,
+                source: strip(text).to_string(),
+                message: message.clone(),
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut out = String::new();
+    for (line, text) in lines.into_iter().zip(source.lines()) {
+        let comment = text.find("//").map(|i| &text[i..]).filter(|_| comments);
+        let code = match line {
+            Line::A(value) => format!("@{value}"),
+            Line::C(code) => code.to_string(),
+            Line::Label(label) if comments => format!("// ({label})"),
+            Line::Other if comments && !text.trim().is_empty() => text.trim().to_string(),
+            Line::Label(_) | Line::Other | Line::Invalid(_) => continue,
+        };
+        match comment {
+            Some(comment) if !code.starts_with("//") => out.push_str(&format!("{code} {comment}\n")),
+            _ => out.push_str(&format!("{code}\n")),
+        }
+    }
+    Ok(out)
+}
+
+/// One source line after symbol resolution.
+enum Line<'s> {
+    A(u16),
+    /// A C-instruction, still to be checked and encoded.
+    C(&'s str),
+    Label(&'s str),
+    /// Blank or only a comment.
+    Other,
+    /// An A-instruction whose value can't be resolved.
+    Invalid(String),
+}
+
+/// Resolves every A-instruction in `source`, returning the symbols it
+/// defined as it went. The errors are duplicate labels; bad
+/// A-instructions come back as `Line::Invalid` so callers can report them
+/// in line order.
+fn resolve(source: &str) -> (Vec<Line<'_>>, Vec<Symbol>, Vec<AssembleError>) {
+    let mut symbols: HashMap<String, u16> = PREDEFINED_SYMBOLS
+        .iter()
+        .map(|(name, address)| (name.to_string(), *address))
+        .collect();
+    let mut defined: Vec<Symbol> = Vec::new();
+    let mut errors: Vec<AssembleError> = Vec::new();
+    let error = |line: usize, text: &str, message: String| AssembleError {
+        line,
+        source: text.to_string(),
+        message,
+    };
+
+    let mut rom = 0;
+    for (i, line) in source.lines().enumerate() {
+        let code = strip(line);
+        if let Some(label) = code.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            if symbols.insert(label.to_string(), rom).is_some() {
+                errors.push(error(i, code, format!("Duplicate label '{label}'")));
+            }
+            defined.push(Symbol {
+                name: label.to_string(),
+                kind: SymbolKind::Label,
+                address: rom,
+            });
+        } else if !code.is_empty() {
+            rom += 1;
+        }
+    }
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut next_variable = VARIABLE_BASE;
+    for line in source.lines() {
+        let code = strip(line);
+        if code.is_empty() {
+            lines.push(Line::Other);
+        } else if let Some(label) = code.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            lines.push(Line::Label(label));
+        } else if let Some(value) = code.strip_prefix('@') {
+            lines.push(match a_instruction(value, &mut symbols, &mut defined, &mut next_variable) {
+                Ok(value) => Line::A(value),
+                Err(message) => Line::Invalid(message),
+            });
+        } else {
+            lines.push(Line::C(code));
+        }
+    }
+
+    (lines, defined, errors)
+}
+
+fn strip(line: &str) -> &str {
+    match line.find("//") {
+        Some(i) => line[..i].trim(),
+        None => line.trim(),
+    }
+}
+
+fn a_instruction(
+    value: &str,
+    symbols: &mut HashMap<String, u16>,
+    defined: &mut Vec<Symbol>,
+    next_variable: &mut u16,
+) -> Result<u16, String> {
+    if value.starts_with(|c: char| c.is_ascii_digit()) {
+        return match value.parse::<u16>() {
+            Ok(n) if n < 0x8000 => Ok(n),
+            _ => Err(format!("Constant out of range: '{value}'")),
+        };
+    }
+    if let Some(address) = symbols.get(value) {
+        return Ok(*address);
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+    {
+        return Err(format!("Invalid symbol: '{value}'"));
+    }
+    let address = *next_variable;
+    symbols.insert(value.to_string(), address);
+    defined.push(Symbol {
+        name: value.to_string(),
+        kind: SymbolKind::Variable,
+        address,
+    });
+    *next_variable += 1;
+    Ok(address)
+}
@@ -0,0 +1,178 @@
+use crate::asm::is_instruction;
+use crate::callgraph::CallGraph;
+use crate::json::Json;
+
+/// One ROM page of a banked program.
+#[derive(Debug, Clone)]
+pub struct Bank {
+    /// Functions placed in this bank, in output order.
+    pub functions: Vec<String>,
+    pub text: String,
+    /// Instructions in `text`.
+    pub size: usize,
+}
+
+/// Splits translated assembly into banks of at most `max_rom`
+/// instructions for bank-switched ROM. Whole functions are packed greedily
+/// in output order; code before the first function (the bootstrap) stays
+/// at the start of bank 0. `shim`, the user's bank-switch routine, is
+/// placed at the start of every bank so each page can reach the others.
+///
+/// Fails if a function can't fit in a bank on its own, or if a call
+/// crosses banks and there is no shim to carry it.
+///
+/// ```
+/// use hack_vmtranslator::bank::partition;
+/// use hack_vmtranslator::callgraph::CallGraph;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "function f 0\nfunction g 0\n")];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let graph = CallGraph::build(&commands);
+///
+/// let asm = "(f)\n@1\nD=A\n(g)\n@2\nD=A\n";
+/// let banks = partition(asm, &graph, 2, None).unwrap();
+/// assert_eq!(banks.len(), 2);
+/// assert_eq!(banks[1].functions, vec!["g"]);
+/// assert!(partition(asm, &graph, 1, None).is_err());
+/// ```
+pub fn partition(
+    asm: &str,
+    graph: &CallGraph,
+    max_rom: usize,
+    shim: Option<&str>,
+) -> Result<Vec<Bank>, String> {
+    let shim_size = shim.map_or(0, |s| s.lines().filter(|l| is_instruction(l)).count());
+    let (preamble, functions) = split_functions(asm, graph);
+
+    let mut banks = vec![Bank {
+        functions: Vec::new(),
+        text: String::new(),
+        size: 0,
+    }];
+    append(&mut banks[0], preamble);
+    if let Some(shim) = shim {
+        append(&mut banks[0], shim);
+    }
+
+    for (name, text) in functions {
+        let size = text.lines().filter(|l| is_instruction(l)).count();
+        if size + shim_size > max_rom {
+            return Err(format!(
+                "Function {name} needs {size} instructions, more than a bank of {max_rom} holds"
+            ));
+        }
+        if banks.last().unwrap().size + size > max_rom {
+            let mut bank = Bank {
+                functions: Vec::new(),
+                text: String::new(),
+                size: 0,
+            };
+            if let Some(shim) = shim {
+                append(&mut bank, shim);
+            }
+            banks.push(bank);
+        }
+        let bank = banks.last_mut().unwrap();
+        bank.functions.push(name.to_string());
+        append(bank, text);
+    }
+
+    if banks[0].size > max_rom {
+        return Err(format!(
+            "The bootstrap needs {} instructions, more than a bank of {max_rom} holds",
+            banks[0].size
+        ));
+    }
+    if shim.is_none() {
+        check_cross_bank_calls(&banks, graph)?;
+    }
+    Ok(banks)
+}
+
+/// The manifest written alongside the banks, mapping functions to banks.
+pub fn manifest(banks: &[Bank], files: &[String], max_rom: usize) -> Json {
+    Json::object(vec![
+        ("max_rom", Json::from(max_rom)),
+        (
+            "banks",
+            Json::Array(
+                banks
+                    .iter()
+                    .zip(files)
+                    .enumerate()
+                    .map(|(i, (bank, file))| {
+                        Json::object(vec![
+                            ("bank", Json::from(i)),
+                            ("file", Json::from(file.as_str())),
+                            ("size", Json::from(bank.size)),
+                            (
+                                "functions",
+                                Json::Array(
+                                    bank.functions.iter().map(|f| Json::from(f.as_str())).collect(),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+/// Splits `asm` at the entry labels of the functions in `graph`.
+fn split_functions<'s>(asm: &'s str, graph: &CallGraph) -> (&'s str, Vec<(&'s str, &'s str)>) {
+    let mut starts: Vec<(usize, &'s str)> = Vec::new();
+    let mut offset = 0;
+    for line in asm.split_inclusive('\n') {
+        let label = line
+            .trim()
+            .strip_prefix('(')
+            .and_then(|l| l.strip_suffix(')'));
+        if let Some(label) = label {
+            if graph.functions().contains(&label) {
+                starts.push((offset, label));
+            }
+        }
+        offset += line.len();
+    }
+
+    let preamble_end = starts.first().map_or(asm.len(), |(start, _)| *start);
+    let functions = starts
+        .iter()
+        .enumerate()
+        .map(|(i, (start, name))| {
+            let end = starts.get(i + 1).map_or(asm.len(), |(end, _)| *end);
+            (*name, &asm[*start..end])
+        })
+        .collect();
+    (&asm[..preamble_end], functions)
+}
+
+fn append(bank: &mut Bank, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    bank.size += text.lines().filter(|l| is_instruction(l)).count();
+    bank.text.push_str(text);
+    if !text.ends_with('\n') {
+        bank.text.push('\n');
+    }
+}
+
+fn check_cross_bank_calls(banks: &[Bank], graph: &CallGraph) -> Result<(), String> {
+    let bank_of = |function: &str| banks.iter().position(|b| b.functions.iter().any(|f| f == function));
+
+    for (i, bank) in banks.iter().enumerate() {
+        for caller in &bank.functions {
+            for callee in graph.callees(caller) {
+                if let Some(j) = bank_of(callee).filter(|j| *j != i) {
+                    return Err(format!(
+                        "{caller} in bank {i} calls {callee} in bank {j}; give a --bank-call-shim to switch banks"
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
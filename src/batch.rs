@@ -0,0 +1,214 @@
+//! Translating many independent programs in one run, such as a class's
+//! worth of submissions. Each project is translated on its own, as if by
+//! a separate invocation: its own bootstrap decision, its own statics and
+//! its own diagnostics. A project that fails doesn't stop the others.
+
+use crate::asm::{is_instruction, CodegenOptions};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::json::Json;
+use crate::translator::Translator;
+use crate::vm::{self, Source};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// A program to translate: a directory of `.vm` files, or a single file.
+#[derive(Debug, Clone)]
+pub struct Project {
+    /// The directory's own name, or the file's minus `.vm`, which names
+    /// the output.
+    pub name: String,
+    pub path: PathBuf,
+    pub sources: Vec<Source>,
+    /// Why the sources couldn't be read, if they couldn't.
+    pub load_error: Option<Diagnostic>,
+}
+
+impl Project {
+    /// Reads the sources at `path`. A project that can't be read is
+    /// still returned, to be reported as failed along with the rest.
+    pub fn load(path: impl Into<PathBuf>) -> Project {
+        let path = path.into();
+        let name = if path.is_file() {
+            path.file_stem().map(OsStr::to_os_string)
+        } else {
+            // `.` is named after the directory it stands for.
+            path.file_name()
+                .map(OsStr::to_os_string)
+                .or_else(|| path.canonicalize().ok()?.file_name().map(OsStr::to_os_string))
+        };
+        let read = || -> std::io::Result<Vec<Source>> {
+            vm::discover_sources(&path)?
+                .into_iter()
+                .map(|file| Ok(Source::new(&file, fs::read_to_string(&file)?)))
+                .collect()
+        };
+        let (sources, load_error) = match read() {
+            Ok(sources) if sources.is_empty() => (Vec::new(), Some(format!("{} has no .vm files", path.display()))),
+            Ok(sources) => (sources, None),
+            Err(e) => (Vec::new(), Some(format!("Error reading {}: {e}", path.display()))),
+        };
+        Project {
+            name: name.unwrap_or_default().to_string_lossy().to_string(),
+            path,
+            sources,
+            load_error: load_error.map(|message| Diagnostic::error("Error", message)),
+        }
+    }
+}
+
+/// How one project went.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub name: String,
+    /// The translated program with its metadata block, as the translator
+    /// writes it; `None` if it failed.
+    pub asm: Option<String>,
+    /// Errors, warnings and notes, in the order found.
+    pub diagnostics: Vec<Diagnostic>,
+    pub instructions: usize,
+}
+
+impl Outcome {
+    pub fn succeeded(&self) -> bool {
+        self.asm.is_some()
+    }
+}
+
+/// Translates one project as `Translator` does, keeping what each file
+/// reported.
+pub fn translate(project: &Project, options: &CodegenOptions) -> Outcome {
+    let mut outcome = Outcome {
+        name: project.name.clone(),
+        asm: None,
+        diagnostics: project.load_error.iter().cloned().collect(),
+        instructions: 0,
+    };
+    if project.load_error.is_some() {
+        return outcome;
+    }
+    let mut translator = Translator::new();
+    for source in &project.sources {
+        match translator.add_source(source.path.clone(), source.text.clone()) {
+            Ok(summary) => outcome.diagnostics.extend(summary.diagnostics),
+            Err(errors) => outcome.diagnostics.extend(errors),
+        }
+    }
+    if outcome.diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return outcome;
+    }
+    match translator.finish(options) {
+        Ok(output) => {
+            outcome.instructions = output.asm.lines().filter(|line| is_instruction(line)).count();
+            outcome.asm = Some(format!("{}\n{}", output.asm, output.manifest));
+        }
+        Err(errors) => outcome.diagnostics.extend(errors),
+    }
+    outcome
+}
+
+/// Translates every project on up to `jobs` threads, returning the
+/// outcomes in the order of `projects`.
+///
+/// ```
+/// use hack_vmtranslator::asm::CodegenOptions;
+/// use hack_vmtranslator::batch::{run, summary, Project};
+/// use hack_vmtranslator::json::Json;
+/// use std::fs;
+///
+/// let root = std::env::temp_dir().join(format!("batch_{}", std::process::id()));
+/// let _ = fs::remove_dir_all(&root);
+/// let projects = [
+///     ("SimpleAdd", vec![("SimpleAdd.vm", "push constant 7\npush constant 8\nadd\n")]),
+///     ("Broken", vec![("Main.vm", "push constant 1\npush nowhere 2\n")]),
+///     (
+///         "Statics",
+///         vec![
+///             ("Class1.vm", "function Class1.get 0\npush static 0\nreturn\n"),
+///             ("Sys.vm", "function Sys.init 0\ncall Class1.get 0\nlabel END\ngoto END\n"),
+///         ],
+///     ),
+/// ];
+/// for (name, files) in &projects {
+///     fs::create_dir_all(root.join(name)).unwrap();
+///     for (file, text) in files {
+///         fs::write(root.join(name).join(file), text).unwrap();
+///     }
+/// }
+/// let mut loaded: Vec<Project> = projects.iter().map(|(name, _)| Project::load(root.join(name))).collect();
+/// loaded.push(Project::load(root.join("Missing")));
+///
+/// for jobs in [1, 3] {
+///     let outcomes = run(&loaded, &CodegenOptions::default(), jobs);
+///     let names: Vec<&str> = outcomes.iter().map(|o| o.name.as_str()).collect();
+///     assert_eq!(names, ["SimpleAdd", "Broken", "Statics", "Missing"]);
+///     let succeeded: Vec<bool> = outcomes.iter().map(|o| o.succeeded()).collect();
+///     assert_eq!(succeeded, [true, false, true, false]);
+///
+///     // Each has its own bootstrap decision.
+///     assert!(!outcomes[0].asm.as_ref().unwrap().contains("Bootstrap"));
+///     assert!(outcomes[2].asm.as_ref().unwrap().contains("Bootstrap"));
+///     assert_eq!(outcomes[1].diagnostics[0].message, "Unknown segment name: 'nowhere'");
+///
+///     let summary = summary(&outcomes);
+///     let project = |name: &str| summary.get("projects").and_then(|p| p.get(name)).unwrap();
+///     let number = |json: &Json, key: &str| match json.get(key) {
+///         Some(Json::Number(n)) => *n as usize,
+///         _ => panic!("no {key}"),
+///     };
+///     assert_eq!(project("SimpleAdd").get("status").and_then(Json::as_str), Some("ok"));
+///     assert_eq!(number(project("SimpleAdd"), "instructions"), outcomes[0].instructions);
+///     assert_eq!(project("Broken").get("status").and_then(Json::as_str), Some("failed"));
+///     assert_eq!(number(project("Broken"), "diagnostics"), 1);
+///     assert_eq!(project("Missing").get("errors").and_then(Json::as_array).map(<[Json]>::len), Some(1));
+///     assert_eq!((number(&summary, "ok"), number(&summary, "failed")), (2, 2));
+/// }
+/// fs::remove_dir_all(&root).unwrap();
+/// ```
+pub fn run(projects: &[Project], options: &CodegenOptions, jobs: usize) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(vec![None; projects.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, projects.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(project) = projects.get(i) else { break };
+                let outcome = translate(project, options);
+                outcomes.lock().unwrap()[i] = Some(outcome);
+            });
+        }
+    });
+    outcomes.into_inner().unwrap().into_iter().map(|o| o.expect("every project is taken once")).collect()
+}
+
+/// The summary `batch` prints: each project's status, how many
+/// diagnostics it had and its errors, and how many instructions it came
+/// to, and the totals.
+pub fn summary(outcomes: &[Outcome]) -> Json {
+    let projects = outcomes
+        .iter()
+        .map(|outcome| {
+            let errors = outcome
+                .diagnostics
+                .iter()
+                .filter(|d| d.severity == Severity::Error)
+                .map(|d| Json::from(d.to_string()))
+                .collect();
+            let project = Json::object(vec![
+                ("status", Json::from(if outcome.succeeded() { "ok" } else { "failed" })),
+                ("diagnostics", Json::from(outcome.diagnostics.len())),
+                ("errors", Json::Array(errors)),
+                ("instructions", Json::from(outcome.instructions)),
+            ]);
+            (outcome.name.clone(), project)
+        })
+        .collect();
+    let ok = outcomes.iter().filter(|o| o.succeeded()).count();
+    Json::object(vec![
+        ("projects", Json::Object(projects)),
+        ("ok", Json::from(ok)),
+        ("failed", Json::from(outcomes.len() - ok)),
+    ])
+}
@@ -0,0 +1,318 @@
+use crate::vm::{Command, SourceCommand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which functions each function calls, built from the parsed commands.
+pub struct CallGraph<'a> {
+    functions: Vec<&'a str>,
+    calls: BTreeMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> CallGraph<'a> {
+    pub fn build(commands: &[SourceCommand<'a>]) -> CallGraph<'a> {
+        let mut functions: Vec<&'a str> = Vec::new();
+        let mut calls: BTreeMap<&'a str, Vec<&'a str>> = BTreeMap::new();
+
+        for (function, sc) in function_bodies(commands) {
+            match sc.command() {
+                Command::Function { name, .. } => {
+                    functions.push(name);
+                    calls.entry(name).or_default();
+                }
+                Command::Call { name, .. } => {
+                    if let Some(caller) = function {
+                        let callees = calls.entry(caller).or_default();
+                        if !callees.contains(name) {
+                            callees.push(name);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        CallGraph { functions, calls }
+    }
+
+    /// Defined functions in source order.
+    pub fn functions(&self) -> &[&'a str] {
+        &self.functions
+    }
+
+    /// Functions called by `function`, in order of first call.
+    pub fn callees(&self, function: &str) -> &[&'a str] {
+        self.calls.get(function).map_or(&[], |c| c.as_slice())
+    }
+
+    /// `roots` plus every function they can transitively call.
+    pub fn closure(&self, roots: &[&'a str]) -> BTreeSet<&'a str> {
+        let mut reached: BTreeSet<&'a str> = BTreeSet::new();
+        let mut pending: Vec<&'a str> = roots.to_vec();
+
+        while let Some(function) = pending.pop() {
+            if reached.insert(function) {
+                pending.extend(self.callees(function));
+            }
+        }
+
+        reached
+    }
+
+    // Appends `function` and then, depth first, what it calls, skipping
+    // anything already visited or not defined here.
+    fn depth_first(&self, function: &str, visited: &mut Vec<&'a str>) {
+        let Some((&function, callees)) = self.calls.get_key_value(function) else { return };
+        if visited.contains(&function) {
+            return;
+        }
+        visited.push(function);
+        for callee in callees {
+            self.depth_first(callee, visited);
+        }
+    }
+
+    /// Groups of functions that call each other recursively (the strongly
+    /// connected components with a cycle), each in source order. Self
+    /// recursion is a group of one.
+    ///
+    /// ```
+    /// use hack_vmtranslator::callgraph::CallGraph;
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let text = "function A.a 0\ncall A.b 0\nfunction A.b 0\ncall A.c 0\n\
+    ///             function A.c 0\ncall A.a 0\nfunction A.d 0\ncall A.a 0\n";
+    /// let sources = [Source::new("A.vm", text)];
+    /// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// assert_eq!(CallGraph::build(&commands).cycles(), vec![vec!["A.a", "A.b", "A.c"]]);
+    /// ```
+    pub fn cycles(&self) -> Vec<Vec<&'a str>> {
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: BTreeMap::new(),
+            low: BTreeMap::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        };
+        for function in &self.functions {
+            if !tarjan.index.contains_key(function) {
+                tarjan.visit(function);
+            }
+        }
+
+        let mut cycles: Vec<Vec<&'a str>> = tarjan
+            .components
+            .into_iter()
+            .filter(|c| c.len() > 1 || self.callees(c[0]).contains(&c[0]))
+            .map(|mut c| {
+                c.sort_by_key(|f| self.functions.iter().position(|g| g == f));
+                c
+            })
+            .collect();
+        cycles.sort_by_key(|c| self.functions.iter().position(|g| *g == c[0]));
+        cycles
+    }
+
+    /// The text printed by `--call-graph`: each function with what it
+    /// calls, then any recursion.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for function in &self.functions {
+            let callees = match self.callees(function) {
+                [] => "(nothing)".to_string(),
+                callees => callees.join(", "),
+            };
+            out.push_str(&format!("{function} -> {callees}\n"));
+        }
+        for cycle in self.cycles() {
+            out.push_str(&format!("recursive: {}\n", cycle.join(", ")));
+        }
+        out
+    }
+}
+
+struct Tarjan<'g, 'a> {
+    graph: &'g CallGraph<'a>,
+    index: BTreeMap<&'a str, usize>,
+    low: BTreeMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    components: Vec<Vec<&'a str>>,
+}
+
+impl<'g, 'a> Tarjan<'g, 'a> {
+    fn visit(&mut self, function: &'a str) {
+        let index = self.index.len();
+        self.index.insert(function, index);
+        self.low.insert(function, index);
+        self.stack.push(function);
+
+        for &callee in self.graph.callees(function) {
+            // Calls out of the program (the OS) can't be part of a cycle.
+            if !self.graph.calls.contains_key(callee) {
+                continue;
+            }
+            if !self.index.contains_key(callee) {
+                self.visit(callee);
+                let low = self.low[function].min(self.low[callee]);
+                self.low.insert(function, low);
+            } else if self.stack.contains(&callee) {
+                let low = self.low[function].min(self.index[callee]);
+                self.low.insert(function, low);
+            }
+        }
+
+        if self.low[function] == index {
+            let start = self.stack.iter().position(|f| *f == function).unwrap();
+            self.components.push(self.stack.split_off(start));
+        }
+    }
+}
+
+/// The order `order_functions` puts function bodies in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionOrder {
+    /// As read.
+    #[default]
+    Source,
+    /// By name.
+    Alpha,
+    /// Depth first from the entry function through the calls it makes,
+    /// then the functions it never reaches, by name.
+    CallGraph,
+}
+
+impl FromStr for FunctionOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FunctionOrder, String> {
+        match s {
+            "source" => Ok(FunctionOrder::Source),
+            "alpha" => Ok(FunctionOrder::Alpha),
+            "callgraph" => Ok(FunctionOrder::CallGraph),
+            _ => Err(format!("Unknown function order: '{s}' (expected source, alpha or callgraph)")),
+        }
+    }
+}
+
+impl fmt::Display for FunctionOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FunctionOrder::Source => write!(f, "source"),
+            FunctionOrder::Alpha => write!(f, "alpha"),
+            FunctionOrder::CallGraph => write!(f, "callgraph"),
+        }
+    }
+}
+
+/// Rearranges whole function bodies into `order`, so a function sits near
+/// the ones it calls and the output doesn't depend on how the code is
+/// split into files. Commands before a file's first function move with
+/// that function; a file with no functions keeps its code at the front.
+///
+#[cfg_attr(feature = "interp", doc = "```")]
+#[cfg_attr(not(feature = "interp"), doc = "```ignore")]
+/// use hack_vmtranslator::callgraph::{order_functions, FunctionOrder};
+/// use hack_vmtranslator::interp::{Config, Limits, Vm};
+/// use hack_vmtranslator::vm::{parse_sources, Command, Source};
+///
+/// let sources = [
+///     Source::new("Math.vm", "function Math.double 0\npush argument 0\npush argument 0\nadd\nreturn\n\
+///                             function Math.unused 0\npush constant 0\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\npush constant 4\ncall Sys.twice 1\npop static 0\n\
+///                            label END\ngoto END\n\
+///                            function Sys.twice 0\npush argument 0\ncall Math.double 1\nreturn\n"),
+/// ];
+/// let parse = || -> Vec<_> { parse_sources(&sources).into_iter().map(Result::unwrap).collect() };
+/// let functions = |order| -> Vec<String> {
+///     order_functions(parse(), order, "Sys.init")
+///         .iter()
+///         .filter_map(|sc| match sc.command() {
+///             Command::Function { name, .. } => Some(name.to_string()),
+///             _ => None,
+///         })
+///         .collect()
+/// };
+/// assert_eq!(functions(FunctionOrder::Source), ["Math.double", "Math.unused", "Sys.init", "Sys.twice"]);
+/// assert_eq!(functions(FunctionOrder::Alpha), ["Math.double", "Math.unused", "Sys.init", "Sys.twice"]);
+/// assert_eq!(functions(FunctionOrder::CallGraph), ["Sys.init", "Sys.twice", "Math.double", "Math.unused"]);
+///
+/// let reordered = order_functions(parse(), FunctionOrder::CallGraph, "Sys.init");
+/// let mut vm = Vm::new(&reordered, Config::default());
+/// vm.run(&Limits::default()).unwrap();
+/// assert_eq!(vm.ram()[16], 8);
+/// ```
+pub fn order_functions<'a>(commands: Vec<SourceCommand<'a>>, order: FunctionOrder, entry: &str) -> Vec<SourceCommand<'a>> {
+    let graph = CallGraph::build(&commands);
+    let mut names: Vec<&'a str> = graph.functions().to_vec();
+    names.sort();
+    names.dedup();
+    match order {
+        FunctionOrder::Source => return commands,
+        FunctionOrder::Alpha => (),
+        FunctionOrder::CallGraph => {
+            let mut visited = Vec::new();
+            for root in std::iter::once(entry).chain(names.iter().copied()) {
+                graph.depth_first(root, &mut visited);
+            }
+            names = visited;
+        }
+    }
+
+    // One block per function, plus one for the code ahead of the first
+    // function in each file.
+    let owners: Vec<(Option<&'a str>, &'a str)> =
+        function_bodies(&commands).map(|(function, sc)| (function, sc.file_base())).collect();
+    let file_of: BTreeMap<&'a str, &'a str> =
+        owners.iter().filter_map(|(function, file)| function.map(|f| (f, *file))).collect();
+    let mut blocks: Vec<Option<Block>> = Vec::new();
+    for ((function, file), sc) in owners.into_iter().zip(commands) {
+        let starts_block = matches!(sc.command(), Command::Function { .. })
+            || blocks.last().is_none_or(|block| block.as_ref().unwrap().file != file);
+        if starts_block {
+            blocks.push(Some(Block { function, file, commands: Vec::new() }));
+        }
+        blocks.last_mut().unwrap().as_mut().unwrap().commands.push(sc);
+    }
+
+    let mut ordered = Vec::new();
+    let mut take = |keep: &dyn Fn(Option<&'a str>, &'a str) -> bool| {
+        for slot in blocks.iter_mut() {
+            if slot.as_ref().is_some_and(|block| keep(block.function, block.file)) {
+                ordered.extend(slot.take().unwrap().commands);
+            }
+        }
+    };
+    let function_files: BTreeSet<&'a str> = file_of.values().copied().collect();
+    take(&|function, file| function.is_none() && !function_files.contains(file));
+    for name in names {
+        take(&|function, file| function.is_none() && Some(&file) == file_of.get(name));
+        take(&|function, _| function == Some(name));
+    }
+    ordered
+}
+
+struct Block<'a> {
+    function: Option<&'a str>,
+    file: &'a str,
+    commands: Vec<SourceCommand<'a>>,
+}
+
+/// Pairs each command with the function it belongs to. Commands before
+/// the first `function` of a file don't belong to any function.
+pub fn function_bodies<'a, 'b>(
+    commands: &'b [SourceCommand<'a>],
+) -> impl Iterator<Item = (Option<&'a str>, &'b SourceCommand<'a>)> {
+    let mut current: Option<&'a str> = None;
+    let mut file: Option<&'a str> = None;
+
+    commands.iter().map(move |sc| {
+        if file != Some(sc.file_base()) {
+            file = Some(sc.file_base());
+            current = None;
+        }
+        if let Command::Function { name, .. } = sc.command() {
+            current = Some(name);
+        }
+        (current, sc)
+    })
+}
@@ -0,0 +1,139 @@
+//! `--compat <level>`, which pins the shape of the assembly to what an
+//! earlier release wrote, for graders that diff the output against stored
+//! snapshots. A level is a choice among behaviours the translator already
+//! has options for, not a copy of an old code generator: a level that can
+//! no longer be expressed that way is dropped, and asking for it is an
+//! error.
+//!
+//! `tests/compat` keeps a snapshot of a few programs at every level, so a
+//! change that alters an old level's output fails the tests.
+
+use crate::asm::{BootstrapOptions, CodegenOptions, Comments, LabelScheme, Manifest};
+use std::fmt;
+use std::str::FromStr;
+
+/// Every level `--compat` accepts, oldest first.
+pub const LEVELS: [&str; 2] = ["0.1", "latest"];
+
+/// Levels no longer supported, with the release that dropped them.
+const DROPPED: [(&str, &str); 0] = [];
+
+/// An output shape.
+///
+/// ```
+/// use hack_vmtranslator::asm::CodegenOptions;
+/// use hack_vmtranslator::compat::{Compat, LEVELS};
+/// use hack_vmtranslator::translator::Translator;
+/// use hack_vmtranslator::vm::discover_sources;
+/// use std::fs;
+/// use std::path::Path;
+///
+/// let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat");
+/// let mut programs: Vec<_> = fs::read_dir(root.join("programs")).unwrap().map(|e| e.unwrap().path()).collect();
+/// programs.sort();
+/// assert!(!programs.is_empty());
+/// for level in LEVELS {
+///     let compat: Compat = level.parse().unwrap();
+///     let options = CodegenOptions { label_scheme: compat.label_scheme(), ..CodegenOptions::default() };
+///     compat.check(&options).unwrap();
+///     for program in &programs {
+///         let mut translator = Translator::new();
+///         for file in discover_sources(program).unwrap() {
+///             translator.add_source(&file, fs::read_to_string(&file).unwrap()).unwrap();
+///         }
+///         let output = translator.finish(&options).unwrap();
+///         let asm = compat.finish(output.asm, &output.manifest);
+///
+///         let name = program.file_name().unwrap().to_string_lossy();
+///         let snapshot = root.join(level).join(format!("{name}.asm"));
+///         // Keep the level's output, or drop the level.
+///         assert!(asm == fs::read_to_string(&snapshot).unwrap(), "{} changed", snapshot.display());
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compat {
+    /// The first release: labels numbered by source line, and no metadata
+    /// block. It read a directory's files in whatever order the file system
+    /// gave; this reads them in name order, as every level does.
+    V0_1,
+    /// Whatever this version writes.
+    #[default]
+    Latest,
+}
+
+impl FromStr for Compat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compat, String> {
+        match s {
+            "0.1" => Ok(Compat::V0_1),
+            "latest" => Ok(Compat::Latest),
+            _ => match DROPPED.iter().find(|(level, _)| *level == s) {
+                Some((_, release)) => Err(format!("--compat {s} was dropped in {release}; its output can no longer be made")),
+                None => Err(format!("Unknown compat level: '{s}' (expected {})", LEVELS.join(", "))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Compat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compat::V0_1 => "0.1",
+            Compat::Latest => "latest",
+        })
+    }
+}
+
+impl Compat {
+    /// The label scheme unless `--label-scheme` says otherwise, which
+    /// `check` refuses at any level but `latest`.
+    pub fn label_scheme(self) -> LabelScheme {
+        match self {
+            Compat::V0_1 => LabelScheme::Lines,
+            Compat::Latest => LabelScheme::default(),
+        }
+    }
+
+    /// Refuses options that change the output from what the level pins.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{CodegenOptions, LabelScheme};
+    /// use hack_vmtranslator::compat::Compat;
+    ///
+    /// let options = CodegenOptions { label_scheme: LabelScheme::Lines, ..CodegenOptions::default() };
+    /// assert_eq!(Compat::V0_1.check(&options), Ok(()));
+    /// assert_eq!(
+    ///     Compat::V0_1.check(&CodegenOptions { safe_compare: true, ..options.clone() }),
+    ///     Err("--compat 0.1 pins the comparisons, so it can't take --safe-compare".to_string())
+    /// );
+    /// assert!(Compat::V0_1.check(&CodegenOptions::default()).is_err());
+    /// assert_eq!(Compat::Latest.check(&CodegenOptions { safe_compare: true, ..options }), Ok(()));
+    /// ```
+    pub fn check(self, options: &CodegenOptions) -> Result<(), String> {
+        if self == Compat::Latest {
+            return Ok(());
+        }
+        let pinned = [
+            ("labels", "--label-scheme counters", options.label_scheme != self.label_scheme()),
+            ("comparisons", "--safe-compare", options.safe_compare),
+            ("comments", "--keep-comments or --comments", options.keep_comments || options.comments != Comments::Headers),
+            ("stack", "--stack-checks", options.stack_checks),
+            ("bootstrap", "--entry, --entry-extern, --bootstrap, --no-bootstrap or --bootstrap-mode", options.bootstrap != BootstrapOptions::default()),
+        ];
+        match pinned.iter().find(|(_, _, changed)| *changed) {
+            Some((what, flag, _)) => Err(format!("--compat {self} pins the {what}, so it can't take {flag}")),
+            None => Ok(()),
+        }
+    }
+
+    /// The assembly as written out, with the metadata block after it if
+    /// the level has one.
+    pub fn finish(self, asm: String, manifest: &Manifest) -> String {
+        match self {
+            Compat::V0_1 => asm,
+            Compat::Latest => format!("{asm}\n{manifest}"),
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Tab completion for `hack_vmtranslator completions <shell>`. The scripts
+//! are generated from `FLAGS` and `SUBCOMMANDS`, which the command-line
+//! parsers also check every flag against, so a flag can't be accepted
+//! without being completed.
+
+use crate::{compat, lint};
+use std::str::FromStr;
+
+/// What follows a flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    None,
+    /// A file or directory.
+    Path,
+    /// Anything else: a name, a number, a list.
+    Text,
+    /// One of these, as the next argument.
+    Choice(&'static [&'static str]),
+    /// One of these, joined on with `=`, as in `--error-format=json`.
+    Joined(&'static [&'static str]),
+}
+
+/// Every flag of the translator itself, in the order `--help` lists them.
+pub const FLAGS: [(&str, Value); 59] = [
+    ("--annotate-addresses", Value::Joined(&["hex", "dec"])),
+    ("--report-json", Value::Path),
+    ("--keep-going", Value::None),
+    ("--fail-fast", Value::None),
+    ("--only", Value::Text),
+    ("--skip", Value::Text),
+    ("--only-closure", Value::None),
+    ("--function-order", Value::Choice(&["source", "alpha", "callgraph"])),
+    ("-o", Value::Path),
+    ("--output", Value::Path),
+    ("--compat", Value::Choice(&compat::LEVELS)),
+    ("--label-scheme", Value::Choice(&["lines", "counters"])),
+    ("--safe-compare", Value::None),
+    ("--entry", Value::Text),
+    ("--entry-extern", Value::None),
+    ("--bootstrap", Value::None),
+    ("--no-bootstrap", Value::None),
+    ("--bootstrap-mode", Value::Joined(&["full", "minimal"])),
+    ("--symbol-prefix", Value::Text),
+    ("--extern", Value::Text),
+    ("--error-format", Value::Joined(&["human", "short", "json"])),
+    ("--emit", Value::Choice(&["asm", "asm-resolved", "hack", "listing", "symbols", "ast", "sym", "doc", "spans"])),
+    ("--emit-sym", Value::Path),
+    ("--emit-doc", Value::Path),
+    ("--emit-debug", Value::Path),
+    ("--emit-spans", Value::Path),
+    ("--emit-cfg", Value::Path),
+    ("--emit-cfg-for", Value::Text),
+    ("--emit-manifest", Value::Path),
+    ("--timings", Value::None),
+    ("--reproducible", Value::None),
+    ("--keep-comments", Value::None),
+    ("--comments", Value::Choice(&["off", "headers", "annotated"])),
+    ("--stack-checks", Value::None),
+    ("--no-stack-checks", Value::None),
+    ("--allow-asm-modules", Value::None),
+    ("--lib", Value::Path),
+    ("--gc-functions", Value::None),
+    ("--stack-report", Value::None),
+    ("--call-graph", Value::None),
+    ("--dump-symbols", Value::None),
+    ("--static-report", Value::None),
+    ("--segment-report", Value::None),
+    ("--summary", Value::None),
+    ("-W", Value::Choice(&lint::OPT_IN)),
+    ("-D", Value::Choice(&lint::OPT_IN)),
+    ("--extensions", Value::None),
+    ("--include-once", Value::None),
+    ("--banked", Value::None),
+    ("--max-rom", Value::Text),
+    ("--max-line-length", Value::Text),
+    ("--bank-call-shim", Value::Path),
+    ("--prelude", Value::Path),
+    ("--epilogue", Value::Path),
+    ("--target", Value::Text),
+    ("--debug", Value::None),
+    ("--release", Value::None),
+    ("--print-config", Value::None),
+    ("--config", Value::Path),
+];
+
+/// The subcommands, which take the place of the input, each with its own
+/// flags.
+pub const SUBCOMMANDS: [(&str, &[(&str, Value)]); 11] = [
+    ("fmt", &[("--check", Value::None)]),
+    ("link", &[("-o", Value::Path)]),
+    (
+        "run",
+        &[
+            ("--max-steps", Value::Text),
+            ("--timeout", Value::Text),
+            ("--ram-init", Value::Text),
+            ("--grade", Value::Text),
+            ("--target", Value::Text),
+            ("--entry", Value::Text),
+            ("--heap-report", Value::None),
+            ("--watch", Value::Text),
+            ("--watch-log", Value::None),
+            ("--screenshot", Value::Path),
+            ("--screenshot-hash", Value::None),
+            ("--at-step", Value::Text),
+            ("--at-halt", Value::None),
+        ],
+    ),
+    ("eval", &[("--emit-asm", Value::None), ("--file", Value::Path)]),
+    ("gen-tests", &[("--out", Value::Path)]),
+    ("doctor", &[]),
+    ("completions", &[]),
+    ("verify-manifest", &[]),
+    ("lift", &[("--out-dir", Value::Path)]),
+    ("batch", &[("--projects-from", Value::Path), ("--output-dir", Value::Path), ("--jobs", Value::Text)]),
+    (
+        "verify",
+        &[
+            ("--max-steps", Value::Text),
+            ("--history", Value::Text),
+            ("--label-scheme", Value::Choice(&["lines", "counters"])),
+            ("--safe-compare", Value::None),
+        ],
+    ),
+];
+
+/// The flags of `subcommand`, if there is one by that name.
+///
+/// ```
+/// use hack_vmtranslator::completions::subcommand_flags;
+///
+/// assert!(subcommand_flags("run").unwrap().iter().any(|(flag, _)| *flag == "--max-steps"));
+/// assert_eq!(subcommand_flags("doctor").map(<[_]>::len), Some(0));
+/// assert!(subcommand_flags("build").is_none());
+/// ```
+pub fn subcommand_flags(subcommand: &str) -> Option<&'static [(&'static str, Value)]> {
+    SUBCOMMANDS.iter().find(|(name, _)| *name == subcommand).map(|(_, flags)| *flags)
+}
+
+fn subcommand_names() -> String {
+    SUBCOMMANDS.map(|(name, _)| name).join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Shell, String> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" => Ok(Shell::PowerShell),
+            _ => Err(format!("Unknown shell: '{s}' (expected bash, zsh, fish or powershell)")),
+        }
+    }
+}
+
+/// The completion script for `shell`: flags, the choices of flags that
+/// take one, files for flags that take a path and for the input, the
+/// subcommands in place of the input, and after a subcommand its own
+/// flags instead.
+///
+/// ```
+/// use hack_vmtranslator::completions::{script, Shell, FLAGS, SUBCOMMANDS};
+///
+/// let bash = script(Shell::Bash);
+/// for (flag, _) in FLAGS {
+///     assert!(bash.contains(flag), "{flag}");
+/// }
+/// for (subcommand, flags) in SUBCOMMANDS {
+///     assert!(bash.contains(&format!("        {subcommand})\n")), "{subcommand}");
+///     for (flag, _) in flags {
+///         for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+///             assert!(script(shell).contains(flag.trim_start_matches('-')), "{subcommand} {flag}");
+///         }
+///     }
+/// }
+/// assert!(bash.contains("--function-order) COMPREPLY=($(compgen -W \"source alpha callgraph\" -- \"$cur\"))"));
+/// assert!(bash.contains("--report-json) COMPREPLY=($(compgen -f -- \"$cur\"))"));
+/// assert!(bash.contains("--error-format=*) _hack_vmtranslator_joined \"human short json\""));
+/// assert!(bash.contains("compgen -W \"--check\" -- \"$cur\""));
+///
+/// let zsh = script(Shell::Zsh);
+/// assert!(zsh.contains("'*--label-scheme:value:(lines counters)'"));
+/// assert!(zsh.contains("    run)\n      _arguments -s \\\n        '*--max-steps:value: ' \\\n"));
+/// let fish = script(Shell::Fish);
+/// assert!(fish.contains("complete -c hack_vmtranslator -n 'not __fish_seen_subcommand_from fmt link "));
+/// assert!(fish.contains(" -l emit-sym -r -F\n"));
+/// assert!(fish.contains("complete -c hack_vmtranslator -n '__fish_seen_subcommand_from fmt' -l check\n"));
+/// let powershell = script(Shell::PowerShell);
+/// assert!(powershell.contains("'--error-format=json'"));
+/// assert!(powershell.contains("'verify' = @('--max-steps', '--history', '--label-scheme', '--safe-compare')"));
+/// ```
+pub fn script(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish(),
+        Shell::PowerShell => powershell(),
+    }
+}
+
+/// The bash `case` arms completing `flags`: the words that name them, the
+/// arms for a joined value being typed and the arms for the argument after
+/// a flag.
+fn bash_cases(flags: &[(&str, Value)], indent: &str) -> (Vec<String>, String, String) {
+    let mut joined = String::new();
+    let mut arguments = String::new();
+    let mut names = Vec::new();
+    for (flag, value) in flags {
+        match value {
+            Value::None => names.push(flag.to_string()),
+            Value::Joined(choices) => {
+                names.push(format!("{flag}="));
+                joined.push_str(&format!("{indent}{flag}=*) _hack_vmtranslator_joined \"{}\"; return ;;\n", choices.join(" ")));
+            }
+            value => {
+                names.push(flag.to_string());
+                let action = match value {
+                    Value::Path => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+                    Value::Choice(choices) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", choices.join(" ")),
+                    _ => "COMPREPLY=()".to_string(),
+                };
+                arguments.push_str(&format!("{indent}{flag}) {action}; return ;;\n"));
+            }
+        }
+    }
+    (names, joined, arguments)
+}
+
+fn bash() -> String {
+    let (names, joined, arguments) = bash_cases(&FLAGS, "        ");
+    let mut subcommands = String::new();
+    for (subcommand, flags) in SUBCOMMANDS {
+        let (names, _, arguments) = bash_cases(flags, "                    ");
+        subcommands.push_str(&format!(
+            "        {subcommand})\n\
+             \x20           case \"$prev\" in\n\
+             {arguments}\
+             \x20           esac\n\
+             \x20           if [[ $cur == -* ]]; then\n\
+             \x20               COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n\
+             \x20           else\n\
+             \x20               COMPREPLY=($(compgen -f -- \"$cur\"))\n\
+             \x20           fi\n\
+             \x20           return ;;\n",
+            names.join(" ")
+        ));
+    }
+    format!(
+        "# bash completion for hack_vmtranslator\n\
+         \n\
+         _hack_vmtranslator_joined() {{\n\
+         \x20   # The value after `=`, which bash may have split off into a word of its own.\n\
+         \x20   if [[ $COMP_WORDBREAKS == *=* ]]; then\n\
+         \x20       COMPREPLY=($(compgen -W \"$1\" -- \"${{cur#*=}}\"))\n\
+         \x20   else\n\
+         \x20       COMPREPLY=($(compgen -P \"${{cur%%=*}}=\" -W \"$1\" -- \"${{cur#*=}}\"))\n\
+         \x20   fi\n\
+         }}\n\
+         \n\
+         _hack_vmtranslator() {{\n\
+         \x20   local line=\"${{COMP_LINE:0:COMP_POINT}}\"\n\
+         \x20   local words=($line)\n\
+         \x20   local cur=\"\" prev=\"${{words[-1]}}\" done=${{#words[@]}}\n\
+         \x20   if [[ $line != *[[:space:]] ]]; then\n\
+         \x20       cur=\"${{words[-1]}}\" prev=\"${{words[-2]}}\" done=$((done - 1))\n\
+         \x20   fi\n\
+         \x20   # Past a subcommand, only its own flags apply.\n\
+         \x20   (( done > 1 )) && case \"${{words[1]}}\" in\n\
+         {subcommands}\
+         \x20   esac\n\
+         \x20   case \"$cur\" in\n\
+         {joined}\
+         \x20   esac\n\
+         \x20   case \"$prev\" in\n\
+         {arguments}\
+         \x20   esac\n\
+         \x20   if [[ $cur == -* ]]; then\n\
+         \x20       COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n\
+         \x20       [[ ${{COMPREPLY[0]}} == *= ]] && compopt -o nospace\n\
+         \x20   elif (( done == 1 )); then\n\
+         \x20       COMPREPLY=($(compgen -W \"{}\" -- \"$cur\") $(compgen -f -- \"$cur\"))\n\
+         \x20   else\n\
+         \x20       COMPREPLY=($(compgen -f -- \"$cur\"))\n\
+         \x20   fi\n\
+         }}\n\
+         \n\
+         complete -o filenames -F _hack_vmtranslator hack_vmtranslator\n",
+        names.join(" "),
+        subcommand_names()
+    )
+}
+
+/// The `_arguments` specs completing `flags`, one per line.
+fn zsh_specs(flags: &[(&str, Value)], indent: &str) -> String {
+    let mut specs = String::new();
+    for (flag, value) in flags {
+        // Every flag may be repeated; a later one overrides an earlier.
+        let spec = match value {
+            Value::None => format!("*{flag}"),
+            Value::Path => format!("*{flag}:path:_files"),
+            Value::Text => format!("*{flag}:value: "),
+            Value::Choice(choices) => format!("*{flag}:value:({})", choices.join(" ")),
+            Value::Joined(choices) => format!("*{flag}=-:value:({})", choices.join(" ")),
+        };
+        specs.push_str(&format!("{indent}'{spec}' \\\n"));
+    }
+    specs
+}
+
+fn zsh() -> String {
+    let mut subcommands = String::new();
+    for (subcommand, flags) in SUBCOMMANDS {
+        subcommands.push_str(&format!(
+            "    {subcommand})\n\
+             \x20     _arguments -s \\\n\
+             {}\
+             \x20       '*:input:_files'\n\
+             \x20     return ;;\n",
+            zsh_specs(flags, "        ")
+        ));
+    }
+    format!(
+        "#compdef hack_vmtranslator\n\
+         \n\
+         # Past a subcommand, only its own flags apply.\n\
+         if (( CURRENT > 2 )); then\n\
+         \x20 case $words[2] in\n\
+         {subcommands}\
+         \x20 esac\n\
+         fi\n\
+         \n\
+         _arguments -s \\\n\
+         {}\
+         \x20 '1: :_alternative \"subcommands:subcommand:({})\" \"files:input:_files\"' \\\n\
+         \x20 '*:input:_files'\n",
+        zsh_specs(&FLAGS, "  "),
+        subcommand_names()
+    )
+}
+
+/// The `complete` lines for `flags`, offered when `condition` holds.
+fn fish_lines(flags: &[(&str, Value)], condition: &str) -> String {
+    let mut out = String::new();
+    for (flag, value) in flags {
+        let option = match flag.strip_prefix("--") {
+            Some(long) => format!("-l {long}"),
+            None => format!("-s {}", flag.trim_start_matches('-')),
+        };
+        let argument = match value {
+            Value::None => String::new(),
+            Value::Path => " -r -F".to_string(),
+            Value::Text => " -x".to_string(),
+            Value::Choice(choices) | Value::Joined(choices) => format!(" -x -a '{}'", choices.join(" ")),
+        };
+        out.push_str(&format!("complete -c hack_vmtranslator -n '{condition}' {option}{argument}\n"));
+    }
+    out
+}
+
+fn fish() -> String {
+    let names = subcommand_names();
+    let mut out = format!(
+        "# fish completion for hack_vmtranslator\n\
+         complete -c hack_vmtranslator -n __fish_use_subcommand -a '{names}'\n"
+    );
+    out.push_str(&fish_lines(&FLAGS, &format!("not __fish_seen_subcommand_from {names}")));
+    for (subcommand, flags) in SUBCOMMANDS {
+        out.push_str(&fish_lines(flags, &format!("__fish_seen_subcommand_from {subcommand}")));
+    }
+    out
+}
+
+fn powershell() -> String {
+    let quoted = |words: &mut dyn Iterator<Item = String>| words.map(|w| format!("'{w}'")).collect::<Vec<_>>().join(", ");
+    // The words that complete `flags`, and the `$choices` entries of the
+    // ones taking a choice.
+    let tables = |flags: &[(&str, Value)], indent: &str| {
+        let mut names = Vec::new();
+        let mut choices = String::new();
+        for (flag, value) in flags {
+            match value {
+                Value::Joined(values) => names.extend(values.iter().map(|v| format!("{flag}={v}"))),
+                Value::Choice(values) => {
+                    names.push(flag.to_string());
+                    let values = quoted(&mut values.iter().map(|v| v.to_string()));
+                    choices.push_str(&format!("{indent}'{flag}' = @({values})\n"));
+                }
+                _ => names.push(flag.to_string()),
+            }
+        }
+        (quoted(&mut names.into_iter()), choices)
+    };
+    let (flags, choices) = tables(&FLAGS, "        ");
+    let mut subcommand_flags = String::new();
+    let mut subcommand_choices = String::new();
+    for (subcommand, flags) in SUBCOMMANDS {
+        let (names, choices) = tables(flags, "            ");
+        subcommand_flags.push_str(&format!("        '{subcommand}' = @({names})\n"));
+        subcommand_choices.push_str(&format!("        '{subcommand}' = @{{\n{choices}        }}\n"));
+    }
+    let subcommands = quoted(&mut SUBCOMMANDS.iter().map(|(s, _)| s.to_string()));
+    format!(
+        "# PowerShell completion for hack_vmtranslator\n\
+         Register-ArgumentCompleter -Native -CommandName hack_vmtranslator -ScriptBlock {{\n\
+         \x20   param($wordToComplete, $commandAst, $cursorPosition)\n\
+         \x20   $choices = @{{\n\
+         {choices}\
+         \x20   }}\n\
+         \x20   $flags = @({flags})\n\
+         \x20   $subcommands = @({subcommands})\n\
+         \x20   $subcommandFlags = @{{\n\
+         {subcommand_flags}\
+         \x20   }}\n\
+         \x20   $subcommandChoices = @{{\n\
+         {subcommand_choices}\
+         \x20   }}\n\
+         \x20   $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})\n\
+         \x20   $done = if ($wordToComplete) {{ $words.Count - 1 }} else {{ $words.Count }}\n\
+         \x20   $previous = $words[$done - 1]\n\
+         \x20   # Past a subcommand, only its own flags apply.\n\
+         \x20   if ($done -gt 1 -and $subcommandFlags.ContainsKey($words[1])) {{\n\
+         \x20       $choices = $subcommandChoices[$words[1]]\n\
+         \x20       $flags = $subcommandFlags[$words[1]]\n\
+         \x20   }}\n\
+         \x20   if ($choices.ContainsKey($previous)) {{\n\
+         \x20       $candidates = $choices[$previous]\n\
+         \x20   }} elseif ($wordToComplete -like '-*') {{\n\
+         \x20       $candidates = $flags\n\
+         \x20   }} elseif ($done -eq 1) {{\n\
+         \x20       $candidates = $subcommands\n\
+         \x20   }} else {{\n\
+         \x20       # Nothing here leaves PowerShell to complete a path.\n\
+         \x20       return\n\
+         \x20   }}\n\
+         \x20   $candidates | Where-Object {{ $_ -like \"$wordToComplete*\" }} | ForEach-Object {{\n\
+         \x20       [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n\
+         \x20   }}\n\
+         }}\n"
+    )
+}
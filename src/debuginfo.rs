@@ -0,0 +1,355 @@
+//! Line tables for debuggers: which VM command each ROM address belongs
+//! to, in the compact binary form `--emit-debug` writes, with the reader
+//! a debugger can link against.
+//!
+//! A file is, in order:
+//!
+//! - the magic bytes `HVDB` and a version byte, currently 1;
+//! - the file table: a count, then each file's path as a length and
+//!   UTF-8 bytes;
+//! - the function table: a count, then each function's name, as paths
+//!   are written, and the ROM address of its first instruction;
+//! - the line table: a count, then each range of ROM addresses as the
+//!   gap since the end of the previous range (or since address 0), its
+//!   length, the index of its file in the file table, and the line.
+//!
+//! Every number is unsigned LEB128: seven bits a byte, low bits first,
+//! the high bit set on all but the last byte. Ranges are in address
+//! order, don't overlap and aren't empty; lines count from 1. Addresses
+//! no range covers, such as the bootstrap's, belong to no command.
+
+use crate::asm;
+use crate::lift;
+
+/// The first bytes of every file.
+pub const MAGIC: &[u8; 4] = b"HVDB";
+
+/// Bumped whenever the layout changes.
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub functions: Vec<Function>,
+    /// In address order.
+    pub lines: Vec<LineRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub address: usize,
+}
+
+/// The instructions from `start` up to `end` came from `line` of the file
+/// at index `file` in the file table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+    pub file: usize,
+    pub line: usize,
+}
+
+impl DebugInfo {
+    /// The line table of translated code, read from the `// File[line]`
+    /// header each command's code starts with. `base` is the ROM address
+    /// of the first instruction of `asm`, for code placed after a
+    /// prelude. Files are named by their static prefix, as in the
+    /// headers; callers that know the paths can replace them.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{expansion_size, generate_code, label_addresses, CodegenOptions};
+    /// use hack_vmtranslator::debuginfo::{self, DebugInfo};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// // FibonacciElement, from the course.
+    /// let sources = [
+    ///     Source::new(
+    ///         "Main.vm",
+    ///         "// Computes the n'th element of the Fibonacci series, recursively.\n\
+    ///          function Main.fibonacci 0\npush argument 0\npush constant 2\nlt\nif-goto N_LT_2\ngoto N_GE_2\n\
+    ///          label N_LT_2\npush argument 0\nreturn\nlabel N_GE_2\npush argument 0\npush constant 2\nsub\n\
+    ///          call Main.fibonacci 1\npush argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\nadd\nreturn\n",
+    ///     ),
+    ///     Source::new("Sys.vm", "function Sys.init 0\npush constant 4\ncall Main.fibonacci 1\nlabel END\ngoto END\n"),
+    /// ];
+    /// let commands = || parse_sources(&sources).into_iter().map(Result::unwrap).collect::<Vec<_>>();
+    /// let options = CodegenOptions::default();
+    /// let asm = generate_code(commands(), &options).unwrap().join("\n");
+    /// let info = DebugInfo::from_asm(&asm, 0);
+    ///
+    /// assert_eq!(info.files, ["Main", "Sys"]);
+    /// let labels = label_addresses(&asm);
+    /// for function in &info.functions {
+    ///     assert!(labels.contains(&(function.name.as_str(), function.address)), "{}", function.name);
+    /// }
+    /// assert_eq!(info.functions.len(), 2);
+    ///
+    /// // A range for every command with code, as long as its code, in order.
+    /// let with_code: Vec<_> = commands()
+    ///     .into_iter()
+    ///     .map(|sc| (sc.line() + 1, expansion_size(sc.into_command(), &options)))
+    ///     .filter(|(_, size)| *size > 0)
+    ///     .collect();
+    /// let ranges: Vec<_> = info.lines.iter().map(|r| (r.line, r.end - r.start)).collect();
+    /// assert_eq!(ranges, with_code);
+    /// let (main, sys) = (info.functions[0].address, info.functions[1].address);
+    /// assert_eq!(info.line_at(main), Some(("Main", 3)));
+    /// assert_eq!(info.line_at(sys + 2), Some(("Sys", 2)));
+    /// assert_eq!(info.function_at(sys - 1), Some("Main.fibonacci"));
+    /// // The bootstrap has no line.
+    /// assert_eq!((info.line_at(0), info.function_at(0)), (None, None));
+    ///
+    /// // After a prelude, every address moves up.
+    /// let moved = DebugInfo::from_asm(&asm, 10);
+    /// assert_eq!(moved.lines[0].start, info.lines[0].start + 10);
+    /// assert_eq!(debuginfo::read(&debuginfo::write(&info).unwrap()).unwrap(), info);
+    /// ```
+    pub fn from_asm(asm: &str, base: usize) -> DebugInfo {
+        let mut info = DebugInfo::default();
+        let mut address = base;
+        let mut open: Option<LineRange> = None;
+        let close = |open: Option<LineRange>, end: usize, lines: &mut Vec<LineRange>| {
+            if let Some(range) = open.filter(|range| range.start < end) {
+                lines.push(LineRange { end, ..range });
+            }
+        };
+        for text in asm.lines().map(str::trim) {
+            if let Some((file, line, command)) = lift::header(text) {
+                close(open, address, &mut info.lines);
+                let file = match info.files.iter().position(|f| f == file) {
+                    Some(index) => index,
+                    None => {
+                        info.files.push(file.to_string());
+                        info.files.len() - 1
+                    }
+                };
+                if let Some(name) = command.strip_prefix("function ").and_then(|rest| rest.split_whitespace().next()) {
+                    info.functions.push(Function {
+                        name: name.to_string(),
+                        address,
+                    });
+                }
+                open = Some(LineRange {
+                    start: address,
+                    end: address,
+                    file,
+                    line: line + 1,
+                });
+            } else if asm::is_instruction(text) {
+                address += 1;
+            }
+        }
+        close(open, address, &mut info.lines);
+        info
+    }
+
+    /// The file and line of the command the instruction at `address`
+    /// belongs to.
+    pub fn line_at(&self, address: usize) -> Option<(&str, usize)> {
+        let index = self.lines.partition_point(|range| range.end <= address);
+        let range = self.lines.get(index).filter(|range| range.start <= address)?;
+        Some((self.files[range.file].as_str(), range.line))
+    }
+
+    /// The function whose code includes `address`: the one that starts
+    /// closest before it, if a line covers it.
+    pub fn function_at(&self, address: usize) -> Option<&str> {
+        self.line_at(address)?;
+        self.functions
+            .iter()
+            .filter(|function| function.address <= address)
+            .max_by_key(|function| function.address)
+            .map(|function| function.name.as_str())
+    }
+
+    /// What `read` requires of a table, checked on both sides so `write`
+    /// can't produce a file `read` rejects.
+    fn check(&self) -> Result<(), String> {
+        let mut end = 0;
+        for range in &self.lines {
+            if range.start < end {
+                return Err(format!("the line range at {} overlaps or precedes the one before", range.start));
+            }
+            if range.end <= range.start {
+                return Err(format!("the line range at {} is empty", range.start));
+            }
+            if range.file >= self.files.len() {
+                return Err(format!("the line range at {} names file {}, of {}", range.start, range.file, self.files.len()));
+            }
+            end = range.end;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes `info` as described in the module documentation.
+pub fn write(info: &DebugInfo) -> Result<Vec<u8>, String> {
+    info.check()?;
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    let number = |bytes: &mut Vec<u8>, mut n: usize| loop {
+        let low = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(low);
+            break;
+        }
+        bytes.push(low | 0x80);
+    };
+    let string = |bytes: &mut Vec<u8>, s: &str| {
+        number(bytes, s.len());
+        bytes.extend_from_slice(s.as_bytes());
+    };
+
+    number(&mut bytes, info.files.len());
+    for file in &info.files {
+        string(&mut bytes, file);
+    }
+    number(&mut bytes, info.functions.len());
+    for function in &info.functions {
+        string(&mut bytes, &function.name);
+        number(&mut bytes, function.address);
+    }
+    number(&mut bytes, info.lines.len());
+    let mut end = 0;
+    for range in &info.lines {
+        number(&mut bytes, range.start - end);
+        number(&mut bytes, range.end - range.start);
+        number(&mut bytes, range.file);
+        number(&mut bytes, range.line);
+        end = range.end;
+    }
+    Ok(bytes)
+}
+
+/// Decodes a file `write` wrote.
+///
+/// ```
+/// use hack_vmtranslator::debuginfo::{read, write, DebugInfo, Function, LineRange};
+///
+/// // Random tables, from a seeded xorshift so a failure can be repeated.
+/// let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+/// let mut below = |n: usize| {
+///     state ^= state << 13;
+///     state ^= state >> 7;
+///     state ^= state << 17;
+///     (state % n as u64) as usize
+/// };
+/// for _ in 0..500 {
+///     let mut info = DebugInfo::default();
+///     for i in 0..below(4) {
+///         info.files.push(format!("dir{}/Fïle{i}.vm", below(1000)));
+///     }
+///     for i in 0..below(6) {
+///         info.functions.push(Function { name: format!("F{i}.f"), address: below(40000) });
+///     }
+///     let mut end = 0;
+///     for _ in 0..below(50) {
+///         if info.files.is_empty() {
+///             break;
+///         }
+///         let start = end + below(3) * below(300);
+///         end = start + 1 + below(200);
+///         info.lines.push(LineRange { start, end, file: below(info.files.len()), line: 1 + below(5000) });
+///     }
+///
+///     let bytes = write(&info).unwrap();
+///     assert_eq!(read(&bytes).unwrap(), info);
+///     // Cut short anywhere, it doesn't read.
+///     let cut = below(bytes.len());
+///     assert!(read(&bytes[..cut]).is_err(), "read {cut} of {} bytes", bytes.len());
+/// }
+///
+/// let info = DebugInfo {
+///     files: vec!["Main.vm".to_string()],
+///     functions: Vec::new(),
+///     lines: vec![LineRange { start: 300, end: 302, file: 0, line: 7 }],
+/// };
+/// let bytes = write(&info).unwrap();
+/// assert_eq!(bytes, b"HVDB\x01\x01\x07Main.vm\x00\x01\xac\x02\x02\x00\x07");
+///
+/// assert_eq!(read(b"HVD").unwrap_err(), "not a debug info file");
+/// assert_eq!(read(b"HVDB\x02").unwrap_err(), "debug info version 2 isn't supported (expected 1)");
+/// assert_eq!(read(&[&bytes[..], b"\0"].concat()).unwrap_err(), "1 byte left over at the end");
+/// let mut out_of_range = bytes.clone();
+/// out_of_range[19] = 1;
+/// assert_eq!(read(&out_of_range).unwrap_err(), "the line range at 300 names file 1, of 1");
+///
+/// let overlapping = DebugInfo {
+///     lines: vec![LineRange { start: 4, end: 9, file: 0, line: 1 }, LineRange { start: 8, end: 9, file: 0, line: 2 }],
+///     ..info
+/// };
+/// assert_eq!(write(&overlapping).unwrap_err(), "the line range at 8 overlaps or precedes the one before");
+/// ```
+pub fn read(bytes: &[u8]) -> Result<DebugInfo, String> {
+    let rest = bytes.strip_prefix(MAGIC).ok_or("not a debug info file")?;
+    let (&version, rest) = rest.split_first().ok_or("not a debug info file")?;
+    if version != VERSION {
+        return Err(format!("debug info version {version} isn't supported (expected {VERSION})"));
+    }
+    let mut reader = Reader { rest };
+
+    let mut info = DebugInfo::default();
+    for _ in 0..reader.number()? {
+        info.files.push(reader.string()?);
+    }
+    for _ in 0..reader.number()? {
+        info.functions.push(Function {
+            name: reader.string()?,
+            address: reader.number()?,
+        });
+    }
+    let mut end: usize = 0;
+    for _ in 0..reader.number()? {
+        let start = end.checked_add(reader.number()?).ok_or("an address is too large")?;
+        end = start.checked_add(reader.number()?).ok_or("an address is too large")?;
+        info.lines.push(LineRange {
+            start,
+            end,
+            file: reader.number()?,
+            line: reader.number()?,
+        });
+    }
+    match reader.rest.len() {
+        0 => (),
+        1 => return Err("1 byte left over at the end".to_string()),
+        n => return Err(format!("{n} bytes left over at the end")),
+    }
+    info.check()?;
+    Ok(info)
+}
+
+struct Reader<'b> {
+    rest: &'b [u8],
+}
+
+impl Reader<'_> {
+    fn number(&mut self) -> Result<usize, String> {
+        let mut n: usize = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let (&byte, rest) = self.rest.split_first().ok_or("the file ends early")?;
+            self.rest = rest;
+            let bits = (byte & 0x7f) as usize;
+            if bits << shift >> shift != bits {
+                break;
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("a number is too large".to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.number()?;
+        if len > self.rest.len() {
+            return Err("the file ends early".to_string());
+        }
+        let (text, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        String::from_utf8(text.to_vec()).map_err(|_| "a name isn't valid UTF-8".to_string())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::json::Json;
+use crate::vm::{ParseError, SourceCommand};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The most characters of source a diagnostic or a comment header echoes.
+pub const EXCERPT_WIDTH: usize = 200;
+
+/// `text` as it's echoed back: whole if it's within `EXCERPT_WIDTH`
+/// characters, or cut there and ended with `...`, so a pathological line
+/// is never copied whole.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::{excerpt, EXCERPT_WIDTH};
+///
+/// assert_eq!(excerpt("push constant 1"), "push constant 1");
+/// let long = "é".repeat(1_000_000);
+/// let cut = excerpt(&long);
+/// assert_eq!(cut.chars().count(), EXCERPT_WIDTH + 3);
+/// assert!(cut.starts_with("éé") && cut.ends_with("é..."));
+/// ```
+pub fn excerpt(text: &str) -> Cow<'_, str> {
+    match text.char_indices().nth(EXCERPT_WIDTH) {
+        None => Cow::Borrowed(text),
+        Some((end, _)) => Cow::Owned(format!("{}...", &text[..end])),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Worth knowing, but almost always intended.
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// How diagnostics are printed.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ErrorFormat {
+    /// Descriptive text including the offending source.
+    #[default]
+    Human,
+    /// `file:line:column: severity: message`, for editors and grep.
+    Short,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "short" => Ok(ErrorFormat::Short),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("Unknown error format: '{}' (expected human, short or json)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorFormat::Human => write!(f, "human"),
+            ErrorFormat::Short => write!(f, "short"),
+            ErrorFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A problem found while translating. Line and column count from zero,
+/// like `SourceCommand::line`; every rendering converts them to the
+/// 1-based numbers editors expect.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Heads the human rendering, e.g. "Parse error".
+    pub kind: &'static str,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// The offending source text, quoted by the human rendering; see
+    /// `excerpt`.
+    pub source: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(kind: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            kind,
+            file: None,
+            line: None,
+            column: None,
+            source: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(kind: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(kind, message)
+        }
+    }
+
+    pub fn note(kind: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Note,
+            ..Diagnostic::error(kind, message)
+        }
+    }
+
+    /// Points the diagnostic at the line `sc` was parsed from.
+    pub fn at(mut self, sc: &SourceCommand) -> Diagnostic {
+        self.file = Some(sc.path().display().to_string());
+        self.line = Some(sc.line());
+        self.column = Some(0);
+        self.source = Some(excerpt(sc.source()).into_owned());
+        self
+    }
+
+    pub fn in_file(mut self, file: impl Into<String>) -> Diagnostic {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn render(&self, format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Human => self.render_human(),
+            ErrorFormat::Short => self.render_short(),
+            ErrorFormat::Json => self.to_json().to_string(),
+        }
+    }
+
+    fn render_human(&self) -> String {
+        match (&self.file, self.line, &self.source) {
+            (Some(file), Some(line), Some(source)) => {
+                format!("{} at line {file}:{} ({source}): {}", self.kind, line + 1, self.message)
+            }
+            (Some(file), Some(line), None) => {
+                format!("{} at line {file}:{}: {}", self.kind, line + 1, self.message)
+            }
+            (Some(file), None, _) => format!("{} in {file}: {}", self.kind, self.message),
+            (None, _, _) => format!("{}: {}", self.kind, self.message),
+        }
+    }
+
+    fn render_short(&self) -> String {
+        let location = match (&self.file, self.line) {
+            (Some(file), Some(line)) => {
+                format!("{file}:{}:{}", line + 1, self.column.unwrap_or(0) + 1)
+            }
+            (Some(file), None) => file.clone(),
+            (None, _) => "hack_vmtranslator".to_string(),
+        };
+        // Keep to one line whatever the message contains.
+        let message = self.message.replace('\n', " ");
+        format!("{location}: {}: {message}", self.severity)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut members = vec![
+            ("severity", Json::from(self.severity.to_string())),
+            ("kind", Json::from(self.kind)),
+        ];
+        if let Some(file) = &self.file {
+            members.push(("file", Json::from(file.as_str())));
+        }
+        if let Some(line) = self.line {
+            members.push(("line", Json::from(line + 1)));
+            members.push(("column", Json::from(self.column.unwrap_or(0) + 1)));
+        }
+        members.push(("message", Json::from(self.message.as_str())));
+        Json::object(members)
+    }
+}
+
+/// Renders `diagnostics` grouped by file, files in the order they first
+/// appear and each file's diagnostics by line. The human format heads each
+/// file that has errors or warnings with their counts; a file with only
+/// notes gets no heading. Json keeps one object per line in the original
+/// order. Diagnostics without a file come last.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::{render_grouped, Diagnostic, ErrorFormat};
+///
+/// let mut b = Diagnostic::error("Parse error", "bad").in_file("B.vm");
+/// b.line = Some(4);
+/// let mut a = Diagnostic::error("Parse error", "worse").in_file("A.vm");
+/// a.line = Some(1);
+/// let mut b_earlier = Diagnostic::warning("Warning", "odd").in_file("B.vm");
+/// b_earlier.line = Some(2);
+///
+/// let text = render_grouped(&[b, a, b_earlier], ErrorFormat::Human);
+/// assert_eq!(
+///     text,
+///     "B.vm: 1 error, 1 warning\n\
+///      \x20 Warning at line B.vm:3: odd\n\
+///      \x20 Parse error at line B.vm:5: bad\n\
+///      A.vm: 1 error, 0 warnings\n\
+///      \x20 Parse error at line A.vm:2: worse\n"
+/// );
+///
+/// let mut spin = Diagnostic::note("Note", "Infinite loop at label END").in_file("Sys.vm");
+/// spin.line = Some(3);
+/// assert_eq!(render_grouped(&[spin], ErrorFormat::Human), "Note at line Sys.vm:4: Infinite loop at label END\n");
+/// ```
+pub fn render_grouped(diagnostics: &[Diagnostic], format: ErrorFormat) -> String {
+    if let ErrorFormat::Json = format {
+        return diagnostics.iter().map(|d| d.render(format) + "\n").collect();
+    }
+
+    let mut files: Vec<Option<&str>> = Vec::new();
+    for diagnostic in diagnostics {
+        let file = diagnostic.file.as_deref();
+        if file.is_some() && !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    files.push(None);
+
+    let mut out = String::new();
+    for file in files {
+        let mut group: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.file.as_deref() == file).collect();
+        if group.is_empty() {
+            continue;
+        }
+        group.sort_by_key(|d| d.line);
+
+        let count = |severity| group.iter().filter(|d| d.severity == severity).count();
+        let (errors, warnings) = (count(Severity::Error), count(Severity::Warning));
+        let indent = match (file, format) {
+            (Some(file), ErrorFormat::Human) if errors + warnings > 0 => {
+                out.push_str(&format!(
+                    "{file}: {errors} error{}, {warnings} warning{}\n",
+                    if errors == 1 { "" } else { "s" },
+                    if warnings == 1 { "" } else { "s" },
+                ));
+                "  "
+            }
+            _ => "",
+        };
+        for diagnostic in group {
+            out.push_str(&format!("{indent}{}\n", diagnostic.render(format)));
+        }
+    }
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render_human())
+    }
+}
+
+impl Error for Diagnostic {}
+
+impl<'a> From<&ParseError<'a>> for Diagnostic {
+    fn from(e: &ParseError<'a>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            kind: "Parse error",
+            file: Some(e.path().display().to_string()),
+            line: Some(e.line()),
+            column: Some(e.column()),
+            source: Some(excerpt(e.source()).into_owned()),
+            message: e.message().to_string(),
+        }
+    }
+}
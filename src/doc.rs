@@ -0,0 +1,153 @@
+use crate::callgraph::{function_bodies, CallGraph};
+use crate::diagnostic::Diagnostic;
+use crate::lint;
+use crate::vm::{Command, SourceCommand};
+use std::collections::BTreeMap;
+
+/// The Markdown written by `--emit-doc`: a section per input file with
+/// its statics and diagnostics, and under it each function with its
+/// locals, size in instructions (from `sizes`), and what it calls and is
+/// called by. Function mentions link to the function's own section.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::Diagnostic;
+/// use hack_vmtranslator::doc::summary;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new("Main.vm", "function Main.main 1\npush static 2\ncall Main.twice 1\ncall Math.abs 1\nreturn\n\
+///                             function Main.twice 0\npush argument 0\npush argument 0\nadd\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let sizes = [("Main.main".to_string(), 60), ("Main.twice".to_string(), 20), ("Sys.init".to_string(), 52)];
+/// let mut note = Diagnostic::note("Note", "Infinite loop at label END");
+/// note.file = Some("Sys.vm".to_string());
+/// note.line = Some(3);
+///
+/// assert_eq!(
+///     summary(&commands, &sizes, &[note]),
+///     "# Program summary\n\
+///      \n## Main.vm\n\
+///      \n\
+///      Statics: Main.2\n\
+///      \n### <a id=\"fn-Main.main\"></a>Main.main\n\
+///      \n\
+///      - Locals: 1\n\
+///      - Instructions: 60\n\
+///      - Calls: [Main.twice](#fn-Main.twice), Math.abs\n\
+///      - Called by: [Sys.init](#fn-Sys.init)\n\
+///      \n### <a id=\"fn-Main.twice\"></a>Main.twice\n\
+///      \n\
+///      - Locals: 0\n\
+///      - Instructions: 20\n\
+///      - Calls: nothing\n\
+///      - Called by: [Main.main](#fn-Main.main)\n\
+///      \n## Sys.vm\n\
+///      \n\
+///      Statics: none\n\
+///      \n\
+///      Diagnostics:\n\
+///      \n\
+///      - note, line 4: Infinite loop at label END\n\
+///      \n### <a id=\"fn-Sys.init\"></a>Sys.init\n\
+///      \n\
+///      - Locals: 0\n\
+///      - Instructions: 52\n\
+///      - Calls: [Main.main](#fn-Main.main)\n\
+///      - Called by: nothing\n"
+/// );
+/// ```
+pub fn summary(commands: &[SourceCommand], sizes: &[(String, usize)], diagnostics: &[Diagnostic]) -> String {
+    Outline::new(commands).render(sizes, diagnostics)
+}
+
+/// What `summary` needs from the commands, gathered up front so that the
+/// document can be rendered after code generation has consumed them.
+pub struct Outline<'a> {
+    graph: CallGraph<'a>,
+    statics: Vec<lint::StaticUse>,
+    /// Each file's functions with their local counts, in source order.
+    files: Vec<(String, Vec<(&'a str, u16)>)>,
+}
+
+impl<'a> Outline<'a> {
+    pub fn new(commands: &[SourceCommand<'a>]) -> Outline<'a> {
+        let mut files: Vec<(String, Vec<(&'a str, u16)>)> = Vec::new();
+        for (_, sc) in function_bodies(commands) {
+            let path = sc.path().display().to_string();
+            if files.last().is_none_or(|(p, _)| *p != path) {
+                files.push((path, Vec::new()));
+            }
+            if let Command::Function { name, nvars } = sc.command() {
+                files.last_mut().unwrap().1.push((name, *nvars));
+            }
+        }
+        Outline {
+            graph: CallGraph::build(commands),
+            statics: lint::static_usage(commands),
+            files,
+        }
+    }
+
+    pub fn render(&self, sizes: &[(String, usize)], diagnostics: &[Diagnostic]) -> String {
+        let graph = &self.graph;
+        let mut callers: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for function in graph.functions() {
+            for callee in graph.callees(function) {
+                callers.entry(callee).or_default().push(function);
+            }
+        }
+        let link = |name: &str| {
+            if graph.functions().contains(&name) {
+                format!("[{name}](#fn-{name})")
+            } else {
+                name.to_string()
+            }
+        };
+        let list = |names: &[&str]| match names {
+            [] => "nothing".to_string(),
+            names => names.iter().map(|name| link(name)).collect::<Vec<_>>().join(", "),
+        };
+
+        let mut out = "# Program summary\n".to_string();
+        for (path, functions) in &self.files {
+            out.push_str(&format!("\n## {path}\n\n"));
+            let used: Vec<String> = self
+                .statics
+                .iter()
+                .filter(|s| s.path == *path)
+                .flat_map(|s| s.indices.iter().map(|i| format!("{}.{i}", s.file_base)))
+                .collect();
+            match used.as_slice() {
+                [] => out.push_str("Statics: none\n"),
+                used => out.push_str(&format!("Statics: {}\n", used.join(", "))),
+            }
+
+            let attached: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.file.as_ref() == Some(path)).collect();
+            if !attached.is_empty() {
+                out.push_str("\nDiagnostics:\n\n");
+                for d in attached {
+                    match d.line {
+                        Some(line) => out.push_str(&format!("- {}, line {}: {}\n", d.severity, line + 1, d.message)),
+                        None => out.push_str(&format!("- {}: {}\n", d.severity, d.message)),
+                    }
+                }
+            }
+
+            for (name, nvars) in functions {
+                let size = sizes
+                    .iter()
+                    .find(|(function, _)| function == name)
+                    .map_or("unknown".to_string(), |(_, size)| size.to_string());
+                let called_by = callers.get(name).map_or(&[][..], |c| c.as_slice());
+                out.push_str(&format!("\n### <a id=\"fn-{name}\"></a>{name}\n\n"));
+                out.push_str(&format!("- Locals: {nvars}\n"));
+                out.push_str(&format!("- Instructions: {size}\n"));
+                out.push_str(&format!("- Calls: {}\n", list(graph.callees(name))));
+                out.push_str(&format!("- Called by: {}\n", list(called_by)));
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,175 @@
+use crate::lint;
+use crate::memory::MemoryMap;
+use crate::vm::{Command, SourceCommand};
+use std::fmt;
+
+/// How a `doctor` check came out. Only `Error` makes the run fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Nothing wrong, but worth knowing.
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mark = match self {
+            Status::Pass => "ok",
+            Status::Note => "note",
+            Status::Warning => "warn",
+            Status::Error => "FAIL",
+        };
+        write!(f, "[{mark:^4}]")
+    }
+}
+
+/// One line of the `doctor` checklist, with what to do about it when it
+/// didn't pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub status: Status,
+    pub title: String,
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn new(status: Status, title: impl Into<String>, detail: Option<String>) -> Check {
+        Check {
+            status,
+            title: title.into(),
+            detail,
+        }
+    }
+}
+
+/// The checks that need only the parsed program: the entry point, the
+/// functions the course tools expect, duplicate definitions and the
+/// static budget of `memory`.
+///
+/// ```
+/// use hack_vmtranslator::doctor::{examine, render, Status};
+/// use hack_vmtranslator::memory::MemoryMap;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let healthy = [
+///     Source::new("Main.vm", "function Main.main 0\npush static 0\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&healthy).into_iter().map(Result::unwrap).collect();
+/// let checks = examine(&commands, &MemoryMap::default());
+/// assert!(checks.iter().all(|check| check.status == Status::Pass));
+///
+/// let broken = [
+///     Source::new("Main.vm", "function Main.start 0\npush static 0\nreturn\n"),
+///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.start 0\nreturn\nfunction Main.start 0\nreturn\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&broken).into_iter().map(Result::unwrap).collect();
+/// let memory = MemoryMap { static_limit: 16, ..MemoryMap::default() };
+///
+/// assert_eq!(
+///     render(&examine(&commands, &memory)),
+///     "[ ok ] Sys.init is defined once\n\
+///      [warn] Main.main exists\n\
+///      \x20      Sys.init usually calls Main.main; check the program's entry function is named that\n\
+///      [FAIL] No function is defined twice\n\
+///      \x20      Main.start is defined in Main.vm line 1 and Sys.vm line 4; rename or remove one\n\
+///      [FAIL] Statics fit in RAM[16..16] (1 used)\n\
+///      \x20      Only 0 static variables fit; use fewer, or fields and arrays on the heap\n"
+/// );
+/// ```
+pub fn examine(commands: &[SourceCommand], memory: &MemoryMap) -> Vec<Check> {
+    let mut functions: Vec<(&str, &SourceCommand)> = Vec::new();
+    for sc in commands {
+        if let Command::Function { name, .. } = sc.command() {
+            functions.push((name, sc));
+        }
+    }
+    let at = |sc: &SourceCommand| format!("{} line {}", sc.path().display(), sc.line() + 1);
+    let mut checks = Vec::new();
+
+    let inits: Vec<&SourceCommand> = functions.iter().filter(|(name, _)| *name == "Sys.init").map(|(_, sc)| *sc).collect();
+    checks.push(match inits.as_slice() {
+        [] => Check::new(
+            Status::Note,
+            "Sys.init is defined once",
+            Some(
+                "There is no Sys.init, so this is translated as a fragment: no bootstrap, and it only runs \
+                 once linked into a program that has one. Add Sys.vm if this is a whole program"
+                    .to_string(),
+            ),
+        ),
+        [_] => Check::new(Status::Pass, "Sys.init is defined once", None),
+        many => Check::new(
+            Status::Error,
+            "Sys.init is defined once",
+            Some(format!(
+                "Sys.init is defined in {}; keep only one",
+                many.iter().map(|sc| at(sc)).collect::<Vec<_>>().join(" and ")
+            )),
+        ),
+    });
+
+    if !inits.is_empty() {
+        checks.push(if functions.iter().any(|(name, _)| *name == "Main.main") {
+            Check::new(Status::Pass, "Main.main exists", None)
+        } else {
+            Check::new(
+                Status::Warning,
+                "Main.main exists",
+                Some("Sys.init usually calls Main.main; check the program's entry function is named that".to_string()),
+            )
+        });
+    }
+
+    let mut duplicates: Vec<String> = Vec::new();
+    for (i, (name, sc)) in functions.iter().enumerate() {
+        let earlier = functions[..i].iter().find(|(n, _)| n == name);
+        if let Some((_, first)) = earlier.filter(|_| *name != "Sys.init") {
+            duplicates.push(format!("{name} is defined in {} and {}", at(first), at(sc)));
+        }
+    }
+    checks.push(if duplicates.is_empty() {
+        Check::new(Status::Pass, "No function is defined twice", None)
+    } else {
+        Check::new(
+            Status::Error,
+            "No function is defined twice",
+            Some(format!("{}; rename or remove one", duplicates.join("; "))),
+        )
+    });
+
+    let used: usize = lint::static_usage(commands).iter().map(|file| file.indices.len()).sum();
+    let budget = memory.static_limit.saturating_sub(memory.static_base) as usize;
+    let title = format!("Statics fit in RAM[{}..{}] ({used} used)", memory.static_base, memory.static_limit);
+    checks.push(if used <= budget {
+        Check::new(Status::Pass, title, None)
+    } else {
+        Check::new(
+            Status::Error,
+            title,
+            Some(format!("Only {budget} static variables fit; use fewer, or fields and arrays on the heap")),
+        )
+    });
+
+    checks
+}
+
+/// The checklist as `doctor` prints it, one check per line with any
+/// detail indented under it.
+pub fn render(checks: &[Check]) -> String {
+    let mut out = String::new();
+    for check in checks {
+        out.push_str(&format!("{} {}\n", check.status, check.title));
+        if let Some(detail) = &check.detail {
+            out.push_str(&format!("       {detail}\n"));
+        }
+    }
+    out
+}
+
+/// Whether any check failed outright.
+pub fn failed(checks: &[Check]) -> bool {
+    checks.iter().any(|check| check.status == Status::Error)
+}
@@ -0,0 +1,166 @@
+use crate::callgraph::{self, CallGraph};
+use crate::vm::SourceCommand;
+use std::collections::BTreeSet;
+
+/// Restricts code generation to a subset of functions, for inspecting
+/// the output for a few functions of a large project.
+#[derive(Debug, Default)]
+pub struct FunctionFilter {
+    only: Vec<String>,
+    skip: Vec<String>,
+    closure: bool,
+}
+
+impl FunctionFilter {
+    /// Patterns are comma separated and are either exact function names
+    /// or a prefix followed by `*`, e.g. `Math.*`.
+    pub fn only(&mut self, patterns: &str) {
+        self.only.extend(split_patterns(patterns));
+    }
+
+    pub fn skip(&mut self, patterns: &str) {
+        self.skip.extend(split_patterns(patterns));
+    }
+
+    /// Also keep the functions transitively called by the `only` set.
+    pub fn with_closure(&mut self) {
+        self.closure = true;
+    }
+
+    pub fn only_patterns(&self) -> &[String] {
+        &self.only
+    }
+
+    pub fn skip_patterns(&self) -> &[String] {
+        &self.skip
+    }
+
+    pub fn has_closure(&self) -> bool {
+        self.closure
+    }
+
+    pub fn is_active(&self) -> bool {
+        !(self.only.is_empty() && self.skip.is_empty())
+    }
+
+    /// Keeps the commands of the selected functions. Everything is parsed
+    /// beforehand, so the call graph used for the closure sees all of it.
+    ///
+    /// ```
+    /// use hack_vmtranslator::filter::FunctionFilter;
+    /// use hack_vmtranslator::vm::{parse_sources, Command, Source};
+    ///
+    /// let sources = [
+    ///     Source::new("Main.vm", "function Main.main 0\ncall Math.double 1\nreturn\nfunction Main.unused 0\nreturn\n"),
+    ///     Source::new("Math.vm", "function Math.double 0\ncall Math.add 2\nreturn\nfunction Math.add 0\nadd\nreturn\n"),
+    ///     Source::new("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+    /// ];
+    /// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let emitted = |filter: &FunctionFilter| -> Result<Vec<String>, String> {
+    ///     let kept = filter.apply(commands.clone())?;
+    ///     Ok(kept
+    ///         .iter()
+    ///         .filter_map(|sc| match sc.command() {
+    ///             Command::Function { name, .. } => Some(name.to_string()),
+    ///             _ => None,
+    ///         })
+    ///         .collect())
+    /// };
+    ///
+    /// let mut filter = FunctionFilter::default();
+    /// filter.only("Math.*, Main.main");
+    /// assert_eq!(emitted(&filter).unwrap(), ["Main.main", "Math.double", "Math.add"]);
+    /// filter.skip("Math.add");
+    /// assert_eq!(emitted(&filter).unwrap(), ["Main.main", "Math.double"]);
+    ///
+    /// // Without `only`, `skip` takes from everything.
+    /// let mut filter = FunctionFilter::default();
+    /// filter.skip("Main.*,Sys.init");
+    /// assert_eq!(emitted(&filter).unwrap(), ["Math.double", "Math.add"]);
+    ///
+    /// // The closure follows calls transitively, and only calls.
+    /// let mut filter = FunctionFilter::default();
+    /// filter.only("Sys.init");
+    /// filter.with_closure();
+    /// assert_eq!(emitted(&filter).unwrap(), ["Main.main", "Math.double", "Math.add", "Sys.init"]);
+    /// let mut filter = FunctionFilter::default();
+    /// filter.only("Math.double");
+    /// filter.with_closure();
+    /// assert_eq!(emitted(&filter).unwrap(), ["Math.double", "Math.add"]);
+    /// // What's skipped goes even when called.
+    /// filter.skip("Math.add");
+    /// assert_eq!(emitted(&filter).unwrap(), ["Math.double"]);
+    ///
+    /// // The body goes with the function, and a pattern has to match.
+    /// let mut filter = FunctionFilter::default();
+    /// filter.only("Math.add");
+    /// assert_eq!(filter.apply(commands.clone()).unwrap().len(), 3);
+    /// filter.only("Maths.*");
+    /// assert_eq!(emitted(&filter), Err("No function matches 'Maths.*'".to_string()));
+    /// ```
+    pub fn apply<'a>(&self, commands: Vec<SourceCommand<'a>>) -> Result<Vec<SourceCommand<'a>>, String> {
+        let selected = self.select(&CallGraph::build(&commands))?;
+
+        let keep: Vec<bool> = callgraph::function_bodies(&commands)
+            .map(|(function, _)| function.is_some_and(|f| selected.contains(f)))
+            .collect();
+
+        Ok(commands
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(sc, keep)| if keep { Some(sc) } else { None })
+            .collect())
+    }
+
+    fn select<'a>(&self, graph: &CallGraph<'a>) -> Result<BTreeSet<&'a str>, String> {
+        for pattern in self.only.iter().chain(&self.skip) {
+            if !graph.functions().iter().any(|f| matches(pattern, f)) {
+                return Err(format!("No function matches '{pattern}'"));
+            }
+        }
+
+        let mut selected: BTreeSet<&'a str> = if self.only.is_empty() {
+            graph.functions().iter().copied().collect()
+        } else {
+            let roots: Vec<&'a str> = graph
+                .functions()
+                .iter()
+                .copied()
+                .filter(|f| self.only.iter().any(|p| matches(p, f)))
+                .collect();
+            if self.closure {
+                graph.closure(&roots)
+            } else {
+                roots.into_iter().collect()
+            }
+        };
+
+        selected.retain(|f| !self.skip.iter().any(|p| matches(p, f)));
+        Ok(selected)
+    }
+}
+
+fn split_patterns(patterns: &str) -> impl Iterator<Item = String> + '_ {
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+}
+
+/// Whether `function` matches a single pattern as accepted by `only`.
+///
+/// ```
+/// use hack_vmtranslator::filter::matches;
+///
+/// assert!(matches("Math.*", "Math.multiply"));
+/// assert!(matches("Math.multiply", "Math.multiply"));
+/// assert!(!matches("Math.mult", "Math.multiply"));
+/// assert!(!matches("Math.*", "Main.main"));
+/// ```
+pub fn matches(pattern: &str, function: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => function.starts_with(prefix),
+        None => pattern == function,
+    }
+}
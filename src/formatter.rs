@@ -0,0 +1,148 @@
+use crate::vm::{self, SourceItem, Trivia};
+use std::path::Path;
+
+struct Line<'a> {
+    number: usize,
+    code: Option<String>,
+    comment: Option<&'a str>,
+}
+
+/// Rewrites a VM source in canonical form: one command per line with
+/// single spaces between words, comments and blank lines kept where they
+/// were, trailing comments aligned within each run of commented lines,
+/// `\n` line endings and exactly one trailing newline.
+///
+/// Returns the parse errors instead if the source doesn't parse, and
+/// refuses to return output that would parse to a different command
+/// sequence than the input.
+///
+/// Over every source in the corpus, formatting is idempotent and keeps
+/// the commands; a source that doesn't parse is left as it is:
+///
+/// ```
+/// use hack_vmtranslator::formatter::format_source;
+/// use hack_vmtranslator::vm::{self, discover_sources, file_base};
+/// use std::path::Path;
+///
+/// let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+/// let mut dirs = vec![root.join("conformance")];
+/// for program in std::fs::read_dir(root.join("compat/programs")).unwrap() {
+///     dirs.push(program.unwrap().path());
+/// }
+/// let mut formatted_files = 0;
+/// for path in dirs.iter().flat_map(|dir| discover_sources(dir).unwrap()) {
+///     let source = std::fs::read_to_string(&path).unwrap();
+///     let base = file_base(&path);
+///     let commands = |text: &str| -> Vec<_> {
+///         vm::parse_source(&base, &path, text)
+///             .into_iter()
+///             .map(|r| r.map(|sc| sc.command().to_string()).map_err(|e| e.to_string()))
+///             .collect()
+///     };
+///     match format_source(&base, &path, &source) {
+///         Ok(formatted) => {
+///             assert_eq!(commands(&formatted), commands(&source), "{}", path.display());
+///             assert_eq!(format_source(&base, &path, &formatted).as_ref(), Ok(&formatted), "{}", path.display());
+///             formatted_files += 1;
+///         }
+///         Err(_) => assert!(commands(&source).iter().any(Result::is_err), "{}", path.display()),
+///     }
+/// }
+/// assert!(formatted_files > 20);
+/// ```
+pub fn format_source(file_base: &str, path: &Path, source: &str) -> Result<String, Vec<String>> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    let mut commands: Vec<String> = Vec::new();
+
+    for item in vm::parse_source_with_trivia(file_base, path, source) {
+        match item {
+            SourceItem::Command(Ok(sc)) => {
+                let code = sc.command().to_string();
+                commands.push(code.clone());
+                lines.push(Line {
+                    number: sc.line(),
+                    code: Some(code),
+                    comment: None,
+                });
+            }
+            SourceItem::Command(Err(e)) => errors.push(e.to_string()),
+            SourceItem::Trivia(Trivia::Comment(number, text)) => match lines.last_mut() {
+                Some(line) if line.number == number => line.comment = Some(text),
+                _ => lines.push(Line {
+                    number,
+                    code: None,
+                    comment: Some(text),
+                }),
+            },
+            SourceItem::Trivia(Trivia::Blank(number)) => lines.push(Line {
+                number,
+                code: None,
+                comment: None,
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    while matches!(lines.last(), Some(Line { code: None, comment: None, .. })) {
+        lines.pop();
+    }
+
+    let formatted = render(&lines);
+    check_round_trip(file_base, path, &formatted, &commands)?;
+    Ok(formatted)
+}
+
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        // Trailing comments line up across a run of consecutive commented
+        // commands, one space past the longest command in the run.
+        let run_end = lines[i..]
+            .iter()
+            .position(|l| l.code.is_none() || l.comment.is_none())
+            .map_or(lines.len(), |n| i + n);
+        let width = lines[i..run_end]
+            .iter()
+            .filter_map(|l| l.code.as_ref().map(|c| c.len()))
+            .max()
+            .unwrap_or(0);
+
+        for line in &lines[i..run_end.max(i + 1)] {
+            let text = match (&line.code, line.comment) {
+                (Some(code), Some(comment)) => {
+                    format!("{code:<width$} //{}", comment.trim_end())
+                }
+                (Some(code), None) => code.clone(),
+                (None, Some(comment)) => format!("//{}", comment.trim_end()),
+                (None, None) => String::new(),
+            };
+            out.push_str(&text);
+            out.push('\n');
+        }
+        i = run_end.max(i + 1);
+    }
+
+    out
+}
+
+fn check_round_trip(file_base: &str, path: &Path, formatted: &str, commands: &[String]) -> Result<(), Vec<String>> {
+    let reparsed: Vec<String> = vm::parse_source(file_base, path, formatted)
+        .into_iter()
+        .map(|r| r.map(|sc| sc.command().to_string()).unwrap_or_default())
+        .collect();
+
+    if reparsed == commands {
+        Ok(())
+    } else {
+        Err(vec![format!(
+            "Formatting {} would change its commands; leaving it untouched",
+            path.display()
+        )])
+    }
+}
@@ -0,0 +1,223 @@
+//! Random but structurally valid VM programs, and the invariants the code
+//! generator must keep for any of them. The parser is exercised by text;
+//! this goes after what only shows up when commands interact: scope
+//! tracking, label numbering and the bootstrap.
+
+use crate::asm::{self, expansion_size, generate_bootstrap, generate_code, is_instruction, CodegenOptions};
+use crate::vm::{parse_sources, Source};
+use std::collections::HashSet;
+
+/// Makes programs from a seed, so a failure can be repeated.
+pub struct Generator {
+    state: u64,
+}
+
+const SEGMENTS: [(&str, u16); 7] = [
+    ("argument", 4),
+    ("local", 4),
+    ("this", 4),
+    ("that", 4),
+    ("pointer", 2),
+    ("temp", 8),
+    ("static", 6),
+];
+
+const OPERATIONS: [&str; 9] = ["add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not"];
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        // The same xorshift64* as `RamInit::Random`.
+        Generator {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 33) as usize % n
+    }
+
+    /// Two or three files of functions, `Sys.vm` with `Sys.init` half the
+    /// time, with loose commands ahead of a file's first function now and
+    /// then. Every label is defined before a jump to it, every call names
+    /// a function of the program, and every function ends in `return`.
+    pub fn sources(&mut self) -> Vec<Source> {
+        let mut files = vec![("A", 1 + self.below(3)), ("B", self.below(3))];
+        if self.below(2) == 0 {
+            files.push(("Sys", 1));
+        }
+        let functions: Vec<String> = files
+            .iter()
+            .flat_map(|(file, count)| {
+                (0..*count).map(move |i| match *file {
+                    "Sys" => "Sys.init".to_string(),
+                    file => format!("{file}.f{i}"),
+                })
+            })
+            .collect();
+
+        let mut sources = Vec::new();
+        for (file, count) in &files {
+            let mut text = String::new();
+            if self.below(4) == 0 {
+                self.body(&mut text, &functions);
+            }
+            for name in functions.iter().filter(|f| f.starts_with(&format!("{file}."))).take(*count) {
+                text.push_str(&format!("function {name} {}\n", self.below(3)));
+                self.body(&mut text, &functions);
+                text.push_str("return\n");
+            }
+            sources.push(Source::new(format!("{file}.vm"), text));
+        }
+        sources
+    }
+
+    fn body(&mut self, text: &mut String, functions: &[String]) {
+        let mut labels = 0;
+        for _ in 0..self.below(12) {
+            let line = match self.below(7) {
+                0 => format!("push constant {}", self.below(32768)),
+                1 => {
+                    let (segment, size) = SEGMENTS[self.below(SEGMENTS.len())];
+                    format!("push {segment} {}", self.below(size as usize))
+                }
+                2 => {
+                    let (segment, size) = SEGMENTS[self.below(SEGMENTS.len())];
+                    format!("pop {segment} {}", self.below(size as usize))
+                }
+                3 => OPERATIONS[self.below(OPERATIONS.len())].to_string(),
+                4 => {
+                    labels += 1;
+                    format!("label L{}", labels - 1)
+                }
+                5 if labels > 0 => {
+                    let kind = ["goto", "if-goto"][self.below(2)];
+                    format!("{kind} L{}", self.below(labels))
+                }
+                _ => format!("call {} {}", functions[self.below(functions.len())], self.below(3)),
+            };
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+}
+
+/// Translates `sources` with `options` and checks the output: it has
+/// exactly as many instructions as the commands' `expansion_size`s plus
+/// the bootstrap, every `@symbol` is a number, a predefined symbol, a
+/// label, a static or one of the translator's own variables, every
+/// C-instruction is one the CPU has, and, with the assembler built in, it
+/// assembles.
+///
+/// ```
+/// use hack_vmtranslator::asm::{CodegenOptions, LabelScheme};
+/// use hack_vmtranslator::fuzz::{check, shrink, Generator};
+/// use hack_vmtranslator::vm::Source;
+///
+/// for (label_scheme, safe_compare) in [(LabelScheme::Lines, false), (LabelScheme::Counters, false), (LabelScheme::Counters, true)] {
+///     let options = CodegenOptions { label_scheme, safe_compare, ..CodegenOptions::default() };
+///     for seed in 0..200 {
+///         let sources = Generator::new(seed).sources();
+///         if let Err(e) = check(&sources, &options) {
+///             let kind = |e: &str| e.split(':').next().unwrap_or_default().to_string();
+///             let fails = |sources: &[Source]| check(sources, &options).is_err_and(|other| kind(&other) == kind(&e));
+///             let minimal = shrink(sources, fails);
+///             let texts: Vec<String> = minimal.iter().map(|s| format!("{}:\n{}", s.path.display(), s.text)).collect();
+///             panic!("seed {seed}: {e}\nadd this to the regressions:\n{}", texts.join("\n"));
+///         }
+///     }
+/// }
+///
+/// // Regressions, shrunk from generated programs.
+/// let regressions = [
+///     vec![Source::new("A.vm", "eq\nfunction A.f0 0\nlt\nreturn\n"), Source::new("B.vm", "eq\n")],
+///     vec![Source::new("A.vm", "call Sys.init 0\n"), Source::new("Sys.vm", "function Sys.init 0\nreturn\n")],
+///     // Loose commands in B.vm were scoped to A.f0 and reused its labels.
+///     vec![Source::new("A.vm", "function A.f0 0\ncall A.f0 0\nreturn\n"), Source::new("B.vm", "push constant 1\ncall A.f0 0\n")],
+/// ];
+/// for sources in regressions {
+///     check(&sources, &CodegenOptions::default()).unwrap();
+/// }
+/// ```
+pub fn check(sources: &[Source], options: &CodegenOptions) -> Result<(), String> {
+    let parse = || {
+        parse_sources(sources)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("doesn't parse: {e}"))
+    };
+    let commands = parse()?;
+    let bootstrap = asm::should_bootstrap(&commands, options);
+    let output = generate_code(commands, options)?.join("\n");
+
+    let mut expected: usize = parse()?.into_iter().map(|sc| expansion_size(sc.into_command(), options)).sum();
+    if bootstrap {
+        expected += generate_bootstrap(&options.bootstrap).lines().filter(|line| is_instruction(line)).count();
+    }
+    let actual = output.lines().filter(|line| is_instruction(line)).count();
+    if actual != expected {
+        return Err(format!("{actual} instructions, but the commands' sizes add up to {expected}"));
+    }
+
+    let labels: HashSet<&str> = asm::label_addresses(&output).into_iter().map(|(label, _)| label).collect();
+    let is_static = |symbol: &str| {
+        symbol
+            .rsplit_once('.')
+            .is_some_and(|(file, index)| sources.iter().any(|s| s.name == file) && index.parse::<u16>().is_ok())
+    };
+    for line in output.lines() {
+        let code = line.split("//").next().unwrap_or_default().trim();
+        let Some(symbol) = code.strip_prefix('@') else { continue };
+        let known = symbol.parse::<u16>().is_ok()
+            || asm::PREDEFINED_SYMBOLS.iter().any(|(name, _)| *name == symbol)
+            || labels.contains(symbol)
+            || is_static(symbol)
+            || ["frame", "retaddr"].contains(&symbol);
+        if !known {
+            return Err(format!("@{symbol} is neither defined nor a variable the translator uses"));
+        }
+    }
+
+    asm::check_instructions(&output).map_err(|e| format!("illegal instruction: {e}"))?;
+
+    #[cfg(feature = "assembler")]
+    if let Err(errors) = crate::assembler::assemble(&output) {
+        return Err(format!("doesn't assemble: {}", errors[0]));
+    }
+    Ok(())
+}
+
+/// Drops lines from `sources` for as long as `fails` still holds, leaving
+/// a small program to keep as a regression.
+pub fn shrink(mut sources: Vec<Source>, fails: impl Fn(&[Source]) -> bool) -> Vec<Source> {
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for file in 0..sources.len() {
+            let mut line = 0;
+            while line < sources[file].text.lines().count() {
+                let text: String = sources[file]
+                    .text
+                    .lines()
+                    .enumerate()
+                    .filter(|(i, _)| *i != line)
+                    .map(|(_, l)| format!("{l}\n"))
+                    .collect();
+                let mut candidate: Vec<Source> = sources
+                    .iter()
+                    .map(|s| Source::new(s.path.clone(), s.text.clone()))
+                    .collect();
+                candidate[file] = Source::new(sources[file].path.clone(), text);
+                if fails(&candidate) {
+                    sources = candidate;
+                    progress = true;
+                } else {
+                    line += 1;
+                }
+            }
+        }
+    }
+    sources
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+/// One check on the final RAM: `RAM[addr]=value`, or a run of cells such
+/// as `RAM[256..258]=1,2,3` (both ends included).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    pub address: usize,
+    /// Expected values from `address` upwards.
+    pub values: Vec<i16>,
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values: Vec<String> = self.values.iter().map(i16::to_string).collect();
+        if self.values.len() == 1 {
+            write!(f, "RAM[{}]={}", self.address, values[0])
+        } else {
+            let last = self.address + self.values.len() - 1;
+            write!(f, "RAM[{}..{last}]={}", self.address, values.join(","))
+        }
+    }
+}
+
+impl Assertion {
+    /// The cells that differ, as `(address, expected, actual)`.
+    pub fn failures(&self, ram: &[i16]) -> Vec<(usize, i16, Option<i16>)> {
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(i, expected)| (self.address + i, *expected, ram.get(self.address + i).copied()))
+            .filter(|(_, expected, actual)| *actual != Some(*expected))
+            .collect()
+    }
+}
+
+/// Parses `;`-separated assertions.
+///
+/// ```
+/// use hack_vmtranslator::grade::parse_assertions;
+///
+/// let assertions = parse_assertions("RAM[0]=266; RAM[256..258]=1,-2,3").unwrap();
+/// assert_eq!(assertions[1].address, 256);
+/// assert_eq!(assertions[1].values, vec![1, -2, 3]);
+/// assert_eq!(assertions[1].to_string(), "RAM[256..258]=1,-2,3");
+///
+/// let mut ram = vec![0i16; 300];
+/// ram[0] = 266;
+/// assert!(assertions[0].failures(&ram).is_empty());
+/// assert_eq!(assertions[1].failures(&ram).len(), 3);
+///
+/// assert!(parse_assertions("RAM[256..258]=1,2").is_err());
+/// ```
+pub fn parse_assertions(text: &str) -> Result<Vec<Assertion>, String> {
+    text.split(';')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(parse_assertion)
+        .collect()
+}
+
+fn parse_assertion(text: &str) -> Result<Assertion, String> {
+    let invalid = |why: &str| format!("Invalid assertion '{text}': {why}");
+    let (cells, values) = text.split_once('=').ok_or_else(|| invalid("expected RAM[address]=value"))?;
+    let range = cells
+        .trim()
+        .strip_prefix("RAM[")
+        .and_then(|c| c.strip_suffix(']'))
+        .ok_or_else(|| invalid("expected RAM[address]=value"))?;
+    let address = |a: &str| a.trim().parse::<usize>().map_err(|_| invalid("bad address"));
+    let (first, last) = match range.split_once("..") {
+        Some((first, last)) => (address(first)?, address(last)?),
+        None => (address(range)?, address(range)?),
+    };
+    let values = values
+        .split(',')
+        .map(|v| v.trim().parse::<i16>().map_err(|_| invalid("values must be 16-bit integers")))
+        .collect::<Result<Vec<i16>, String>>()?;
+
+    if last < first || values.len() != last - first + 1 {
+        return Err(invalid(&format!(
+            "{} value(s) given for {} cell(s)",
+            values.len(),
+            (last + 1).saturating_sub(first)
+        )));
+    }
+    Ok(Assertion {
+        address: first,
+        values,
+    })
+}
+
+/// Reads the expected final RAM from a course `.cmp` file: a header row
+/// naming `RAM[n]` columns and a row of values, separated by `|`. Later
+/// rows, if any, are ignored.
+///
+/// ```
+/// use hack_vmtranslator::grade::parse_cmp;
+///
+/// let assertions = parse_cmp("|RAM[0]  |RAM[256]|\n|    257 |     -1 |\n").unwrap();
+/// assert_eq!(assertions[0].to_string(), "RAM[0]=257");
+/// assert_eq!(assertions[1].to_string(), "RAM[256]=-1");
+///
+/// assert!(parse_cmp("|PC|\n|0|\n").is_err());
+/// ```
+pub fn parse_cmp(text: &str) -> Result<Vec<Assertion>, String> {
+    let cells = |line: &str| -> Vec<String> {
+        line.split('|').map(str::trim).filter(|c| !c.is_empty()).map(str::to_string).collect()
+    };
+    let mut rows = text.lines().filter(|line| !line.trim().is_empty());
+    let header = cells(rows.next().ok_or("Empty comparison file")?);
+    let values = cells(rows.next().ok_or("Comparison file has no values")?);
+    if header.len() != values.len() {
+        return Err(format!("{} columns but {} values", header.len(), values.len()));
+    }
+
+    header
+        .iter()
+        .zip(&values)
+        .map(|(column, value)| {
+            let address = column
+                .strip_prefix("RAM[")
+                .and_then(|c| c.strip_suffix(']'))
+                .and_then(|a| a.parse::<usize>().ok())
+                .ok_or_else(|| format!("Unsupported column '{column}' (expected RAM[address])"))?;
+            let value = value
+                .parse::<i16>()
+                .map_err(|_| format!("Value '{value}' for {column} isn't a 16-bit integer"))?;
+            Ok(Assertion {
+                address,
+                values: vec![value],
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,1069 @@
+use crate::callgraph::function_bodies;
+use crate::memory::MemoryMap;
+use crate::vm::{Command, Segment, SourceCommand};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Words of RAM, as on the Hack computer.
+pub const RAM_SIZE: usize = 32768;
+
+/// The screen in pixels. Each row is 32 words, and the low bit of a word
+/// is its leftmost pixel.
+pub const SCREEN_WIDTH: usize = 512;
+pub const SCREEN_HEIGHT: usize = 256;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+
+/// Stops a run that doesn't halt on its own.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_steps: u64,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: 500_000_000,
+            timeout: None,
+        }
+    }
+}
+
+/// What RAM holds before the program starts. Anything but zero shows up
+/// code that reads memory it never wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    /// Every bit set: -1 in every word.
+    Ones,
+    Pattern(u16),
+    /// Pseudo-random words from a seed, so a failing run can be repeated.
+    Random(u64),
+}
+
+impl FromStr for RamInit {
+    type Err = String;
+
+    /// Parses `zero`, `ones`, `pattern:<hex>` or `random:<seed>`.
+    fn from_str(s: &str) -> Result<RamInit, String> {
+        let invalid = || format!("Invalid RAM fill: '{s}' (expected zero, ones, pattern:<hex> or random:<seed>)");
+        match s.split_once(':') {
+            None if s == "zero" => Ok(RamInit::Zero),
+            None if s == "ones" => Ok(RamInit::Ones),
+            Some(("pattern", hex)) => {
+                let hex = hex.trim_start_matches("0x");
+                u16::from_str_radix(hex, 16).map(RamInit::Pattern).map_err(|_| invalid())
+            }
+            Some(("random", seed)) => seed.parse().map(RamInit::Random).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl RamInit {
+    /// ```
+    /// use hack_vmtranslator::interp::RamInit;
+    ///
+    /// let (mut a, mut b) = ([0; 8], [0; 8]);
+    /// RamInit::Random(7).fill(&mut a);
+    /// RamInit::Random(7).fill(&mut b);
+    /// assert_eq!(a, b);
+    ///
+    /// "pattern:BEEF".parse::<RamInit>().unwrap().fill(&mut a);
+    /// assert_eq!(a[3], 0xBEEFu16 as i16);
+    /// ```
+    pub fn fill(self, ram: &mut [i16]) {
+        match self {
+            RamInit::Zero => ram.fill(0),
+            RamInit::Ones => ram.fill(-1),
+            RamInit::Pattern(word) => ram.fill(word as i16),
+            RamInit::Random(seed) => {
+                // xorshift64*; the seed is mixed so 0 still works.
+                let mut state = seed ^ 0x9E37_79B9_7F4A_7C15;
+                for word in ram {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    *word = (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 48) as i16;
+                }
+            }
+        }
+    }
+}
+
+/// How often `run` looks at the clock, in steps.
+const TIMEOUT_CHECK_INTERVAL: u64 = 4096;
+
+/// Where execution is: the command about to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub function: Option<String>,
+    pub path: String,
+    /// Counts from zero, like `SourceCommand::line`.
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "{function} {}:{}", self.path, self.line),
+            None => write!(f, "{}:{}", self.path, self.line),
+        }
+    }
+}
+
+/// A function active on the call stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub function: String,
+    /// The `call` that entered the function; `None` for the entry point.
+    pub call_site: Option<Location>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The program did something the VM can't: a bad address, a call to a
+    /// function that isn't defined, a jump to a missing label.
+    Fault,
+    StepLimit,
+    Timeout,
+}
+
+/// Why a run stopped before halting.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub location: Option<Location>,
+    /// Innermost frame last.
+    pub call_stack: Vec<Frame>,
+    /// Read back from the frames saved in RAM when the error happened.
+    pub backtrace: Box<Backtrace>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {location}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The active calls as the saved frames in RAM record them: where
+/// execution is, then the `call` each enclosing function is waiting on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Backtrace {
+    /// Innermost first.
+    pub frames: Vec<Location>,
+    /// Why the walk stopped short of the entry function, if it did.
+    pub broken: Option<String>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, location) in self.frames.iter().enumerate() {
+            writeln!(f, "  #{i} {location}")?;
+        }
+        if let Some(why) = &self.broken {
+            writeln!(f, "  (backtrace stops here: {why})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A write to a watched address; see `Vm::watch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: usize,
+    pub old: i16,
+    pub new: i16,
+    /// The command that wrote it.
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RAM[{}]: {} -> {}", self.address, self.old, self.new)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {location}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a watchpoint: `RAM[2051]`, or a run of cells such as
+/// `RAM[2048..2060]` (both ends included, as in assertions). The `RAM[]`
+/// may be left off.
+///
+/// ```
+/// use hack_vmtranslator::interp::parse_watch;
+///
+/// assert_eq!(parse_watch("RAM[2051]"), Ok(2051..=2051));
+/// assert_eq!(parse_watch("RAM[2048..2060]"), Ok(2048..=2060));
+/// assert_eq!(parse_watch("16"), Ok(16..=16));
+/// assert!(parse_watch("RAM[9..3]").is_err());
+/// assert!(parse_watch("RAM[40000]").is_err());
+/// ```
+pub fn parse_watch(text: &str) -> Result<RangeInclusive<usize>, String> {
+    let invalid = |why: &str| format!("Invalid watchpoint '{text}': {why}");
+    let cells = text.trim();
+    let cells = cells.strip_prefix("RAM[").and_then(|c| c.strip_suffix(']')).unwrap_or(cells);
+    let address = |a: &str| {
+        a.trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|a| *a < RAM_SIZE)
+            .ok_or_else(|| invalid(&format!("expected an address below {RAM_SIZE}")))
+    };
+    let (first, last) = match cells.split_once("..") {
+        Some((first, last)) => (address(first)?, address(last)?),
+        None => (address(cells)?, address(cells)?),
+    };
+    if last < first {
+        return Err(invalid("the range is empty"));
+    }
+    Ok(first..=last)
+}
+
+/// What the built-in `Memory.alloc` and `Memory.deAlloc` did during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapStats {
+    pub allocations: u64,
+    pub frees: u64,
+    /// Blocks allocated and not yet freed, and the words they take.
+    pub live_blocks: usize,
+    pub words_in_use: usize,
+    /// The most words in use at any one time.
+    pub peak_words: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks allocated, {} freed, {} words in use in {} blocks, peak {} words",
+            self.allocations, self.frees, self.words_in_use, self.live_blocks, self.peak_words
+        )
+    }
+}
+
+/// A first-fit free list over the words between the stack and the
+/// screen, as the course's `Memory` class keeps. Block sizes are kept
+/// here rather than in RAM, so a program writing past the end of a block
+/// can't corrupt the list.
+struct Heap {
+    /// Free blocks as `(base, size)`, in address order and never adjacent.
+    free: Vec<(usize, usize)>,
+    allocated: HashMap<usize, usize>,
+    stats: HeapStats,
+}
+
+impl Heap {
+    fn new(memory: &MemoryMap) -> Heap {
+        let (start, end) = (memory.stack_limit as usize, memory.screen as usize);
+        Heap {
+            free: vec![(start, end.saturating_sub(start))],
+            allocated: HashMap::new(),
+            stats: HeapStats::default(),
+        }
+    }
+
+    fn alloc(&mut self, size: i16) -> Result<usize, String> {
+        if size <= 0 {
+            return Err(format!("Memory.alloc of {size} words"));
+        }
+        let size = size as usize;
+        let Some(i) = self.free.iter().position(|(_, free)| *free >= size) else {
+            return Err(format!("heap exhausted: no free block of {size} words"));
+        };
+        let (base, free) = self.free[i];
+        if free == size {
+            self.free.remove(i);
+        } else {
+            self.free[i] = (base + size, free - size);
+        }
+        self.allocated.insert(base, size);
+        self.stats.allocations += 1;
+        self.stats.live_blocks += 1;
+        self.stats.words_in_use += size;
+        self.stats.peak_words = self.stats.peak_words.max(self.stats.words_in_use);
+        Ok(base)
+    }
+
+    fn dealloc(&mut self, base: i16) -> Result<(), String> {
+        let Some(size) = self.allocated.remove(&(base as usize)) else {
+            return Err(format!("Memory.deAlloc of {base}, which isn't an allocated block"));
+        };
+        let base = base as usize;
+        let i = self.free.partition_point(|(free, _)| *free < base);
+        self.free.insert(i, (base, size));
+        // Merge with the following block, then the preceding one.
+        if let Some(&(next, next_size)) = self.free.get(i + 1) {
+            if base + size == next {
+                self.free[i].1 += next_size;
+                self.free.remove(i + 1);
+            }
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == base {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+        self.stats.frees += 1;
+        self.stats.live_blocks -= 1;
+        self.stats.words_in_use -= size;
+        Ok(())
+    }
+}
+
+/// Settings for a `Vm`.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub ram_init: RamInit,
+    /// Where segments live; should match what the program was translated
+    /// for.
+    pub memory: MemoryMap,
+    /// The function to start in, like the bootstrap's entry; `Sys.init`
+    /// when not given.
+    pub entry: Option<String>,
+}
+
+/// Something a hook registered with `Vm::on_event` is told about.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'e> {
+    /// The command is about to run.
+    Step(&'e SourceCommand<'e>),
+    /// A function was entered by `call`.
+    Call(&'e str),
+    /// A function returned.
+    Return(&'e str),
+    /// A RAM word changed, registers included.
+    Write { address: usize, old: i16, new: i16 },
+    /// A `debug.print` ran with `value` on top of the stack, in
+    /// `function` if it is inside one.
+    DebugPrint { function: Option<&'e str>, command: &'e SourceCommand<'e>, value: i16 },
+}
+
+type Hook<'p> = Box<dyn FnMut(&Event) + Send + 'p>;
+
+/// What a single step did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    /// The program reached its final spin loop, ran off the end, or
+    /// returned from its entry function.
+    Halted,
+    /// A watched address was written, and watchpoints pause; the program
+    /// can carry on from here.
+    Paused,
+}
+
+/// Executes parsed VM commands directly, without translating them, on a
+/// model of the Hack RAM. Nothing is printed: results are read back
+/// through `ram`, `sp` and the error a run returns, and tracing is done by
+/// registering hooks. A `Vm` is `Send`, so it can run on a worker thread.
+///
+/// ```
+/// use hack_vmtranslator::interp::{Config, ErrorKind, Limits, Vm};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "push constant 2\npush constant 3\nadd\n")];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let mut vm = Vm::new(&program, Config::default());
+/// vm.run(&Limits::default()).unwrap();
+/// assert_eq!((vm.sp(), vm.ram()[256]), (257, 5));
+///
+/// let sources = [Source::new("Main.vm", "label A\npush constant 0\ngoto A\n")];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let limits = Limits { max_steps: 100, ..Limits::default() };
+/// let error = Vm::new(&program, Config::default()).run(&limits).unwrap_err();
+/// assert_eq!(error.kind, ErrorKind::StepLimit);
+///
+/// fn is_send<T: Send>(_: &T) {}
+/// is_send(&Vm::new(&program, Config::default()));
+/// ```
+pub struct Vm<'p, 'a> {
+    program: &'p [SourceCommand<'a>],
+    scopes: Vec<Option<&'a str>>,
+    labels: HashMap<(Option<&'a str>, &'a str), usize>,
+    functions: HashMap<&'a str, usize>,
+    statics: HashMap<(&'a str, u16), usize>,
+    memory: MemoryMap,
+    ram: Vec<i16>,
+    pc: usize,
+    steps: u64,
+    frames: Vec<Frame>,
+    halted: bool,
+    heap: Heap,
+    hooks: Vec<Hook<'p>>,
+    watches: Vec<RangeInclusive<usize>>,
+    watch_hits: Vec<WatchHit>,
+    pause_on_watch: bool,
+    /// Step counts at which `run` stops, soonest first.
+    pauses: BTreeSet<u64>,
+}
+
+impl<'p, 'a> Vm<'p, 'a> {
+    /// Prepares `program` to run. When it defines the entry function
+    /// (`Sys.init` unless `config.entry` names another) it starts
+    /// there the way the bootstrap does, with SP at the stack base (256
+    /// normally) and a frame saved for the call; otherwise it starts at
+    /// the first command with SP at the stack base.
+    ///
+    /// RAM is filled from `config.ram_init` first; only what the bootstrap
+    /// sets (SP, the frame pointers and the saved frame) is written over
+    /// it.
+    pub fn new(program: &'p [SourceCommand<'a>], config: Config) -> Vm<'p, 'a> {
+        let mut scopes = Vec::with_capacity(program.len());
+        let mut labels = HashMap::new();
+        let mut functions = HashMap::new();
+        let mut statics = HashMap::new();
+
+        for (i, (scope, sc)) in function_bodies(program).enumerate() {
+            scopes.push(scope);
+            match sc.command() {
+                Command::Label(label) => {
+                    labels.insert((scope, *label), i);
+                }
+                Command::Function { name, .. } => {
+                    functions.insert(*name, i);
+                }
+                Command::Push { segment: Segment::Static, index }
+                | Command::Pop { segment: Segment::Static, index } => {
+                    let next = config.memory.static_base as usize + statics.len();
+                    statics.entry((sc.file_base(), *index)).or_insert(next);
+                }
+                _ => (),
+            }
+        }
+
+        let mut vm = Vm {
+            program,
+            scopes,
+            labels,
+            functions,
+            statics,
+            heap: Heap::new(&config.memory),
+            memory: config.memory.clone(),
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            steps: 0,
+            frames: Vec::new(),
+            halted: false,
+            hooks: Vec::new(),
+            watches: Vec::new(),
+            watch_hits: Vec::new(),
+            pause_on_watch: false,
+            pauses: BTreeSet::new(),
+        };
+        config.ram_init.fill(&mut vm.ram);
+        let stack_base = vm.memory.stack_base as i16;
+        vm.ram[SP] = stack_base;
+        let entry_name = config.entry.as_deref().unwrap_or("Sys.init");
+        if let Some(&entry) = vm.functions.get(entry_name) {
+            // Mirror the bootstrap's call: a return address nothing can
+            // reach, and the sentinel frame pointers.
+            for value in [-1, -1, -2, -3, -4] {
+                vm.push(value).expect("the stack starts in range");
+            }
+            vm.ram[LCL] = vm.ram[SP];
+            vm.ram[ARG] = stack_base;
+            vm.ram[THIS] = -3;
+            vm.ram[THAT] = -4;
+            vm.pc = entry;
+            vm.frames.push(Frame {
+                function: entry_name.to_string(),
+                call_site: None,
+            });
+        }
+        vm
+    }
+
+    /// Calls `hook` for every `Event` from now on.
+    ///
+    /// ```
+    /// use hack_vmtranslator::interp::{Config, Event, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let sources = [Source::new("Main.vm", "push constant 9\npop temp 1\n")];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let writes = Arc::new(Mutex::new(Vec::new()));
+    /// let log = Arc::clone(&writes);
+    ///
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// vm.on_event(move |event| {
+    ///     if let Event::Write { address: 6, new, .. } = event {
+    ///         log.lock().unwrap().push(*new);
+    ///     }
+    /// });
+    /// vm.run(&Limits::default()).unwrap();
+    /// assert_eq!(*writes.lock().unwrap(), vec![9]);
+    /// ```
+    pub fn on_event(&mut self, hook: impl FnMut(&Event) + Send + 'p) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Records every write to `addresses` from now on, with the command
+    /// that made it, for `take_watch_hits`. With `pause_on_watch` set a
+    /// write also stops `run` after the command that made it.
+    ///
+    /// ```
+    /// use hack_vmtranslator::interp::{Config, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let text = "function Sys.init 0\npush constant 7\npop temp 2\ncall Main.f 0\npop temp 0\nlabel END\ngoto END\n";
+    /// let main = "function Main.f 0\npush constant 8\npop temp 2\npush constant 0\nreturn\n";
+    /// let sources = [Source::new("Sys.vm", text), Source::new("Main.vm", main)];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    ///
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// vm.watch(7..=7);
+    /// vm.run(&Limits::default()).unwrap();
+    /// let hits: Vec<String> = vm.take_watch_hits().iter().map(ToString::to_string).collect();
+    /// assert_eq!(hits, ["RAM[7]: 0 -> 7 at Sys.init Sys.vm:2", "RAM[7]: 7 -> 8 at Main.f Main.vm:2"]);
+    ///
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// vm.watch(7..=7);
+    /// vm.pause_on_watch(true);
+    /// vm.run(&Limits::default()).unwrap();
+    /// assert!(!vm.is_halted());
+    /// assert_eq!(vm.take_watch_hits()[0].new, 7);
+    /// vm.run(&Limits::default()).unwrap();
+    /// assert_eq!(vm.take_watch_hits()[0].location.as_ref().unwrap().function.as_deref(), Some("Main.f"));
+    /// vm.run(&Limits::default()).unwrap();
+    /// assert!(vm.is_halted());
+    /// ```
+    pub fn watch(&mut self, addresses: RangeInclusive<usize>) {
+        self.watches.push(addresses);
+    }
+
+    pub fn pause_on_watch(&mut self, pause: bool) {
+        self.pause_on_watch = pause;
+    }
+
+    /// Stops `run` once `step` commands have run in all, so the program
+    /// can be looked at there and then carried on. Steps already past are
+    /// ignored.
+    pub fn pause_at_step(&mut self, step: u64) {
+        if step > self.steps {
+            self.pauses.insert(step);
+        }
+    }
+
+    /// The writes to watched addresses since the last call, oldest first.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    /// The screen as a binary PBM image (`P4`), black where a bit is set,
+    /// for comparing what a program drew byte for byte, or by hash.
+    ///
+    /// ```
+    /// use hack_vmtranslator::interp::{Config, Limits, Vm};
+    /// use hack_vmtranslator::sha256::hex_digest;
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// // A dot at (0, 0), one at (24, 0), then a 16x2 rectangle at (32, 2).
+    /// let text = "function Sys.init 0\n\
+    ///             push constant 16384\npop pointer 1\n\
+    ///             push constant 1\npop that 0\npush constant 256\npop that 1\n\
+    ///             push constant 16450\npop pointer 1\n\
+    ///             push constant 0\nnot\npop that 0\npush constant 0\nnot\npop that 32\n\
+    ///             label END\ngoto END\n";
+    /// let sources = [Source::new("Sys.vm", text)];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let mut vm = Vm::new(&program, Config::default());
+    ///
+    /// let mut expected = b"P4\n512 256\n".to_vec();
+    /// let header = expected.len();
+    /// expected.resize(header + 64 * 256, 0);
+    /// let blank = expected.clone();
+    ///
+    /// // After the dots, before the rectangle.
+    /// vm.pause_at_step(7);
+    /// vm.run(&Limits::default()).unwrap();
+    /// expected[header] = 0x80;
+    /// expected[header + 3] = 0x80;
+    /// let dots = vm.screenshot();
+    /// assert_eq!(dots, expected);
+    ///
+    /// vm.run(&Limits::default()).unwrap();
+    /// assert!(vm.is_halted());
+    /// for row in [2, 3] {
+    ///     expected[header + 64 * row + 4] = 0xff;
+    ///     expected[header + 64 * row + 5] = 0xff;
+    /// }
+    /// assert_eq!(vm.screenshot(), expected);
+    ///
+    /// assert_eq!(hex_digest(&blank), "16b12dd5ce10c74c5a24258aa2c34e95db3ac64274a02af4c9a7a6b871521bfd");
+    /// assert_eq!(hex_digest(&dots), "c0f2a3a74920d408def1f76c97dc61f34b1cd8ab93a90d7207ecc1530157d19c");
+    /// ```
+    pub fn screenshot(&self) -> Vec<u8> {
+        let mut image = format!("P4\n{SCREEN_WIDTH} {SCREEN_HEIGHT}\n").into_bytes();
+        let screen = self.memory.screen as usize;
+        for i in 0..SCREEN_WIDTH * SCREEN_HEIGHT / 16 {
+            let word = self.ram.get(screen + i).copied().unwrap_or(0) as u16;
+            // PBM puts the leftmost pixel in the high bit.
+            image.extend(word.reverse_bits().to_be_bytes());
+        }
+        image
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [i16] {
+        &mut self.ram
+    }
+
+    pub fn sp(&self) -> i16 {
+        self.ram[SP]
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The command about to run, or `None` once the program has halted.
+    pub fn current_location(&self) -> Option<Location> {
+        if self.halted {
+            None
+        } else {
+            self.location(self.pc)
+        }
+    }
+
+    /// What the built-in heap has handed out so far.
+    ///
+    /// ```
+    /// use hack_vmtranslator::interp::{Config, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let text = "function Sys.init 0\n\
+    ///             push constant 3\ncall Memory.alloc 1\npop static 0\n\
+    ///             push constant 5\ncall Memory.alloc 1\npop static 1\n\
+    ///             push constant 2\ncall Memory.alloc 1\npop static 2\n\
+    ///             push static 1\ncall Memory.deAlloc 1\npop temp 0\n\
+    ///             push constant 4\ncall Memory.alloc 1\npop static 3\n\
+    ///             push constant 10\ncall Memory.alloc 1\npop static 4\n\
+    ///             label END\ngoto END\n";
+    /// let sources = [Source::new("Sys.vm", text)];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// vm.run(&Limits::default()).unwrap();
+    ///
+    /// // The freed block is reused first-fit; live blocks don't overlap.
+    /// let live = [(vm.ram()[16], 3), (vm.ram()[18], 2), (vm.ram()[19], 4), (vm.ram()[20], 10)];
+    /// assert_eq!(live.map(|(base, _)| base), [2048, 2056, 2051, 2058]);
+    /// for (i, (a, a_size)) in live.iter().enumerate() {
+    ///     for (b, _) in &live[i + 1..] {
+    ///         assert!(b < a || *b >= a + a_size);
+    ///     }
+    /// }
+    ///
+    /// let heap = vm.heap();
+    /// assert_eq!((heap.allocations, heap.frees, heap.live_blocks), (5, 1, 4));
+    /// assert_eq!(heap.words_in_use, live.iter().map(|(_, size)| size).sum::<i16>() as usize);
+    /// assert_eq!(heap.peak_words, 19);
+    /// ```
+    pub fn heap(&self) -> HeapStats {
+        self.heap.stats
+    }
+
+    /// Active functions, outermost first.
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Walks the frames saved in RAM the way `return` reads them: the
+    /// return address at LCL-5 names the `call` that made the frame, and
+    /// the saved LCL at LCL-4 leads to the caller's. A frame that doesn't
+    /// hold up (a return address that isn't just after a `call`, or a
+    /// saved LCL that doesn't lead down the stack) ends the walk rather
+    /// than being followed.
+    ///
+    /// ```
+    /// use hack_vmtranslator::interp::{Config, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let sys = "function Sys.init 0\ncall Main.a 0\nlabel END\ngoto END\n";
+    /// let main = "function Main.a 0\ncall Main.b 0\nreturn\n\
+    ///             function Main.b 1\npush constant 1\ncall Main.c 1\nreturn\n\
+    ///             function Main.c 0\ncall Missing.f 0\nreturn\n";
+    /// let sources = [Source::new("Sys.vm", sys), Source::new("Main.vm", main)];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// let error = vm.run(&Limits::default()).unwrap_err();
+    ///
+    /// assert_eq!(*error.backtrace, vm.backtrace());
+    /// assert_eq!(
+    ///     error.backtrace.to_string(),
+    ///     "  #0 Main.c Main.vm:8\n  #1 Main.b Main.vm:5\n  #2 Main.a Main.vm:1\n  #3 Sys.init Sys.vm:1\n"
+    /// );
+    ///
+    /// // Main.c's saved LCL, overwritten to point up the stack.
+    /// let lcl = vm.ram()[1] as usize;
+    /// vm.ram_mut()[lcl - 4] = lcl as i16 + 10;
+    /// let backtrace = vm.backtrace();
+    /// assert_eq!(backtrace.frames.len(), 2);
+    /// assert_eq!(backtrace.broken.as_deref(), Some("the frame of Main.c at RAM[278] has a saved LCL of 288"));
+    /// ```
+    pub fn backtrace(&self) -> Backtrace {
+        let mut backtrace = Backtrace::default();
+        let Some(location) = self.location(self.pc) else {
+            return backtrace;
+        };
+        backtrace.frames.push(location);
+        if self.scopes[self.pc].is_none() {
+            return backtrace;
+        }
+
+        let mut lcl = self.ram[LCL] as isize;
+        let mut function = self.scopes[self.pc].unwrap_or_default();
+        loop {
+            let saved = |offset: isize| self.read(lcl - offset).ok();
+            let Some(return_address) = saved(5) else {
+                backtrace.broken = Some(format!("the frame of {function} at RAM[{lcl}] is out of range"));
+                break;
+            };
+            if return_address < 0 {
+                // The bootstrap's call into the entry function.
+                break;
+            }
+            let call = (return_address as usize).checked_sub(1);
+            let Some(call) = call.filter(|c| matches!(self.program.get(*c).map(SourceCommand::command), Some(Command::Call { .. }))) else {
+                backtrace.broken = Some(format!("the frame of {function} at RAM[{lcl}] has a return address of {return_address}"));
+                break;
+            };
+            backtrace.frames.extend(self.location(call));
+            let caller_lcl = saved(4).unwrap_or_default() as isize;
+            if !(5..lcl).contains(&caller_lcl) {
+                backtrace.broken = Some(format!("the frame of {function} at RAM[{lcl}] has a saved LCL of {caller_lcl}"));
+                break;
+            }
+            function = self.scopes[call].unwrap_or_default();
+            lcl = caller_lcl;
+        }
+        backtrace
+    }
+
+    /// Runs until the program halts, a watchpoint or `pause_at_step`
+    /// pauses it, or `limits` stop it. `limits` count from the start of the
+    /// program, not of this call.
+    pub fn run(&mut self, limits: &Limits) -> Result<(), RuntimeError> {
+        let started = Instant::now();
+        loop {
+            if matches!(self.step()?, StepResult::Halted | StepResult::Paused) {
+                return Ok(());
+            }
+            if self.pauses.first() == Some(&self.steps) {
+                self.pauses.pop_first();
+                return Ok(());
+            }
+            if self.steps >= limits.max_steps {
+                return Err(self.error(
+                    ErrorKind::StepLimit,
+                    format!("step limit exceeded ({} steps)", limits.max_steps),
+                ));
+            }
+            if let Some(timeout) = limits.timeout {
+                if self.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && started.elapsed() > timeout {
+                    return Err(self.error(
+                        ErrorKind::Timeout,
+                        format!("timed out after {:.1}s", timeout.as_secs_f64()),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Runs one command.
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        if self.halted {
+            return Ok(StepResult::Halted);
+        }
+        let Some(sc) = self.program.get(self.pc) else {
+            self.halted = true;
+            return Ok(StepResult::Halted);
+        };
+        self.steps += 1;
+        if !self.hooks.is_empty() {
+            self.emit(Event::Step(sc));
+        }
+        let hits = self.watch_hits.len();
+        let mut next = self.pc + 1;
+
+        match sc.command() {
+            Command::Push { segment, index } => {
+                let value = match segment {
+                    Segment::Constant => *index as i16,
+                    _ => {
+                        let address = self.address(sc, segment, *index)?;
+                        self.ram[address]
+                    }
+                };
+                self.push(value)?;
+            }
+            Command::Pop { segment, index } => {
+                if let Segment::Constant = segment {
+                    return Err(self.error(ErrorKind::Fault, "can't pop to constant".to_string()));
+                }
+                let address = self.address(sc, segment, *index)?;
+                let value = self.pop()?;
+                self.store(address, value);
+            }
+            Command::Add => self.binary(|x, y| x.wrapping_add(y))?,
+            Command::Sub => self.binary(|x, y| x.wrapping_sub(y))?,
+            Command::And => self.binary(|x, y| x & y)?,
+            Command::Or => self.binary(|x, y| x | y)?,
+            Command::Eq => self.binary(|x, y| -((x == y) as i16))?,
+            Command::Gt => self.binary(|x, y| -((x > y) as i16))?,
+            Command::Lt => self.binary(|x, y| -((x < y) as i16))?,
+            Command::Neg => self.unary(|x| x.wrapping_neg())?,
+            Command::Not => self.unary(|x| !x)?,
+            Command::Label(_) => (),
+            Command::Goto(label) => {
+                let target = self.label(label)?;
+                if target + 1 == self.pc {
+                    // `label L` / `goto L`: the end-of-program spin.
+                    self.halted = true;
+                    return Ok(StepResult::Halted);
+                }
+                next = target;
+            }
+            Command::IfGoto(label) => {
+                let target = self.label(label)?;
+                if self.pop()? != 0 {
+                    next = target;
+                }
+            }
+            Command::Function { nvars, .. } => {
+                for _ in 0..*nvars {
+                    self.push(0)?;
+                }
+            }
+            Command::Call { name, nargs } if !self.functions.contains_key(name) => {
+                self.call_builtin(name, *nargs)?;
+            }
+            Command::Call { name, nargs } => {
+                let entry = self.functions[name];
+                let sp = self.ram[SP];
+                self.push(next as i16)?;
+                for pointer in [LCL, ARG, THIS, THAT] {
+                    self.push(self.ram[pointer])?;
+                }
+                self.store(ARG, sp - *nargs as i16);
+                self.store(LCL, self.ram[SP]);
+                self.frames.push(Frame {
+                    function: name.to_string(),
+                    call_site: self.location(self.pc),
+                });
+                if !self.hooks.is_empty() {
+                    self.emit(Event::Call(name));
+                }
+                next = entry;
+            }
+            Command::Return => {
+                let frame = self.ram[LCL];
+                let return_address = self.read(frame as isize - 5)?;
+                let value = self.pop()?;
+                let arg = self.ram[ARG];
+                self.write(arg as isize, value)?;
+                self.store(SP, arg + 1);
+                for (offset, pointer) in [(1, THAT), (2, THIS), (3, ARG), (4, LCL)] {
+                    let saved = self.read(frame as isize - offset)?;
+                    self.store(pointer, saved);
+                }
+                if let Some(frame) = self.frames.pop() {
+                    if !self.hooks.is_empty() {
+                        self.emit(Event::Return(&frame.function));
+                    }
+                }
+                if return_address < 0 {
+                    // Returned from the entry function.
+                    self.halted = true;
+                    return Ok(StepResult::Halted);
+                }
+                next = return_address as usize;
+            }
+            Command::Trap => {
+                return Err(self.error(ErrorKind::Fault, "reached a line that failed to parse".to_string()));
+            }
+            Command::Include(name) => {
+                return Err(self.error(ErrorKind::Fault, format!("include \"{name}\" wasn't spliced in")));
+            }
+            Command::RawAsm(_) => {
+                return Err(self.error(ErrorKind::Fault, "can't interpret inline assembly".to_string()));
+            }
+            Command::DebugPrint => {
+                let value = self.read(self.ram[SP] as isize - 1)?;
+                if !self.hooks.is_empty() {
+                    self.emit(Event::DebugPrint {
+                        function: self.scopes[self.pc],
+                        command: sc,
+                        value,
+                    });
+                }
+            }
+        }
+
+        self.pc = next;
+        if self.pause_on_watch && self.watch_hits.len() > hits {
+            return Ok(StepResult::Paused);
+        }
+        Ok(StepResult::Running)
+    }
+
+    /// Runs a call to an OS function the program doesn't define itself,
+    /// when the interpreter has one built in: `Memory.alloc` and
+    /// `Memory.deAlloc`. Like a real call, the arguments are replaced by
+    /// the return value.
+    fn call_builtin(&mut self, name: &str, nargs: u16) -> Result<(), RuntimeError> {
+        if !matches!(name, "Memory.alloc" | "Memory.deAlloc") {
+            return Err(self.error(ErrorKind::Fault, format!("call to undefined function {name}")));
+        }
+        if nargs != 1 {
+            return Err(self.error(ErrorKind::Fault, format!("{name} takes 1 argument, not {nargs}")));
+        }
+        let argument = self.pop()?;
+        let result = if name == "Memory.alloc" {
+            self.heap.alloc(argument).map(|base| base as i16)
+        } else {
+            self.heap.dealloc(argument).map(|()| 0)
+        };
+        let value = result.map_err(|message| self.error(ErrorKind::Fault, message))?;
+        if !self.hooks.is_empty() {
+            self.emit(Event::Call(name));
+            self.emit(Event::Return(name));
+        }
+        self.push(value)
+    }
+
+    fn location(&self, pc: usize) -> Option<Location> {
+        let sc = self.program.get(pc)?;
+        Some(Location {
+            function: self.scopes[pc].map(str::to_string),
+            path: sc.path().display().to_string(),
+            line: sc.line(),
+        })
+    }
+
+    fn error(&self, kind: ErrorKind, message: String) -> RuntimeError {
+        RuntimeError {
+            kind,
+            message,
+            location: self.current_location(),
+            call_stack: self.frames.clone(),
+            backtrace: Box::new(self.backtrace()),
+        }
+    }
+
+    fn label(&self, label: &str) -> Result<usize, RuntimeError> {
+        let scope = self.scopes[self.pc];
+        self.labels
+            .get(&(scope, label))
+            .copied()
+            .ok_or_else(|| self.error(ErrorKind::Fault, format!("no label {label}")))
+    }
+
+    fn address(&self, sc: &SourceCommand, segment: &Segment, index: u16) -> Result<usize, RuntimeError> {
+        let index = index as isize;
+        let address = match segment {
+            Segment::Local => self.ram[LCL] as isize + index,
+            Segment::Argument => self.ram[ARG] as isize + index,
+            Segment::This => self.ram[THIS] as isize + index,
+            Segment::That => self.ram[THAT] as isize + index,
+            Segment::Pointer => self.memory.pointer_base as isize + index,
+            Segment::Temp => self.memory.temp_base as isize + index,
+            Segment::Reg => index,
+            Segment::Static => self.statics[&(sc.file_base(), index as u16)] as isize,
+            Segment::Constant => unreachable!("constant has no address"),
+        };
+        self.check_address(address)
+    }
+
+    fn check_address(&self, address: isize) -> Result<usize, RuntimeError> {
+        if (0..RAM_SIZE as isize).contains(&address) {
+            Ok(address as usize)
+        } else {
+            Err(self.error(ErrorKind::Fault, format!("RAM address {address} out of range")))
+        }
+    }
+
+    /// Every write the program makes goes through here.
+    fn store(&mut self, address: usize, value: i16) {
+        let old = self.ram[address];
+        self.ram[address] = value;
+        if !self.hooks.is_empty() {
+            self.emit(Event::Write { address, old, new: value });
+        }
+        if self.watches.iter().any(|watch| watch.contains(&address)) {
+            self.watch_hits.push(WatchHit {
+                address,
+                old,
+                new: value,
+                location: self.location(self.pc),
+            });
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        for hook in &mut self.hooks {
+            hook(&event);
+        }
+    }
+
+    fn read(&self, address: isize) -> Result<i16, RuntimeError> {
+        Ok(self.ram[self.check_address(address)?])
+    }
+
+    fn write(&mut self, address: isize, value: i16) -> Result<(), RuntimeError> {
+        let address = self.check_address(address)?;
+        self.store(address, value);
+        Ok(())
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), RuntimeError> {
+        let sp = self.ram[SP];
+        self.write(sp as isize, value)?;
+        self.store(SP, sp + 1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, RuntimeError> {
+        let sp = self.ram[SP] - 1;
+        let value = self.read(sp as isize)?;
+        self.store(SP, sp);
+        Ok(value)
+    }
+
+    fn binary(&mut self, op: impl Fn(i16, i16) -> i16) -> Result<(), RuntimeError> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        self.push(op(x, y))
+    }
+
+    fn unary(&mut self, op: impl Fn(i16) -> i16) -> Result<(), RuntimeError> {
+        let x = self.pop()?;
+        self.push(op(x))
+    }
+}
+
@@ -0,0 +1,160 @@
+#[cfg(feature = "assembler")]
+pub mod acceptance;
+#[cfg(feature = "cli")]
+pub mod argfile;
+pub mod asm;
+#[cfg(feature = "assembler")]
+pub mod assembler;
+pub mod bank;
+pub mod batch;
+pub mod callgraph;
+pub mod compat;
+#[cfg(feature = "cli")]
+pub mod completions;
+pub mod debuginfo;
+pub mod diagnostic;
+pub mod doc;
+pub mod doctor;
+pub mod filter;
+pub mod formatter;
+pub mod fuzz;
+pub mod grade;
+#[cfg(feature = "interp")]
+pub mod interp;
+pub mod json;
+pub mod lift;
+pub mod link;
+pub mod lint;
+#[cfg(all(feature = "assembler", feature = "interp"))]
+pub mod lockstep;
+pub mod memory;
+pub mod metrics;
+pub mod options;
+pub mod project;
+pub mod provenance;
+pub mod segments;
+pub mod sha256;
+pub mod stack;
+pub mod testgen;
+pub mod translator;
+pub mod vm;
+
+use std::time::Instant;
+
+// The library's types hold no `Rc`, `RefCell` or global state, so each of
+// several threads can translate (or run) a program of its own. These stop
+// that from changing unnoticed.
+const _: () = {
+    #[cfg(feature = "interp")]
+    fn send<T: Send>() {}
+    fn send_sync<T: Send + Sync>() {}
+
+    #[allow(dead_code)]
+    fn check() {
+        send_sync::<asm::CodegenOptions>();
+        send_sync::<asm::Manifest>();
+        send_sync::<debuginfo::DebugInfo>();
+        send_sync::<diagnostic::Diagnostic>();
+        send_sync::<link::Library>();
+        send_sync::<options::Options>();
+        send_sync::<provenance::BuildManifest>();
+        send_sync::<translator::Translator>();
+        send_sync::<translator::Output>();
+        send_sync::<vm::Source>();
+        send_sync::<vm::Command<'static>>();
+        send_sync::<vm::SourceCommand<'static>>();
+        send_sync::<vm::ParseError<'static>>();
+        // Hooks are `FnMut`, so a machine can move between threads but
+        // not be shared.
+        #[cfg(feature = "interp")]
+        send::<interp::Vm<'static, 'static>>();
+    }
+};
+
+/// Translates a program given as `(path, text)` pairs, one per `.vm` file,
+/// with the default `CodegenOptions`, the metadata block included. Errors
+/// are every parse error, or the first that stopped code generation;
+/// warnings aren't reported, so use `translator::Translator` for those.
+///
+/// ```
+/// let asm = hack_vmtranslator::translate(&[
+///     ("Sys.vm".to_string(), "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n".to_string()),
+///     ("Main.vm".to_string(), "function Main.main 0\npush constant 1\nreturn\n".to_string()),
+/// ])
+/// .unwrap();
+/// assert!(asm.starts_with("@256\nD=A\n@SP\nM=D"));
+/// assert!(asm.contains("// Main[1]: push constant 1\n@1\nD=A"));
+/// assert!(asm.ends_with("// function Main.main\n// function Sys.init\n// == end metadata =="));
+///
+/// let errors = hack_vmtranslator::translate(&[("Main.vm".to_string(), "push constant 1\npop nowhere 0\nadd 1\n".to_string())])
+///     .unwrap_err();
+/// assert_eq!(errors.len(), 2);
+/// ```
+pub fn translate(sources: &[(String, String)]) -> Result<String, Vec<diagnostic::Diagnostic>> {
+    translate_with_metrics(sources).map(|(asm, _)| asm)
+}
+
+/// `translate`, also measuring the run: how long parsing and generating
+/// code took, and the counts `--timings` and `--report-json` give.
+///
+/// ```
+/// let sys = "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n";
+/// let main = "// Adds two numbers.\nfunction Main.main 0\npush constant 7\npush constant 8\nadd\nreturn\n";
+/// let sources = |main: String| vec![("Sys.vm".to_string(), sys.to_string()), ("Main.vm".to_string(), main)];
+///
+/// let (asm, metrics) = hack_vmtranslator::translate_with_metrics(&sources(main.to_string())).unwrap();
+/// assert_eq!(asm, hack_vmtranslator::translate(&sources(main.to_string())).unwrap());
+/// assert_eq!((metrics.input_lines, metrics.commands, metrics.instructions), (10, 9, 183));
+/// assert_eq!(metrics.peak_bytes, sys.len() + main.len() + asm.len());
+/// let phases: Vec<_> = metrics.phases.iter().map(|(phase, _)| *phase).collect();
+/// assert_eq!(phases, ["parse", "codegen"]);
+/// assert!(metrics.phases.iter().all(|(_, duration)| !duration.is_zero()));
+///
+/// // A program a few hundred times the size takes longer.
+/// let functions: String = (0..500).map(|i| format!("function Main.f{i} 1\npush local 0\npush constant {i}\nlt\nreturn\n")).collect();
+/// let (_, bigger) = hack_vmtranslator::translate_with_metrics(&sources(format!("{main}{functions}"))).unwrap();
+/// assert_eq!(bigger.commands, metrics.commands + 2500);
+/// assert!(bigger.total() > metrics.total(), "{bigger:?} vs {metrics:?}");
+/// ```
+pub fn translate_with_metrics(sources: &[(String, String)]) -> Result<(String, metrics::Metrics), Vec<diagnostic::Diagnostic>> {
+    let mut metrics = metrics::Metrics::default();
+    let started = Instant::now();
+    let mut translator = translator::Translator::new();
+    let mut errors = Vec::new();
+    for (path, text) in sources {
+        metrics.input_lines += text.lines().count();
+        metrics.peak_bytes += text.len();
+        match translator.add_source(path, text.as_str()) {
+            Ok(summary) => metrics.commands += summary.commands,
+            Err(e) => errors.extend(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    metrics.finish_phase("parse", started);
+
+    let started = Instant::now();
+    let output = translator.finish(&asm::CodegenOptions::default())?;
+    let text = format!("{}\n{}", output.asm, output.manifest);
+    metrics.finish_phase("codegen", started);
+    metrics.instructions = output.asm.lines().filter(|line| asm::is_instruction(line)).count();
+    metrics.peak_bytes += text.len();
+    Ok((text, metrics))
+}
+
+/// `translate` for a single file, `name.vm`.
+///
+/// ```
+/// let asm = hack_vmtranslator::translate_str("Foo", "push constant 1\npush constant 2\nadd").unwrap();
+/// assert!(asm.starts_with("// Foo[0]: push constant 1\n@1\nD=A\n"));
+/// assert_eq!(hack_vmtranslator::translate_str("Foo.vm", "add\n").unwrap(), hack_vmtranslator::translate_str("Foo", "add\n").unwrap());
+/// ```
+pub fn translate_str(name: &str, source: &str) -> Result<String, Vec<diagnostic::Diagnostic>> {
+    let path = if vm::is_vm_source(std::path::Path::new(name)) {
+        name.to_string()
+    } else {
+        format!("{name}.vm")
+    };
+    translate(&[(path, source.to_string())])
+}
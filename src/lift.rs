@@ -0,0 +1,500 @@
+//! Recovers the VM commands behind assembly this translator generated, to
+//! audit its output or to get back sources that were lost. Experimental.
+//!
+//! The code for each command starts with a `// File[line]: command`
+//! header; where those are present, lifting is reading them and checking
+//! that the code under each is what its command generates. Where the
+//! comments were stripped, commands are recognised by the shape of their
+//! code and checked the same way. Code that no command generates, such as
+//! a hand-written module linked in, is kept as an `asm { ... }` block.
+//!
+//! Without the headers some code reads two ways: `function f 1` is the
+//! same code as `function f 0` and then `push constant 0`, and the first
+//! is assumed. Files are told apart only by function names, statics and
+//! labels, and comparisons translated with `--safe-compare` aren't
+//! recognised. Output translated with `--symbol-prefix` isn't lifted.
+
+use crate::asm::{self, CodegenOptions, LabelScheme, BOOTSTRAP_LABEL};
+use crate::memory::MemoryMap;
+use crate::vm::{self, Command, Source, SourceCommand};
+
+/// A command recovered from the assembly, or code that couldn't be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lifted {
+    /// With the line its header gave, when it had one.
+    Command { file: String, line: Option<usize>, command: String },
+    /// Code no command generates, in order.
+    Asm { file: String, instructions: Vec<String> },
+}
+
+impl Lifted {
+    /// The file base the piece belongs to.
+    pub fn file(&self) -> &str {
+        match self {
+            Lifted::Command { file, .. } | Lifted::Asm { file, .. } => file,
+        }
+    }
+
+    /// The piece as a line of a `.vm` file.
+    pub fn to_vm(&self) -> String {
+        match self {
+            Lifted::Command { command, .. } => command.clone(),
+            Lifted::Asm { instructions, .. } => format!("asm {{ {} }}", instructions.join(" / ")),
+        }
+    }
+}
+
+/// What `lift` recovered.
+#[derive(Debug, Clone, Default)]
+pub struct Lift {
+    /// The function the bootstrap calls, when there is one.
+    pub entry: Option<String>,
+    pub pieces: Vec<Lifted>,
+}
+
+impl Lift {
+    pub fn commands(&self) -> usize {
+        self.pieces.iter().filter(|piece| matches!(piece, Lifted::Command { .. })).count()
+    }
+
+    /// How many regions were kept as `asm` blocks.
+    pub fn unlifted(&self) -> usize {
+        self.pieces.len() - self.commands()
+    }
+
+    /// A `.vm` file for each file the pieces came from, in the order they
+    /// first appear.
+    pub fn sources(&self) -> Vec<Source> {
+        let mut files: Vec<(&str, String)> = Vec::new();
+        for piece in &self.pieces {
+            let text = match files.iter_mut().find(|(file, _)| *file == piece.file()) {
+                Some((_, text)) => text,
+                None => {
+                    files.push((piece.file(), String::new()));
+                    &mut files.last_mut().expect("just pushed").1
+                }
+            };
+            text.push_str(&piece.to_vm());
+            text.push('\n');
+        }
+        files.into_iter().map(|(file, text)| Source::new(format!("{file}.vm"), text)).collect()
+    }
+}
+
+/// Lifts `asm`, translated with `options` (the label scheme is read from
+/// the labels themselves).
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions, LabelScheme};
+/// use hack_vmtranslator::lift::{lift, Lifted};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+/// use std::fs;
+/// use std::path::Path;
+///
+/// // The course's FibonacciElement and StaticsTest, and every conformance
+/// // case that translates as it is.
+/// let mut programs = vec![
+///     vec![
+///         Source::new(
+///             "Main.vm",
+///             "function Main.fibonacci 0\npush argument 0\npush constant 2\nlt\nif-goto N_LT_2\ngoto N_GE_2\n\
+///              label N_LT_2\npush argument 0\nreturn\nlabel N_GE_2\npush argument 0\npush constant 2\nsub\n\
+///              call Main.fibonacci 1\npush argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\nadd\nreturn\n",
+///         ),
+///         Source::new("Sys.vm", "function Sys.init 0\npush constant 4\ncall Main.fibonacci 1\nlabel END\ngoto END\n"),
+///     ],
+///     vec![
+///         Source::new(
+///             "Class1.vm",
+///             "function Class1.set 0\npush argument 0\npop static 0\npush argument 1\npop static 1\n\
+///              push constant 0\nreturn\nfunction Class1.get 0\npush static 0\npush static 1\nsub\nreturn\n",
+///         ),
+///         Source::new(
+///             "Class2.vm",
+///             "function Class2.set 0\npush argument 0\npop static 0\npush argument 1\npop static 1\n\
+///              push constant 0\nreturn\nfunction Class2.get 0\npush static 0\npush static 1\nsub\nreturn\n",
+///         ),
+///         Source::new(
+///             "Sys.vm",
+///             "function Sys.init 0\npush constant 6\npush constant 8\ncall Class1.set 2\npop temp 0\n\
+///              push constant 23\npush constant 15\ncall Class2.set 2\npop temp 0\ncall Class1.get 0\n\
+///              call Class2.get 0\nlabel END\ngoto END\n",
+///         ),
+///     ],
+/// ];
+/// let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+/// for entry in fs::read_dir(&dir).unwrap() {
+///     let path = entry.unwrap().path();
+///     let expected = fs::read_to_string(path.with_extension("expected")).unwrap_or_default();
+///     let text = fs::read_to_string(&path).unwrap_or_default();
+///     // Extensions such as `debug.print` can leave no code to lift.
+///     let extensions = text.starts_with("// flags: --extensions");
+///     if path.extension().is_some_and(|e| e == "vm") && expected.trim_end() == "ok" && !extensions {
+///         let name = path.file_name().unwrap().to_string_lossy().replace('-', "_");
+///         programs.push(vec![Source::new(name, text)]);
+///     }
+/// }
+///
+/// for sources in &programs {
+///     let commands: Vec<_> = parse_sources(sources).into_iter().map(Result::unwrap).collect();
+///     let original: Vec<(String, String)> = commands
+///         .iter()
+///         .map(|sc| (sc.file_base().to_string(), sc.command().to_string()))
+///         .collect();
+///     for label_scheme in [LabelScheme::Counters, LabelScheme::Lines] {
+///         let options = CodegenOptions { label_scheme, ..CodegenOptions::default() };
+///         let asm = generate_code(commands.clone(), &options).unwrap().join("\n");
+///         let stripped: String = asm
+///             .lines()
+///             .map(|line| line.split("//").next().unwrap())
+///             .filter(|line| !line.trim().is_empty())
+///             .map(|line| format!("{line}\n"))
+///             .collect();
+///         let recovered = |asm: &str| -> Vec<(String, String)> {
+///             lift(asm, &options).pieces.iter().map(|p| (p.file().to_string(), p.to_vm())).collect()
+///         };
+///         assert_eq!(recovered(&asm), original, "{}", sources[0].path.display());
+///         // With the headers, `--safe-compare` code lifts too.
+///         let safe = CodegenOptions { safe_compare: true, ..options.clone() };
+///         let safe_asm = generate_code(commands.clone(), &safe).unwrap().join("\n");
+///         assert_eq!(recovered(&safe_asm), original, "{} with --safe-compare", sources[0].path.display());
+///         // Without the headers, a file's name is only known from its
+///         // functions, statics and labels.
+///         let commands = |pieces: Vec<(String, String)>| pieces.into_iter().map(|(_, command)| command).collect::<Vec<_>>();
+///         assert_eq!(commands(recovered(&stripped)), commands(original.clone()), "{} stripped", sources[0].path.display());
+///     }
+/// }
+///
+/// let fibonacci: Vec<_> = parse_sources(&programs[0]).into_iter().map(Result::unwrap).collect();
+/// let asm = generate_code(fibonacci, &CodegenOptions::default()).unwrap().join("\n");
+/// assert_eq!(lift(&asm, &CodegenOptions::default()).entry.as_deref(), Some("Sys.init"));
+///
+/// // Code no command generates is kept.
+/// let lifted = lift("// Main[0]: push constant 1\n@1\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@SCREEN\nM=-1\n", &CodegenOptions::default());
+/// assert_eq!(
+///     lifted.pieces,
+///     [
+///         Lifted::Command { file: "Main".to_string(), line: Some(0), command: "push constant 1".to_string() },
+///         Lifted::Asm { file: "Main".to_string(), instructions: vec!["@SCREEN".to_string(), "M=-1".to_string()] },
+///     ]
+/// );
+/// assert_eq!(lifted.sources()[0].text, "push constant 1\nasm { @SCREEN / M=-1 }\n");
+/// ```
+pub fn lift(asm: &str, options: &CodegenOptions) -> Lift {
+    let mut lines: Vec<Line> = Vec::new();
+    for text in asm.lines().map(str::trim) {
+        if let Some((file, line, command)) = header(text) {
+            lines.push(Line::Header { file, line, command });
+        } else {
+            let code = text.split("//").next().unwrap_or_default().trim();
+            if !code.is_empty() {
+                lines.push(Line::Code(code));
+            }
+        }
+    }
+    let next_header = |from: usize| {
+        lines[from..]
+            .iter()
+            .position(|line| matches!(line, Line::Header { .. }))
+            .map_or(lines.len(), |i| from + i)
+    };
+
+    // The bootstrap comes first and has no header.
+    let mut entry = None;
+    let returned = format!("({BOOTSTRAP_LABEL})");
+    let mut start = 0;
+    if let Some(at) = lines[..next_header(0)].iter().position(|line| matches!(line, Line::Code(code) if *code == returned)) {
+        if let (Some(Line::Code(jump)), Some(Line::Code("0;JMP"))) = (at.checked_sub(2).map(|i| &lines[i]), at.checked_sub(1).map(|i| &lines[i])) {
+            entry = jump.strip_prefix('@').map(str::to_string);
+        }
+        start = at + 1;
+    }
+
+    let first_function = lines[start..].iter().find_map(|line| match line {
+        Line::Code(code) => code.strip_prefix('(')?.strip_suffix(')').filter(|label| !label.contains('$')),
+        Line::Header { file, .. } => Some(*file),
+    });
+    let mut lifter = Lifter {
+        options,
+        file: first_function.map_or("Main", |name| name.split('.').next().unwrap_or(name)).to_string(),
+        scope: None,
+        pieces: Vec::new(),
+    };
+    let mut at = start;
+    while at < lines.len() {
+        let end = next_header(at + 1);
+        let code = |from: usize| -> Vec<&str> {
+            lines[from..end]
+                .iter()
+                .filter_map(|line| match line {
+                    Line::Code(code) => Some(*code),
+                    Line::Header { .. } => None,
+                })
+                .collect()
+        };
+        match lines[at] {
+            Line::Header { file, line, command } => lifter.read_header(file, line, command, &code(at + 1)),
+            Line::Code(_) => lifter.recognise(&code(at)),
+        }
+        at = end;
+    }
+
+    Lift {
+        entry,
+        pieces: lifter.pieces,
+    }
+}
+
+enum Line<'s> {
+    Header { file: &'s str, line: usize, command: &'s str },
+    /// An instruction or a label.
+    Code(&'s str),
+}
+
+/// The file, line and command of a `// File[line]: command` header.
+pub(crate) fn header(comment: &str) -> Option<(&str, usize, &str)> {
+    let (file, rest) = comment.strip_prefix("// ")?.split_once('[')?;
+    let (line, command) = rest.split_once("]: ")?;
+    if file.is_empty() || file.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((file, line.parse().ok()?, command))
+}
+
+struct Lifter<'o> {
+    options: &'o CodegenOptions,
+    file: String,
+    /// The function the last command was in.
+    scope: Option<String>,
+    pieces: Vec<Lifted>,
+}
+
+impl Lifter<'_> {
+    fn read_header(&mut self, file: &str, line: usize, command: &str, code: &[&str]) {
+        let used = self.accept(file, Some(line), command, code).unwrap_or_else(|| {
+            if file != self.file {
+                self.file = file.to_string();
+                self.scope = None;
+            }
+            0
+        });
+        for code in &code[used..] {
+            self.unlifted(code);
+        }
+    }
+
+    fn recognise(&mut self, code: &[&str]) {
+        let mut at = 0;
+        while at < code.len() {
+            let used = guesses(&code[at..], &self.file, &self.options.memory)
+                .into_iter()
+                .find_map(|(file, command)| self.accept(&file, None, &command, &code[at..]));
+            match used {
+                Some(used) => at += used,
+                None => {
+                    self.unlifted(code[at]);
+                    at += 1;
+                }
+            }
+        }
+    }
+
+    /// Records `command` if `code` starts with what it generates, and
+    /// returns how many lines of `code` that is.
+    fn accept(&mut self, file: &str, line: Option<usize>, command: &str, code: &[&str]) -> Option<usize> {
+        let sc = vm::parse_command(file, line.unwrap_or_default(), command).ok()?;
+        // Function bodies end at the end of a file.
+        let scope = match sc.command() {
+            Command::Function { name, .. } => Some(name.to_string()),
+            _ if file == self.file => self.scope.clone(),
+            _ => None,
+        };
+        let expected = expansion(&sc, scope.as_deref(), code, self.options)?;
+        if expected.is_empty() || expected.len() > code.len() || expected.iter().zip(code).any(|(e, c)| e != c) {
+            return None;
+        }
+        self.file = file.to_string();
+        self.scope = scope;
+        self.pieces.push(Lifted::Command {
+            file: file.to_string(),
+            line,
+            command: sc.command().to_string(),
+        });
+        Some(expected.len())
+    }
+
+    fn unlifted(&mut self, code: &str) {
+        match self.pieces.last_mut() {
+            Some(Lifted::Asm { file, instructions }) if *file == self.file => instructions.push(code.to_string()),
+            _ => self.pieces.push(Lifted::Asm {
+                file: self.file.clone(),
+                instructions: vec![code.to_string()],
+            }),
+        }
+    }
+}
+
+/// The code lines `sc` generates in `scope`. Calls and comparisons are
+/// numbered across the program, so their number, and for comparisons the
+/// label scheme, come from the label in `code`.
+fn expansion(sc: &SourceCommand, scope: Option<&str>, code: &[&str], options: &CodegenOptions) -> Option<Vec<String>> {
+    let mut options = options.clone();
+    let site = match sc.command() {
+        Command::Call { .. } | Command::Eq | Command::Gt | Command::Lt => {
+            let label = code
+                .iter()
+                .take(8)
+                .filter_map(|code| code.strip_prefix('@'))
+                .find(|symbol| symbol.contains("$ret.") || symbol.contains("$cmp.") || symbol.starts_with("COMP_"))?;
+            // `--safe-compare` code starts with its own label.
+            options.safe_compare = label.starts_with("COMP_NEG_") || label.ends_with(".neg");
+            if label.starts_with("COMP_") {
+                options.label_scheme = LabelScheme::Lines;
+            } else if label.contains("$cmp.") {
+                options.label_scheme = LabelScheme::Counters;
+            }
+            label.trim_end_matches(".neg").rsplit('.').next()?.parse().ok()?
+        }
+        _ => 0,
+    };
+    let code = asm::command_code(sc, scope, site, &options).ok()?;
+    Some(
+        code.lines()
+            .map(|line| line.split("//").next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// The commands whose code `code` might start with, each with the file it
+/// would be in.
+fn guesses(code: &[&str], file: &str, memory: &MemoryMap) -> Vec<(String, String)> {
+    let at = |i: usize| code.get(i).copied().unwrap_or_default();
+    let symbol = |i: usize| at(i).strip_prefix('@');
+    let here = |command: String| vec![(file.to_string(), command)];
+
+    if let Some(label) = at(0).strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+        if let Some((file, label)) = scoped(label, file) {
+            return vec![(file, format!("label {label}"))];
+        }
+        let zero = ["@0", "D=A", "@SP", "A=M", "M=D", "@SP", "M=M+1"];
+        let nvars = code[1..].chunks(zero.len()).take_while(|chunk| *chunk == zero).count();
+        let class = label.split('.').next().unwrap_or(label);
+        return vec![(class.to_string(), format!("function {label} {nvars}"))];
+    }
+
+    match (symbol(0), at(1), at(2), at(3)) {
+        (Some("SP"), "AM=M-1", "D=M", "@SP") if at(4) == "AM=M-1" => {
+            let command = match (at(5), at(7)) {
+                ("D=D+M", _) => "add",
+                ("D=D&M", _) => "and",
+                ("D=D|M", _) => "or",
+                ("D=M-D", "D;JEQ") => "eq",
+                ("D=M-D", "D;JGT") => "gt",
+                ("D=M-D", "D;JLT") => "lt",
+                ("D=M-D", _) => "sub",
+                _ => return Vec::new(),
+            };
+            // A comparison's labels name its file.
+            let file = match symbol(6) {
+                Some(label) if at(7).starts_with("D;") => comparison_file(label, file),
+                _ => file.to_string(),
+            };
+            vec![(file, command.to_string())]
+        }
+        (Some("SP"), "AM=M-1", "D=M", "D=-D") => here("neg".to_string()),
+        (Some("SP"), "AM=M-1", "D=M", "D=!D") => here("not".to_string()),
+        (Some("SP"), "AM=M-1", "D=M", _) => match (symbol(3), at(4)) {
+            (Some(variable), "M=D") => variable_segments(variable, file, memory)
+                .into_iter()
+                .map(|(file, segment)| (file, format!("pop {segment}")))
+                .collect(),
+            (Some(label), "D;JNE") => scoped(label, file)
+                .map(|(file, label)| vec![(file, format!("if-goto {label}"))])
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        },
+        (Some("LCL"), "D=M", "@frame", _) => here("return".to_string()),
+        (Some(pointer), "D=M", _, "D=D+A") if segment_name(pointer).is_some() => match symbol(2) {
+            Some(index) => here(format!("pop {} {index}", segment_name(pointer).unwrap_or_default())),
+            None => Vec::new(),
+        },
+        (Some(index), "D=A", _, _) if index.starts_with(|c: char| c.is_ascii_digit()) => {
+            match symbol(2).and_then(segment_name).filter(|_| at(3) == "A=D+M") {
+                Some(segment) => here(format!("push {segment} {index}")),
+                None => here(format!("push constant {index}")),
+            }
+        }
+        (Some(returned), "D=A", _, _) if returned.contains("$ret.") => {
+            let Some(end) = code.iter().position(|c| *c == format!("({returned})")) else { return Vec::new() };
+            let Some(name) = end.checked_sub(2).and_then(symbol) else { return Vec::new() };
+            let nargs = code[..end]
+                .windows(4)
+                .find(|w| w[0] == "@SP" && w[1] == "D=M" && w[3] == "D=D-A")
+                .and_then(|w| w[2].strip_prefix('@')?.parse::<u16>().ok()?.checked_sub(5));
+            let Some(nargs) = nargs else { return Vec::new() };
+            let file = scoped(returned, file).map_or(file.to_string(), |(file, _)| file);
+            vec![(file, format!("call {name} {nargs}"))]
+        }
+        (Some(variable), "D=M", _, _) => variable_segments(variable, file, memory)
+            .into_iter()
+            .map(|(file, segment)| (file, format!("push {segment}")))
+            .collect(),
+        (Some(label), "0;JMP", _, _) => scoped(label, file)
+            .map(|(file, label)| vec![(file, format!("goto {label}"))])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Splits a `scope$label` label. A scope that isn't a function is a file
+/// of its own.
+fn scoped(label: &str, file: &str) -> Option<(String, String)> {
+    let (scope, label) = label.split_once('$')?;
+    let file = if scope.contains('.') { file } else { scope };
+    Some((file.to_string(), label.to_string()))
+}
+
+fn segment_name(pointer: &str) -> Option<&'static str> {
+    match pointer {
+        "LCL" => Some("local"),
+        "ARG" => Some("argument"),
+        "THIS" => Some("this"),
+        "THAT" => Some("that"),
+        _ => None,
+    }
+}
+
+/// The file of a comparison label, `scope$cmp.N` or `COMP_TRUE_File.N`.
+fn comparison_file(label: &str, file: &str) -> String {
+    match label.strip_prefix("COMP_TRUE_").and_then(|rest| rest.rsplit_once('.')) {
+        Some((file, _)) => file.to_string(),
+        None => scoped(label, file).map_or(file.to_string(), |(file, _)| file),
+    }
+}
+
+/// The `segment index`es a variable the code reads or writes may stand
+/// for, with the file each implies. An address is the pointer or temp it
+/// falls in, or else an index past the end of the nearest segment below.
+fn variable_segments(variable: &str, file: &str, memory: &MemoryMap) -> Vec<(String, String)> {
+    if let Ok(address) = variable.parse::<u16>() {
+        let mut segments: Vec<(bool, u16, &str)> = [("pointer", memory.pointer_base, memory.pointer_size), ("temp", memory.temp_base, memory.temp_size)]
+            .into_iter()
+            .filter(|(_, base, _)| address >= *base)
+            .map(|(segment, base, size)| (address - base >= size, address - base, segment))
+            .collect();
+        segments.sort();
+        return segments
+            .into_iter()
+            .map(|(_, index, segment)| (file.to_string(), format!("{segment} {index}")))
+            .collect();
+    }
+    if let Some(register) = variable.strip_prefix('R').filter(|r| r.parse::<u16>().is_ok()) {
+        return vec![(file.to_string(), format!("reg {register}"))];
+    }
+    match variable.rsplit_once('.') {
+        Some((file, index)) if index.parse::<u16>().is_ok() => vec![(file.to_string(), format!("static {index}"))],
+        _ => Vec::new(),
+    }
+}
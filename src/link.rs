@@ -0,0 +1,347 @@
+use crate::asm;
+use std::collections::HashMap;
+
+/// Assembly previously written by this translator, as read from disk.
+pub struct Fragment {
+    pub name: String,
+    pub text: String,
+}
+
+impl Fragment {
+    pub fn defines(&self, label: &str) -> bool {
+        label_definitions(&self.text).any(|l| l == label)
+    }
+}
+
+/// Joins fragments into one program. Exactly one bootstrap may be
+/// present, and it is moved to the front; if none is present the
+/// standard bootstrap is injected. A label defined by more than one
+/// fragment is an error. Fragment text is otherwise copied unchanged.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_bootstrap, generate_code, BootstrapOptions, CodegenOptions, BOOTSTRAP_LABEL};
+/// use hack_vmtranslator::link::{link, Fragment};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let fragment = |name: &str, source: &str| {
+///     let sources = [Source::new(name, source)];
+///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     let text = generate_code(commands, &CodegenOptions::default()).unwrap().join("\n");
+///     Fragment { name: name.replace(".vm", ".asm"), text }
+/// };
+/// let sys = || fragment("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n");
+/// let main = || fragment("Main.vm", "function Main.main 0\npush constant 7\nreturn\n");
+/// let bootstrap = format!("({BOOTSTRAP_LABEL})");
+///
+/// // A clean link: the fragment bringing the bootstrap goes first.
+/// let (main_text, sys_text) = (main().text, sys().text);
+/// let program = link(vec![main(), sys()]).unwrap();
+/// assert_eq!(program, format!("{sys_text}\n{main_text}"));
+/// assert_eq!(program.matches(&bootstrap).count(), 1);
+///
+/// // Without one, the standard bootstrap is put in front.
+/// let library = fragment("Lib.vm", "function Lib.f 0\npush constant 1\nreturn\n");
+/// let library_text = library.text.clone();
+/// let program = link(vec![library]).unwrap();
+/// assert_eq!(program, format!("{}\n{library_text}", generate_bootstrap(&BootstrapOptions::default())));
+///
+/// // Two fragments defining the same function.
+/// let errors = link(vec![main(), sys(), main()]).unwrap_err();
+/// assert!(errors.contains(&"Label 'Main.main' is defined in both Main.asm and Main.asm".to_string()), "{errors:?}");
+///
+/// let errors = link(vec![sys(), main(), sys()]).unwrap_err();
+/// assert_eq!(errors, ["Multiple fragments contain a bootstrap: Sys.asm, Sys.asm"]);
+/// ```
+pub fn link(mut fragments: Vec<Fragment>) -> Result<String, Vec<String>> {
+    let with_bootstrap: Vec<usize> = fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| label_definitions(&f.text).any(|l| l == asm::BOOTSTRAP_LABEL))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut program: Vec<String> = Vec::new();
+    match with_bootstrap.as_slice() {
+        [] => program.push(asm::generate_bootstrap(&asm::BootstrapOptions::default())),
+        [i] => {
+            let fragment = fragments.remove(*i);
+            fragments.insert(0, fragment);
+        }
+        _ => {
+            let names: Vec<&str> = with_bootstrap.iter().map(|i| fragments[*i].name.as_str()).collect();
+            return Err(vec![format!("Multiple fragments contain a bootstrap: {}", names.join(", "))]);
+        }
+    }
+
+    let sources = program
+        .iter()
+        .map(|text| ("<bootstrap>", text.as_str()))
+        .chain(fragments.iter().map(|f| (f.name.as_str(), f.text.as_str())));
+    check_duplicate_labels(sources)?;
+
+    program.extend(fragments.into_iter().map(|f| f.text));
+    Ok(program.join("\n"))
+}
+
+/// Appends hand-written assembly modules to translated code, in the order
+/// given. A label a module shares with the translated code or another
+/// module is an error. VM code calls a module's routines by label like
+/// any function defined elsewhere.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, variable_allocation, CodegenOptions};
+/// use hack_vmtranslator::link::{append_modules, Fragment};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "function Main.main 0\ncall Blit.copy 0\nreturn\n")];
+/// let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let generated = generate_code(commands, &CodegenOptions::default()).unwrap().join("\n");
+///
+/// let blit = Fragment { name: "Blit.asm".to_string(), text: "(Blit.copy)\n@SCREEN\nM=-1\n".to_string() };
+/// let program = append_modules(generated.clone(), vec![blit]).unwrap();
+/// assert!(program.ends_with("(Blit.copy)\n@SCREEN\nM=-1\n"));
+/// // The call now lands on the module's label rather than a variable.
+/// assert!(!variable_allocation(&program).iter().any(|(name, _)| name == "Blit.copy"));
+///
+/// let clash = Fragment { name: "Bad.asm".to_string(), text: "(Main.main)\n0;JMP\n".to_string() };
+/// assert!(append_modules(generated, vec![clash]).is_err());
+/// ```
+pub fn append_modules(generated: String, modules: Vec<Fragment>) -> Result<String, Vec<String>> {
+    let sources = std::iter::once(("<translated code>", generated.as_str()))
+        .chain(modules.iter().map(|m| (m.name.as_str(), m.text.as_str())));
+    check_duplicate_labels(sources)?;
+
+    let mut program = generated;
+    for module in modules {
+        if !program.ends_with('\n') {
+            program.push('\n');
+        }
+        program.push_str(&module.text);
+    }
+    Ok(program)
+}
+
+/// Puts `prelude` before everything else in `program`, bootstrap
+/// included, and `epilogue` after it. Both are copied verbatim; a label
+/// either shares with the program or the other is an error.
+///
+/// ```
+/// use hack_vmtranslator::link::{surround, Fragment};
+///
+/// let fragment = |name: &str, text: &str| Fragment { name: name.to_string(), text: text.to_string() };
+/// let program = "@256\nD=A\n(Sys.init)\n0;JMP".to_string();
+///
+/// let prelude = fragment("prelude.asm", "@7\nD=A\n@CONTROL\nM=D\n");
+/// let epilogue = fragment("halt.asm", "(HALT)\n@HALT\n0;JMP\n");
+/// let wrapped = surround(program.clone(), Some(prelude), Some(epilogue)).unwrap();
+/// assert_eq!(wrapped, "@7\nD=A\n@CONTROL\nM=D\n@256\nD=A\n(Sys.init)\n0;JMP\n(HALT)\n@HALT\n0;JMP\n");
+///
+/// let clash = fragment("prelude.asm", "(Sys.init)\n");
+/// let errors = surround(program, Some(clash), None).unwrap_err();
+/// assert_eq!(errors, ["Label 'Sys.init' is defined in both prelude.asm and <translated code>"]);
+/// ```
+pub fn surround(program: String, prelude: Option<Fragment>, epilogue: Option<Fragment>) -> Result<String, Vec<String>> {
+    let sources = prelude
+        .iter()
+        .map(|f| (f.name.as_str(), f.text.as_str()))
+        .chain(std::iter::once(("<translated code>", program.as_str())))
+        .chain(epilogue.iter().map(|f| (f.name.as_str(), f.text.as_str())));
+    check_duplicate_labels(sources)?;
+
+    let mut wrapped = String::new();
+    for text in prelude.map(|f| f.text).into_iter().chain([program]).chain(epilogue.map(|f| f.text)) {
+        if !wrapped.is_empty() && !wrapped.ends_with('\n') {
+            wrapped.push('\n');
+        }
+        wrapped.push_str(&text);
+    }
+    Ok(wrapped)
+}
+
+/// Fails if `program`, the final assembly, runs a bootstrap more than
+/// once, naming the (1-based) lines of the first two. Overlapping inputs
+/// or fragments that each brought one would otherwise reset the stack
+/// partway through.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+/// use hack_vmtranslator::link::check_single_bootstrap;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Sys.vm", "function Sys.init 0\npush constant 1\npush constant 2\nlt\nreturn\n")];
+/// let translate = || {
+///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     generate_code(commands, &CodegenOptions::default()).unwrap().join("\n")
+/// };
+/// let program = translate();
+///
+/// // Translating again in the same process gives the same output.
+/// assert_eq!(translate(), program);
+/// assert!(check_single_bootstrap(&program).is_ok());
+/// assert_eq!(
+///     check_single_bootstrap(&format!("{program}\n{program}")).unwrap_err(),
+///     "The program runs the bootstrap twice, at lines 66 and 217"
+/// );
+/// ```
+pub fn check_single_bootstrap(program: &str) -> Result<(), String> {
+    let bootstrap = format!("({})", asm::BOOTSTRAP_LABEL);
+    let lines: Vec<usize> = program
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.trim() == bootstrap)
+        .map(|(i, _)| i + 1)
+        .collect();
+    match lines.as_slice() {
+        [first, second, ..] => Err(format!("The program runs the bootstrap twice, at lines {first} and {second}")),
+        _ => Ok(()),
+    }
+}
+
+/// Fails with a message for each label that more than one of `sources`,
+/// named assembly texts, defines.
+pub fn check_duplicate_labels<'t>(sources: impl Iterator<Item = (&'t str, &'t str)>) -> Result<(), Vec<String>> {
+    let mut defined: HashMap<&str, &str> = HashMap::new();
+    let mut errors: Vec<String> = Vec::new();
+
+    for (name, text) in sources {
+        for label in label_definitions(text) {
+            if let Some(first) = defined.insert(label, name) {
+                errors.push(format!("Label '{label}' is defined in both {first} and {name}"));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn label_definitions(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix('(').and_then(|l| l.strip_suffix(')')))
+}
+
+/// One fragment of a precompiled library, with the functions its `.sym`
+/// file (from `--emit-sym`) says it defines.
+pub struct LibraryFragment {
+    pub fragment: Fragment,
+    pub functions: Vec<String>,
+}
+
+/// Previously translated code, such as the OS, linked into a program
+/// instead of being translated again.
+#[derive(Default)]
+pub struct Library {
+    pub fragments: Vec<LibraryFragment>,
+}
+
+impl Library {
+    pub fn defines(&self, function: &str) -> bool {
+        self.fragments.iter().any(|f| f.functions.iter().any(|name| name == function))
+    }
+
+    /// The fragments needed for `calls`, in library order: those defining
+    /// a called function, the one defining `Sys.init`, and any they refer
+    /// to in turn.
+    pub fn reachable(&self, calls: &[&str]) -> Vec<&Fragment> {
+        let defining = |name: &str| self.fragments.iter().position(|f| f.functions.iter().any(|n| n == name));
+        let mut needed = vec![false; self.fragments.len()];
+        let mut pending: Vec<usize> = calls.iter().chain(&["Sys.init"]).filter_map(|c| defining(c)).collect();
+
+        while let Some(i) = pending.pop() {
+            if std::mem::replace(&mut needed[i], true) {
+                continue;
+            }
+            let text = &self.fragments[i].fragment.text;
+            pending.extend(
+                text.lines()
+                    .filter_map(|line| line.trim().strip_prefix('@'))
+                    .filter_map(defining)
+                    .filter(|j| !needed[*j]),
+            );
+        }
+
+        self.fragments
+            .iter()
+            .zip(needed)
+            .filter(|(_, needed)| *needed)
+            .map(|(f, _)| &f.fragment)
+            .collect()
+    }
+}
+
+/// The functions listed in a `.sym` file written by `--emit-sym`.
+pub fn symbol_file_functions(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|symbol| *symbol != "Bootstrap" && !symbol.ends_with("PARSE_ERROR_TRAP"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Links translated `program` against `library`, taking every fragment or,
+/// with `gc`, only those `calls` can reach. The bootstrap comes from
+/// whichever side defines `Sys.init`; both defining it is an error.
+///
+/// ```
+/// use hack_vmtranslator::link::{link_with_library, symbol_file_functions, Fragment, Library, LibraryFragment};
+///
+/// let fragment = |name: &str, text: &str, sym: &str| LibraryFragment {
+///     fragment: Fragment { name: name.to_string(), text: text.to_string() },
+///     functions: symbol_file_functions(sym),
+/// };
+/// let library = Library {
+///     fragments: vec![
+///         fragment("Math.asm", "(Math.multiply)\n@Memory.peek\n0;JMP\n", "Math.multiply 0\n"),
+///         fragment("Memory.asm", "(Memory.peek)\n0;JMP\n", "Memory.peek 0\n"),
+///         fragment("Screen.asm", "(Screen.clear)\n0;JMP\n", "Screen.clear 0\n"),
+///     ],
+/// };
+/// let program = "(Main.main)\n@Math.multiply\n0;JMP".to_string();
+///
+/// let all = link_with_library(program.clone(), &library, &["Math.multiply"], false).unwrap();
+/// assert!(all.contains("(Screen.clear)"));
+///
+/// let needed = link_with_library(program.clone(), &library, &["Math.multiply"], true).unwrap();
+/// assert!(needed.contains("(Memory.peek)"));
+/// assert!(!needed.contains("(Screen.clear)"));
+///
+/// let sys = Library { fragments: vec![fragment("Sys.asm", "(Sys.init)\n0;JMP\n", "Sys.init 0\n")] };
+/// assert!(link_with_library("(Sys.init)\n0;JMP".to_string(), &sys, &[], false).is_err());
+/// ```
+pub fn link_with_library(
+    program: String,
+    library: &Library,
+    calls: &[&str],
+    gc: bool,
+) -> Result<String, Vec<String>> {
+    let fragments: Vec<&Fragment> = if gc {
+        library.reachable(calls)
+    } else {
+        library.fragments.iter().map(|f| &f.fragment).collect()
+    };
+    let program_has_sys_init = label_definitions(&program).any(|l| l == "Sys.init");
+    if program_has_sys_init && library.defines("Sys.init") {
+        return Err(vec!["Sys.init is defined by both the program and the library".to_string()]);
+    }
+
+    let fragments: Vec<Fragment> = fragments
+        .into_iter()
+        .map(|f| Fragment {
+            name: f.name.clone(),
+            text: f.text.clone(),
+        })
+        .collect();
+    if program_has_sys_init || library.defines("Sys.init") {
+        let mut all = vec![Fragment {
+            name: "<translated code>".to_string(),
+            text: program,
+        }];
+        all.extend(fragments);
+        link(all)
+    } else {
+        append_modules(program, fragments)
+    }
+}
@@ -0,0 +1,756 @@
+use crate::callgraph::function_bodies;
+use crate::diagnostic::Diagnostic;
+use crate::asm::{self, BootstrapMode, BootstrapOptions, CodegenOptions};
+use crate::filter;
+use crate::vm::{cfg, file_base, raw_asm_lines, Command, Segment, Source, SourceCommand};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Lints that only run when asked for with `-W <name>`.
+pub const OPT_IN: [&str; 3] = ["argument-write", "static-gaps", "temp-across-call"];
+
+/// Everything translating `sources` reports before writing any code, in
+/// source order: lines that don't parse, extensions used without
+/// `extensions`, the lints that are on by default, and commands the code
+/// generator refuses.
+///
+/// The cases in `tests/conformance` pin down what this accepts, rejects
+/// and warns about. Each `<case>.vm` has a `<case>.expected` beside it
+/// listing one diagnostic per line as `<severity> <line>: <message>`, or
+/// `ok` for none; a first line of `// flags: --extensions` turns the
+/// extensions on. Adding a case is adding those two files.
+///
+/// ```
+/// use hack_vmtranslator::lint::analyze;
+/// use hack_vmtranslator::vm::Source;
+/// use std::fs;
+/// use std::path::Path;
+///
+/// let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+/// let mut cases: Vec<_> = fs::read_dir(&dir)
+///     .unwrap()
+///     .map(|entry| entry.unwrap().path())
+///     .filter(|path| path.extension().is_some_and(|e| e == "vm"))
+///     .collect();
+/// cases.sort();
+/// assert!(cases.len() >= 60);
+///
+/// let mut failures = Vec::new();
+/// for case in &cases {
+///     let text = fs::read_to_string(case).unwrap();
+///     let extensions = text.lines().next() == Some("// flags: --extensions");
+///     let name = case.file_name().unwrap();
+///     let diagnostics = analyze(&[Source::new(name, text)], extensions);
+///     let actual: Vec<String> = diagnostics
+///         .iter()
+///         .map(|d| format!("{} {}: {}", d.severity, d.line.map_or(0, |line| line + 1), d.message))
+///         .collect();
+///     let actual = if actual.is_empty() { "ok".to_string() } else { actual.join("\n") };
+///     let expected = fs::read_to_string(case.with_extension("expected")).unwrap_or_default();
+///     if actual != expected.trim_end() {
+///         failures.push(format!("{}:\n  expected: {}\n  actual:   {}", case.display(), expected.trim_end(), actual));
+///     }
+/// }
+/// assert!(failures.is_empty(), "{} of {} cases differ:\n{}", failures.len(), cases.len(), failures.join("\n"));
+/// ```
+pub fn analyze(sources: &[Source], extensions: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut commands = Vec::new();
+    for result in sources.iter().flat_map(Source::parse) {
+        match result {
+            Ok(sc) => commands.push(sc),
+            Err(e) => diagnostics.push(Diagnostic::from(&e)),
+        }
+    }
+    diagnostics.extend(check_extensions(&commands, extensions));
+    diagnostics.extend(check_spin_loops(&commands));
+    diagnostics.extend(check_code(&commands, &CodegenOptions::default()));
+    diagnostics.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    diagnostics
+}
+
+/// Commands the code generator refuses with `options`, each at its line:
+/// `pop constant`, a `pointer` or `temp` index past the words the memory
+/// map gives it, or a label named like the ones the translator makes up.
+///
+/// ```
+/// use hack_vmtranslator::asm::CodegenOptions;
+/// use hack_vmtranslator::lint::check_code;
+/// use hack_vmtranslator::memory::MemoryMap;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "push constant 1\npop pointer 5\npush temp 7\npop temp 8\n")];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let check = |options: &CodegenOptions| -> Vec<(Option<usize>, String)> {
+///     check_code(&commands, options).into_iter().map(|d| (d.line, d.message)).collect()
+/// };
+///
+/// let standard = CodegenOptions::default();
+/// assert_eq!(
+///     check(&standard),
+///     [(Some(1), "pointer index must be 0-1, got 5".to_string()), (Some(3), "temp index must be 0-7, got 8".to_string())]
+/// );
+///
+/// // Eight pointers, and temp moved up past them.
+/// let mut wide = CodegenOptions::default();
+/// wide.memory = MemoryMap { pointer_size: 8, temp_base: 16, static_base: 25, ..MemoryMap::default() };
+/// assert_eq!(check(&wide), [(Some(3), "temp index must be 0-7, got 8".to_string())]);
+/// wide.memory.temp_size = 9;
+/// assert!(check(&wide).is_empty());
+/// wide.memory.temp_size = 0;
+/// assert_eq!(check(&wide)[0].1, "The memory map has no temp segment");
+///
+/// let sources = [Source::new("Main.vm", "function Main.f 0\ncall Main.f 0\nlabel ret.0\ngoto ret.0\n")];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let lines: Vec<_> = check_code(&commands, &standard).into_iter().map(|d| d.line).collect();
+/// assert_eq!(lines, [Some(2), Some(3)]);
+/// ```
+pub fn check_code(commands: &[SourceCommand], options: &CodegenOptions) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for sc in commands {
+        if matches!(sc.command(), Command::Include(_)) {
+            continue;
+        }
+        if let Err(message) = asm::generate_code(vec![sc.clone()], options) {
+            diagnostics.push(Diagnostic::error("Error", message).at(sc));
+        }
+    }
+    diagnostics
+}
+
+/// Rejects input files whose names aren't UTF-8, which can't be turned
+/// into symbols, and warns about names that had to be changed to make
+/// `file_base`: statics named after something other than the file are
+/// hard to find in the output. Paths are quoted as `Path::display` shows
+/// them.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::Severity;
+/// use hack_vmtranslator::lint::check_file_names;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+/// use std::path::PathBuf;
+///
+/// let files = [PathBuf::from("Main.vm"), PathBuf::from("dir/Противник.vm")];
+/// let diagnostics = check_file_names(&files);
+/// assert_eq!(diagnostics.len(), 1);
+/// assert_eq!(diagnostics[0].severity, Severity::Warning);
+/// assert_eq!(diagnostics[0].file.as_deref(), Some("dir/Противник.vm"));
+/// assert_eq!(
+///     diagnostics[0].message,
+///     "statics are named _________.<index>, since 'Противник' isn't a valid symbol"
+/// );
+///
+/// let sources = [Source::new(&files[1], "push constant 1\npop static 0\n")];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// assert_eq!(commands[1].file_base(), "_________");
+///
+/// #[cfg(unix)]
+/// {
+///     use std::ffi::OsStr;
+///     use std::os::unix::ffi::OsStrExt;
+///
+///     let dir = std::env::temp_dir().join(format!("hack_vmtranslator-names-{}", std::process::id()));
+///     std::fs::create_dir_all(&dir).unwrap();
+///     std::fs::write(dir.join(OsStr::from_bytes(b"Ma\xffin.vm")), "push constant 1\n").unwrap();
+///     let files = hack_vmtranslator::vm::discover_sources(&dir).unwrap();
+///     std::fs::remove_dir_all(&dir).unwrap();
+///
+///     let diagnostics = check_file_names(&files);
+///     assert_eq!(diagnostics[0].severity, Severity::Error);
+///     assert!(diagnostics[0].file.as_ref().unwrap().ends_with("Ma\u{FFFD}in.vm"));
+///     assert_eq!(diagnostics[0].message, "the file name isn't valid UTF-8; rename it");
+/// }
+/// ```
+pub fn check_file_names(files: &[PathBuf]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for file in files {
+        let Some(stem) = file.file_stem() else { continue };
+        let diagnostic = match stem.to_str() {
+            None => Diagnostic::error("Error", "the file name isn't valid UTF-8; rename it"),
+            Some(stem) if file_base(file) != stem => Diagnostic::warning(
+                "Warning",
+                format!("statics are named {}.<index>, since '{stem}' isn't a valid symbol", file_base(file)),
+            ),
+            Some(_) => continue,
+        };
+        diagnostics.push(diagnostic.in_file(file.display().to_string()));
+    }
+    diagnostics
+}
+
+/// Rejects extension commands unless `extensions` is set, warns about
+/// `reg` indexes that alias registers the generated code relies on, and
+/// checks the instructions in `asm` blocks.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+/// use hack_vmtranslator::lint::check_extensions;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new("Main.vm", "push constant 1\nasm { @SCREEN / M=-1 }\npop temp 0\n"),
+///     Source::new("Bad.vm", "asm { (LOOP) / 0;JMP }\nasm { M=Q }\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///
+/// assert_eq!(check_extensions(&commands[..3], false).len(), 1);
+/// assert!(check_extensions(&commands[..3], true).is_empty());
+/// let errors = check_extensions(&commands[3..], true);
+/// assert_eq!(errors[0].message, "asm blocks can't define labels, found (LOOP)");
+/// assert_eq!(errors.len(), 2);
+///
+/// let asm = generate_code(commands.into_iter().take(3).collect(), &CodegenOptions::default()).unwrap();
+/// assert!(asm[0].ends_with("M=M+1"));
+/// assert!(asm[1].ends_with("@SCREEN\nM=-1"));
+/// assert!(asm[2].contains("@5"));
+/// ```
+pub fn check_extensions(commands: &[SourceCommand], extensions: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for sc in commands {
+        let index = match sc.command() {
+            Command::Push { segment: Segment::Reg, index }
+            | Command::Pop { segment: Segment::Reg, index } => *index,
+            Command::RawAsm(body) => {
+                if !extensions {
+                    diagnostics.push(
+                        Diagnostic::error("Parse error", "Inline asm is an extension; enable it with --extensions").at(sc),
+                    );
+                } else {
+                    for message in check_raw_asm(body) {
+                        diagnostics.push(Diagnostic::error("Parse error", message).at(sc));
+                    }
+                }
+                continue;
+            }
+            Command::Include(_) if !extensions => {
+                diagnostics.push(
+                    Diagnostic::error("Parse error", "include is an extension; enable it with --extensions").at(sc),
+                );
+                continue;
+            }
+            Command::DebugPrint if !extensions => {
+                diagnostics.push(
+                    Diagnostic::error("Parse error", "debug.print is an extension; enable it with --extensions").at(sc),
+                );
+                continue;
+            }
+            _ => continue,
+        };
+
+        if !extensions {
+            diagnostics.push(
+                Diagnostic::error("Parse error", "The reg segment is an extension; enable it with --extensions")
+                    .at(sc),
+            );
+        } else if let Some(role) = register_role(index) {
+            diagnostics.push(Diagnostic::warning("Warning", format!("R{index} is {role}")).at(sc));
+        }
+    }
+
+    diagnostics
+}
+
+/// What the translated program already uses register `index` for.
+fn register_role(index: u16) -> Option<&'static str> {
+    match index {
+        0 => Some("the stack pointer SP"),
+        1 => Some("the LCL segment pointer"),
+        2 => Some("the ARG segment pointer"),
+        3 => Some("the THIS segment pointer"),
+        4 => Some("the THAT segment pointer"),
+        13..=15 => Some("a scratch register the translator may clobber"),
+        _ => None,
+    }
+}
+
+/// What is wrong with the instructions in an `asm` block. Labels are
+/// refused: each copy of the block would define them again, outside the
+/// translator's naming scheme.
+fn check_raw_asm(body: &str) -> Vec<String> {
+    let lines: Vec<&str> = raw_asm_lines(body).collect();
+    if let Some(label) = lines.iter().find(|line| line.starts_with('(')) {
+        return vec![format!("asm blocks can't define labels, found {label}")];
+    }
+    #[cfg(feature = "assembler")]
+    let errors: Vec<String> = match crate::assembler::assemble(&lines.join("\n")) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.into_iter().map(|e| format!("Invalid instruction in asm block: {} ({})", e.source, e.message)).collect(),
+    };
+    // Without the assembler only the C-instructions can be checked.
+    #[cfg(not(feature = "assembler"))]
+    let errors: Vec<String> = lines
+        .iter()
+        .filter(|line| !line.starts_with('@'))
+        .filter_map(|line| asm::c_instruction(line).err().map(|e| format!("Invalid instruction in asm block: {line} ({e})")))
+        .collect();
+    errors
+}
+
+/// Notes each `label L` followed directly by a jump back to `L`: the
+/// usual end-of-program spin, which is fine, but also what a misplaced
+/// label looks like. Covers `goto L` and an `if-goto L` on a constant
+/// that is always true.
+pub fn check_spin_loops(commands: &[SourceCommand]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for (i, sc) in commands.iter().enumerate() {
+        let Command::Label(label) = sc.command() else { continue };
+        let rest = &commands[i + 1..];
+        let next: Vec<&Command> = rest.iter().take(2).map(SourceCommand::command).collect();
+        let jump = match next[..] {
+            [Command::Goto(target), ..] if target == label => &rest[0],
+            [Command::Push { segment: Segment::Constant, index }, Command::IfGoto(target), ..]
+                if target == label && *index != 0 =>
+            {
+                &rest[1]
+            }
+            _ => continue,
+        };
+        diagnostics.push(
+            Diagnostic::note("Note", format!("Infinite loop at label {label}; fine if this is where the program stops"))
+                .at(jump),
+        );
+    }
+
+    diagnostics
+}
+
+/// Checks that the bootstrap and the entry function agree. An entry
+/// named with `--entry` must be defined unless `--entry-extern` says it
+/// comes from elsewhere; even then it gets a warning, as a jump to an
+/// undefined symbol assembles fine and only fails at run time, unless
+/// `--extern` lists it too. And with the bootstrap left out by
+/// `--symbol-prefix` or `--no-bootstrap`, a `directory` that defines the
+/// entry gets a warning, as nothing will ever call it. `--bootstrap`
+/// needs the entry defined even when it's `Sys.init`.
+///
+/// ```
+/// use hack_vmtranslator::asm::{BootstrapMode, BootstrapOptions, CodegenOptions};
+/// use hack_vmtranslator::lint::check_bootstrap;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "function Main.main 0\nreturn\n")];
+/// let fragment: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let sources = [Source::new("Sys.vm", "function Sys.init 0\nlabel END\ngoto END\n")];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let options = |entry: &str, extern_entry: bool, symbol_prefix: Option<&str>| CodegenOptions {
+///     bootstrap: BootstrapOptions { entry: entry.to_string(), extern_entry, ..BootstrapOptions::default() },
+///     symbol_prefix: symbol_prefix.map(str::to_string),
+///     ..CodegenOptions::default()
+/// };
+/// let check = |commands, options, directory| -> Vec<_> {
+///     check_bootstrap(commands, &options, directory).into_iter().map(|d| (d.severity, d.message)).collect()
+/// };
+///
+/// // A fragment is fine without a bootstrap, a program is fine with one.
+/// assert!(check(&fragment, options("Sys.init", false, None), true).is_empty());
+/// assert!(check(&program, options("Sys.init", false, None), true).is_empty());
+///
+/// // A bootstrap into an entry nothing defines.
+/// let (severity, message) = &check(&fragment, options("Main.start", false, None), false)[0];
+/// assert_eq!(severity.to_string(), "error");
+/// assert_eq!(message, "--entry Main.start isn't defined; define it, or pass --entry-extern if a linked library does");
+/// let (severity, message) = &check(&fragment, options("Main.start", true, None), false)[0];
+/// assert_eq!(severity.to_string(), "warning");
+/// assert_eq!(message, "The bootstrap calls Main.start, which isn't defined here, so it must be linked in; list it with --extern to say so");
+/// let mut declared = options("Main.start", true, None);
+/// declared.externs.push("Main.*".to_string());
+/// assert!(check(&fragment, declared, false).is_empty());
+///
+/// // No bootstrap for a program that has an entry.
+/// let warnings = check(&program, options("Sys.init", false, Some("lib_")), true);
+/// assert_eq!(warnings[0].1, "Sys.init is defined, but --symbol-prefix leaves out the bootstrap, so nothing calls it");
+/// assert!(check(&program, options("Sys.init", false, Some("lib_")), false).is_empty());
+///
+/// // Forced on, the entry has to be there; forced off, defining it is
+/// // usually a mistake.
+/// let mut forced = options("Sys.init", false, None);
+/// forced.bootstrap.mode = BootstrapMode::Always;
+/// assert_eq!(
+///     check(&fragment, forced.clone(), true)[0].1,
+///     "--bootstrap calls Sys.init, but no source defines it; define it, or pass --entry-extern if a linked library does"
+/// );
+/// assert!(check(&program, forced, true).is_empty());
+/// let mut never = options("Sys.init", false, None);
+/// never.bootstrap.mode = BootstrapMode::Never;
+/// let (severity, message) = &check(&program, never.clone(), true)[0];
+/// assert_eq!(severity.to_string(), "warning");
+/// assert_eq!(message, "Sys.init is defined, but --no-bootstrap leaves out the bootstrap, so nothing calls it");
+/// assert!(check(&fragment, never.clone(), true).is_empty());
+/// // A single file is a fragment on purpose.
+/// assert!(check(&program, never, false).is_empty());
+/// ```
+pub fn check_bootstrap(commands: &[SourceCommand], options: &CodegenOptions, directory: bool) -> Vec<Diagnostic> {
+    let entry = options.bootstrap.entry.as_str();
+    let definition = commands
+        .iter()
+        .find(|sc| matches!(sc.command(), Command::Function { name, .. } if *name == entry));
+    match definition {
+        Some(sc) if options.bootstrap.mode == BootstrapMode::Never && directory => vec![Diagnostic::warning(
+            "Warning",
+            format!("{entry} is defined, but --no-bootstrap leaves out the bootstrap, so nothing calls it"),
+        )
+        .at(sc)],
+        Some(sc) if options.symbol_prefix.is_some() && directory => vec![Diagnostic::warning(
+            "Warning",
+            format!("{entry} is defined, but --symbol-prefix leaves out the bootstrap, so nothing calls it"),
+        )
+        .at(sc)],
+        Some(_) => Vec::new(),
+        None if options.bootstrap.mode == BootstrapMode::Never => Vec::new(),
+        None if options.bootstrap.mode == BootstrapMode::Always && !options.bootstrap.extern_entry => vec![Diagnostic::error(
+            "Error",
+            format!("--bootstrap calls {entry}, but no source defines it; define it, or pass --entry-extern if a linked library does"),
+        )],
+        // `Sys.init` may be missing: that makes a fragment.
+        None if entry == BootstrapOptions::default().entry && !options.bootstrap.extern_entry => Vec::new(),
+        None if !options.bootstrap.extern_entry => vec![Diagnostic::error(
+            "Error",
+            format!("--entry {entry} isn't defined; define it, or pass --entry-extern if a linked library does"),
+        )],
+        None if options.symbol_prefix.is_some() || options.externs.iter().any(|pattern| filter::matches(pattern, entry)) => Vec::new(),
+        None => vec![Diagnostic::warning(
+            "Warning",
+            format!("The bootstrap calls {entry}, which isn't defined here, so it must be linked in; list it with --extern to say so"),
+        )],
+    }
+}
+
+/// Warns about the first call to each function nothing defines, when the
+/// program gets a bootstrap and so is meant to be whole: the assembler
+/// takes the missing label for a variable, and the call jumps to its RAM
+/// address instead. Functions matching `--extern`, and those `elsewhere`
+/// says a hand-written module or library defines, are fine.
+///
+/// ```
+/// use hack_vmtranslator::asm::CodegenOptions;
+/// use hack_vmtranslator::lint::check_calls;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let text = "function Sys.init 0\ncall Main.main 0\ncall Output.printInt 1\ncall Output.printInt 1\n\
+///             label END\ngoto END\nfunction Main.main 0\ncall Blit.copy 0\nreturn\n";
+/// let sources = [Source::new("Sys.vm", text)];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let check = |options: &CodegenOptions| -> Vec<(Option<usize>, String)> {
+///     check_calls(&commands, options, |name| name == "Blit.copy").into_iter().map(|d| (d.line, d.message)).collect()
+/// };
+///
+/// let mut options = CodegenOptions::default();
+/// assert_eq!(
+///     check(&options),
+///     [(Some(2), "Output.printInt is called, but nothing defines it; define it, or list it with --extern if a linked library does".to_string())]
+/// );
+/// options.externs.push("Output.*".to_string());
+/// assert!(check(&options).is_empty());
+///
+/// // A fragment calls out of itself on purpose.
+/// let options = CodegenOptions { symbol_prefix: Some("lib_".to_string()), ..CodegenOptions::default() };
+/// assert!(check(&options).is_empty());
+/// ```
+pub fn check_calls(commands: &[SourceCommand], options: &CodegenOptions, elsewhere: impl Fn(&str) -> bool) -> Vec<Diagnostic> {
+    if !asm::should_bootstrap(commands, options) {
+        return Vec::new();
+    }
+    let defined: BTreeSet<&str> = commands
+        .iter()
+        .filter_map(|sc| match sc.command() {
+            Command::Function { name, .. } => Some(*name),
+            _ => None,
+        })
+        .collect();
+    let mut reported = BTreeSet::new();
+    let mut diagnostics = Vec::new();
+    for sc in commands {
+        let Command::Call { name, .. } = sc.command() else {
+            continue;
+        };
+        if defined.contains(name)
+            || elsewhere(name)
+            || options.externs.iter().any(|pattern| filter::matches(pattern, name))
+            || !reported.insert(*name)
+        {
+            continue;
+        }
+        diagnostics.push(
+            Diagnostic::warning(
+                "Warning",
+                format!("{name} is called, but nothing defines it; define it, or list it with --extern if a linked library does"),
+            )
+            .at(sc),
+        );
+    }
+    diagnostics
+}
+
+/// `-W argument-write`: warns about `pop argument i`, which overwrites the
+/// caller's stack. When the function reads the same argument back later
+/// it is probably updating a parameter in place, so that gets a note
+/// instead.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::Severity;
+/// use hack_vmtranslator::lint::check_argument_writes;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let text = "function Main.f 0\npush constant 1\npop argument 0\n\
+///             push constant 2\npop argument 1\npush argument 1\nreturn\n\
+///             function Main.g 0\npush argument 0\nreturn\n";
+/// let sources = [Source::new("Main.vm", text)];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let diagnostics = check_argument_writes(&commands);
+///
+/// assert_eq!(diagnostics.len(), 2);
+/// assert_eq!(diagnostics[0].severity, Severity::Warning);
+/// assert_eq!(diagnostics[1].severity, Severity::Note);
+/// ```
+pub fn check_argument_writes(commands: &[SourceCommand]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let bodies: Vec<_> = function_bodies(commands).collect();
+
+    for (i, (function, sc)) in bodies.iter().enumerate() {
+        let Command::Pop { segment: Segment::Argument, index } = sc.command() else { continue };
+        let read_back = bodies[i + 1..]
+            .iter()
+            .take_while(|(f, _)| f == function)
+            .any(|(_, later)| {
+                matches!(later.command(), Command::Push { segment: Segment::Argument, index: i } if i == index)
+            });
+
+        diagnostics.push(if read_back {
+            Diagnostic::note(
+                "Note",
+                format!("pop argument {index} is read back later; fine if it updates the parameter in place"),
+            )
+            .at(sc)
+        } else {
+            Diagnostic::warning(
+                "Warning",
+                format!("pop argument {index} overwrites the caller's stack [-W argument-write]"),
+            )
+            .at(sc)
+        });
+    }
+
+    diagnostics
+}
+
+/// `-W temp-across-call`: warns about `push temp i` reading a value
+/// popped into `temp i` before a `call`, which the callee is free to
+/// overwrite. The warning names the write, the call and the read. Paths
+/// through a function's control flow are merged, so a read can be
+/// flagged for a write and a call that never happen together; in
+/// straight-line code the three lines are exact.
+///
+/// ```
+/// use hack_vmtranslator::lint::check_temps_across_calls;
+/// use hack_vmtranslator::project::parse;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+/// use std::path::Path;
+///
+/// let check = |text: &str| {
+///     let sources = [Source::new("Main.vm", text)];
+///     let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     check_temps_across_calls(&commands).into_iter().map(|d| (d.line, d.message)).collect::<Vec<_>>()
+/// };
+///
+/// let flagged = "function Main.f 0\npush argument 0\npop temp 0\ncall Math.abs 0\npop temp 1\npush temp 0\nreturn\n";
+/// assert_eq!(
+///     check(flagged),
+///     [(Some(5), "temp 0 is read here, but was set at line 3 before the call at line 4, which may change it \
+///                 [-W temp-across-call]".to_string())]
+/// );
+///
+/// // Set again after the call, or only ever set after it: nothing to warn about.
+/// assert!(check("function Main.f 0\npop temp 0\ncall Math.abs 0\npop temp 0\npush temp 0\nreturn\n").is_empty());
+/// assert!(check("function Main.f 0\ncall Math.abs 0\npop temp 0\npush temp 0\nreturn\n").is_empty());
+///
+/// // A loop carries the value around to the read at its top.
+/// let looped = "function Main.f 0\nlabel LOOP\npush temp 2\npop temp 2\ncall Main.g 0\ngoto LOOP\n";
+/// assert_eq!(check(looped)[0].0, Some(2));
+///
+/// // Like every opt-in lint, it is off unless asked for.
+/// assert!(parse(Path::new("hackvm.toml"), "[lints]\ntemp-across-call = \"allow\"\n").unwrap().is_empty());
+/// ```
+pub fn check_temps_across_calls(commands: &[SourceCommand]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for graph in cfg::functions(commands) {
+        // What may hold at a point: `temp index` was set by the command at
+        // `set`, and then the command at `call`, if any, was a call.
+        type Fact = (u16, usize, Option<usize>);
+        let transfer = |facts: &mut BTreeSet<Fact>, i: usize, mut read: Option<&mut BTreeSet<(usize, Fact)>>| {
+            match graph.commands[i].command() {
+                Command::Pop { segment: Segment::Temp, index } => {
+                    facts.retain(|(slot, _, _)| slot != index);
+                    facts.insert((*index, i, None));
+                }
+                Command::Call { .. } => {
+                    let fresh: Vec<Fact> = facts.iter().filter(|(_, _, call)| call.is_none()).copied().collect();
+                    for (slot, set, _) in fresh {
+                        facts.remove(&(slot, set, None));
+                        facts.insert((slot, set, Some(i)));
+                    }
+                }
+                Command::Push { segment: Segment::Temp, index } => {
+                    if let Some(reads) = read.as_mut() {
+                        reads.extend(facts.iter().filter(|(slot, _, call)| slot == index && call.is_some()).map(|f| (i, *f)));
+                    }
+                }
+                _ => (),
+            }
+        };
+
+        let mut entry: Vec<BTreeSet<Fact>> = vec![BTreeSet::new(); graph.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (b, block) in graph.blocks.iter().enumerate() {
+                let mut facts = entry[b].clone();
+                for i in block.start..block.end {
+                    transfer(&mut facts, i, None);
+                }
+                for (successor, _) in &block.edges {
+                    let before = entry[*successor].len();
+                    entry[*successor].extend(facts.iter().copied());
+                    changed |= entry[*successor].len() != before;
+                }
+            }
+        }
+
+        let mut reads = BTreeSet::new();
+        for (b, block) in graph.blocks.iter().enumerate() {
+            let mut facts = entry[b].clone();
+            for i in block.start..block.end {
+                transfer(&mut facts, i, Some(&mut reads));
+            }
+        }
+        let line = |i: usize| graph.commands[i].line() + 1;
+        let mut reported = BTreeSet::new();
+        for (read, (index, set, call)) in reads {
+            // The earliest write and call are enough to go on.
+            if !reported.insert(read) {
+                continue;
+            }
+            diagnostics.push(
+                Diagnostic::warning(
+                    "Warning",
+                    format!(
+                        "temp {index} is read here, but was set at line {} before the call at line {}, which may change it \
+                         [-W temp-across-call]",
+                        line(set),
+                        line(call.unwrap_or_default())
+                    ),
+                )
+                .at(graph.commands[read]),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// The static indices one file uses.
+#[derive(Debug, Clone)]
+pub struct StaticUse {
+    pub file_base: String,
+    pub path: String,
+    pub indices: BTreeSet<u16>,
+}
+
+impl StaticUse {
+    /// Indices between 0 and the highest used that nothing uses.
+    pub fn gaps(&self) -> Vec<u16> {
+        let last = self.indices.last().copied().unwrap_or(0);
+        (0..last).filter(|i| !self.indices.contains(i)).collect()
+    }
+}
+
+/// Collects the static indices used by each file, in source order.
+///
+/// ```
+/// use hack_vmtranslator::lint::{check_static_gaps, static_usage};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new("A.vm", "push static 0\npop static 1\n"),
+///     Source::new("B.vm", "push static 0\npush static 3\n"),
+///     Source::new("C.vm", "push static 2\npush static 3\n"),
+/// ];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let usage = static_usage(&commands);
+///
+/// assert!(usage[0].gaps().is_empty());
+/// assert_eq!(usage[1].gaps(), vec![1, 2]);
+/// assert_eq!(usage[2].gaps(), vec![0, 1]);
+/// // A is contiguous from 0; B has a gap; C starts above 0.
+/// assert_eq!(check_static_gaps(&usage).len(), 2);
+/// ```
+pub fn static_usage(commands: &[SourceCommand]) -> Vec<StaticUse> {
+    let mut usage: Vec<StaticUse> = Vec::new();
+
+    for sc in commands {
+        let index = match sc.command() {
+            Command::Push { segment: Segment::Static, index }
+            | Command::Pop { segment: Segment::Static, index } => *index,
+            _ => continue,
+        };
+        let position = match usage.iter().position(|u| u.file_base == sc.file_base()) {
+            Some(position) => position,
+            None => {
+                usage.push(StaticUse {
+                    file_base: sc.file_base().to_string(),
+                    path: sc.path().display().to_string(),
+                    indices: BTreeSet::new(),
+                });
+                usage.len() - 1
+            }
+        };
+        usage[position].indices.insert(index);
+    }
+
+    usage
+}
+
+/// `-W static-gaps`: warns about files whose static indices don't start at
+/// 0 or skip some, which usually means the compiler numbered them wrong.
+pub fn check_static_gaps(usage: &[StaticUse]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for file in usage {
+        let first = file.indices.first().copied().unwrap_or(0);
+        let gaps = file.gaps();
+        if first > 0 {
+            diagnostics.push(
+                Diagnostic::warning("Warning", format!("Static indices start at {first}, not 0 [-W static-gaps]"))
+                    .in_file(&file.path),
+            );
+        } else if !gaps.is_empty() {
+            diagnostics.push(
+                Diagnostic::warning(
+                    "Warning",
+                    format!("Static indices {} are never used [-W static-gaps]", join(&gaps)),
+                )
+                .in_file(&file.path),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// The text printed by `--static-report`.
+pub fn render_static_report(usage: &[StaticUse]) -> String {
+    let mut out = format!("{:<24} {:>5}  {}\n", "file", "count", "indices");
+    for file in usage {
+        let indices: Vec<u16> = file.indices.iter().copied().collect();
+        let gaps = file.gaps();
+        let gaps = if gaps.is_empty() { String::new() } else { format!("  (unused: {})", join(&gaps)) };
+        out.push_str(&format!("{:<24} {:>5}  {}{gaps}\n", file.file_base, indices.len(), join(&indices)));
+    }
+    out
+}
+
+fn join(indices: &[u16]) -> String {
+    indices.iter().map(u16::to_string).collect::<Vec<_>>().join(",")
+}
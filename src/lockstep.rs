@@ -0,0 +1,316 @@
+//! Running a program's VM commands in the interpreter and its translation
+//! on the CPU side by side, one command at a time, to find the first
+//! command whose code doesn't do what the command does. After each
+//! command the interpreter runs, the CPU runs through the ROM range the
+//! line table gives that command, and the two are compared: the stack and
+//! segment pointers, and every word either of them wrote.
+
+use crate::assembler::{assemble, Cpu, SymbolKind};
+use crate::debuginfo::DebugInfo;
+use crate::interp::{Config, Event, StepResult, Vm};
+use crate::vm::{Command, Segment, SourceCommand};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// How many instructions the CPU may run on the way to a command's code
+/// and through it, which is far more than the bootstrap and any one
+/// command's expansion take.
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+/// The generated code's scratch registers, R13-R15, which no command
+/// writes itself.
+const SCRATCH: std::ops::RangeInclusive<usize> = 13..=15;
+
+const POINTERS: [&str; 5] = ["SP", "LCL", "ARG", "THIS", "THAT"];
+
+/// A machine as far as it's compared after a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    /// SP, LCL, ARG, THIS and THAT.
+    pub pointers: [i16; 5],
+    /// The other words either machine wrote, at their addresses in this
+    /// machine, which differ for statics.
+    pub words: Vec<(usize, i16)>,
+}
+
+impl State {
+    /// Reads the words at `addresses` in the interpreter's RAM, which are
+    /// at `place(address)` in `ram`.
+    fn read(ram: &[i16], addresses: &BTreeSet<usize>, place: impl Fn(usize) -> usize) -> State {
+        State {
+            pointers: [ram[0], ram[1], ram[2], ram[3], ram[4]],
+            words: addresses.iter().map(|&address| (place(address), ram[place(address)])).collect(),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pointers: Vec<String> = POINTERS.iter().zip(self.pointers).map(|(name, value)| format!("{name}={value}")).collect();
+        write!(f, "{}", pointers.join(" "))?;
+        for (address, value) in &self.words {
+            write!(f, " RAM[{address}]={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The first command the two machines disagree on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Counts commands from 1, as the interpreter does.
+    pub step: u64,
+    /// Where the command is and what it is.
+    pub command: String,
+    /// What differs, or where the CPU went instead.
+    pub reason: String,
+    pub vm: State,
+    pub cpu: State,
+    /// The commands before it, which agreed, the latest last.
+    pub recent: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged at step {}, {}", self.step, self.command)?;
+        writeln!(f, "  {}", self.reason)?;
+        writeln!(f, "  interpreter: {}", self.vm)?;
+        writeln!(f, "  CPU:         {}", self.cpu)?;
+        if !self.recent.is_empty() {
+            writeln!(f, "after:")?;
+            for command in &self.recent {
+                writeln!(f, "  {command}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every command agreed; `halted` is false if `max_steps` ran out
+    /// first.
+    Agreed { steps: u64, halted: bool },
+    Diverged(Box<Divergence>),
+}
+
+/// What the interpreter's hook saw during a step.
+#[derive(Default)]
+struct Seen {
+    /// The file, line and text of the command.
+    command: Option<(String, usize, String)>,
+    /// The function it called, if it was a call.
+    called: Option<String>,
+    writes: BTreeSet<usize>,
+}
+
+/// Runs `program` in the interpreter and `asm`, its translation from
+/// address 0, on the CPU, in lockstep for up to `max_steps` commands,
+/// keeping the last `history` commands that agreed to report with a
+/// divergence. The CPU starts with the interpreter's RAM, before the
+/// bootstrap if there is one. `asm` is taken to have been translated
+/// without a symbol prefix, so that its statics can be found by name:
+/// the assembler puts them among the translator's own variables, where
+/// the interpreter puts them from the static base in the order they
+/// appear. Errors are assembly errors and the interpreter's own, and a
+/// call to a function the interpreter has built in, which the CPU has no
+/// code for.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+/// use hack_vmtranslator::interp::Config;
+/// use hack_vmtranslator::lockstep::{run, Outcome};
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [
+///     Source::new(
+///         "Main.vm",
+///         "function Main.fibonacci 0\npush argument 0\npush constant 2\nlt\nif-goto N_LT_2\ngoto N_GE_2\n\
+///          label N_LT_2\npush argument 0\nreturn\nlabel N_GE_2\npush argument 0\npush constant 2\nsub\n\
+///          call Main.fibonacci 1\npush argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\nadd\nreturn\n",
+///     ),
+///     Source::new("Sys.vm", "function Sys.init 0\npush constant 6\ncall Main.fibonacci 1\npop static 0\nlabel END\ngoto END\n"),
+/// ];
+/// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let asm = generate_code(program.clone(), &CodegenOptions::default()).unwrap().join("\n");
+/// assert!(matches!(run(&program, &asm, Config::default(), 100_000, 3), Ok(Outcome::Agreed { halted: true, .. })));
+///
+/// // A deliberate bug in one command's code: Main.vm's `add`, on line 18
+/// // counting from 0, subtracts. It goes unnoticed while the sums are
+/// // 1 + 0 and 0 + 1.
+/// let header = "// Main[18]: add\n";
+/// let at = asm.find(header).unwrap() + header.len();
+/// let broken = format!("{}{}", &asm[..at], asm[at..].replacen("D=D+M", "D=D-M", 1));
+/// let Ok(Outcome::Diverged(divergence)) = run(&program, &broken, Config::default(), 100_000, 3) else {
+///     panic!("no divergence");
+/// };
+/// assert_eq!(divergence.command, "Main.fibonacci Main.vm:18: add");
+/// assert_eq!(divergence.reason, "RAM[280] differs");
+/// assert_eq!((divergence.vm.words[0], divergence.cpu.words[0]), ((280, 2), (280, 0)));
+/// assert_eq!(
+///     divergence.recent,
+///     ["Main.fibonacci Main.vm:8: return", "Main.fibonacci Main.vm:18: add", "Main.fibonacci Main.vm:19: return"]
+/// );
+///
+/// // One that jumps the wrong way.
+/// let header = "// Main[4]: if-goto N_LT_2\n";
+/// let at = asm.find(header).unwrap() + header.len();
+/// let broken = format!("{}{}", &asm[..at], asm[at..].replacen("D;JNE", "D;JEQ", 1));
+/// let Ok(Outcome::Diverged(divergence)) = run(&program, &broken, Config::default(), 100_000, 3) else {
+///     panic!("no divergence");
+/// };
+/// assert_eq!(divergence.command, "Main.fibonacci Main.vm:5: goto N_GE_2");
+/// assert_eq!(divergence.reason, "the CPU went to Main[7] instead");
+/// ```
+pub fn run(program: &[SourceCommand], asm: &str, config: Config, max_steps: u64, history: usize) -> Result<Outcome, String> {
+    let rom = assemble(asm).map_err(|errors| errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))?;
+    let info = DebugInfo::from_asm(asm, 0);
+    let ranges: HashMap<(&str, usize), std::ops::Range<usize>> = info
+        .lines
+        .iter()
+        .map(|range| ((info.files[range.file].as_str(), range.line), range.start..range.end))
+        .collect();
+    let defined: HashSet<&str> = program
+        .iter()
+        .filter_map(|sc| match sc.command() {
+            Command::Function { name, .. } => Some(*name),
+            _ => None,
+        })
+        .collect();
+    let variables: HashMap<&str, usize> = rom
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Variable)
+        .map(|symbol| (symbol.name.as_str(), symbol.address as usize))
+        .collect();
+    // Each static's address in the interpreter, and on the CPU.
+    let mut statics: HashMap<usize, usize> = HashMap::new();
+    let mut names = HashSet::new();
+    for sc in program {
+        if let Command::Push { segment: Segment::Static, index } | Command::Pop { segment: Segment::Static, index } = sc.command() {
+            let name = format!("{}.{index}", sc.file_base());
+            if let Some(&address) = variables.get(name.as_str()) {
+                if names.insert(name) {
+                    statics.insert(config.memory.static_base as usize + names.len() - 1, address);
+                }
+            }
+        }
+    }
+    // The translator's own variables, which the interpreter has no use for.
+    let private: HashSet<usize> = variables.values().copied().filter(|address| !statics.values().any(|a| a == address)).collect();
+    let interpreter_address: HashMap<usize, usize> = statics.iter().map(|(&here, &there)| (there, here)).collect();
+    let cpu_address = |address: usize| statics.get(&address).copied().unwrap_or(address);
+
+    let mut vm = Vm::new(program, config);
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let hook = Arc::clone(&seen);
+    vm.on_event(move |event| {
+        let mut seen = hook.lock().unwrap();
+        match event {
+            Event::Step(sc) => {
+                seen.command = Some((sc.file_base().to_string(), sc.line(), sc.command().to_string()));
+                seen.called = match sc.command() {
+                    Command::Call { name, .. } => Some(name.to_string()),
+                    _ => None,
+                };
+            }
+            Event::Write { address, .. } => {
+                seen.writes.insert(*address);
+            }
+            _ => (),
+        }
+    });
+    let mut cpu = Cpu::new();
+    cpu.ram.copy_from_slice(vm.ram());
+    let mut recent = VecDeque::with_capacity(history + 1);
+
+    while vm.steps() < max_steps {
+        let location = vm.current_location();
+        let sp = vm.sp();
+        seen.lock().unwrap().writes.clear();
+        if vm.step().map_err(|e| format!("The interpreter stopped: {e}"))? == StepResult::Halted {
+            return Ok(Outcome::Agreed { steps: vm.steps(), halted: true });
+        }
+        let Some((file, line, text)) = seen.lock().unwrap().command.take() else {
+            continue;
+        };
+        let command = match location {
+            Some(location) => format!("{location}: {text}"),
+            None => text.clone(),
+        };
+        let (mut compared, called) = {
+            let mut seen = seen.lock().unwrap();
+            (std::mem::take(&mut seen.writes), seen.called.take())
+        };
+        // The return address is a command index here and a ROM address
+        // there.
+        let return_address = called.is_some().then_some(sp as usize);
+        if let Some(name) = called {
+            if !defined.contains(name.as_str()) {
+                return Err(format!("{command}: {name} is built into the interpreter, so the CPU has no code for it"));
+            }
+        }
+
+        let mut diverged = |reason: String, compared: &BTreeSet<usize>, cpu: &Cpu| {
+            Outcome::Diverged(Box::new(Divergence {
+                step: vm.steps(),
+                command: command.clone(),
+                reason,
+                vm: State::read(vm.ram(), compared, |address| address),
+                cpu: State::read(&cpu.ram, compared, cpu_address),
+                recent: recent.drain(..).collect(),
+            }))
+        };
+        // Labels and functions without locals have no code.
+        if let Some(range) = ranges.get(&(file.as_str(), line + 1)) {
+            let mut budget = MAX_INSTRUCTIONS;
+            // Up to the command's code: through the bootstrap, or nothing.
+            while !range.contains(&cpu.pc) {
+                if let Some((other, line)) = info.line_at(cpu.pc) {
+                    return Ok(diverged(format!("the CPU went to {other}[{}] instead", line - 1), &compared, &cpu));
+                }
+                if budget == 0 || !cpu.step(&rom) {
+                    return Ok(diverged("the CPU never reached the command's code".to_string(), &compared, &cpu));
+                }
+                budget -= 1;
+            }
+            // And through it, noting what it writes.
+            while range.contains(&cpu.pc) {
+                let word = rom.words[cpu.pc];
+                let address = cpu.a as u16 as usize & 0x7FFF;
+                if word & 0x8000 != 0 && word & 0x8 != 0 && !private.contains(&address) {
+                    compared.insert(interpreter_address.get(&address).copied().unwrap_or(address));
+                }
+                if budget == 0 || !cpu.step(&rom) {
+                    return Ok(diverged("the CPU didn't get through the command's code".to_string(), &compared, &cpu));
+                }
+                budget -= 1;
+            }
+        }
+        compared.retain(|address| *address >= POINTERS.len() && !SCRATCH.contains(address) && Some(*address) != return_address);
+        let (here, there) = (State::read(vm.ram(), &compared, |address| address), State::read(&cpu.ram, &compared, cpu_address));
+        let mut differ: Vec<String> = POINTERS
+            .iter()
+            .zip(here.pointers.iter().zip(there.pointers))
+            .filter(|(_, (a, b))| **a != *b)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        differ.extend(
+            here.words
+                .iter()
+                .zip(&there.words)
+                .filter(|((_, a), (_, b))| a != b)
+                .map(|((address, _), _)| format!("RAM[{address}]")),
+        );
+        if !differ.is_empty() {
+            let verb = if differ.len() == 1 { "differs" } else { "differ" };
+            return Ok(diverged(format!("{} {verb}", differ.join(" and ")), &compared, &cpu));
+        }
+        recent.push_back(command);
+        if recent.len() > history {
+            recent.pop_front();
+        }
+    }
+    Ok(Outcome::Agreed { steps: vm.steps(), halted: false })
+}
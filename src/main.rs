@@ -1,111 +1,2520 @@
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::fs;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-pub mod asm;
-pub mod vm;
+#[cfg(feature = "assembler")]
+use hack_vmtranslator::assembler;
+use hack_vmtranslator::diagnostic::{self, Diagnostic, ErrorFormat, Severity};
+use hack_vmtranslator::translator::Translator;
+use hack_vmtranslator::json::Json;
+use hack_vmtranslator::options::Options;
+use hack_vmtranslator::{
+    argfile, asm, bank, batch, callgraph, compat, completions, debuginfo, doc, doctor, filter, formatter, grade, interp, json, lift, link, lint, lockstep, memory, metrics, project, provenance, segments, sha256, stack, testgen, vm,
+};
 
-fn parse_args(args: &[String]) -> Result<String, &'static str> {
-    if args.len() > 1 {
-        Ok(args[1].clone())
-    } else {
-        Err("not enough arguments")
+mod report;
+
+const PARTIAL_OUTPUT_HEADER: &str = "\
+// ============================================================
+// PARTIAL TRANSLATION: only the functions selected with --only
+// and --skip were emitted. This is not a runnable program.
+// ============================================================
+";
+
+/// How many (segment, index) pairs `--segment-report` lists.
+const SEGMENT_REPORT_TOP: usize = 10;
+
+/// An artifact `--emit` can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Asm,
+    Hack,
+    Listing,
+    Ast,
+    Sym,
+    AsmResolved,
+    Doc,
+    Symbols,
+    Spans,
+}
+
+impl Emit {
+    /// Appended to the output stem to name the artifact by default.
+    fn extension(self) -> &'static str {
+        match self {
+            Emit::Asm => "asm",
+            Emit::Hack => "hack",
+            Emit::Listing => "lst",
+            Emit::Ast => "ast.json",
+            Emit::Sym => "sym",
+            Emit::AsmResolved => "resolved.asm",
+            Emit::Doc => "md",
+            Emit::Symbols => "symbols",
+            Emit::Spans => "spans.json",
+        }
+    }
+
+    /// As `--emit` names it.
+    fn name(self) -> &'static str {
+        match self {
+            Emit::Asm => "asm",
+            Emit::Hack => "hack",
+            Emit::Listing => "listing",
+            Emit::Ast => "ast",
+            Emit::Sym => "sym",
+            Emit::AsmResolved => "asm-resolved",
+            Emit::Doc => "doc",
+            Emit::Symbols => "symbols",
+            Emit::Spans => "spans",
+        }
+    }
+
+    fn needs_assembler(self) -> bool {
+        matches!(self, Emit::Hack | Emit::Listing | Emit::AsmResolved | Emit::Symbols)
     }
 }
 
-fn list_files(path: &Path) -> Vec<PathBuf> {
-    let mut files: Vec<PathBuf> = Vec::new();
+impl FromStr for Emit {
+    type Err = String;
 
-    if path.is_file() {
-        files.push(path.to_path_buf());
-    } else if path.is_dir() {
-        let paths = fs::read_dir(path).unwrap();
+    fn from_str(s: &str) -> Result<Emit, String> {
+        match s {
+            "asm" => Ok(Emit::Asm),
+            "hack" => Ok(Emit::Hack),
+            "listing" => Ok(Emit::Listing),
+            "ast" => Ok(Emit::Ast),
+            "sym" => Ok(Emit::Sym),
+            "asm-resolved" => Ok(Emit::AsmResolved),
+            "doc" => Ok(Emit::Doc),
+            "symbols" => Ok(Emit::Symbols),
+            "spans" => Ok(Emit::Spans),
+            _ => Err(format!(
+                "Unknown artifact: '{s}' (expected asm, asm-resolved, hack, listing, symbols, ast, sym, doc or spans)"
+            )),
+        }
+    }
+}
 
-        for path in paths {
-            let path = path.unwrap().path();
-            if let Some(ext) = path.extension() {
-                if ext == "vm" {
-                    files.push(path);
-                }
+/// Parses `--emit asm,hack=out.hack,listing`: artifact kinds, each with an
+/// optional path overriding the default name.
+fn parse_emit(list: &str) -> Result<Vec<(Emit, Option<PathBuf>)>, String> {
+    let mut emits: Vec<(Emit, Option<PathBuf>)> = Vec::new();
+    for item in list.split(',').map(str::trim) {
+        let (kind, path) = match item.split_once('=') {
+            Some((kind, path)) => (kind, Some(PathBuf::from(path))),
+            None => (item, None),
+        };
+        let kind = kind.parse::<Emit>()?;
+        if kind.needs_assembler() && !cfg!(feature = "assembler") {
+            return Err(format!(
+                "--emit {item} needs the assembler, which this build doesn't include"
+            ));
+        }
+        emits.retain(|(k, _)| *k != kind);
+        emits.push((kind, path));
+    }
+    Ok(emits)
+}
+
+#[derive(Debug, Default)]
+struct Config {
+    source: String,
+    annotate_addresses: Option<asm::AddressFormat>,
+    report_json: Option<PathBuf>,
+    keep_going: bool,
+    fail_fast: bool,
+    filter: filter::FunctionFilter,
+    function_order: callgraph::FunctionOrder,
+    /// From `--label-scheme`; `counters` when not given.
+    label_scheme: Option<asm::LabelScheme>,
+    compat: compat::Compat,
+    /// From `--entry`: the function the bootstrap calls instead of
+    /// `Sys.init`, which must be defined unless `--entry-extern`.
+    entry: Option<String>,
+    codegen: asm::CodegenOptions,
+    error_format: ErrorFormat,
+    timings: bool,
+    /// Leaves anything that varies between identical runs, such as
+    /// timings, out of the artifacts.
+    reproducible: bool,
+    stack_report: bool,
+    static_report: bool,
+    segment_report: bool,
+    /// Prints a single line for CI on stdout instead of the progress
+    /// lines.
+    summary: bool,
+    /// Opt-in lints enabled with `-W`.
+    lints: Vec<String>,
+    /// Opt-in lints whose warnings are errors, from `-D`.
+    denied: Vec<String>,
+    dump_symbols: bool,
+    call_graph: bool,
+    /// Allows the features that target non-standard Hack hardware, and
+    /// the `reg` segment.
+    extensions: bool,
+    /// With `extensions`, drops repeated includes of a file instead of
+    /// failing.
+    include_once: bool,
+    max_rom: Option<usize>,
+    /// From `--max-line-length`, if not `vm::MAX_LINE_LENGTH`.
+    max_line_length: Option<usize>,
+    /// Takes the `.asm` files in an input directory as hand-written modules.
+    allow_asm_modules: bool,
+    /// A directory of precompiled fragments to link against.
+    lib: Option<PathBuf>,
+    /// With `lib`, links only the fragments the program can reach.
+    gc_functions: bool,
+    banked: bool,
+    /// Assembly placed before the bootstrap.
+    prelude: Option<PathBuf>,
+    /// Assembly placed after everything else.
+    epilogue: Option<PathBuf>,
+    bank_call_shim: Option<PathBuf>,
+    /// From `-o`: where the assembly goes, or a directory to put it in.
+    /// Other artifacts are named after it.
+    output: Option<PathBuf>,
+    /// Artifacts to write; just the assembly when empty.
+    emit: Vec<(Emit, Option<PathBuf>)>,
+    /// Where `--emit-sym` writes the symbol file, on top of `emit`.
+    emit_sym: Option<PathBuf>,
+    /// Where `--emit-doc` writes the Markdown summary, on top of `emit`.
+    emit_doc: Option<PathBuf>,
+    /// Where `--emit-debug` writes the binary line table.
+    emit_debug: Option<PathBuf>,
+    /// Where `--emit-spans` writes each command's place in the assembly.
+    emit_spans: Option<PathBuf>,
+    /// A directory for `--emit-cfg` to write a `.dot` file per function.
+    emit_cfg: Option<PathBuf>,
+    /// Limits `emit_cfg` to one function.
+    emit_cfg_for: Option<String>,
+    /// Where `--emit-manifest` records the inputs, options and outputs.
+    emit_manifest: Option<PathBuf>,
+    /// Prints this configuration instead of translating.
+    print_config: bool,
+    /// The project file given with `--config`, instead of looking for one
+    /// beside the input.
+    project_file: Option<PathBuf>,
+    /// Each flag applied and where it came from, for `--print-config`.
+    origins: Vec<(String, String)>,
+    /// Each flag applied with its value, in order, for the build manifest;
+    /// see `UNRECORDED` for the ones left out.
+    flags: Vec<String>,
+    /// Set by `verify-manifest`: works out the artifacts without writing
+    /// them.
+    dry_run: bool,
+}
+
+/// Flags that don't change the artifacts, left out of build manifests so
+/// that verifying one writes no reports.
+const UNRECORDED: [&str; 6] = ["--emit-manifest", "--report-json", "--config", "--print-config", "--summary", "--timings"];
+
+impl Config {
+    /// Starts from a preset, `--debug` or `--release`; see `Options`.
+    fn preset(options: Options) -> Config {
+        let mut emit = Vec::new();
+        if options.listing || options.source_map {
+            emit.push((Emit::Asm, None));
+        }
+        if options.listing {
+            emit.push((Emit::Listing, None));
+        }
+        if options.source_map {
+            emit.push((Emit::Spans, None));
+        }
+        Config {
+            codegen: options.codegen,
+            reproducible: options.reproducible,
+            max_rom: options.max_rom,
+            annotate_addresses: options.annotate_addresses,
+            emit,
+            ..Config::default()
+        }
+    }
+}
+
+/// Builds the configuration from `layers` of flags, each named by where it
+/// came from, lowest precedence first: the project file, then
+/// `HACK_VM_FLAGS`, then the command line. A flag in a later layer
+/// overrides the same setting in an earlier one.
+fn parse_args(layers: &[(String, &[String])]) -> Result<Config, String> {
+    // Presets come first so that flags given alongside them win. The
+    // highest layer naming one chooses it.
+    let is_preset = |arg: &&String| *arg == "--debug" || *arg == "--release";
+    let presets: Vec<&String> = layers
+        .iter()
+        .rev()
+        .map(|(_, args)| args.iter().take_while(|arg| *arg != "--").filter(is_preset).collect::<Vec<_>>())
+        .find(|presets| !presets.is_empty())
+        .unwrap_or_default();
+    let mut config = match presets.as_slice() {
+        [] => Config::default(),
+        [preset] if *preset == "--debug" => Config::preset(Options::debug()),
+        [preset] if *preset == "--release" => Config::preset(Options::release()),
+        _ => return Err("Give at most one of --debug and --release".to_string()),
+    };
+    let mut source: Option<String> = None;
+
+    for (origin, args) in layers {
+        apply_flags(&mut config, &mut source, origin, args).map_err(|err| match origin.as_str() {
+            COMMAND_LINE => err,
+            _ => format!("{err} (from {origin})"),
+        })?;
+    }
+
+    validate(config, source)
+}
+
+/// Where flags typed on the command line come from, for `parse_args`.
+const COMMAND_LINE: &str = "command line";
+
+/// The environment variable holding flags for every translation, quoted
+/// like a response file, so a Makefile can set them once.
+const FLAGS_VARIABLE: &str = "HACK_VM_FLAGS";
+
+/// Applies `args`, the flags from one layer, on top of `config`.
+fn apply_flags(config: &mut Config, source: &mut Option<String>, origin: &str, args: &[String]) -> Result<(), String> {
+    let mut emit_given = false;
+    let mut args = args.iter();
+    loop {
+        let rest = args.as_slice();
+        let Some(arg) = args.next() else { break };
+        let is_flag = arg.starts_with('-') && arg != "-" && arg != "--";
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        if is_flag {
+            // Completion scripts are generated from this table, so a flag
+            // missing from it would never be completed.
+            if !completions::FLAGS.iter().any(|(flag, _)| *flag == name) {
+                return Err(format!("Unknown flag: '{arg}'"));
+            }
+            config.origins.push((arg.clone(), origin.to_string()));
+        }
+        if arg == "--" {
+            // Everything after `--` is a path, even if it starts with `-`.
+            for path in args.by_ref() {
+                *source = Some(positional(source.take(), path)?);
             }
+        } else if arg == "--report-json" {
+            config.report_json = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--keep-going" {
+            config.keep_going = true;
+        } else if arg == "--fail-fast" {
+            config.fail_fast = true;
+        } else if arg == "--only" {
+            config.filter.only(flag_value(arg, args.next())?);
+        } else if arg == "--skip" {
+            config.filter.skip(flag_value(arg, args.next())?);
+        } else if arg == "--safe-compare" {
+            config.codegen.safe_compare = true;
+        } else if arg == "--symbol-prefix" {
+            config.codegen.symbol_prefix = Some(flag_value(arg, args.next())?.clone());
+        } else if arg == "--extern" {
+            let patterns = flag_value(arg, args.next())?;
+            config.codegen.externs.extend(patterns.split(',').map(|p| p.trim().to_string()));
+        } else if arg == "--function-order" {
+            config.function_order = flag_value(arg, args.next())?.parse()?;
+        } else if arg == "--compat" {
+            config.compat = flag_value(arg, args.next())?.parse()?;
+        } else if arg == "--label-scheme" {
+            config.label_scheme = Some(flag_value(arg, args.next())?.parse()?);
+        } else if arg == "--entry" {
+            config.entry = Some(flag_value(arg, args.next())?.clone());
+        } else if arg == "--entry-extern" {
+            config.codegen.bootstrap.extern_entry = true;
+        } else if arg == "--bootstrap" {
+            config.codegen.bootstrap.mode = asm::BootstrapMode::Always;
+        } else if arg == "--no-bootstrap" {
+            config.codegen.bootstrap.mode = asm::BootstrapMode::Never;
+        } else if arg == "--only-closure" {
+            config.filter.with_closure();
+        } else if let Some(style) = arg.strip_prefix("--bootstrap-mode=") {
+            config.codegen.bootstrap.style = style.parse()?;
+        } else if let Some(format) = arg.strip_prefix("--error-format=") {
+            config.error_format = format.parse::<ErrorFormat>()?;
+        } else if arg == "--extensions" {
+            config.extensions = true;
+        } else if arg == "--include-once" {
+            config.include_once = true;
+        } else if arg == "--target" {
+            let map = parse_target(flag_value(arg, args.next())?)?;
+            config.codegen.bootstrap.stack_base = map.stack_base;
+            config.codegen.memory = map;
+        } else if arg == "--max-rom" {
+            let value = flag_value(arg, args.next())?;
+            let max_rom = value
+                .parse::<usize>()
+                .map_err(|_| format!("--max-rom expects a number of instructions, got '{value}'"))?;
+            config.max_rom = Some(max_rom);
+        } else if arg == "--max-line-length" {
+            let value = flag_value(arg, args.next())?;
+            let max = value
+                .parse::<usize>()
+                .map_err(|_| format!("--max-line-length expects a number of bytes, got '{value}'"))?;
+            config.max_line_length = Some(max);
+        } else if arg == "--banked" {
+            config.banked = true;
+        } else if arg == "--prelude" {
+            config.prelude = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--epilogue" {
+            config.epilogue = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--bank-call-shim" {
+            config.bank_call_shim = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--call-graph" {
+            config.call_graph = true;
+        } else if arg == "-W" || arg == "-D" {
+            let lint = flag_value(arg, args.next())?;
+            if !lint::OPT_IN.contains(&lint.as_str()) {
+                return Err(format!("Unknown lint: '{lint}' (available: {})", lint::OPT_IN.join(", ")));
+            }
+            config.lints.retain(|l| l != lint);
+            config.denied.retain(|l| l != lint);
+            match arg.as_str() {
+                "-W" => config.lints.push(lint.clone()),
+                _ => config.denied.push(lint.clone()),
+            }
+        } else if arg == "--config" {
+            config.project_file = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--dump-symbols" {
+            config.dump_symbols = true;
+        } else if arg == "--static-report" {
+            config.static_report = true;
+        } else if arg == "--segment-report" {
+            config.segment_report = true;
+        } else if arg == "--summary" {
+            config.summary = true;
+        } else if arg == "--stack-report" {
+            config.stack_report = true;
+        } else if arg == "--lib" {
+            config.lib = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--gc-functions" {
+            config.gc_functions = true;
+        } else if arg == "--allow-asm-modules" {
+            config.allow_asm_modules = true;
+        } else if arg == "--reproducible" {
+            config.reproducible = true;
+        } else if arg == "--keep-comments" {
+            config.codegen.keep_comments = true;
+        } else if arg == "--comments" {
+            config.codegen.comments = flag_value(arg, args.next())?.parse()?;
+        } else if arg == "--stack-checks" {
+            config.codegen.stack_checks = true;
+        } else if arg == "--no-stack-checks" {
+            config.codegen.stack_checks = false;
+        } else if arg == "--timings" {
+            config.timings = true;
+        } else if arg == "--debug" || arg == "--release" {
+            // Applied above.
+        } else if arg == "--print-config" {
+            config.print_config = true;
+        } else if arg == "--emit" || arg.starts_with("--emit=") {
+            if !std::mem::replace(&mut emit_given, true) {
+                config.emit.clear();
+            }
+            let list = match arg.strip_prefix("--emit=") {
+                Some(list) => list,
+                None => flag_value(arg, args.next())?,
+            };
+            config.emit.extend(parse_emit(list)?);
+        } else if arg == "-o" || arg == "--output" {
+            config.output = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--emit-sym" {
+            config.emit_sym = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--emit-cfg" {
+            config.emit_cfg = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--emit-cfg-for" {
+            config.emit_cfg_for = Some(flag_value(arg, args.next())?.clone());
+        } else if arg == "--emit-doc" {
+            config.emit_doc = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--emit-debug" {
+            config.emit_debug = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--emit-spans" {
+            config.emit_spans = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--annotate-addresses" {
+            config.annotate_addresses = Some(asm::AddressFormat::Hex);
+        } else if let Some(format) = arg.strip_prefix("--annotate-addresses=") {
+            config.annotate_addresses = Some(format.parse::<asm::AddressFormat>()?);
+        } else if arg == "--emit-manifest" {
+            config.emit_manifest = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("Unknown flag: '{arg}'"));
+        } else {
+            *source = Some(positional(source.take(), arg)?);
+        }
+        if is_flag && !UNRECORDED.contains(&name) {
+            let consumed = rest.len() - args.as_slice().len();
+            config.flags.extend(rest[..consumed].iter().cloned());
         }
     }
+    Ok(())
+}
 
-    files
+/// Each setting `--print-config` shows: its name, the flags that set it
+/// and its value in `config`.
+fn settings(config: &Config) -> Vec<(&'static str, &'static [&'static str], String)> {
+    let on = |value: bool| value.to_string();
+    let path = |path: &Option<PathBuf>| path.as_ref().map_or("none".to_string(), |path| path.display().to_string());
+    let text = |text: &Option<String>| text.clone().unwrap_or_else(|| "none".to_string());
+    let list = |items: &[String]| if items.is_empty() { "none".to_string() } else { items.join(",") };
+    let emit = match config.emit.as_slice() {
+        [] => Emit::Asm.name().to_string(),
+        emits => emits
+            .iter()
+            .map(|(kind, path)| match path {
+                Some(path) => format!("{}={}", kind.name(), path.display()),
+                None => kind.name().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+    };
+    let target = config.flags.windows(2).rev().find(|pair| pair[0] == "--target").map_or("default", |pair| &pair[1]);
+    let codegen = &config.codegen;
+    vec![
+        ("--annotate-addresses", &["--annotate-addresses"], config.annotate_addresses.map_or("off".to_string(), |f| f.to_string())),
+        ("--report-json", &["--report-json"], path(&config.report_json)),
+        ("--keep-going", &["--keep-going"], on(config.keep_going)),
+        ("--fail-fast", &["--fail-fast"], on(config.fail_fast)),
+        ("--only", &["--only"], list(config.filter.only_patterns())),
+        ("--skip", &["--skip"], list(config.filter.skip_patterns())),
+        ("--only-closure", &["--only-closure"], on(config.filter.has_closure())),
+        ("--function-order", &["--function-order"], config.function_order.to_string()),
+        ("--output", &["-o", "--output"], path(&config.output)),
+        ("--compat", &["--compat"], config.compat.to_string()),
+        // The compatibility level picks the scheme unless it's given.
+        ("--label-scheme", &["--label-scheme", "--compat"], codegen.label_scheme.to_string()),
+        ("--safe-compare", &["--safe-compare"], on(codegen.safe_compare)),
+        ("--entry", &["--entry"], codegen.bootstrap.entry.clone()),
+        ("--entry-extern", &["--entry-extern"], on(codegen.bootstrap.extern_entry)),
+        ("--bootstrap", &["--bootstrap", "--no-bootstrap"], codegen.bootstrap.mode.to_string()),
+        ("--bootstrap-mode", &["--bootstrap-mode"], codegen.bootstrap.style.to_string()),
+        ("--symbol-prefix", &["--symbol-prefix"], text(&codegen.symbol_prefix)),
+        ("--extern", &["--extern"], list(&codegen.externs)),
+        ("--error-format", &["--error-format"], config.error_format.to_string()),
+        ("--emit", &["--emit"], emit),
+        ("--emit-sym", &["--emit-sym"], path(&config.emit_sym)),
+        ("--emit-doc", &["--emit-doc"], path(&config.emit_doc)),
+        ("--emit-debug", &["--emit-debug"], path(&config.emit_debug)),
+        ("--emit-spans", &["--emit-spans"], path(&config.emit_spans)),
+        ("--emit-cfg", &["--emit-cfg"], path(&config.emit_cfg)),
+        ("--emit-cfg-for", &["--emit-cfg-for"], text(&config.emit_cfg_for)),
+        ("--emit-manifest", &["--emit-manifest"], path(&config.emit_manifest)),
+        ("--timings", &["--timings"], on(config.timings)),
+        ("--reproducible", &["--reproducible"], on(config.reproducible)),
+        ("--keep-comments", &["--keep-comments"], on(codegen.keep_comments)),
+        ("--comments", &["--comments"], codegen.comments.to_string()),
+        ("--stack-checks", &["--stack-checks", "--no-stack-checks"], on(codegen.stack_checks)),
+        ("--allow-asm-modules", &["--allow-asm-modules"], on(config.allow_asm_modules)),
+        ("--lib", &["--lib"], path(&config.lib)),
+        ("--gc-functions", &["--gc-functions"], on(config.gc_functions)),
+        ("--stack-report", &["--stack-report"], on(config.stack_report)),
+        ("--call-graph", &["--call-graph"], on(config.call_graph)),
+        ("--dump-symbols", &["--dump-symbols"], on(config.dump_symbols)),
+        ("--static-report", &["--static-report"], on(config.static_report)),
+        ("--segment-report", &["--segment-report"], on(config.segment_report)),
+        ("--summary", &["--summary"], on(config.summary)),
+        ("-W", &["-W"], list(&config.lints)),
+        ("-D", &["-D"], list(&config.denied)),
+        ("--extensions", &["--extensions"], on(config.extensions)),
+        ("--include-once", &["--include-once"], on(config.include_once)),
+        ("--banked", &["--banked"], on(config.banked)),
+        ("--max-rom", &["--max-rom"], config.max_rom.map_or("none".to_string(), |n| n.to_string())),
+        ("--max-line-length", &["--max-line-length"], config.max_line_length.unwrap_or(vm::MAX_LINE_LENGTH).to_string()),
+        ("--bank-call-shim", &["--bank-call-shim"], path(&config.bank_call_shim)),
+        ("--prelude", &["--prelude"], path(&config.prelude)),
+        ("--epilogue", &["--epilogue"], path(&config.epilogue)),
+        ("--target", &["--target"], target.to_string()),
+    ]
 }
 
-fn load_sources(path: &Path) -> Result<Vec<(String, String)>, String> {
-    list_files(path)
-        .into_iter()
-        .map(|file| {
-            let name = file.file_stem().unwrap().to_str().unwrap().to_string();
-            println!("Reading file {}", file.display());
-            match fs::read_to_string(file) {
-                Ok(s) => Ok((name, s)),
-                Err(e) => Err(format!("Error reading file: {e}")),
+/// `--print-config`: one line per setting with its value and where it
+/// came from: `default`, or the project file, `HACK_VM_FLAGS` or the
+/// command line, naming the preset when `--debug` or `--release` set it.
+fn print_config(config: &Config) {
+    let mut defaults = Config::default();
+    defaults.codegen.label_scheme = defaults.compat.label_scheme();
+    let defaults = settings(&defaults);
+    let preset = config
+        .origins
+        .iter()
+        .rev()
+        .find(|(flag, _)| flag == "--debug" || flag == "--release");
+    // These add to what earlier layers gave; the rest replace it.
+    let accumulating = ["--only", "--skip", "--extern", "-W", "-D"];
+    for ((name, flags, value), (_, _, default)) in settings(config).into_iter().zip(defaults) {
+        let mut origins: Vec<&str> = Vec::new();
+        for (flag, origin) in &config.origins {
+            let flag = flag.split_once('=').map_or(flag.as_str(), |(flag, _)| flag);
+            if flags.contains(&flag) && !origins.contains(&origin.as_str()) {
+                origins.push(origin);
             }
-        })
-        .collect()
+        }
+        if !accumulating.contains(&name) {
+            origins = origins.split_off(origins.len().saturating_sub(1));
+        }
+        let source = match (origins.is_empty(), preset) {
+            (false, _) => origins.join(", "),
+            (true, Some((preset, origin))) if value != default => format!("{preset} from {origin}"),
+            (true, _) => "default".to_string(),
+        };
+        println!("{name:<22} {value}  ({source})");
+    }
 }
 
-fn parse_sources<'a>(
-    sources: &'a Vec<(String, String)>,
-) -> Vec<Result<vm::SourceCommand<'a>, String>> {
-    sources
-        .into_iter()
-        .flat_map(|(file, source)| vm::parse_source(file, &source))
-        .collect()
+fn validate(mut config: Config, source: Option<String>) -> Result<Config, String> {
+    if config.gc_functions && config.lib.is_none() {
+        return Err("--gc-functions only applies with --lib".to_string());
+    }
+    if config.keep_going && config.fail_fast {
+        return Err("--keep-going and --fail-fast can't be used together".to_string());
+    }
+    if config.emit_debug.is_some() && config.codegen.comments == asm::Comments::Off {
+        return Err("--emit-debug reads the line table from the command headers, which --comments off leaves out".to_string());
+    }
+    if config.emit_cfg_for.is_some() && config.emit_cfg.is_none() {
+        return Err("--emit-cfg-for needs --emit-cfg <dir> to write to".to_string());
+    }
+    if config.include_once && !config.extensions {
+        return Err("--include-once only applies with --extensions".to_string());
+    }
+    if config.summary {
+        let reports = [
+            ("--static-report", config.static_report),
+            ("--segment-report", config.segment_report),
+            ("--stack-report", config.stack_report),
+            ("--call-graph", config.call_graph),
+            ("--dump-symbols", config.dump_symbols),
+        ];
+        if let Some((flag, _)) = reports.iter().find(|(_, given)| *given) {
+            return Err(format!("--summary keeps stdout to one line; it can't be combined with {flag}"));
+        }
+    }
+    if config.banked {
+        if !config.extensions {
+            return Err("--banked targets bank-switched ROM; enable it with --extensions".to_string());
+        }
+        if config.max_rom.is_none() {
+            return Err("--banked needs --max-rom to size the banks".to_string());
+        }
+        if config.prelude.is_some() || config.epilogue.is_some() {
+            return Err("--banked can't place a --prelude or --epilogue".to_string());
+        }
+        let extra = [&config.emit_sym, &config.emit_doc, &config.emit_cfg, &config.emit_debug, &config.emit_spans];
+        if extra.iter().any(|path| path.is_some()) || config.emit.iter().any(|(kind, _)| *kind != Emit::Asm) {
+            return Err("--banked only writes assembly; drop the other --emit kinds".to_string());
+        }
+    } else if config.bank_call_shim.is_some() {
+        return Err("--bank-call-shim only applies with --banked".to_string());
+    }
+    if config.output.is_some() && config.emit.iter().any(|(kind, path)| *kind == Emit::Asm && path.is_some()) {
+        return Err("-o and --emit asm=<path> both say where the assembly goes; give one".to_string());
+    }
+    if config.emit_manifest.is_some() {
+        if config.banked {
+            return Err("--emit-manifest can't record banked output".to_string());
+        }
+        // A manifest is only worth keeping if the same inputs and options
+        // give the same bytes.
+        config.reproducible = true;
+    }
+
+    if let Some(entry) = &config.entry {
+        config.codegen.bootstrap.entry = entry.clone();
+    }
+    config.codegen.label_scheme = config.label_scheme.unwrap_or(config.compat.label_scheme());
+    config.compat.check(&config.codegen)?;
+
+    match source {
+        Some(source) => Ok(Config { source, ..config }),
+        None => Err("not enough arguments".to_string()),
+    }
+}
+
+/// Parses `--target default|file:<map.toml>`.
+fn parse_target(value: &str) -> Result<memory::MemoryMap, String> {
+    if value == "default" {
+        return Ok(memory::MemoryMap::default());
+    }
+    let Some(file) = value.strip_prefix("file:") else {
+        return Err(format!("Unknown target: '{value}' (expected default or file:<map.toml>)"));
+    };
+    let text = fs::read_to_string(file).map_err(|e| format!("Error reading memory map {file}: {e}"))?;
+    memory::MemoryMap::parse(Path::new(file), &text).map_err(|errors| {
+        let rendered: Vec<String> = errors.iter().map(Diagnostic::to_string).collect();
+        rendered.join("\n")
+    })
+}
+
+/// Takes `arg` as the input path unless one was already given.
+fn positional(source: Option<String>, arg: &str) -> Result<String, String> {
+    match source {
+        None => Ok(arg.to_string()),
+        Some(source) => Err(format!(
+            "Unexpected argument: '{arg}' (already translating '{source}'; only one input is accepted)"
+        )),
+    }
+}
+
+/// Rejects a flag `subcommand` doesn't list in `completions::SUBCOMMANDS`,
+/// so that every flag a subcommand accepts is completed.
+fn subcommand_flag(subcommand: &str, arg: &str) -> Result<(), String> {
+    let flags = completions::subcommand_flags(subcommand).unwrap_or_default();
+    if arg.starts_with('-') && arg != "-" && !flags.iter().any(|(flag, _)| *flag == arg) {
+        return Err(format!("Unknown flag: '{arg}'"));
+    }
+    Ok(())
+}
+
+fn flag_value<'a>(flag: &str, value: Option<&'a String>) -> Result<&'a String, String> {
+    value.ok_or_else(|| format!("{flag} requires a value"))
+}
+
+/// The `.vm` files `path` names. A directory that can't be listed, or
+/// that vanishes while it's read, fails like a file that can't be read.
+fn list_files(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    vm::discover_sources(path).map_err(|e| {
+        Diagnostic::error("Error reading directory", e.to_string()).in_file(path.display().to_string()).into()
+    })
+}
+
+/// A directory's own name, resolving `.` and `..`, so that translating
+/// `.` names the output after the directory it stands for.
+fn dir_name(path: &Path) -> OsString {
+    match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => path
+            .canonicalize()
+            .ok()
+            .and_then(|path| path.file_name().map(OsStr::to_os_string))
+            .unwrap_or_default(),
+    }
+}
+
+/// Drops inputs that are the same file as an earlier one (via a symlink,
+/// say), so nothing is translated twice, and rejects distinct files that
+/// would share a static prefix.
+fn dedupe_inputs(files: Vec<PathBuf>, config: &Config) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut seen: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut stems: HashMap<String, PathBuf> = HashMap::new();
+    let mut inputs = Vec::new();
+
+    for file in files {
+        let canonical = file.canonicalize().unwrap_or_else(|_| file.clone());
+        if let Some((first, _)) = seen.iter().find(|(_, c)| *c == canonical) {
+            progress(config, &format!("Skipping {} (same file as {})", file.display(), first.display()));
+            continue;
+        }
+        if let Some(other) = stems.insert(vm::file_base(&file), file.clone()) {
+            return Err(Diagnostic::error(
+                "Error",
+                format!(
+                    "{} and {} would share the static prefix '{}'",
+                    other.display(),
+                    file.display(),
+                    vm::file_base(&file)
+                ),
+            )
+            .into());
+        }
+        seen.push((file.clone(), canonical));
+        inputs.push(file);
+    }
+
+    Ok(inputs)
+}
+
+/// Where an artifact of `kind` goes by default: `stem` with its extension
+/// added, so `Project.v2` gives `Project.v2.asm`.
+fn artifact_path(stem: &Path, kind: Emit) -> PathBuf {
+    let mut name = stem.as_os_str().to_os_string();
+    name.push(".");
+    name.push(kind.extension());
+    PathBuf::from(name)
+}
+
+/// Whether the assembly can be written as it's generated: only when it's
+/// the one artifact, and nothing wants the whole program first to link
+/// into, filter, measure, annotate or describe.
+fn streams(config: &Config) -> bool {
+    let whole_program = config.codegen.keep_comments
+        || config.filter.is_active()
+        || config.allow_asm_modules
+        || config.lib.is_some()
+        || config.prelude.is_some()
+        || config.epilogue.is_some()
+        || config.banked
+        || config.max_rom.is_some()
+        || config.annotate_addresses.is_some();
+    let other_artifacts = config.emit.len() > 1
+        || config.emit.iter().any(|(kind, _)| *kind != Emit::Asm)
+        || config.emit_sym.is_some()
+        || config.emit_doc.is_some()
+        || config.emit_debug.is_some()
+        || config.emit_spans.is_some()
+        || config.emit_cfg.is_some()
+        || config.emit_manifest.is_some()
+        || config.report_json.is_some();
+    !whole_program && !other_artifacts && !config.dry_run
+}
+
+/// Writes the assembly for `commands` to `path` a command at a time,
+/// counting it and finding its variables line by line on the way. It goes
+/// to `path.partial` first and is renamed into place once whole, so a
+/// failure leaves the last good output alone.
+fn stream_output(
+    config: &Config,
+    commands: &[vm::SourceCommand],
+    path: &Path,
+    manifest: &asm::Manifest,
+    report: &mut report::Report,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| format!("Error creating {}: {e}", parent.display()))?;
+    }
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let file = File::create(&partial).map_err(|e| format!("Error writing {}: {e}", partial.display()))?;
+
+    let mut variables = asm::VariableScan::default();
+    let write = || -> Result<(), String> {
+        let mut out = LineObserver::new(BufWriter::new(file), |line| {
+            report.record_output_line(line);
+            variables.line(line);
+        });
+        asm::generate_code_to(commands, &config.codegen, &mut out)?;
+        // The metadata is neither code nor counted.
+        let mut out = out.finish();
+        let error = |e: io::Error| format!("Error writing {}: {e}", partial.display());
+        out.write_all(config.compat.finish(String::new(), manifest).as_bytes()).map_err(error)?;
+        out.flush().map_err(error)
+    };
+    let written = write().and_then(|()| check_variables(config, &variables.finish()));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&partial);
+        return Err(e.into());
+    }
+    progress(config, &format!("output file = {}", path.display()));
+    fs::rename(&partial, path).map_err(|e| format!("Error writing {}: {e}", path.display()))?;
+    report.add_artifact(path)?;
+    Ok(())
+}
+
+/// Passes bytes through to `inner`, handing each whole line to `observe`
+/// as it goes by.
+struct LineObserver<W, F> {
+    inner: W,
+    observe: F,
+    /// The line written so far.
+    pending: Vec<u8>,
+}
+
+impl<W: Write, F: FnMut(&str)> LineObserver<W, F> {
+    fn new(inner: W, observe: F) -> Self {
+        LineObserver { inner, observe, pending: Vec::new() }
+    }
+
+    /// Hands over the last line, which has no newline, and gives back
+    /// `inner`.
+    fn finish(mut self) -> W {
+        if !self.pending.is_empty() {
+            (self.observe)(&String::from_utf8_lossy(&self.pending));
+        }
+        self.inner
+    }
+}
+
+impl<W: Write, F: FnMut(&str)> Write for LineObserver<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pending.extend_from_slice(&buf[..written]);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            (self.observe)(&String::from_utf8_lossy(&line[..end]));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes an artifact, creating any directories missing on the way to it,
+/// and names the path in any error.
+fn write_output(path: &Path, contents: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| format!("Error creating {}: {e}", parent.display()))?;
+    }
+    fs::write(path, contents).map_err(|e| format!("Error writing {}: {e}", path.display()))
 }
 
-fn extract_and_report_errors(
-    parse_results: Vec<Result<vm::SourceCommand, String>>,
-) -> Result<Vec<vm::SourceCommand>, String> {
-    let mut error_count = 0;
+/// The assembly files named on the command line: the prelude, the
+/// epilogue and the bank call shim.
+fn asm_inputs(config: &Config) -> impl Iterator<Item = PathBuf> + '_ {
+    config.prelude.iter().chain(&config.epilogue).chain(&config.bank_call_shim).cloned()
+}
+
+/// Refuses to let `output` overwrite one of `inputs`. Both sides are
+/// canonicalized, so `./Main.vm`, `../dir/Main.vm` and symlinks to it are
+/// all caught. An output that doesn't exist yet is resolved through the
+/// nearest directory above it that does, as `sub/../Main.vm` is
+/// `Main.vm` once `write_output` has made `sub`.
+fn ensure_not_input(output: &Path, inputs: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let Some(resolved) = resolve_output(output) else {
+        // The write itself will report the missing directory.
+        return Ok(());
+    };
+
+    for input in inputs {
+        if input.canonicalize().is_ok_and(|input| input == resolved) {
+            return Err(Diagnostic::error(
+                "Error",
+                format!("Refusing to overwrite input file {}", input.display()),
+            )
+            .in_file(output.display().to_string())
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Where `output` will be once written: its nearest existing ancestor,
+// canonicalized, with the rest of it applied a component at a time.
+fn resolve_output(output: &Path) -> Option<PathBuf> {
+    let (mut resolved, rest) = output.ancestors().find_map(|ancestor| {
+        let dir = if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor };
+        Some((dir.canonicalize().ok()?, output.strip_prefix(ancestor).ok()?))
+    })?;
+    for component in rest.components() {
+        match component {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Some(resolved)
+}
+
+/// Reads every input into a `Translator`. With `--fail-fast` the first
+/// file with a parse error stops the run before later files are opened;
+/// otherwise errors are reported with the rest once everything is read.
+fn load_sources(files: &[PathBuf], config: &Config) -> Result<Vec<vm::Source>, Box<dyn Error>> {
+    let mut translator = Translator::new();
+    if let Some(max) = config.max_line_length {
+        translator.set_max_line_length(max);
+    }
+    for file in files {
+        progress(config, &format!("Reading file {}", file.display()));
+        let text = fs::read_to_string(file).map_err(|e| {
+            Diagnostic::error("Error reading file", e.to_string()).in_file(file.display().to_string())
+        })?;
+        let added = translator.sources().len();
+        if let Err(diagnostics) = translator.add_source(file, text) {
+            if translator.sources().len() == added {
+                return Err(diagnostics.into_iter().next().unwrap().into());
+            }
+            if config.fail_fast {
+                eprintln!("{}", diagnostics[0].render(config.error_format));
+                return Err("Stopped at the first parse error (--fail-fast)".into());
+            }
+        }
+    }
+    Ok(translator.into_sources())
+}
+
+/// Reads every file `sources` include, directly or through other
+/// included files, once each.
+fn load_includes(sources: &[vm::Source], config: &Config) -> Result<Vec<vm::Source>, Box<dyn Error>> {
+    let mut included: Vec<vm::Source> = Vec::new();
+    let mut pending: Vec<PathBuf> = Vec::new();
+    let scan = |source: &vm::Source, pending: &mut Vec<PathBuf>| {
+        for sc in source.parse().iter().flatten() {
+            if let vm::Command::Include(name) = sc.command() {
+                pending.push(vm::include_path(sc, name));
+            }
+        }
+    };
+    for source in sources {
+        scan(source, &mut pending);
+    }
+    pending.reverse();
+
+    while let Some(path) = pending.pop() {
+        if included.iter().any(|s| s.path == path) || sources.iter().any(|s| s.path == path) {
+            continue;
+        }
+        let source = load_sources(std::slice::from_ref(&path), config)?.remove(0);
+        let mut nested = Vec::new();
+        scan(&source, &mut nested);
+        pending.extend(nested.into_iter().rev());
+        included.push(source);
+    }
+    Ok(included)
+}
+
+/// The hand-written `.asm` modules in directory `path`, sorted by name.
+/// Files named after the directory are the translator's own output and
+/// are left out.
+fn load_asm_modules(path: &Path, config: &Config) -> Result<Vec<link::Fragment>, Box<dyn Error>> {
+    if !path.is_dir() {
+        return Ok(Vec::new());
+    }
+    let own_output = format!("{}.", dir_name(path).to_string_lossy());
+    let mut files: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|file| {
+        file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("asm"))
+            && !file.file_name().unwrap().to_string_lossy().starts_with(&own_output)
+    });
+    files.sort();
+
+    let mut modules = Vec::new();
+    for file in files {
+        progress(config, &format!("Reading module {}", file.display()));
+        modules.push(read_asm(&file)?);
+    }
+    Ok(modules)
+}
+
+/// Reads hand-written assembly, checking it assembles on its own when the
+/// assembler is built in.
+fn read_asm(file: &Path) -> Result<link::Fragment, Box<dyn Error>> {
+    let text = fs::read_to_string(file).map_err(|e| format!("Error reading {}: {e}", file.display()))?;
+    #[cfg(feature = "assembler")]
+    if let Err(errors) = assembler::assemble(&text) {
+        for e in &errors {
+            eprintln!("{}: {e}", file.display());
+        }
+        return Err(format!("Assembly errors found: {}", errors.len()).into());
+    }
+    Ok(link::Fragment {
+        name: file.display().to_string(),
+        text,
+    })
+}
+/// Reads the library in `dir`: each `.asm` fragment and the `.sym` file
+/// beside it. A fragment named like one of `sources` would share its
+/// statics, so that is an error.
+fn load_library(dir: &Path, sources: &[vm::Source], config: &Config) -> Result<link::Library, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Error reading library {}: {e}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|file| file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("asm")));
+    files.sort();
+
+    let mut library = link::Library::default();
+    for file in files {
+        let base = vm::file_base(&file);
+        if let Some(source) = sources.iter().find(|s| s.name == base) {
+            return Err(format!("{} is also in the library as {}", source.path.display(), file.display()).into());
+        }
+        progress(config, &format!("Reading library {}", file.display()));
+        let text = fs::read_to_string(&file)?;
+        // A symbol file wins; otherwise the manifest at the bottom of the
+        // fragment says what it defines.
+        let sym = file.with_extension("sym");
+        let functions = match (fs::read_to_string(&sym), asm::read_metadata(&text)) {
+            (Ok(symbols), _) => link::symbol_file_functions(&symbols),
+            (Err(_), Some(manifest)) => manifest.functions,
+            (Err(_), None) => {
+                return Err(format!(
+                    "{} has no symbol file or metadata; translate it again, or with --emit-sym {}",
+                    file.display(),
+                    sym.display()
+                )
+                .into())
+            }
+        };
+        library.fragments.push(link::LibraryFragment {
+            fragment: link::Fragment {
+                name: file.display().to_string(),
+                text,
+            },
+            functions,
+        });
+    }
+    Ok(library)
+}
+
+// Prints each parse error, along with `refused`: the commands that parsed
+// but that the code generator refuses (see `lint::check_code`). Normally
+// any error fails the translation, but with `keep_going` the failed
+// commands are replaced by traps so the rest of the program can still be
+// generated; the error count is returned so the caller can still fail
+// afterwards. A refused command has no trap, so it fails either way.
+fn extract_and_report_errors<'a>(
+    parse_results: Vec<Result<vm::SourceCommand<'a>, vm::ParseError<'a>>>,
+    refused: Vec<Diagnostic>,
+    keep_going: bool,
+    format: ErrorFormat,
+) -> Result<(Vec<vm::SourceCommand<'a>>, usize), String> {
+    let refused_count = refused.len();
+    let mut errors: Vec<Diagnostic> = refused;
     let mut parsed_commands: Vec<vm::SourceCommand> = Vec::new();
 
     for result in parse_results {
         match result {
             Ok(c) => parsed_commands.push(c),
             Err(e) => {
-                error_count = error_count + 1;
-                println!("{}", e)
+                errors.push(Diagnostic::from(&e));
+                if keep_going {
+                    parsed_commands.push(vm::SourceCommand::trap(&e));
+                }
             }
         }
     }
+    eprint!("{}", diagnostic::render_grouped(&errors, format));
+    let error_count = errors.len();
 
-    if error_count > 0 {
+    if error_count > 0 && (!keep_going || refused_count > 0) {
         Err(format!("Parse errors found: {error_count}"))
     } else {
-        Ok(parsed_commands)
+        Ok((parsed_commands, error_count))
+    }
+}
+
+/// Warns when the deepest call chain from `Sys.init` can overflow the
+/// stack, and prints the whole analysis under `--stack-report`.
+fn check_stack_depth(
+    config: &Config,
+    commands: &[vm::SourceCommand],
+    graph: &callgraph::CallGraph,
+    report: &mut report::Report,
+) {
+    let analysis = stack::analyze(commands, graph);
+    // The stack runs from its base up to the heap.
+    let limit = (config.codegen.memory.stack_limit as usize).saturating_sub(config.codegen.bootstrap.stack_base as usize);
+    if config.stack_report {
+        print!("{}", analysis.render(limit));
+    }
+
+    let mut warnings = Vec::new();
+    if let Some(depth) = analysis
+        .function(&config.codegen.bootstrap.entry)
+        .and_then(|f| f.depth)
+        .filter(|depth| *depth > limit)
+    {
+        warnings.push(Diagnostic::warning(
+            "Warning",
+            format!(
+                "{} can use {depth} words of stack, more than the {limit} available",
+                config.codegen.bootstrap.entry
+            ),
+        ));
+    }
+    report_diagnostics(warnings, config, report);
+}
+
+/// The `--emit-sym` file: `<symbol> <address>` for the bootstrap, each
+/// function entry and the parse error trap, sorted by ROM address. The
+/// bootstrap starts after the `bootstrap_address` instructions of any
+/// prelude.
+fn symbol_file(output: &str, graph: &callgraph::CallGraph, codegen: &asm::CodegenOptions, bootstrap_address: usize) -> String {
+    let prefix = codegen.symbol_prefix.as_deref().unwrap_or("");
+    let mut symbols: Vec<(&str, usize)> = asm::label_addresses(output)
+        .into_iter()
+        .filter(|(label, _)| {
+            let name = label.strip_prefix(prefix).unwrap_or(label);
+            graph.functions().contains(&name) || name == "PARSE_ERROR_TRAP"
+        })
+        .collect();
+    if output.contains(&format!("({})", asm::BOOTSTRAP_LABEL)) {
+        symbols.insert(0, ("Bootstrap", bootstrap_address));
+    }
+    symbols.sort_by_key(|(_, address)| *address);
+    symbols.iter().map(|(symbol, address)| format!("{symbol} {address}\n")).collect()
+}
+
+/// The `--emit-spans` file: for each command with code, its file, line
+/// and text, and where its code is in `asm`, the assembly as written, in
+/// bytes and in lines counted from 0. `shift` is how many lines went ahead
+/// of the code the spans were taken from; the lines keep their number
+/// through the rest, even where `--annotate-addresses` widens them.
+fn span_file(spans: &[asm::CommandSpan], located: &[(String, usize, String)], asm: &str, shift: usize) -> Json {
+    let mut starts = vec![0];
+    starts.extend(asm.match_indices('\n').map(|(i, _)| i + 1));
+    let line_end = |line: usize| starts.get(line + 1).map_or(asm.len(), |next| next - 1);
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let (file, line, command) = &located[span.index];
+            let lines = span.lines.start + shift..span.lines.end + shift;
+            Json::object(vec![
+                ("file", Json::from(file.as_str())),
+                ("line", Json::from(*line)),
+                ("command", Json::from(command.as_str())),
+                ("bytes", Json::Array(vec![Json::from(starts[lines.start]), Json::from(line_end(lines.end - 1))])),
+                ("lines", Json::Array(vec![Json::from(lines.start), Json::from(lines.end)])),
+                ("instructions", Json::from(span.instructions)),
+            ])
+        })
+        .collect();
+    Json::Array(spans)
+}
+
+/// The `--emit-cfg` files: `dir/<function>.dot` for each function, or
+/// just for `only`.
+fn cfg_files(dir: &Path, commands: &[vm::SourceCommand], only: Option<&str>) -> Result<Vec<(PathBuf, String)>, String> {
+    let files: Vec<(PathBuf, String)> = vm::cfg::functions(commands)
+        .iter()
+        .filter(|graph| only.is_none_or(|name| graph.function == name))
+        .map(|graph| (dir.join(format!("{}.dot", graph.function)), graph.to_dot()))
+        .collect();
+    match only {
+        Some(name) if files.is_empty() => Err(format!("--emit-cfg-for: no function named '{name}'")),
+        _ => Ok(files),
     }
 }
 
+/// Prints where the assembler will put each variable under
+/// `--dump-symbols`, and fails if any would land in the stack.
+fn check_variables(config: &Config, variables: &[(String, u16)]) -> Result<(), String> {
+    if config.dump_symbols {
+        for (name, address) in variables {
+            println!("{address:5} {name}");
+        }
+    }
+
+    let overflow: Vec<&str> = variables
+        .iter()
+        .filter(|(_, address)| *address >= config.codegen.memory.stack_base)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    match overflow.first() {
+        None => Ok(()),
+        Some(first) => Err(format!(
+            "{} variables need RAM past {}, where the stack starts (first: {first})",
+            overflow.len(),
+            config.codegen.memory.stack_base - 1
+        )),
+    }
+}
+
+/// Runs opt-in lint `name` if `-W` or `-D` asked for it; under `-D` its
+/// warnings are errors.
+fn opt_in_lint(config: &Config, name: &str, check: impl FnOnce() -> Vec<Diagnostic>) -> Vec<Diagnostic> {
+    let denied = config.denied.iter().any(|l| l == name);
+    if !denied && !config.lints.iter().any(|l| l == name) {
+        return Vec::new();
+    }
+    let mut diagnostics = check();
+    if denied {
+        for diagnostic in &mut diagnostics {
+            if diagnostic.severity == Severity::Warning {
+                diagnostic.severity = Severity::Error;
+                diagnostic.kind = "Error";
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Prints `diagnostics`, records the warnings in the report, and returns
+/// how many were errors.
+fn report_diagnostics(diagnostics: Vec<Diagnostic>, config: &Config, report: &mut report::Report) -> usize {
+    let mut errors = 0;
+    eprint!("{}", diagnostic::render_grouped(&diagnostics, config.error_format));
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Error => {
+                report.add_error(&diagnostic);
+                errors += 1;
+            }
+            Severity::Warning => report.add_warning(&diagnostic.to_string()),
+            Severity::Note => (),
+        }
+    }
+    errors
+}
+
+/// Prints a progress line such as `Reading file ...`, unless `--summary`
+/// has stdout to itself.
+fn progress(config: &Config, line: &str) {
+    if !config.summary && !config.dry_run {
+        println!("{line}");
+    }
+}
+
+/// Reports a bad command line and exits with the usage status.
+fn usage_error(err: &str) -> ! {
+    println!("Argument Error: {}", err);
+    println!("Usage: hack_vmtranslator [--annotate-addresses[=hex|dec]] [--report-json <path>] [--keep-going | --fail-fast] [--only <functions>] [--skip <functions>] [--only-closure] [--function-order source|alpha|callgraph] [-o <path|dir>] [--compat 0.1|latest] [--label-scheme lines|counters] [--safe-compare] [--entry <function> [--entry-extern]] [--bootstrap | --no-bootstrap] [--bootstrap-mode=full|minimal] [--symbol-prefix <prefix>] [--extern <functions>] [--error-format=human|short|json] [--emit asm,asm-resolved,hack,listing,symbols,ast,sym,doc,spans] [--emit-sym <path>] [--emit-doc <path>] [--emit-debug <file.hvd>] [--emit-spans <spans.json>] [--emit-cfg <dir> [--emit-cfg-for <function>]] [--emit-manifest <path>] [--timings] [--reproducible] [--keep-comments] [--comments off|headers|annotated] [--stack-checks | --no-stack-checks] [--allow-asm-modules] [--lib <dir> [--gc-functions]] [--stack-report] [--call-graph] [--dump-symbols] [--static-report] [--segment-report] [--summary] [-W <lint>] [--extensions [--include-once] --banked --max-rom <n> [--bank-call-shim <file.asm>]] [--max-line-length <bytes>] [--prelude <file.asm>] [--epilogue <file.asm>] [--target default|file:<map.toml>] [--debug | --release] [--print-config] [--config <hackvm.toml>] [-D <lint>] [--] <vmfile|directory>");
+    // Misuse, as opposed to a failed translation.
+    process::exit(2);
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    let source = parse_args(&args).unwrap_or_else(|err| {
+    let args = argfile::expand(env::args().collect()).unwrap_or_else(|err| {
         println!("Argument Error: {}", err);
-        println!("Usage: hack_vmtranslator <vmfile|directory>");
+        process::exit(2);
+    });
+    match args.get(1).map(String::as_str) {
+        Some("fmt") => return run_fmt(&args[2..]),
+        Some("link") => return run_link(&args[2..]),
+        Some("run") => return run_vm(&args[2..]),
+        Some("eval") => return run_eval(&args[2..]),
+        Some("gen-tests") => return run_gen_tests(&args[2..]),
+        Some("doctor") => return run_doctor(&args[2..]),
+        Some("completions") => return run_completions(&args[2..]),
+        Some("verify-manifest") => return run_verify_manifest(&args[2..]),
+        Some("lift") => return run_lift(&args[2..]),
+        Some("batch") => return run_batch(&args[2..]),
+        Some("verify") => return run_verify(&args[2..]),
+        _ => (),
+    }
+
+    let env_flags = match env::var(FLAGS_VARIABLE) {
+        Ok(value) => argfile::tokenize(&value).unwrap_or_else(|err| usage_error(&format!("{err} in {FLAGS_VARIABLE}"))),
+        Err(env::VarError::NotPresent) => Vec::new(),
+        Err(env::VarError::NotUnicode(_)) => usage_error(&format!("{FLAGS_VARIABLE} isn't valid UTF-8")),
+    };
+    let environment = (FLAGS_VARIABLE.to_string(), env_flags.as_slice());
+    let command_line = (COMMAND_LINE.to_string(), &args[1..]);
+    let mut config = parse_args(&[environment.clone(), command_line.clone()]).unwrap_or_else(|err| usage_error(&err));
+    let project_file = config.project_file.clone().or_else(|| project::discover(Path::new(&config.source)));
+    if let Some(file) = project_file {
+        let text = fs::read_to_string(&file)
+            .unwrap_or_else(|e| usage_error(&format!("Error reading {}: {e}", file.display())));
+        let flags = project::parse(&file, &text).unwrap_or_else(|errors| {
+            eprint!("{}", diagnostic::render_grouped(&errors, config.error_format));
+            eprintln!("Config errors found: {}", errors.len());
+            process::exit(2);
+        });
+        config = parse_args(&[(file.display().to_string(), &flags), environment, command_line])
+            .unwrap_or_else(|err| usage_error(&err));
+    }
+
+    if config.print_config {
+        print_config(&config);
+        return Ok(());
+    }
+
+    let mut report = report::Report::default();
+    report.reproducible = config.reproducible;
+    // A directory that can't be listed fails in `translate`.
+    let mut inputs = list_files(Path::new(&config.source)).unwrap_or_default();
+    inputs.extend(asm_inputs(&config));
+    let guard = match &config.report_json {
+        Some(path) => ensure_not_input(path, &inputs),
+        None => Ok(()),
+    };
+    let report_is_safe = guard.is_ok();
+    let result = guard.and_then(|()| translate(&config, &mut report));
+
+    if config.timings {
+        eprint!("{}", report.metrics.render());
+    }
+
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let failure = result.err().map(|e| match e.downcast::<Diagnostic>() {
+        Ok(diagnostic) => *diagnostic,
+        Err(e) => Diagnostic::error("Error", e.to_string()),
+    });
+    // The short format is one located line per problem, for editors; a
+    // closing tally of problems already listed would only get in the way.
+    let tallied = matches!(config.error_format, ErrorFormat::Short) && report.has_errors();
+    if let Some(diagnostic) = &failure {
+        report.fail(diagnostic);
+    }
+
+    if let (Some(path), true) = (&config.report_json, report_is_safe) {
+        fs::write(path, report.to_json(error.as_deref()).pretty())?;
+    }
+
+    if config.summary {
+        println!("{}", report.summary());
+    }
+
+    if let Some(diagnostic) = failure {
+        if !tallied {
+            eprintln!("{}", diagnostic.render(config.error_format));
+        }
         process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn translate(config: &Config, report: &mut report::Report) -> Result<(), Box<dyn Error>> {
+    let source = &config.source;
+    let source_path = Path::new(source);
+    let started = Instant::now();
+    report.set_options(config.codegen.to_json());
+    if report_diagnostics(config.codegen.validate(), config, report) > 0 {
+        return Err("Invalid options".into());
+    }
+    // Otherwise the output directory would be made for it.
+    if !source_path.exists() {
+        return Err(format!("Error reading {source}: no such file or directory").into());
+    }
+    let files = dedupe_inputs(list_files(source_path)?, config)?;
+    if report_diagnostics(lint::check_file_names(&files), config, report) > 0 {
+        return Err("Invalid input file names".into());
+    }
+    // Every file the run reads, none of which it may write.
+    let mut inputs = files.clone();
+    inputs.extend(asm_inputs(config));
+    let sources = load_sources(&files, config)?;
+    for source in &sources {
+        report.add_input(&source.path, &source.text);
+    }
+    let modules = if config.allow_asm_modules {
+        load_asm_modules(source_path, config)?
+    } else {
+        Vec::new()
+    };
+    for module in &modules {
+        report.add_input(Path::new(&module.name), &module.text);
+        inputs.push(PathBuf::from(&module.name));
+    }
+    let library = match &config.lib {
+        Some(dir) => load_library(dir, &sources, config)?,
+        None => link::Library::default(),
+    };
+    for f in &library.fragments {
+        report.add_input(Path::new(&f.fragment.name), &f.fragment.text);
+        let fragment = PathBuf::from(&f.fragment.name);
+        inputs.push(fragment.with_extension("sym"));
+        inputs.push(fragment);
+    }
+    let [prelude, epilogue] = [&config.prelude, &config.epilogue].map(|path| {
+        path.as_deref().map(|path| {
+            progress(config, &format!("Reading module {}", path.display()));
+            read_asm(path)
+        })
     });
+    let (prelude, epilogue) = (prelude.transpose()?, epilogue.transpose()?);
+    for fragment in prelude.iter().chain(&epilogue) {
+        report.add_input(Path::new(&fragment.name), &fragment.text);
+    }
+    let included = if config.extensions {
+        load_includes(&sources, config)?
+    } else {
+        Vec::new()
+    };
+    for source in &included {
+        report.add_input(&source.path, &source.text);
+        inputs.push(source.path.clone());
+    }
+    report.metrics.finish_phase("read", started);
 
-    let source_path = Path::new(&source);
-    let sources = load_sources(&source_path)?;
-    let ast = parse_sources(&sources);
-    let ast = extract_and_report_errors(ast)?;
-    let asm = asm::generate_code(ast)?;
+    let started = Instant::now();
+    let mut ast = vm::parse_sources(&sources);
+    if config.extensions {
+        ast = vm::splice_includes(ast, &included, config.include_once).map_err(|e| *e)?;
+    }
+    for e in ast.iter().filter_map(|result| result.as_ref().err()) {
+        report.add_error(&Diagnostic::from(e));
+    }
+    // Checked alongside the parse errors, so that neither hides the other.
+    let parsed: Vec<_> = ast.iter().filter_map(|result| result.as_ref().ok()).cloned().collect();
+    let refused = lint::check_code(&parsed, &config.codegen);
+    for diagnostic in &refused {
+        report.add_error(diagnostic);
+    }
+    let (mut ast, error_count) = extract_and_report_errors(ast, refused, config.keep_going, config.error_format)?;
+    let mut diagnostics = lint::check_extensions(&ast, config.extensions);
+    diagnostics.extend(lint::check_spin_loops(&ast));
+    diagnostics.extend(lint::check_bootstrap(&ast, &config.codegen, source_path.is_dir()));
+    diagnostics.extend(lint::check_calls(&ast, &config.codegen, |name| {
+        library.defines(name) || modules.iter().any(|module| module.defines(name))
+    }));
+    diagnostics.extend(opt_in_lint(config, "argument-write", || lint::check_argument_writes(&ast)));
+    diagnostics.extend(opt_in_lint(config, "temp-across-call", || lint::check_temps_across_calls(&ast)));
+    let statics = lint::static_usage(&ast);
+    if config.static_report {
+        print!("{}", lint::render_static_report(&statics));
+    }
+    diagnostics.extend(opt_in_lint(config, "static-gaps", || lint::check_static_gaps(&statics)));
+    let lint_diagnostics = diagnostics.clone();
+    let lint_errors = report_diagnostics(diagnostics, config, report);
+    if lint_errors > 0 {
+        return Err(format!("Parse errors found: {lint_errors}").into());
+    }
+    if config.filter.is_active() {
+        ast = config.filter.apply(ast)?;
+    }
+    ast = callgraph::order_functions(ast, config.function_order, &config.codegen.bootstrap.entry);
+    if config.segment_report {
+        let usage = segments::SegmentUsage::collect(&ast);
+        print!("{}", usage.render(SEGMENT_REPORT_TOP));
+        report.set_segments(usage.to_json(SEGMENT_REPORT_TOP));
+    }
+    report.record_commands(&ast);
+    report.set_size(asm::estimate_program_size(&ast, &config.codegen).to_json());
+    report.metrics.finish_phase("parse", started);
+
+    let started = Instant::now();
+    let ast_json = config
+        .emit
+        .iter()
+        .any(|(kind, _)| *kind == Emit::Ast)
+        .then(|| ast_to_json(&ast));
+    let outline = doc::Outline::new(&ast);
+    let cfg_files = match &config.emit_cfg {
+        Some(dir) => cfg_files(dir, &ast, config.emit_cfg_for.as_deref())?,
+        None => Vec::new(),
+    };
+    let bootstrap = asm::should_bootstrap(&ast, &config.codegen);
+    let graph = callgraph::CallGraph::build(&ast);
+    check_stack_depth(config, &ast, &graph, report);
+    if config.call_graph {
+        print!("{}", graph.render());
+    }
+    let manifest = asm::Manifest::new(&ast, &config.codegen);
+    progress(config, &format!("source file = {}", source));
 
-    println!("source file = {}", source);
+    let stem = if source_path.is_file() {
+        source_path.with_extension("")
+    } else {
+        // The whole directory name, dots and all: `Project.v2/` gives
+        // `Project.v2.asm`.
+        source_path.join(dir_name(source_path))
+    };
+    // `-o build/` keeps the input's name; `-o build/prog.asm` names the
+    // assembly, and the other artifacts after it.
+    let (stem, asm_path) = match &config.output {
+        None => (stem, None),
+        Some(out) if out.is_dir() || out.as_os_str().to_string_lossy().ends_with(['/', std::path::MAIN_SEPARATOR]) => {
+            (out.join(stem.file_name().unwrap_or_default()), None)
+        }
+        Some(out) => (out.with_extension(""), Some(out.clone())),
+    };
+    if streams(config) {
+        let path = match config.emit.first() {
+            Some((_, Some(path))) => path.clone(),
+            _ => asm_path.unwrap_or_else(|| artifact_path(&stem, Emit::Asm)),
+        };
+        ensure_not_input(&path, &inputs)?;
+        report.set_bootstrap(bootstrap);
+        stream_output(config, &ast, &path, &manifest, report)?;
+        // Generating and writing are one phase here.
+        report.metrics.finish_phase("codegen", started);
+        if error_count > 0 {
+            return Err(format!("Parse errors found: {error_count}").into());
+        }
+        return Ok(());
+    }
+    let wants_spans = config.emit_spans.is_some() || config.emit.iter().any(|(kind, _)| *kind == Emit::Spans);
+    let located: Vec<(String, usize, String)> = if wants_spans {
+        ast.iter().map(|sc| (sc.path().display().to_string(), sc.line() + 1, sc.command().to_string())).collect()
+    } else {
+        Vec::new()
+    };
+    let (mut output, spans) = if config.codegen.keep_comments {
+        let commented: Vec<vm::Source> = sources.iter().chain(&included).cloned().collect();
+        asm::generate_with_spans_and_comments(ast, &commented, &config.codegen)?
+    } else {
+        asm::generate_with_spans(ast, &config.codegen)?
+    };
+    // Lines put ahead of the translated code, which the spans move down by.
+    let mut spans_shift = 0;
+    let prelude_size = prelude.as_ref().map_or(0, |f| f.text.lines().filter(|l| asm::is_instruction(l)).count());
+    // Only the translated code has lines; modules, library functions and
+    // the epilogue come after it.
+    let debug_info = config.emit_debug.as_ref().map(|_| {
+        let mut info = debuginfo::DebugInfo::from_asm(&output, prelude_size);
+        for file in &mut info.files {
+            if let Some(source) = sources.iter().chain(&included).find(|s| s.name == *file) {
+                *file = source.path.display().to_string();
+            }
+        }
+        info
+    });
+    if config.filter.is_active() {
+        output.insert_str(0, PARTIAL_OUTPUT_HEADER);
+        spans_shift += PARTIAL_OUTPUT_HEADER.lines().count();
+    }
+    if !modules.is_empty() {
+        output = link::append_modules(output, modules).map_err(|errors| {
+            for e in &errors {
+                eprintln!("Link error: {e}");
+            }
+            format!("Link errors found: {}", errors.len())
+        })?;
+    }
+    if config.lib.is_some() {
+        let mut calls: Vec<&str> = graph
+            .functions()
+            .iter()
+            .flat_map(|f| graph.callees(f))
+            .filter(|callee| !graph.functions().contains(callee))
+            .copied()
+            .collect();
+        calls.sort();
+        calls.dedup();
+        output = link::link_with_library(output, &library, &calls, config.gc_functions).map_err(|errors| {
+            for e in &errors {
+                eprintln!("Link error: {e}");
+            }
+            format!("Link errors found: {}", errors.len())
+        })?;
+    }
+    if prelude.is_some() || epilogue.is_some() {
+        spans_shift += prelude.as_ref().map_or(0, |f| f.text.lines().count());
+        output = link::surround(output, prelude, epilogue).map_err(|errors| {
+            for e in &errors {
+                eprintln!("Link error: {e}");
+            }
+            format!("Link errors found: {}", errors.len())
+        })?;
+    }
+    link::check_single_bootstrap(&output)?;
+    report.record_output(&output, bootstrap);
+    if let Some(max_rom) = config.max_rom.filter(|_| !config.banked) {
+        if report.metrics.instructions > max_rom {
+            return Err(format!(
+                "The program needs {} instructions, more than the {max_rom} ROM words available",
+                report.metrics.instructions
+            )
+            .into());
+        }
+    }
+    check_variables(config, &asm::variable_allocation(&output))?;
+    report.metrics.finish_phase("codegen", started);
 
-    let target_file_name = if source_path.is_file() {
-        source_path.with_extension("asm")
+    let started = Instant::now();
+    if config.banked {
+        write_banks(config, &output, &graph, &stem, &inputs, report)?;
+        report.metrics.finish_phase("write", started);
+        return Ok(());
+    }
+    output = config.compat.finish(output, &manifest);
+    let mut emits = if config.emit.is_empty() {
+        vec![(Emit::Asm, None)]
     } else {
-        let base_name = source_path.file_stem().unwrap();
-        source_path.join(PathBuf::from(base_name).with_extension("asm"))
+        config.emit.clone()
+    };
+    for (kind, path) in [(Emit::Sym, &config.emit_sym), (Emit::Doc, &config.emit_doc), (Emit::Spans, &config.emit_spans)] {
+        if let Some(path) = path {
+            emits.retain(|(k, _)| *k != kind);
+            emits.push((kind, Some(path.clone())));
+        }
+    }
+    for (kind, path) in &mut emits {
+        if *kind == Emit::Asm && path.is_none() {
+            path.clone_from(&asm_path);
+        }
+    }
+    // Assembled once for every artifact that needs it, so the symbol
+    // table is the one the machine code was encoded with.
+    #[cfg(feature = "assembler")]
+    let program = if emits.iter().any(|(kind, _)| matches!(kind, Emit::Hack | Emit::Listing | Emit::Symbols)) {
+        let program = assemble(&output)?;
+        report.set_assembler(Json::object(vec![("symbols", program.symbols_to_json())]));
+        Some(program)
+    } else {
+        None
+    };
+    #[cfg(feature = "assembler")]
+    let assembled = || program.as_ref().expect("assembled above for every kind that needs it");
+    // Work out every artifact before writing any, so a clash leaves
+    // nothing half written.
+    let mut artifacts: Vec<(PathBuf, Vec<u8>)> = Vec::new();
+    for (kind, path) in emits {
+        let path = path.clone().unwrap_or_else(|| artifact_path(&stem, kind));
+        ensure_not_input(&path, &inputs)?;
+        let annotated = || match config.annotate_addresses {
+            Some(format) => asm::annotate_addresses(&output, format),
+            None => output.clone(),
+        };
+        let text = match kind {
+            Emit::Asm => annotated(),
+            Emit::Spans => span_file(&spans, &located, &annotated(), spans_shift).pretty(),
+            Emit::Ast => ast_json.as_ref().map(Json::pretty).unwrap_or_default(),
+            Emit::Doc => outline.render(report.function_sizes(), &lint_diagnostics),
+            Emit::Sym => symbol_file(&output, &graph, &config.codegen, prelude_size),
+            #[cfg(feature = "assembler")]
+            Emit::Hack => assembled().to_hack(),
+            #[cfg(feature = "assembler")]
+            Emit::Listing => assembled().listing(&output),
+            #[cfg(feature = "assembler")]
+            Emit::Symbols => assembled().symbol_table(),
+            #[cfg(feature = "assembler")]
+            Emit::AsmResolved => resolve_symbols(&output, config.codegen.keep_comments)?,
+            #[cfg(not(feature = "assembler"))]
+            Emit::Hack | Emit::Listing | Emit::AsmResolved | Emit::Symbols => unreachable!("rejected by parse_emit"),
+        };
+        artifacts.push((path, text.into_bytes()));
+    }
+
+    for (path, text) in cfg_files {
+        ensure_not_input(&path, &inputs)?;
+        artifacts.push((path, text.into_bytes()));
+    }
+    if let (Some(path), Some(info)) = (&config.emit_debug, &debug_info) {
+        ensure_not_input(path, &inputs)?;
+        artifacts.push((path.clone(), debuginfo::write(info)?));
+    }
+    if let Some(dir) = config.emit_cfg.as_ref().filter(|_| !config.dry_run) {
+        fs::create_dir_all(dir)?;
+    }
+
+    for (path, bytes) in artifacts {
+        progress(config, &format!("output file = {}", path.display()));
+        if !config.dry_run {
+            write_output(&path, &bytes)?;
+        }
+        report.add_output(&path, &bytes);
+    }
+    report.metrics.finish_phase("write", started);
+
+    if error_count > 0 {
+        return Err(format!("Parse errors found: {error_count}").into());
+    }
+
+    if let Some(path) = &config.emit_manifest {
+        ensure_not_input(path, &inputs)?;
+        let manifest = provenance::BuildManifest {
+            translator: provenance::TRANSLATOR_VERSION.to_string(),
+            source: config.source.clone(),
+            options: config.flags.clone(),
+            inputs: report.input_hashes(),
+            outputs: report.output_hashes(),
+        };
+        progress(config, &format!("manifest file = {}", path.display()));
+        fs::write(path, manifest.to_json().pretty())?;
+        report.add_artifact(path)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `stem.bank0.asm`, `stem.bank1.asm`, ... and a `stem.banks.json`
+/// manifest saying which functions went where.
+fn write_banks(
+    config: &Config,
+    output: &str,
+    graph: &callgraph::CallGraph,
+    stem: &Path,
+    inputs: &[PathBuf],
+    report: &mut report::Report,
+) -> Result<(), Box<dyn Error>> {
+    let max_rom = config.max_rom.unwrap_or_default();
+    let shim = match &config.bank_call_shim {
+        Some(path) => Some(
+            fs::read_to_string(path).map_err(|e| format!("Error reading {}: {e}", path.display()))?,
+        ),
+        None => None,
+    };
+    let banks = bank::partition(output, graph, max_rom, shim.as_deref())?;
+
+    let name = |suffix: &str| {
+        let mut name = stem.as_os_str().to_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let paths: Vec<PathBuf> = (0..banks.len()).map(|i| name(&format!(".bank{i}.asm"))).collect();
+    let manifest_path = name(".banks.json");
+    for path in paths.iter().chain([&manifest_path]) {
+        ensure_not_input(path, inputs)?;
+    }
+
+    for (bank, path) in banks.iter().zip(&paths) {
+        progress(config, &format!("output file = {}", path.display()));
+        write_output(path, bank.text.as_bytes())?;
+        report.add_artifact(path)?;
+    }
+    let files: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    write_output(&manifest_path, bank::manifest(&banks, &files, max_rom).pretty().as_bytes())?;
+    report.add_artifact(&manifest_path)?;
+    Ok(())
+}
+
+/// The parsed commands for `--emit ast`, one object per command.
+fn ast_to_json(commands: &[vm::SourceCommand]) -> Json {
+    Json::Array(
+        commands
+            .iter()
+            .map(|sc| {
+                Json::object(vec![
+                    ("file", Json::from(sc.path().display().to_string())),
+                    ("line", Json::from(sc.line())),
+                    ("command", Json::from(sc.command().to_string())),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[cfg(feature = "assembler")]
+fn assemble(output: &str) -> Result<assembler::Program, String> {
+    assembler::assemble(output).map_err(|errors| {
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        format!("Assembly errors found: {}", errors.len())
+    })
+}
+
+#[cfg(feature = "assembler")]
+fn resolve_symbols(output: &str, comments: bool) -> Result<String, String> {
+    assembler::resolve_symbols(output, comments).map_err(|errors| {
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        format!("Assembly errors found: {}", errors.len())
+    })
+}
+
+/// Writes what each `debug.print` reports to stderr.
+fn print_debug_output(machine: &mut interp::Vm) {
+    machine.on_event(|event| {
+        if let interp::Event::DebugPrint { function, command, value } = event {
+            let at = format!("{}:{}", command.path().display(), command.line() + 1);
+            match function {
+                Some(function) => eprintln!("debug.print {at} in {function}: {value}"),
+                None => eprintln!("debug.print {at}: {value}"),
+            }
+        }
+    });
+}
+
+/// `run`: executes the program in the VM interpreter. Exits 1 if the
+/// program faults and 3 if a limit stops it.
+fn run_vm(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut limits = interp::Limits::default();
+    let mut ram_init = interp::RamInit::Zero;
+    let mut assertions: Vec<grade::Assertion> = Vec::new();
+    let mut memory = memory::MemoryMap::default();
+    let mut entry: Option<String> = None;
+    let mut heap_report = false;
+    let mut watches = Vec::new();
+    let mut watch_log = false;
+    let mut screenshots: Vec<Screenshot> = Vec::new();
+    let mut source: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("run", arg)?;
+        if arg == "--entry" {
+            entry = Some(flag_value(arg, args.next())?.clone());
+        } else if arg == "--heap-report" {
+            heap_report = true;
+        } else if arg == "--watch" {
+            for watch in flag_value(arg, args.next())?.split(',') {
+                watches.push(interp::parse_watch(watch)?);
+            }
+        } else if arg == "--watch-log" {
+            watch_log = true;
+        } else if arg == "--screenshot" || arg == "--screenshot-hash" {
+            let path = match arg.as_str() {
+                "--screenshot" => Some(PathBuf::from(flag_value(arg, args.next())?)),
+                _ => None,
+            };
+            screenshots.push(Screenshot { path, step: None });
+        } else if arg == "--at-step" || arg == "--at-halt" {
+            let Some(screenshot) = screenshots.last_mut() else {
+                return Err(format!("{arg} applies to the --screenshot or --screenshot-hash before it").into());
+            };
+            screenshot.step = match arg.as_str() {
+                "--at-step" => {
+                    let value = flag_value(arg, args.next())?;
+                    Some(value.parse().map_err(|_| format!("--at-step expects a number of steps, got '{value}'"))?)
+                }
+                _ => None,
+            };
+        } else if arg == "--max-steps" {
+            let value = flag_value(arg, args.next())?;
+            limits.max_steps = value
+                .parse()
+                .map_err(|_| format!("--max-steps expects a number, got '{value}'"))?;
+        } else if arg == "--timeout" {
+            let value = flag_value(arg, args.next())?;
+            let seconds: f64 = value
+                .parse()
+                .map_err(|_| format!("--timeout expects seconds, got '{value}'"))?;
+            limits.timeout = Some(Duration::from_secs_f64(seconds));
+        } else if arg == "--target" {
+            memory = parse_target(flag_value(arg, args.next())?)?;
+        } else if arg == "--grade" {
+            assertions.extend(grade::parse_assertions(flag_value(arg, args.next())?)?);
+        } else if arg == "--ram-init" {
+            let value = flag_value(arg, args.next())?;
+            ram_init = match value.as_str() {
+                // Pick a seed, and say which so the run can be repeated.
+                "random" => interp::RamInit::Random(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_nanos() as u64),
+                ),
+                value => value.parse()?,
+            };
+        } else {
+            source = Some(positional(source, arg)?);
+        }
+    }
+    let Some(source) = source else {
+        println!("Usage: hack_vmtranslator run [--max-steps <n>] [--timeout <secs>] [--ram-init zero|ones|pattern:<hex>|random[:<seed>]] [--grade \"RAM[a]=v;...\"] [--target default|file:<map.toml>] [--entry <function>] [--heap-report] [--watch <RAM[a]|RAM[a..b]>,... [--watch-log]] [--screenshot <out.pbm> | --screenshot-hash [--at-step <n> | --at-halt]]... <vmfile|directory>");
+        process::exit(2);
     };
-    println!("output file = {}", target_file_name.to_str().unwrap());
-    fs::write(target_file_name, asm.join("\n"))?;
 
+    let config = Config::default();
+    let files = dedupe_inputs(list_files(Path::new(&source)).map_err(|e| e.to_string())?, &config)?;
+    let names = lint::check_file_names(&files);
+    eprint!("{}", diagnostic::render_grouped(&names, config.error_format));
+    if names.iter().any(|d| d.severity == Severity::Error) {
+        return Err("Invalid input file names".into());
+    }
+    let sources = load_sources(&files, &config)?;
+    let (program, _) = extract_and_report_errors(vm::parse_sources(&sources), Vec::new(), false, config.error_format)?;
+
+    if let interp::RamInit::Random(seed) = ram_init {
+        println!("RAM filled with random values (--ram-init random:{seed})");
+    }
+    let stack_base = memory.stack_base as i16;
+    let mut machine = interp::Vm::new(&program, interp::Config { ram_init, memory, entry });
+    print_debug_output(&mut machine);
+    // A watchpoint stops the run at the first write, or with --watch-log
+    // is reported and the run carries on.
+    machine.pause_on_watch(true);
+    for watch in watches {
+        machine.watch(watch);
+    }
+    for step in screenshots.iter().filter_map(|screenshot| screenshot.step) {
+        machine.pause_at_step(step);
+    }
+    let result = loop {
+        let result = machine.run(&limits);
+        let hits = machine.take_watch_hits();
+        for hit in &hits {
+            println!("Watchpoint: {hit}");
+        }
+        for screenshot in screenshots.iter().filter(|s| s.step == Some(machine.steps())) {
+            screenshot.take(&machine)?;
+        }
+        // Otherwise it only paused for a screenshot.
+        if result.is_err() || machine.is_halted() || (!hits.is_empty() && !watch_log) {
+            break result;
+        }
+    };
+    if machine.is_halted() {
+        for screenshot in &screenshots {
+            match screenshot.step {
+                None => screenshot.take(&machine)?,
+                Some(step) if step > machine.steps() => {
+                    eprintln!("No screenshot at step {step}: the program halted after {} steps", machine.steps());
+                }
+                Some(_) => (),
+            }
+        }
+    }
+    if heap_report {
+        println!("Heap: {}", machine.heap());
+    }
+    match result {
+        Ok(()) if !machine.is_halted() => {
+            println!("Stopped at a watchpoint after {} steps", machine.steps());
+            for frame in machine.call_stack().iter().rev() {
+                match &frame.call_site {
+                    Some(site) => println!("  in {} (called from {site})", frame.function),
+                    None => println!("  in {}", frame.function),
+                }
+            }
+            Ok(())
+        }
+        Ok(()) => {
+            let sp = machine.sp();
+            println!("Halted after {} steps", machine.steps());
+            println!("SP = {sp}");
+            if sp > stack_base {
+                println!("top of stack = {}", machine.ram()[sp as usize - 1]);
+            }
+            let mut failed = 0;
+            for assertion in &assertions {
+                let failures = assertion.failures(machine.ram());
+                if failures.is_empty() {
+                    println!("PASS {assertion}");
+                } else {
+                    failed += 1;
+                    println!("FAIL {assertion}");
+                    for (address, expected, actual) in failures {
+                        let actual = actual.map_or("nothing".to_string(), |a| a.to_string());
+                        println!("  RAM[{address}]: expected {expected}, got {actual}");
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{failed} of {} assertions failed", assertions.len()).into());
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            eprint!("{}", e.backtrace);
+            process::exit(match e.kind {
+                interp::ErrorKind::Fault => 1,
+                interp::ErrorKind::StepLimit | interp::ErrorKind::Timeout => 3,
+            });
+        }
+    }
+}
+
+/// `verify`: runs the program in the VM interpreter and its translation
+/// on the CPU in lockstep, and reports the first command whose code
+/// doesn't agree with the interpreter. Exits 1 if one doesn't.
+fn run_verify(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut max_steps = interp::Limits::default().max_steps;
+    let mut history = 8;
+    let mut codegen = asm::CodegenOptions::default();
+    let mut source: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("verify", arg)?;
+        if arg == "--max-steps" || arg == "--history" {
+            let value = flag_value(arg, args.next())?;
+            let n: u64 = value.parse().map_err(|_| format!("{arg} expects a number, got '{value}'"))?;
+            match arg.as_str() {
+                "--max-steps" => max_steps = n,
+                _ => history = n as usize,
+            }
+        } else if arg == "--label-scheme" {
+            codegen.label_scheme = flag_value(arg, args.next())?.parse()?;
+        } else if arg == "--safe-compare" {
+            codegen.safe_compare = true;
+        } else {
+            source = Some(positional(source, arg)?);
+        }
+    }
+    let Some(source) = source else {
+        println!("Usage: hack_vmtranslator verify [--max-steps <n>] [--history <n>] [--label-scheme lines|counters] [--safe-compare] <vmfile|directory>");
+        process::exit(2);
+    };
+
+    let config = Config::default();
+    let files = dedupe_inputs(list_files(Path::new(&source)).map_err(|e| e.to_string())?, &config)?;
+    let sources = load_sources(&files, &config)?;
+    let (program, _) = extract_and_report_errors(vm::parse_sources(&sources), Vec::new(), false, config.error_format)?;
+    let asm = asm::generate_code(program.clone(), &codegen)?.join("\n");
+    match lockstep::run(&program, &asm, interp::Config::default(), max_steps, history)? {
+        lockstep::Outcome::Agreed { steps, halted: true } => println!("Agreed on all {steps} steps"),
+        lockstep::Outcome::Agreed { steps, halted: false } => println!("Agreed on {steps} steps; the program hadn't halted"),
+        lockstep::Outcome::Diverged(divergence) => {
+            print!("{divergence}");
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// `eval`: runs a few lines of VM code given on the command line, or
+/// translates them with `--emit-asm`. A literal `\n` in the snippet
+/// separates lines, so it can be typed in single quotes.
+fn run_eval(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut emit_asm = false;
+    let mut text: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("eval", arg)?;
+        if arg == "--emit-asm" {
+            emit_asm = true;
+        } else if arg == "--file" {
+            let file = flag_value(arg, args.next())?;
+            let read = if file == "-" {
+                let mut input = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut input).map(|_| input)
+            } else {
+                fs::read_to_string(file)
+            };
+            text = Some(positional(text, &read.map_err(|e| format!("Error reading {file}: {e}"))?)?);
+        } else {
+            text = Some(positional(text, &arg.replace("\\n", "\n"))?);
+        }
+    }
+    let Some(text) = text else {
+        println!("Usage: hack_vmtranslator eval [--emit-asm] <vm code> | --file <path|->");
+        process::exit(2);
+    };
+
+    let source = vm::Source::new("eval", text);
+    let commands = match extract_and_report_errors(source.parse(), Vec::new(), false, ErrorFormat::Human) {
+        Ok((commands, _)) => commands,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    };
+    let program = vm::wrap_snippet(&source, commands);
+    if emit_asm {
+        println!("{}", asm::generate_code(program, &asm::CodegenOptions::default())?.join("\n"));
+        return Ok(());
+    }
+
+    let memory = memory::MemoryMap::default();
+    let mut machine = interp::Vm::new(&program, interp::Config::default());
+    print_debug_output(&mut machine);
+    if let Err(e) = machine.run(&interp::Limits::default()) {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    }
+    let sp = machine.sp();
+    println!("SP = {sp}");
+    if sp > memory.stack_base as i16 {
+        println!("top of stack = {}", machine.ram()[sp as usize - 1]);
+    }
+    Ok(())
+}
+
+/// `gen-tests`: writes a Rust test file locking down the translation of
+/// `dir` and of each subdirectory holding `.vm` files. A `.cmp` file
+/// beside the sources adds a test of the final RAM in the interpreter.
+fn run_gen_tests(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut out: Option<PathBuf> = None;
+    let mut dir: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("gen-tests", arg)?;
+        if arg == "--out" {
+            out = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else {
+            dir = Some(positional(dir, arg)?);
+        }
+    }
+    let (Some(dir), Some(out)) = (dir, out) else {
+        println!("Usage: hack_vmtranslator gen-tests <dir> --out <tests/generated.rs>");
+        process::exit(2);
+    };
+
+    let dir = PathBuf::from(dir);
+    let mut dirs = vec![dir.clone()];
+    let mut subdirs: Vec<PathBuf> = fs::read_dir(&dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    subdirs.retain(|path| path.is_dir());
+    subdirs.sort();
+    dirs.extend(subdirs);
+
+    let mut scenarios = Vec::new();
+    for dir in dirs {
+        let files = list_files(&dir).map_err(|e| e.to_string())?;
+        if files.is_empty() {
+            continue;
+        }
+        let mut sources = Vec::new();
+        for file in &files {
+            println!("Reading file {}", file.display());
+            let name = file.file_name().unwrap_or_default();
+            sources.push(vm::Source::new(name, fs::read_to_string(file)?));
+        }
+        let mut cmps: Vec<PathBuf> = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        cmps.retain(|path| path.extension().is_some_and(|ext| ext == "cmp"));
+        cmps.sort();
+        let expected_ram = match cmps.first() {
+            Some(cmp) => Some(grade::parse_cmp(&fs::read_to_string(cmp)?).map_err(|e| format!("{}: {e}", cmp.display()))?),
+            None => None,
+        };
+        scenarios.push(testgen::Scenario {
+            name: vm::file_base(&dir),
+            sources,
+            expected_ram,
+        });
+    }
+
+    let tests = testgen::generate(&scenarios)?;
+    println!("output file = {}", out.display());
+    fs::write(out, tests)?;
+    Ok(())
+}
+
+/// `completions`: prints the tab completion script for a shell.
+fn run_completions(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [shell] = args else {
+        println!("Usage: hack_vmtranslator completions bash|zsh|fish|powershell");
+        process::exit(2);
+    };
+    print!("{}", completions::script(shell.parse()?));
+    Ok(())
+}
+
+/// A `run --screenshot` or `--screenshot-hash`.
+struct Screenshot {
+    /// Where to write the image; `None` prints its hash instead.
+    path: Option<PathBuf>,
+    /// When to take it; `None` once the program halts.
+    step: Option<u64>,
+}
+
+impl Screenshot {
+    fn take(&self, machine: &interp::Vm) -> Result<(), Box<dyn Error>> {
+        let image = machine.screenshot();
+        let when = match self.step {
+            Some(step) => format!("step {step}"),
+            None => format!("halt (step {})", machine.steps()),
+        };
+        match &self.path {
+            Some(path) => {
+                fs::write(path, image).map_err(|e| format!("Error writing {}: {e}", path.display()))?;
+                println!("Screenshot at {when}: {}", path.display());
+            }
+            None => println!("Screen at {when}: sha256 {}", sha256::hex_digest(&image)),
+        }
+        Ok(())
+    }
+}
+
+/// Translates the inputs recorded in a `--emit-manifest` manifest again,
+/// with the recorded options, and fails unless the outputs come out the
+/// same, saying which inputs changed if any did.
+fn run_verify_manifest(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [path] = args else {
+        println!("Usage: hack_vmtranslator verify-manifest <manifest.json>");
+        process::exit(2);
+    };
+    subcommand_flag("verify-manifest", path)?;
+    let text = fs::read_to_string(path).map_err(|e| format!("Error reading {path}: {e}"))?;
+    let manifest = provenance::BuildManifest::parse(&text).map_err(|e| format!("{path}: {e}"))?;
+
+    let mut flags = manifest.options.clone();
+    flags.extend(["--".to_string(), manifest.source.clone()]);
+    let mut config = parse_args(&[(path.clone(), &flags)]).map_err(|e| format!("{e} (from {path})"))?;
+    config.dry_run = true;
+    let mut report = report::Report::default();
+    let result = translate(&config, &mut report);
+
+    let mut inputs: Vec<provenance::FileHash> = manifest
+        .inputs
+        .iter()
+        .filter_map(|input| fs::read(&input.path).ok().map(|contents| provenance::FileHash::new(&input.path, &contents)))
+        .collect();
+    for input in report.input_hashes() {
+        if !inputs.iter().any(|known| known.path == input.path) {
+            inputs.push(input);
+        }
+    }
+    let outputs = match &result {
+        Ok(()) => report.output_hashes(),
+        Err(e) => {
+            eprintln!("Translating again failed: {e}");
+            Vec::new()
+        }
+    };
+    let verification = manifest.verify(&inputs, &outputs);
+    print!("{verification}");
+    if !verification.matches() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Translates each project listed in `--projects-from` into
+/// `<output-dir>/<name>.asm`, reporting each one's diagnostics on stderr
+/// and a JSON summary on stdout. Every project is attempted; the exit
+/// status is 1 if any failed.
+fn run_batch(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut list: Option<PathBuf> = None;
+    let mut out_dir: Option<PathBuf> = None;
+    let mut jobs = std::thread::available_parallelism().map_or(1, usize::from);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("batch", arg)?;
+        if arg == "--projects-from" {
+            list = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--output-dir" {
+            out_dir = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else if arg == "--jobs" {
+            let value = flag_value(arg, args.next())?;
+            jobs = value
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("--jobs must be a positive number, got '{value}'"))?;
+        } else {
+            return Err(format!("Unknown flag: '{arg}'").into());
+        }
+    }
+    let (Some(list), Some(out_dir)) = (list, out_dir) else {
+        println!("Usage: hack_vmtranslator batch --projects-from <list.txt> --output-dir <dir> [--jobs <n>]");
+        process::exit(2);
+    };
+
+    // One directory per line; blank lines and `#` comments are skipped.
+    let text = fs::read_to_string(&list).map_err(|e| format!("Error reading {}: {e}", list.display()))?;
+    let projects: Vec<batch::Project> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(batch::Project::load)
+        .collect();
+    for (i, project) in projects.iter().enumerate() {
+        if let Some(other) = projects[..i].iter().find(|p| p.name == project.name) {
+            return Err(format!(
+                "{} and {} would both be written to {}.asm",
+                other.path.display(),
+                project.path.display(),
+                project.name
+            )
+            .into());
+        }
+    }
+
+    fs::create_dir_all(&out_dir)?;
+    let outcomes = batch::run(&projects, &asm::CodegenOptions::default(), jobs);
+    for outcome in &outcomes {
+        if !outcome.diagnostics.is_empty() {
+            eprintln!("== {}", outcome.name);
+            eprint!("{}", diagnostic::render_grouped(&outcome.diagnostics, ErrorFormat::Human));
+        }
+        if let Some(asm) = &outcome.asm {
+            fs::write(out_dir.join(format!("{}.asm", outcome.name)), asm)?;
+        }
+    }
+    print!("{}", batch::summary(&outcomes).pretty());
+    if outcomes.iter().any(|outcome| !outcome.succeeded()) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Recovers VM code from assembly this translator generated: printed,
+/// file by file, or written into `--out-dir`.
+fn run_lift(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if let Some(flag) = args.first() {
+        subcommand_flag("lift", flag)?;
+    }
+    let (out_dir, path) = match args {
+        [path] => (None, path),
+        [flag, dir, path] if flag == "--out-dir" => (Some(PathBuf::from(dir)), path),
+        _ => {
+            println!("Usage: hack_vmtranslator lift [--out-dir <dir>] <file.asm>");
+            process::exit(2);
+        }
+    };
+    let asm = fs::read_to_string(path).map_err(|e| format!("Error reading {path}: {e}"))?;
+    let lifted = lift::lift(&asm, &asm::CodegenOptions::default());
+
+    for source in lifted.sources() {
+        match &out_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                let path = dir.join(&source.path);
+                fs::write(&path, &source.text)?;
+                eprintln!("output file = {}", path.display());
+            }
+            None => print!("// {}\n{}", source.path.display(), source.text),
+        }
+    }
+    if let Some(entry) = &lifted.entry {
+        eprintln!("The bootstrap calls {entry}");
+    }
+    eprintln!("Lifted {} commands from {path}", lifted.commands());
+    if lifted.unlifted() > 0 {
+        eprintln!(
+            "{} regions aren't code this translator generates; they are kept as asm blocks, so translate the result with --extensions",
+            lifted.unlifted()
+        );
+    }
+    Ok(())
+}
+
+/// Checks a project is set up to translate and prints a checklist,
+/// failing if any check did.
+fn run_doctor(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [path] = args else {
+        println!("Usage: hack_vmtranslator doctor <vmfile|directory>");
+        process::exit(2);
+    };
+    subcommand_flag("doctor", path)?;
+    let path = Path::new(path);
+    let mut checks = Vec::new();
+    let finish = |checks: &[doctor::Check]| {
+        print!("{}", doctor::render(checks));
+        if doctor::failed(checks) {
+            process::exit(1);
+        }
+    };
+
+    if !path.exists() {
+        checks.push(doctor::Check::new(
+            doctor::Status::Error,
+            format!("{} exists", path.display()),
+            Some("Check the path; give the directory holding the .vm files, or one .vm file".to_string()),
+        ));
+        finish(&checks);
+        return Ok(());
+    }
+    checks.push(doctor::Check::new(doctor::Status::Pass, format!("{} exists", path.display()), None));
+
+    let files: Vec<PathBuf> = vm::discover_sources(path)?
+        .into_iter()
+        .filter(|file| file.extension().is_some_and(|ext| ext == "vm"))
+        .collect();
+    if files.is_empty() {
+        checks.push(doctor::Check::new(
+            doctor::Status::Error,
+            "There are .vm files to translate",
+            Some(format!("{} has none; point at the directory the compiler wrote them to", path.display())),
+        ));
+        finish(&checks);
+        return Ok(());
+    }
+    checks.push(doctor::Check::new(
+        doctor::Status::Pass,
+        format!("There are .vm files to translate ({})", files.len()),
+        None,
+    ));
+
+    let mut sources = Vec::new();
+    for file in &files {
+        sources.push(vm::Source::new(file, fs::read_to_string(file)?));
+    }
+    let mut commands = Vec::new();
+    let mut errors = Vec::new();
+    for result in vm::parse_sources(&sources) {
+        match result {
+            Ok(sc) => commands.push(sc),
+            Err(e) => errors.push(Diagnostic::from(&e)),
+        }
+    }
+    checks.push(match errors.first() {
+        None => doctor::Check::new(doctor::Status::Pass, "Every command parses", None),
+        Some(first) => doctor::Check::new(
+            doctor::Status::Error,
+            "Every command parses",
+            Some(format!("{} parse errors, the first: {first}", errors.len())),
+        ),
+    });
+    checks.extend(doctor::examine(&commands, &memory::MemoryMap::default()));
+
+    let output = if path.is_file() {
+        path.with_extension("asm")
+    } else {
+        let mut name = path.join(dir_name(path)).into_os_string();
+        name.push(".asm");
+        PathBuf::from(name)
+    };
+    let writable = if output.exists() {
+        fs::OpenOptions::new().append(true).open(&output).map(drop)
+    } else {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&output)
+            .and_then(|_| fs::remove_file(&output))
+    };
+    checks.push(match writable {
+        Ok(()) => doctor::Check::new(doctor::Status::Pass, format!("{} can be written", output.display()), None),
+        Err(e) => doctor::Check::new(
+            doctor::Status::Error,
+            format!("{} can be written", output.display()),
+            Some(format!("{e}; fix the permissions or translate a copy somewhere writable")),
+        ),
+    });
+
+    finish(&checks);
+    Ok(())
+}
+
+fn run_fmt(args: &[String]) -> Result<(), Box<dyn Error>> {
+    const USAGE: &str = "Usage: hack_vmtranslator fmt [--check] <vmfile|directory>...";
+    let mut check = false;
+    let mut paths: Vec<&String> = Vec::new();
+    for arg in args {
+        if let Err(err) = subcommand_flag("fmt", arg) {
+            println!("Argument Error: {err}");
+            println!("{USAGE}");
+            process::exit(2);
+        }
+        if arg == "--check" {
+            check = true;
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.is_empty() {
+        println!("{USAGE}");
+        process::exit(2);
+    }
+    if let Some(missing) = paths.iter().find(|path| !Path::new(path).exists()) {
+        return Err(format!("Error reading {missing}: no such file or directory").into());
+    }
+
+    let mut failed = false;
+    let mut files = Vec::new();
+    for path in paths {
+        files.extend(list_files(Path::new(path)).map_err(|e| e.to_string())?);
+    }
+    for file in files {
+        let name = vm::file_base(&file);
+        let source = fs::read_to_string(&file)?;
+
+        match formatter::format_source(&name, &file, &source) {
+            Ok(formatted) if formatted == source => (),
+            Ok(_) if check => {
+                println!("Would reformat {}", file.display());
+                failed = true;
+            }
+            Ok(formatted) => {
+                fs::write(&file, formatted)?;
+                println!("Formatted {}", file.display());
+            }
+            Err(errors) => {
+                for e in errors {
+                    println!("{}", e);
+                }
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_link(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut output: Option<PathBuf> = None;
+    let mut fragments: Vec<link::Fragment> = Vec::new();
+    let mut inputs: Vec<PathBuf> = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        subcommand_flag("link", arg)?;
+        if arg == "-o" {
+            output = Some(PathBuf::from(flag_value(arg, args.next())?));
+        } else {
+            println!("Reading fragment {}", arg);
+            let text = fs::read_to_string(arg).map_err(|e| format!("Error reading {arg}: {e}"))?;
+            inputs.push(PathBuf::from(arg));
+            fragments.push(link::Fragment {
+                name: arg.clone(),
+                text,
+            });
+        }
+    }
+
+    let output = match output {
+        Some(output) if !fragments.is_empty() => output,
+        _ => {
+            println!("Usage: hack_vmtranslator link <fragment.asm>... -o <program.asm>");
+            process::exit(2);
+        }
+    };
+
+    let program = link::link(fragments).map_err(|errors| {
+        for e in &errors {
+            println!("{}", e);
+        }
+        format!("Link errors found: {}", errors.len())
+    })?;
+    link::check_single_bootstrap(&program)?;
+    println!("output file = {}", output.display());
+    ensure_not_input(&output, &inputs).map_err(|e| e.to_string())?;
+    fs::write(output, program)?;
     Ok(())
 }
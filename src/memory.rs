@@ -0,0 +1,233 @@
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
+use std::path::Path;
+
+/// Where each part of the VM lives in RAM. The default is the standard
+/// Hack layout the course tools expect; other maps describe modified
+/// hardware. SP, LCL and ARG (RAM[0..3]) and the scratch registers
+/// R13-R15 stay where they are, since generated code names them.
+///
+#[cfg_attr(feature = "interp", doc = "```")]
+#[cfg_attr(not(feature = "interp"), doc = "```ignore")]
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+/// use hack_vmtranslator::interp::{Config, Limits, Vm};
+/// use hack_vmtranslator::memory::MemoryMap;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let sources = [Source::new("Main.vm", "push constant 7\npop temp 1\npush constant 9\npop static 0\n")];
+/// let program = || -> Vec<_> { parse_sources(&sources).into_iter().map(Result::unwrap).collect() };
+/// let map = MemoryMap { pointer_size: 8, temp_base: 16, static_base: 24, ..MemoryMap::default() };
+///
+/// let standard = generate_code(program(), &CodegenOptions::default()).unwrap().join("\n");
+/// let shifted = CodegenOptions { memory: map.clone(), ..CodegenOptions::default() };
+/// let shifted = generate_code(program(), &shifted).unwrap().join("\n");
+/// assert!(standard.contains("@6\n") && !standard.contains("@17\n"));
+/// assert!(shifted.contains("@17\n") && !shifted.contains("@6\n"));
+///
+/// let program = program();
+/// let mut vm = Vm::new(&program, Config { memory: map, ..Config::default() });
+/// vm.run(&Limits::default()).unwrap();
+/// assert_eq!((vm.ram()[17], vm.ram()[24]), (7, 9));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// `pointer i` is RAM[pointer_base + i]. Pointers 0 and 1 should stay
+    /// THIS and THAT (RAM[3] and RAM[4]) for the `this` and `that`
+    /// segments to follow them.
+    pub pointer_base: u16,
+    pub pointer_size: u16,
+    /// `temp i` is RAM[temp_base + i].
+    pub temp_base: u16,
+    pub temp_size: u16,
+    /// Statics take RAM[static_base..static_limit].
+    pub static_base: u16,
+    pub static_limit: u16,
+    /// The stack grows from stack_base up to stack_limit, where the heap
+    /// starts.
+    pub stack_base: u16,
+    pub stack_limit: u16,
+    pub screen: u16,
+    pub keyboard: u16,
+}
+
+impl Default for MemoryMap {
+    fn default() -> MemoryMap {
+        MemoryMap {
+            pointer_base: 3,
+            pointer_size: 2,
+            temp_base: 5,
+            temp_size: 8,
+            static_base: 16,
+            static_limit: 256,
+            stack_base: 256,
+            stack_limit: 2048,
+            screen: 16384,
+            keyboard: 24576,
+        }
+    }
+}
+
+/// Words of screen memory.
+const SCREEN_SIZE: u16 = 8192;
+
+impl MemoryMap {
+    /// Every region as `(name, start, end)`, end excluded.
+    fn regions(&self) -> Vec<(&'static str, u32, u32)> {
+        let region = |name, base: u16, size: u16| (name, base as u32, base as u32 + size as u32);
+        vec![
+            region("SP, LCL and ARG", 0, 3),
+            region("pointer", self.pointer_base, self.pointer_size),
+            region("temp", self.temp_base, self.temp_size),
+            region("R13-R15", 13, 3),
+            (
+                "static",
+                self.static_base as u32,
+                self.static_limit as u32,
+            ),
+            ("stack", self.stack_base as u32, self.stack_limit as u32),
+            region("screen", self.screen, SCREEN_SIZE),
+            region("keyboard", self.keyboard, 1),
+        ]
+    }
+
+    /// Rejects regions that overlap or end before they start.
+    ///
+    /// ```
+    /// use hack_vmtranslator::memory::MemoryMap;
+    ///
+    /// assert!(MemoryMap::default().validate().is_ok());
+    ///
+    /// let map = MemoryMap { temp_base: 12, ..MemoryMap::default() };
+    /// assert_eq!(map.validate().unwrap_err(), "temp (12-19) overlaps R13-R15 (13-15)");
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        let regions = self.regions();
+        for (name, start, end) in &regions {
+            if end < start {
+                return Err(format!("{name} ends at {end}, before it starts at {start}"));
+            }
+        }
+        for (i, (a, a_start, a_end)) in regions.iter().enumerate() {
+            for (b, b_start, b_end) in &regions[i + 1..] {
+                if a_start < b_end && b_start < a_end {
+                    return Err(format!(
+                        "{a} ({a_start}-{}) overlaps {b} ({b_start}-{})",
+                        a_end - 1,
+                        b_end - 1
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a map file: `key = number` lines naming the fields to change
+    /// from the default, with `#` comments. The result is validated.
+    ///
+    /// ```
+    /// use hack_vmtranslator::memory::MemoryMap;
+    /// use std::path::Path;
+    ///
+    /// let text = "# eight pointer registers\npointer_size = 8\ntemp_base = 16\nstatic_base = 24\n";
+    /// let map = MemoryMap::parse(Path::new("map.toml"), text).unwrap();
+    /// assert_eq!((map.pointer_size, map.temp_base, map.static_base), (8, 16, 24));
+    ///
+    /// let errors = MemoryMap::parse(Path::new("map.toml"), "temp = 16\n").unwrap_err();
+    /// assert_eq!(errors[0].line, Some(0));
+    /// assert!(MemoryMap::parse(Path::new("map.toml"), "temp_base = 2\n").is_err());
+    /// ```
+    pub fn parse(path: &Path, text: &str) -> Result<MemoryMap, Vec<Diagnostic>> {
+        let mut map = MemoryMap::default();
+        let mut errors = Vec::new();
+        let error = |line: Option<usize>, source: &str, message: String| {
+            let mut error = Diagnostic::error("Memory map error", message).in_file(path.display().to_string());
+            error.line = line;
+            error.source = Some(source.to_string()).filter(|s| !s.is_empty());
+            error
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or_default().trim();
+            if code.is_empty() {
+                continue;
+            }
+            if let Err(message) = map.set(code) {
+                errors.push(error(Some(i), code, message));
+            }
+        }
+        if errors.is_empty() {
+            if let Err(message) = map.validate() {
+                errors.push(error(None, "", message));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(map)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The map as an object with a member for every field, named as in
+    /// map files. `from_json` reads it back.
+    pub fn to_json(&self) -> Json {
+        let mut map = self.clone();
+        Json::Object(
+            map.fields()
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), Json::from(*value as usize)))
+                .collect(),
+        )
+    }
+
+    /// Reads an object like `to_json` writes, where members left out keep
+    /// their default. The result is validated.
+    pub fn from_json(json: &Json) -> Result<MemoryMap, String> {
+        let Json::Object(members) = json else {
+            return Err("the memory map must be an object".to_string());
+        };
+        let mut map = MemoryMap::default();
+        for (key, value) in members {
+            let value = match value {
+                Json::Number(n) => u16::try_from(*n).map_err(|_| format!("{key} must be an address from 0 to 65535, got {n}"))?,
+                _ => return Err(format!("{key} must be a number")),
+            };
+            *map.field(key)? = value;
+        }
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn set(&mut self, code: &str) -> Result<(), String> {
+        let (key, value) = code.split_once('=').ok_or("Expected key = number")?;
+        let (key, value) = (key.trim(), value.trim());
+        let value: u16 = value
+            .parse()
+            .map_err(|_| format!("{key} must be an address from 0 to 65535, got '{value}'"))?;
+        *self.field(key)? = value;
+        Ok(())
+    }
+
+    fn field(&mut self, key: &str) -> Result<&mut u16, String> {
+        self.fields()
+            .into_iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, field)| field)
+            .ok_or_else(|| format!("Unknown memory map key '{key}'"))
+    }
+
+    fn fields(&mut self) -> [(&'static str, &mut u16); 10] {
+        [
+            ("pointer_base", &mut self.pointer_base),
+            ("pointer_size", &mut self.pointer_size),
+            ("temp_base", &mut self.temp_base),
+            ("temp_size", &mut self.temp_size),
+            ("static_base", &mut self.static_base),
+            ("static_limit", &mut self.static_limit),
+            ("stack_base", &mut self.stack_base),
+            ("stack_limit", &mut self.stack_limit),
+            ("screen", &mut self.screen),
+            ("keyboard", &mut self.keyboard),
+        ]
+    }
+}
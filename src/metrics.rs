@@ -0,0 +1,75 @@
+use crate::json::Json;
+use std::time::{Duration, Instant};
+
+/// What a translation run cost, phase by phase. Filled in as the run
+/// goes, so a failed run still reports the phases it finished.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Wall-clock time per phase, in the order the phases ran.
+    pub phases: Vec<(&'static str, Duration)>,
+    pub input_lines: usize,
+    pub commands: usize,
+    /// Instructions in the emitted assembly, i.e. the ROM words it needs.
+    pub instructions: usize,
+    /// A rough upper bound on the bytes held at once: the source texts
+    /// plus the generated assembly.
+    pub peak_bytes: usize,
+}
+
+impl Metrics {
+    /// Records `phase` as having run from `started` until now.
+    pub fn finish_phase(&mut self, phase: &'static str, started: Instant) {
+        self.phases.push((phase, started.elapsed()));
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, d)| *d).sum()
+    }
+
+    /// A small table for `--timings`.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (phase, duration) in &self.phases {
+            out.push_str(&format!("{phase:<10} {:>10.3} ms\n", millis(*duration)));
+        }
+        out.push_str(&format!("{:<10} {:>10.3} ms\n", "total", millis(self.total())));
+        out.push_str(&format!(
+            "{} lines, {} commands, {} instructions, ~{} bytes peak\n",
+            self.input_lines, self.commands, self.instructions, self.peak_bytes
+        ));
+        out
+    }
+
+    /// The `metrics` object of the JSON report. Without `timings` the
+    /// phases are left out, since they differ from run to run.
+    pub fn to_json(&self, timings: bool) -> Json {
+        let mut members = Vec::new();
+        if timings {
+            members.push((
+                "phases",
+                Json::Array(
+                    self.phases
+                        .iter()
+                        .map(|(phase, duration)| {
+                            Json::object(vec![
+                                ("name", Json::from(*phase)),
+                                ("micros", Json::from(duration.as_micros() as u64)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ));
+        }
+        members.extend([
+            ("input_lines", Json::from(self.input_lines)),
+            ("commands", Json::from(self.commands)),
+            ("instructions", Json::from(self.instructions)),
+            ("peak_bytes", Json::from(self.peak_bytes)),
+        ]);
+        Json::object(members)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
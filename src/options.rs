@@ -0,0 +1,104 @@
+//! The settings `--debug` and `--release` start from. Each preset is a
+//! whole `Options` value, so what it turns on can be read and tested here;
+//! flags given alongside a preset are applied on top of it and win.
+
+use crate::asm::{self, AddressFormat, CodegenOptions, Comments};
+
+/// Everything a preset decides: the code generator's options and what is
+/// written around the assembly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub codegen: CodegenOptions,
+    /// Leaves anything that varies between identical runs, such as
+    /// timings, out of the artifacts.
+    pub reproducible: bool,
+    /// A program needing more ROM words than this is an error.
+    pub max_rom: Option<usize>,
+    /// Writes each instruction's ROM address after it.
+    pub annotate_addresses: Option<AddressFormat>,
+    /// Writes a listing next to the assembly.
+    pub listing: bool,
+    /// Writes each command's place in the assembly next to it, as
+    /// `--emit-spans` does.
+    pub source_map: bool,
+}
+
+impl Options {
+    /// `--release`, for the program that ships: no comments, no stack
+    /// checks, artifacts that are the same from run to run, and a
+    /// program too big for the Hack ROM is an error. Everything else is
+    /// the default, so no listing or source map is written.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{self, CodegenOptions, Comments};
+    /// use hack_vmtranslator::options::Options;
+    ///
+    /// let release = Options::release();
+    /// assert_eq!(
+    ///     release,
+    ///     Options {
+    ///         codegen: CodegenOptions { comments: Comments::Off, stack_checks: false, ..CodegenOptions::default() },
+    ///         reproducible: true,
+    ///         max_rom: Some(asm::ROM_SIZE),
+    ///         ..Options::default()
+    ///     }
+    /// );
+    ///
+    /// // A setting given on top of the preset wins.
+    /// let checked = Options { max_rom: None, ..Options::release() };
+    /// assert_eq!((checked.max_rom, checked.reproducible), (None, true));
+    /// ```
+    pub fn release() -> Options {
+        Options {
+            codegen: CodegenOptions {
+                comments: Comments::Off,
+                stack_checks: false,
+                ..CodegenOptions::default()
+            },
+            reproducible: true,
+            max_rom: Some(asm::ROM_SIZE),
+            ..Options::default()
+        }
+    }
+
+    /// `--debug`, for finding out what went wrong: every command's header
+    /// names its function and stack depth, every function checks its
+    /// stack, every instruction is annotated with its ROM address in hex,
+    /// and a listing (when the assembler is built in) and a source map
+    /// are written next to the assembly.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{AddressFormat, CodegenOptions, Comments};
+    /// use hack_vmtranslator::options::Options;
+    ///
+    /// let debug = Options::debug();
+    /// assert_eq!(
+    ///     debug,
+    ///     Options {
+    ///         codegen: CodegenOptions { comments: Comments::Annotated, stack_checks: true, ..CodegenOptions::default() },
+    ///         annotate_addresses: Some(AddressFormat::Hex),
+    ///         listing: cfg!(feature = "assembler"),
+    ///         source_map: true,
+    ///         ..Options::default()
+    ///     }
+    /// );
+    /// assert!(!debug.reproducible && debug.max_rom.is_none());
+    ///
+    /// let mut quiet = Options::debug();
+    /// quiet.codegen.comments = Comments::Headers;
+    /// assert_eq!((quiet.codegen.comments, quiet.codegen.stack_checks), (Comments::Headers, true));
+    /// ```
+    pub fn debug() -> Options {
+        Options {
+            codegen: CodegenOptions {
+                comments: Comments::Annotated,
+                stack_checks: true,
+                ..CodegenOptions::default()
+            },
+            annotate_addresses: Some(AddressFormat::Hex),
+            listing: cfg!(feature = "assembler"),
+            source_map: true,
+            ..Options::default()
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::diagnostic::Diagnostic;
+use crate::lint;
+use std::path::{Path, PathBuf};
+
+/// The project file looked for beside the input.
+pub const FILE_NAME: &str = "hackvm.toml";
+
+/// What a key's value means on the command line.
+#[derive(Clone, Copy)]
+enum Kind {
+    /// `debug` or `release`.
+    Preset,
+    /// `true` passes the flag.
+    Switch(&'static str),
+    /// A string passed as the flag's value.
+    Text(&'static str),
+    /// Like `Text`, but relative to the project file's directory.
+    Path(&'static str),
+    /// A string joined to the flag with `=`.
+    Joined(&'static str),
+    Number(&'static str),
+    /// An array of strings passed comma separated.
+    List(&'static str),
+}
+
+/// Every key the project file accepts, by section.
+const KEYS: [(&str, &str, Kind); 42] = [
+    ("", "preset", Kind::Preset),
+    ("inputs", "allow_asm_modules", Kind::Switch("--allow-asm-modules")),
+    ("inputs", "lib", Kind::Path("--lib")),
+    ("inputs", "gc_functions", Kind::Switch("--gc-functions")),
+    ("inputs", "keep_going", Kind::Switch("--keep-going")),
+    ("inputs", "fail_fast", Kind::Switch("--fail-fast")),
+    ("inputs", "max_line_length", Kind::Number("--max-line-length")),
+    ("output", "path", Kind::Path("--output")),
+    ("output", "emit", Kind::List("--emit")),
+    ("output", "emit_sym", Kind::Path("--emit-sym")),
+    ("output", "emit_doc", Kind::Path("--emit-doc")),
+    ("output", "emit_debug", Kind::Path("--emit-debug")),
+    ("output", "emit_spans", Kind::Path("--emit-spans")),
+    ("output", "emit_cfg", Kind::Path("--emit-cfg")),
+    ("output", "emit_cfg_for", Kind::Text("--emit-cfg-for")),
+    ("output", "emit_manifest", Kind::Path("--emit-manifest")),
+    ("output", "report_json", Kind::Path("--report-json")),
+    ("output", "annotate_addresses", Kind::Joined("--annotate-addresses")),
+    ("output", "error_format", Kind::Joined("--error-format")),
+    ("output", "reproducible", Kind::Switch("--reproducible")),
+    ("output", "keep_comments", Kind::Switch("--keep-comments")),
+    ("output", "comments", Kind::Text("--comments")),
+    ("output", "segment_report", Kind::Switch("--segment-report")),
+    ("output", "summary", Kind::Switch("--summary")),
+    ("codegen", "symbol_prefix", Kind::Text("--symbol-prefix")),
+    ("codegen", "extern", Kind::List("--extern")),
+    ("codegen", "function_order", Kind::Text("--function-order")),
+    ("codegen", "compat", Kind::Text("--compat")),
+    ("codegen", "label_scheme", Kind::Text("--label-scheme")),
+    ("codegen", "safe_compare", Kind::Switch("--safe-compare")),
+    ("codegen", "stack_checks", Kind::Switch("--stack-checks")),
+    ("codegen", "entry", Kind::Text("--entry")),
+    ("codegen", "entry_extern", Kind::Switch("--entry-extern")),
+    ("codegen", "bootstrap_mode", Kind::Joined("--bootstrap-mode")),
+    ("codegen", "extensions", Kind::Switch("--extensions")),
+    ("codegen", "include_once", Kind::Switch("--include-once")),
+    ("codegen", "max_rom", Kind::Number("--max-rom")),
+    ("codegen", "banked", Kind::Switch("--banked")),
+    ("codegen", "bank_call_shim", Kind::Path("--bank-call-shim")),
+    ("codegen", "stack_report", Kind::Switch("--stack-report")),
+    ("codegen", "prelude", Kind::Path("--prelude")),
+    ("codegen", "epilogue", Kind::Path("--epilogue")),
+];
+
+/// The project file for `input`: `hackvm.toml` in the input directory, or
+/// beside an input file.
+///
+/// ```
+/// use hack_vmtranslator::project::discover;
+/// use std::fs;
+///
+/// let dir = std::env::temp_dir().join(format!("project_discover_{}", std::process::id()));
+/// fs::create_dir_all(&dir).unwrap();
+/// assert_eq!(discover(&dir), None);
+///
+/// fs::write(dir.join("hackvm.toml"), "").unwrap();
+/// assert_eq!(discover(&dir), Some(dir.join("hackvm.toml")));
+/// assert_eq!(discover(&dir.join("Main.vm")), Some(dir.join("hackvm.toml")));
+/// fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn discover(input: &Path) -> Option<PathBuf> {
+    let dir = if input.is_dir() { input } else { input.parent()? };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    Some(dir.join(FILE_NAME)).filter(|file| file.is_file())
+}
+
+/// Reads a project file into the command-line flags it stands for, so
+/// that flags given on the command line can follow and override them.
+///
+/// The file is a small subset of TOML: `[section]` headers and
+/// `key = value` lines, where a value is a string, `true` or `false`, a
+/// number, or an array of strings on one line. `#` starts a comment.
+/// Sections are `inputs`, `output`, `codegen` and `lints`; `preset` at the
+/// top chooses `debug` or `release`, and each lint under `[lints]` is
+/// `allow`, `warn` or `deny`.
+///
+/// ```
+/// use hack_vmtranslator::project::parse;
+/// use std::path::Path;
+///
+/// let text = "preset = \"release\"\n\
+///             [output]\n\
+///             emit = [\"asm\", \"hack\"]  # both\n\
+///             report_json = \"build/report.json\"\n\
+///             [lints]\n\
+///             static-gaps = \"deny\"\n";
+/// let flags = parse(Path::new("game/hackvm.toml"), text).unwrap();
+/// assert_eq!(
+///     flags,
+///     ["--release", "--emit", "asm,hack", "--report-json", "game/build/report.json", "-D", "static-gaps"]
+/// );
+///
+/// let errors = parse(Path::new("hackvm.toml"), "[output]\nemitt = [\"asm\"]\n").unwrap_err();
+/// assert_eq!(errors[0].line, Some(1));
+/// assert_eq!(errors[0].message, "Unknown key 'emitt' in [output]");
+/// ```
+pub fn parse(path: &Path, text: &str) -> Result<Vec<String>, Vec<Diagnostic>> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut section = String::new();
+    let mut flags = Vec::new();
+    let mut errors = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let code = strip_comment(line).trim();
+        if code.is_empty() {
+            continue;
+        }
+        let result = match code.strip_prefix('[').and_then(|c| c.strip_suffix(']')) {
+            Some(name) => {
+                section = name.trim().to_string();
+                match section.as_str() {
+                    "inputs" | "output" | "codegen" | "lints" => Ok(Vec::new()),
+                    _ => Err(format!("Unknown section [{section}]")),
+                }
+            }
+            None => setting(&section, code, base),
+        };
+        match result {
+            Ok(more) => flags.extend(more),
+            Err(message) => {
+                let mut error = Diagnostic::error("Config error", message).in_file(path.display().to_string());
+                error.line = Some(i);
+                error.source = Some(code.to_string());
+                errors.push(error);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(flags)
+    } else {
+        Err(errors)
+    }
+}
+
+fn setting(section: &str, code: &str, base: &Path) -> Result<Vec<String>, String> {
+    let (key, value) = code.split_once('=').ok_or("Expected key = value")?;
+    let (key, value) = (key.trim(), Value::parse(value.trim())?);
+
+    if section == "lints" {
+        if !lint::OPT_IN.contains(&key) {
+            return Err(format!("Unknown lint '{key}' (available: {})", lint::OPT_IN.join(", ")));
+        }
+        return match value.text()? {
+            "allow" => Ok(Vec::new()),
+            "warn" => Ok(vec!["-W".to_string(), key.to_string()]),
+            "deny" => Ok(vec!["-D".to_string(), key.to_string()]),
+            other => Err(format!("Lint level must be allow, warn or deny, got '{other}'")),
+        };
+    }
+
+    let kind = KEYS
+        .iter()
+        .find(|(s, k, _)| *s == section && *k == key)
+        .map(|(_, _, kind)| *kind)
+        .ok_or_else(|| match section {
+            "" => format!("Unknown key '{key}'"),
+            _ => format!("Unknown key '{key}' in [{section}]"),
+        })?;
+
+    Ok(match kind {
+        Kind::Preset => match value.text()? {
+            "debug" | "release" => vec![format!("--{}", value.text()?)],
+            other => return Err(format!("preset must be debug or release, got '{other}'")),
+        },
+        Kind::Switch(flag) => match value {
+            Value::Bool(true) => vec![flag.to_string()],
+            Value::Bool(false) => Vec::new(),
+            _ => return Err(format!("{key} must be true or false")),
+        },
+        Kind::Text(flag) => vec![flag.to_string(), value.text()?.to_string()],
+        Kind::Path(flag) => vec![flag.to_string(), base.join(value.text()?).display().to_string()],
+        Kind::Joined(flag) => vec![format!("{flag}={}", value.text()?)],
+        Kind::Number(flag) => match value {
+            Value::Number(n) => vec![flag.to_string(), n.to_string()],
+            _ => return Err(format!("{key} must be a number")),
+        },
+        Kind::List(flag) => match value {
+            Value::List(items) => vec![flag.to_string(), items.join(",")],
+            _ => return Err(format!("{key} must be an array of strings")),
+        },
+    })
+}
+
+enum Value {
+    Bool(bool),
+    Number(u64),
+    Text(String),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn parse(text: &str) -> Result<Value, String> {
+        match text {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => (),
+        }
+        if let Ok(n) = text.parse::<u64>() {
+            return Ok(Value::Number(n));
+        }
+        if let Some(items) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(string)
+                .collect::<Result<_, _>>()
+                .map(Value::List);
+        }
+        string(text).map(Value::Text)
+    }
+
+    fn text(&self) -> Result<&str, String> {
+        match self {
+            Value::Text(text) => Ok(text),
+            _ => Err("Expected a string".to_string()),
+        }
+    }
+}
+
+/// A double-quoted string, with `\"` and `\\` escapes.
+fn string(text: &str) -> Result<String, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected a string, number, boolean or array, got '{text}'"))?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' { chars.next().ok_or("Unfinished escape")? } else { c });
+    }
+    Ok(out)
+}
+
+/// `line` up to a `#` that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
@@ -0,0 +1,269 @@
+//! Build manifests, which record what a translation was made from and what
+//! it produced, so that an archived `.asm` can be checked against its
+//! sources later: `--emit-manifest` writes one, and `verify-manifest`
+//! translates the recorded inputs again and compares.
+
+use crate::json::Json;
+use crate::sha256;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The version written into every manifest.
+pub const TRANSLATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Bumped whenever a field is removed or changes meaning.
+pub const MANIFEST_VERSION: usize = 1;
+
+/// A file and the SHA-256 of its contents, in hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHash {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+impl FileHash {
+    pub fn new(path: impl Into<PathBuf>, contents: &[u8]) -> FileHash {
+        FileHash {
+            path: path.into(),
+            sha256: sha256::hex_digest(contents),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildManifest {
+    pub translator: String,
+    /// The input as it was given on the command line. Paths here are as
+    /// given too, so a manifest is verified from the directory it was
+    /// written in.
+    pub source: String,
+    /// Every flag that went into the output, with its value, in the order
+    /// applied: the project file's, then `HACK_VM_FLAGS`, then the
+    /// command line.
+    pub options: Vec<String>,
+    /// In the order they were read.
+    pub inputs: Vec<FileHash>,
+    pub outputs: Vec<FileHash>,
+}
+
+impl BuildManifest {
+    /// The manifest as `--emit-manifest` writes it.
+    ///
+    /// ```
+    /// use hack_vmtranslator::provenance::{BuildManifest, FileHash, TRANSLATOR_VERSION};
+    ///
+    /// let manifest = BuildManifest {
+    ///     translator: TRANSLATOR_VERSION.to_string(),
+    ///     source: "Prog".to_string(),
+    ///     options: vec!["--label-scheme".to_string(), "lines".to_string()],
+    ///     inputs: vec![FileHash::new("Prog/Main.vm", b"push constant 1\n")],
+    ///     outputs: vec![FileHash::new("Prog/Prog.asm", b"@1\n")],
+    /// };
+    /// let text = manifest.to_json().pretty();
+    /// assert!(text.contains("\"sha256\": \"a1a8f5e4"));
+    /// assert_eq!(BuildManifest::parse(&text).unwrap(), manifest);
+    ///
+    /// assert_eq!(BuildManifest::parse("{\"manifest_version\": 1}").unwrap_err(), "missing \"translator\"");
+    /// ```
+    pub fn to_json(&self) -> Json {
+        let files = |files: &[FileHash]| {
+            Json::Array(
+                files
+                    .iter()
+                    .map(|file| {
+                        Json::object(vec![
+                            ("path", Json::from(file.path.display().to_string())),
+                            ("sha256", Json::from(file.sha256.as_str())),
+                        ])
+                    })
+                    .collect(),
+            )
+        };
+        Json::object(vec![
+            ("manifest_version", Json::from(MANIFEST_VERSION)),
+            ("translator", Json::from(self.translator.as_str())),
+            ("source", Json::from(self.source.as_str())),
+            ("options", Json::Array(self.options.iter().map(|o| Json::from(o.as_str())).collect())),
+            ("inputs", files(&self.inputs)),
+            ("outputs", files(&self.outputs)),
+        ])
+    }
+
+    pub fn parse(text: &str) -> Result<BuildManifest, String> {
+        let json = Json::parse(text)?;
+        match json.get("manifest_version") {
+            Some(Json::Number(n)) if *n == MANIFEST_VERSION as i64 => (),
+            Some(Json::Number(n)) => return Err(format!("manifest version {n} isn't supported (expected {MANIFEST_VERSION})")),
+            _ => return Err("missing \"manifest_version\"".to_string()),
+        }
+        let string = |json: &Json, key: &str| {
+            json.get(key)
+                .and_then(Json::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format!("missing \"{key}\""))
+        };
+        let array = |key: &str| json.get(key).and_then(Json::as_array).ok_or_else(|| format!("missing \"{key}\""));
+        let files = |key: &str| -> Result<Vec<FileHash>, String> {
+            array(key)?
+                .iter()
+                .map(|file| {
+                    Ok(FileHash {
+                        path: PathBuf::from(string(file, "path")?),
+                        sha256: string(file, "sha256")?,
+                    })
+                })
+                .collect()
+        };
+        Ok(BuildManifest {
+            translator: string(&json, "translator")?,
+            source: string(&json, "source")?,
+            options: array("options")?
+                .iter()
+                .map(|o| o.as_str().map(str::to_string).ok_or("\"options\" must be strings".to_string()))
+                .collect::<Result<_, _>>()?,
+            inputs: files("inputs")?,
+            outputs: files("outputs")?,
+        })
+    }
+
+    /// Compares a fresh translation with the one recorded. `inputs` are
+    /// the files as they are now: the recorded ones that can still be
+    /// read, and any the fresh run read that weren't recorded. `outputs`
+    /// are what the recorded options make of them, or nothing if the
+    /// translation failed.
+    ///
+    /// ```
+    /// use hack_vmtranslator::provenance::{BuildManifest, FileHash, TRANSLATOR_VERSION};
+    ///
+    /// let main = FileHash::new("Prog/Main.vm", b"push constant 1\n");
+    /// let sys = FileHash::new("Prog/Sys.vm", b"function Sys.init 0\n");
+    /// let asm = FileHash::new("Prog/Prog.asm", b"@1\n");
+    /// let manifest = BuildManifest {
+    ///     translator: TRANSLATOR_VERSION.to_string(),
+    ///     source: "Prog".to_string(),
+    ///     options: Vec::new(),
+    ///     inputs: vec![main.clone(), sys.clone()],
+    ///     outputs: vec![asm.clone()],
+    /// };
+    ///
+    /// let same = manifest.verify(&[main.clone(), sys.clone()], &[asm.clone()]);
+    /// assert!(same.matches());
+    /// assert_eq!(same.to_string(), "ok: Prog/Prog.asm matches; 2 inputs unchanged\n");
+    ///
+    /// // A comment added: a different input, but the same output.
+    /// let commented = FileHash::new("Prog/Sys.vm", b"function Sys.init 0 // start\n");
+    /// assert_eq!(
+    ///     manifest.verify(&[main.clone(), commented], &[asm.clone()]).to_string(),
+    ///     "ok: Prog/Prog.asm matches; 1 input unchanged\n\
+    ///      \x20 changed input: Prog/Sys.vm\n"
+    /// );
+    ///
+    /// // A source edited since, and one deleted.
+    /// let edited = FileHash::new("Prog/Main.vm", b"push constant 2\n");
+    /// let tampered = manifest.verify(&[edited], &[FileHash::new("Prog/Prog.asm", b"@2\n")]);
+    /// assert!(!tampered.matches());
+    /// assert_eq!(
+    ///     tampered.to_string(),
+    ///     "MISMATCH: Prog/Prog.asm differs from the recorded output\n\
+    ///      \x20 changed input: Prog/Main.vm\n\
+    ///      \x20 missing input: Prog/Sys.vm\n"
+    /// );
+    ///
+    /// // The same sources, translated differently: the options in the
+    /// // manifest were edited.
+    /// let drifted = manifest.verify(&[main, sys], &[FileHash::new("Prog/Prog.asm", b"@1\n@2\n")]);
+    /// assert_eq!(
+    ///     drifted.to_string(),
+    ///     "MISMATCH: Prog/Prog.asm differs from the recorded output\n\
+    ///      \x20 no input changed, so the options or the translator did\n"
+    /// );
+    /// ```
+    pub fn verify(&self, inputs: &[FileHash], outputs: &[FileHash]) -> Verification {
+        let now = |path: &PathBuf| inputs.iter().find(|file| file.path == *path);
+        let mut verification = Verification {
+            outputs: self.outputs.iter().map(|file| file.path.clone()).collect(),
+            unchanged_inputs: 0,
+            changed_inputs: Vec::new(),
+            missing_inputs: Vec::new(),
+            new_inputs: Vec::new(),
+            changed_outputs: Vec::new(),
+            other_translator: (self.translator != TRANSLATOR_VERSION).then(|| self.translator.clone()),
+        };
+        for recorded in &self.inputs {
+            match now(&recorded.path) {
+                Some(file) if file.sha256 == recorded.sha256 => verification.unchanged_inputs += 1,
+                Some(_) => verification.changed_inputs.push(recorded.path.clone()),
+                None => verification.missing_inputs.push(recorded.path.clone()),
+            }
+        }
+        for file in inputs {
+            if !self.inputs.iter().any(|recorded| recorded.path == file.path) {
+                verification.new_inputs.push(file.path.clone());
+            }
+        }
+        for recorded in &self.outputs {
+            if !outputs.contains(recorded) {
+                verification.changed_outputs.push(recorded.path.clone());
+            }
+        }
+        verification
+    }
+}
+
+/// What `BuildManifest::verify` found. The outputs decide; the inputs say
+/// why they differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub outputs: Vec<PathBuf>,
+    pub unchanged_inputs: usize,
+    pub changed_inputs: Vec<PathBuf>,
+    pub missing_inputs: Vec<PathBuf>,
+    /// Read by the fresh run but not recorded, such as a file added to
+    /// the input directory since.
+    pub new_inputs: Vec<PathBuf>,
+    /// Recorded outputs the fresh run produced differently, or not at all.
+    pub changed_outputs: Vec<PathBuf>,
+    /// The version that wrote the manifest, when it isn't this one.
+    pub other_translator: Option<String>,
+}
+
+impl Verification {
+    pub fn matches(&self) -> bool {
+        self.changed_outputs.is_empty()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths = |paths: &[PathBuf]| paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ");
+        if self.matches() {
+            let inputs = match self.unchanged_inputs {
+                1 => "1 input".to_string(),
+                n => format!("{n} inputs"),
+            };
+            let verb = if self.outputs.len() == 1 { "matches" } else { "match" };
+            writeln!(f, "ok: {} {verb}; {inputs} unchanged", paths(&self.outputs))?;
+        } else {
+            let verb = if self.changed_outputs.len() == 1 { "differs" } else { "differ" };
+            writeln!(f, "MISMATCH: {} {verb} from the recorded output", paths(&self.changed_outputs))?;
+        }
+        for path in &self.changed_inputs {
+            writeln!(f, "  changed input: {}", path.display())?;
+        }
+        for path in &self.missing_inputs {
+            writeln!(f, "  missing input: {}", path.display())?;
+        }
+        for path in &self.new_inputs {
+            writeln!(f, "  new input: {}", path.display())?;
+        }
+        // Inputs can change without changing the output, say in a comment.
+        let inputs_changed = !(self.changed_inputs.is_empty() && self.missing_inputs.is_empty() && self.new_inputs.is_empty());
+        if !self.matches() && !inputs_changed {
+            writeln!(f, "  no input changed, so the options or the translator did")?;
+        }
+        if let Some(version) = &self.other_translator {
+            writeln!(f, "  recorded by translator {version}; this is {TRANSLATOR_VERSION}")?;
+        }
+        Ok(())
+    }
+}
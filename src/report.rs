@@ -0,0 +1,296 @@
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
+use crate::metrics::Metrics;
+use crate::provenance::FileHash;
+use crate::sha256;
+use crate::vm::{Command, Segment, SourceCommand};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever a field is removed or changes meaning.
+pub const SCHEMA_VERSION: usize = 1;
+
+struct InputFile {
+    path: PathBuf,
+    sha256: String,
+    lines: usize,
+}
+
+struct Artifact {
+    path: PathBuf,
+    bytes: u64,
+    sha256: String,
+}
+
+/// Collects what CI wants to know about a translation run, filled in
+/// stage by stage so a partial report can still be written on failure.
+#[derive(Default)]
+pub struct Report {
+    inputs: Vec<InputFile>,
+    functions: Vec<String>,
+    function_sizes: Vec<(String, usize)>,
+    /// The entry in `function_sizes` output lines are counted towards.
+    current_function: Option<usize>,
+    statics: BTreeSet<String>,
+    bootstrap: bool,
+    warnings: Vec<String>,
+    /// Each error with the file it is in, if any.
+    errors: Vec<(Option<String>, String)>,
+    artifacts: Vec<Artifact>,
+    /// From `--segment-report`.
+    segments: Option<Json>,
+    /// `asm::SizeEstimate` of the translated program.
+    size: Option<Json>,
+    /// What the built-in assembler resolved, when it ran.
+    assembler: Option<Json>,
+    /// `CodegenOptions::to_json` of the options the program was translated with.
+    options: Option<Json>,
+    /// Counts and timings, shared with `--timings`.
+    pub metrics: Metrics,
+    /// Leaves the timings out, so identical runs write identical reports.
+    pub reproducible: bool,
+}
+
+impl Report {
+    pub fn add_input(&mut self, path: &Path, text: &str) {
+        self.inputs.push(InputFile {
+            path: path.to_path_buf(),
+            sha256: sha256::hex_digest(text.as_bytes()),
+            lines: text.lines().count(),
+        });
+        self.metrics.input_lines += text.lines().count();
+        self.metrics.peak_bytes += text.len();
+    }
+
+    pub fn record_commands(&mut self, commands: &[SourceCommand]) {
+        self.metrics.commands = commands.len();
+        for sc in commands {
+            match sc.command() {
+                Command::Function { name, .. } => self.functions.push(name.to_string()),
+                Command::Push { segment: Segment::Static, index }
+                | Command::Pop { segment: Segment::Static, index } => {
+                    self.statics.insert(format!("{}.{index}", sc.file_base()));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    pub fn record_output(&mut self, asm: &str, bootstrap: bool) {
+        self.current_function = None;
+        for line in asm.lines() {
+            self.record_output_line(line);
+        }
+        self.bootstrap = bootstrap;
+        self.metrics.peak_bytes += asm.len();
+    }
+
+    /// `record_output` a line at a time, for output that is written as
+    /// it's generated; `set_bootstrap` says the rest.
+    pub fn record_output_line(&mut self, line: &str) {
+        if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            if self.functions.iter().any(|f| f == label) {
+                self.function_sizes.push((label.to_string(), 0));
+                self.current_function = Some(self.function_sizes.len() - 1);
+            }
+        } else if crate::asm::is_instruction(line) {
+            self.metrics.instructions += 1;
+            if let Some(i) = self.current_function {
+                self.function_sizes[i].1 += 1;
+            }
+        }
+    }
+
+    pub fn set_bootstrap(&mut self, bootstrap: bool) {
+        self.bootstrap = bootstrap;
+    }
+
+    /// Instructions per function, from `record_output`.
+    pub fn function_sizes(&self) -> &[(String, usize)] {
+        &self.function_sizes
+    }
+
+    pub fn set_segments(&mut self, segments: Json) {
+        self.segments = Some(segments);
+    }
+
+    pub fn set_size(&mut self, size: Json) {
+        self.size = Some(size);
+    }
+
+    pub fn set_assembler(&mut self, assembler: Json) {
+        self.assembler = Some(assembler);
+    }
+
+    pub fn set_options(&mut self, options: Json) {
+        self.options = Some(options);
+    }
+
+    pub fn add_warning(&mut self, warning: &str) {
+        self.warnings.push(warning.to_string());
+    }
+
+    pub fn add_error(&mut self, error: &Diagnostic) {
+        self.errors.push((error.file.clone(), error.to_string()));
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /// Records the error that stopped the run, unless the errors behind it
+    /// were already recorded one by one.
+    pub fn fail(&mut self, error: &Diagnostic) {
+        if self.errors.is_empty() {
+            self.add_error(error);
+        }
+    }
+
+    pub fn add_artifact(&mut self, path: &Path) -> io::Result<()> {
+        let contents = fs::read(path)?;
+        self.add_output(path, &contents);
+        Ok(())
+    }
+
+    /// Records an artifact from `contents`, whether or not it was written
+    /// to `path`.
+    pub fn add_output(&mut self, path: &Path, contents: &[u8]) {
+        self.artifacts.push(Artifact {
+            path: path.to_path_buf(),
+            bytes: contents.len() as u64,
+            sha256: sha256::hex_digest(contents),
+        });
+    }
+
+    pub fn input_hashes(&self) -> Vec<FileHash> {
+        self.inputs
+            .iter()
+            .map(|input| FileHash {
+                path: input.path.clone(),
+                sha256: input.sha256.clone(),
+            })
+            .collect()
+    }
+
+    pub fn output_hashes(&self) -> Vec<FileHash> {
+        self.artifacts
+            .iter()
+            .map(|artifact| FileHash {
+                path: artifact.path.clone(),
+                sha256: artifact.sha256.clone(),
+            })
+            .collect()
+    }
+
+    /// Renders the report; `error` marks the run as failed.
+    pub fn to_json(&self, error: Option<&str>) -> Json {
+        let mut members = vec![
+            ("schema_version", Json::from(SCHEMA_VERSION)),
+            ("status", Json::from(if error.is_some() { "failed" } else { "ok" })),
+        ];
+        if let Some(error) = error {
+            members.push(("error", Json::from(error)));
+        }
+        members.push((
+            "inputs",
+            Json::Array(
+                self.inputs
+                    .iter()
+                    .map(|input| {
+                        Json::object(vec![
+                            ("path", Json::from(input.path.display().to_string())),
+                            ("sha256", Json::from(input.sha256.as_str())),
+                            ("lines", Json::from(input.lines)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+        members.push((
+            "functions",
+            Json::Array(
+                self.function_sizes
+                    .iter()
+                    .map(|(name, size)| {
+                        Json::object(vec![
+                            ("name", Json::from(name.as_str())),
+                            ("instructions", Json::from(*size)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+        members.push(("rom_size", Json::from(self.metrics.instructions)));
+        members.push(("static_slots", Json::from(self.statics.len())));
+        members.push(("bootstrap", Json::from(self.bootstrap)));
+        members.push((
+            "warnings",
+            Json::Array(self.warnings.iter().map(|w| Json::from(w.as_str())).collect()),
+        ));
+        members.push((
+            "errors",
+            Json::Array(self.errors.iter().map(|(_, e)| Json::from(e.as_str())).collect()),
+        ));
+        members.push((
+            "artifacts",
+            Json::Array(
+                self.artifacts
+                    .iter()
+                    .map(|artifact| {
+                        Json::object(vec![
+                            ("path", Json::from(artifact.path.display().to_string())),
+                            ("bytes", Json::from(artifact.bytes)),
+                            ("sha256", Json::from(artifact.sha256.as_str())),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+        if let Some(segments) = &self.segments {
+            members.push(("segments", segments.clone()));
+        }
+        if let Some(size) = &self.size {
+            members.push(("size", size.clone()));
+        }
+        if let Some(assembler) = &self.assembler {
+            members.push(("assembler", assembler.clone()));
+        }
+        if let Some(options) = &self.options {
+            members.push(("options", options.clone()));
+        }
+        members.push(("metrics", self.metrics.to_json(!self.reproducible)));
+
+        Json::object(members)
+    }
+
+    /// The one line `--summary` prints: counts and artifacts on success,
+    /// or how many errors there were and in how many files.
+    pub fn summary(&self) -> String {
+        if !self.errors.is_empty() {
+            let files: BTreeSet<&str> = self.errors.iter().filter_map(|(file, _)| file.as_deref()).collect();
+            return match files.len() {
+                0 => format!("error: {}", count(self.errors.len(), "error")),
+                n => format!("error: {} in {}", count(self.errors.len(), "error"), count(n, "file")),
+            };
+        }
+        let artifacts: Vec<String> = self.artifacts.iter().map(|a| a.path.display().to_string()).collect();
+        format!(
+            "ok: {}, {}, {}, {}, wrote {}",
+            count(self.inputs.len(), "file"),
+            count(self.metrics.commands, "command"),
+            count(self.metrics.instructions, "instruction"),
+            count(self.warnings.len(), "warning"),
+            artifacts.join(", ")
+        )
+    }
+}
+
+/// `n` and `noun`, plural unless `n` is 1.
+fn count(n: usize, noun: &str) -> String {
+    match n {
+        1 => format!("1 {noun}"),
+        n => format!("{n} {noun}s"),
+    }
+}
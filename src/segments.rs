@@ -0,0 +1,176 @@
+use crate::callgraph::function_bodies;
+use crate::json::Json;
+use crate::vm::{Command, Segment, SourceCommand};
+use std::collections::BTreeMap;
+
+/// The highest `local` and `argument` index a function touches, which
+/// tells how big its frame really is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameUse {
+    pub function: String,
+    pub max_local: Option<u16>,
+    pub max_argument: Option<u16>,
+}
+
+/// How often each segment and each (segment, index) pair is pushed and
+/// popped, for `--segment-report`.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentUsage {
+    /// `(pushes, pops)` per segment name.
+    pub segments: BTreeMap<String, (usize, usize)>,
+    /// Pushes plus pops per segment and index.
+    pub pairs: BTreeMap<(String, u16), usize>,
+    /// One entry per function, in source order.
+    pub frames: Vec<FrameUse>,
+}
+
+impl SegmentUsage {
+    /// ```
+    /// use hack_vmtranslator::segments::SegmentUsage;
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    ///
+    /// let text = "function Main.f 2\npush argument 1\npush local 0\nadd\npop local 1\n\
+    ///             push local 0\npush constant 3\nreturn\n\
+    ///             function Main.g 0\npush constant 0\npush constant 1\nreturn\n";
+    /// let sources = [Source::new("Main.vm", text)];
+    /// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let usage = SegmentUsage::collect(&commands);
+    ///
+    /// assert_eq!(
+    ///     usage.render(2),
+    ///     "segment       push    pop\n\
+    ///      argument         1      0\n\
+    ///      constant         3      0\n\
+    ///      local            2      1\n\
+    ///      \n\
+    ///      most used           count\n\
+    ///      local 0                 2\n\
+    ///      argument 1              1\n\
+    ///      \n\
+    ///      function         max local  max argument\n\
+    ///      Main.f                   1             1\n\
+    ///      Main.g                   -             -\n"
+    /// );
+    /// ```
+    pub fn collect(commands: &[SourceCommand]) -> SegmentUsage {
+        let mut usage = SegmentUsage::default();
+
+        for (function, sc) in function_bodies(commands) {
+            if let Command::Function { name, .. } = sc.command() {
+                usage.frames.push(FrameUse {
+                    function: name.to_string(),
+                    max_local: None,
+                    max_argument: None,
+                });
+            }
+            let (segment, index, push) = match sc.command() {
+                Command::Push { segment, index } => (segment, *index, true),
+                Command::Pop { segment, index } => (segment, *index, false),
+                _ => continue,
+            };
+
+            let counts = usage.segments.entry(segment.to_string()).or_default();
+            if push {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+            // Constants are values rather than places, so their indices
+            // say nothing about storage.
+            if *segment != Segment::Constant {
+                *usage.pairs.entry((segment.to_string(), index)).or_default() += 1;
+            }
+
+            let frame = usage.frames.last_mut().filter(|f| Some(f.function.as_str()) == function);
+            if let Some(frame) = frame {
+                let max = match segment {
+                    Segment::Local => &mut frame.max_local,
+                    Segment::Argument => &mut frame.max_argument,
+                    _ => continue,
+                };
+                *max = Some(max.map_or(index, |m| m.max(index)));
+            }
+        }
+
+        usage
+    }
+
+    /// The `top` most used (segment, index) pairs, most used first.
+    pub fn most_used(&self, top: usize) -> Vec<(&str, u16, usize)> {
+        let mut pairs: Vec<(&str, u16, usize)> =
+            self.pairs.iter().map(|((segment, index), count)| (segment.as_str(), *index, *count)).collect();
+        pairs.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(&b.1)));
+        pairs.truncate(top);
+        pairs
+    }
+
+    /// The text printed by `--segment-report`, listing the `top` most used
+    /// pairs.
+    pub fn render(&self, top: usize) -> String {
+        let mut out = format!("{:<10} {:>7} {:>6}\n", "segment", "push", "pop");
+        for (segment, (pushes, pops)) in &self.segments {
+            out.push_str(&format!("{segment:<10} {pushes:>7} {pops:>6}\n"));
+        }
+        out.push_str(&format!("\n{:<18} {:>6}\n", "most used", "count"));
+        for (segment, index, count) in self.most_used(top) {
+            out.push_str(&format!("{:<18} {count:>6}\n", format!("{segment} {index}")));
+        }
+        out.push_str(&format!("\n{:<15} {:>10} {:>13}\n", "function", "max local", "max argument"));
+        let max = |m: Option<u16>| m.map_or("-".to_string(), |m| m.to_string());
+        for frame in &self.frames {
+            out.push_str(&format!(
+                "{:<15} {:>10} {:>13}\n",
+                frame.function,
+                max(frame.max_local),
+                max(frame.max_argument)
+            ));
+        }
+        out
+    }
+
+    /// The `segments` object of the JSON report.
+    pub fn to_json(&self, top: usize) -> Json {
+        let counts = self
+            .segments
+            .iter()
+            .map(|(segment, (pushes, pops))| {
+                Json::object(vec![
+                    ("segment", Json::from(segment.as_str())),
+                    ("push", Json::from(*pushes)),
+                    ("pop", Json::from(*pops)),
+                ])
+            })
+            .collect();
+        let most_used = self
+            .most_used(top)
+            .into_iter()
+            .map(|(segment, index, count)| {
+                Json::object(vec![
+                    ("segment", Json::from(segment)),
+                    ("index", Json::from(index as usize)),
+                    ("count", Json::from(count)),
+                ])
+            })
+            .collect();
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let mut members = vec![("function", Json::from(frame.function.as_str()))];
+                if let Some(max) = frame.max_local {
+                    members.push(("max_local", Json::from(max as usize)));
+                }
+                if let Some(max) = frame.max_argument {
+                    members.push(("max_argument", Json::from(max as usize)));
+                }
+                Json::object(members)
+            })
+            .collect();
+
+        Json::object(vec![
+            ("counts", Json::Array(counts)),
+            ("most_used", Json::Array(most_used)),
+            ("frames", Json::Array(frames)),
+        ])
+    }
+}
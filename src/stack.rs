@@ -0,0 +1,180 @@
+use crate::callgraph::{function_bodies, CallGraph};
+use crate::vm::{Command, SourceCommand};
+use std::collections::BTreeMap;
+
+/// Words `call` saves on the stack: the return address, LCL, ARG, THIS
+/// and THAT.
+pub const CALL_OVERHEAD: usize = 5;
+
+/// Stack use of one function.
+#[derive(Debug, Clone)]
+pub struct FunctionStack {
+    pub name: String,
+    /// Locals plus the deepest the operand stack gets in the body.
+    pub frame: usize,
+    /// The most stack the function can need, counting everything it calls,
+    /// or `None` when it can reach a recursive cycle and so has no bound.
+    pub depth: Option<usize>,
+}
+
+/// The result of `analyze`.
+#[derive(Debug, Clone, Default)]
+pub struct StackReport {
+    /// Every defined function, in source order.
+    pub functions: Vec<FunctionStack>,
+    /// Recursive cycles, each listed from the function where it was found
+    /// back round to it.
+    pub cycles: Vec<Vec<String>>,
+}
+
+impl StackReport {
+    pub fn function(&self, name: &str) -> Option<&FunctionStack> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// The text printed by `--stack-report`. `limit` is the number of words
+    /// the stack has room for.
+    pub fn render(&self, limit: usize) -> String {
+        let mut out = format!("{:<40} {:>6} {:>8}\n", "function", "frame", "depth");
+        for f in &self.functions {
+            let depth = f.depth.map_or("unbounded".to_string(), |d| d.to_string());
+            out.push_str(&format!("{:<40} {:>6} {:>8}\n", f.name, f.frame, depth));
+        }
+        for cycle in &self.cycles {
+            out.push_str(&format!("recursion: {}\n", cycle.join(" -> ")));
+        }
+        out.push_str(&format!("stack space: {limit} words\n"));
+        out
+    }
+}
+
+/// Estimates how deep the stack can get below each function. Each call
+/// costs `CALL_OVERHEAD` words plus the callee's frame, the operand stack
+/// bound comes from walking the body in order (ignoring jumps), and calls
+/// to functions that aren't defined, such as the OS, count only the
+/// overhead.
+///
+/// ```
+/// use hack_vmtranslator::callgraph::CallGraph;
+/// use hack_vmtranslator::stack::analyze;
+/// use hack_vmtranslator::vm::{parse_sources, Source};
+///
+/// let text = "function Main.a 2\ncall Main.b 0\nreturn\n\
+///             function Main.b 0\npush constant 1\nreturn\n\
+///             function Main.loop 0\ncall Main.loop 0\nreturn\n";
+/// let sources = [Source::new("Main.vm", text)];
+/// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+/// let report = analyze(&commands, &CallGraph::build(&commands));
+///
+/// // Main.a: 2 locals + 1 return value, then 5 saved words + Main.b's frame of 1.
+/// assert_eq!(report.function("Main.a").unwrap().depth, Some(9));
+/// assert_eq!(report.function("Main.loop").unwrap().depth, None);
+/// assert_eq!(report.cycles, vec![vec!["Main.loop", "Main.loop"]]);
+/// ```
+pub fn analyze(commands: &[SourceCommand], graph: &CallGraph) -> StackReport {
+    let frames = frame_sizes(commands);
+    let mut analysis = Analysis {
+        graph,
+        frames: &frames,
+        depths: BTreeMap::new(),
+        path: Vec::new(),
+        cycles: Vec::new(),
+    };
+    for function in graph.functions() {
+        analysis.depth(function);
+    }
+
+    StackReport {
+        functions: graph
+            .functions()
+            .iter()
+            .map(|name| FunctionStack {
+                name: name.to_string(),
+                frame: frames.get(name).copied().unwrap_or(0),
+                depth: analysis.depths.get(name).copied().flatten(),
+            })
+            .collect(),
+        cycles: analysis.cycles,
+    }
+}
+
+fn frame_sizes<'a>(commands: &[SourceCommand<'a>]) -> BTreeMap<&'a str, usize> {
+    let mut frames: BTreeMap<&'a str, usize> = BTreeMap::new();
+    let mut operands: usize = 0;
+    let mut locals: usize = 0;
+
+    for (function, sc) in function_bodies(commands) {
+        let Some(function) = function else { continue };
+        if let Command::Function { nvars, .. } = sc.command() {
+            operands = 0;
+            locals = *nvars as usize;
+        }
+        operands = operands.saturating_add_signed(stack_effect(sc.command()));
+        let frame = frames.entry(function).or_default();
+        *frame = (*frame).max(locals + operands);
+    }
+
+    frames
+}
+
+/// How many words `command` leaves on the operand stack, less those it
+/// takes off. A function's locals aren't counted, nor is what `return`
+/// leaves in the caller.
+pub fn stack_effect(command: &Command) -> isize {
+    match command {
+        Command::Push { .. } => 1,
+        Command::Pop { .. } | Command::IfGoto(_) => -1,
+        Command::Add
+        | Command::Sub
+        | Command::And
+        | Command::Or
+        | Command::Eq
+        | Command::Gt
+        | Command::Lt => -1,
+        Command::Call { nargs, .. } => 1 - *nargs as isize,
+        _ => 0,
+    }
+}
+
+struct Analysis<'g, 'a> {
+    graph: &'g CallGraph<'a>,
+    frames: &'g BTreeMap<&'a str, usize>,
+    /// `None` once a function is known to reach recursion.
+    depths: BTreeMap<&'a str, Option<usize>>,
+    /// Functions being worked on, outermost first.
+    path: Vec<&'a str>,
+    cycles: Vec<Vec<String>>,
+}
+
+impl<'g, 'a> Analysis<'g, 'a> {
+    fn depth(&mut self, function: &'a str) -> Option<usize> {
+        if let Some(depth) = self.depths.get(function) {
+            return *depth;
+        }
+        if let Some(start) = self.path.iter().position(|f| *f == function) {
+            let mut cycle: Vec<String> = self.path[start..].iter().map(|f| f.to_string()).collect();
+            cycle.push(function.to_string());
+            self.cycles.push(cycle);
+            return None;
+        }
+        let Some(frame) = self.frames.get(function).copied() else {
+            // Not defined here: just the cost of calling it.
+            return Some(0);
+        };
+
+        self.path.push(function);
+        let mut deepest = Some(0);
+        for callee in self.graph.callees(function) {
+            let below = self.depth(callee).map(|d| CALL_OVERHEAD + d);
+            deepest = match (deepest, below) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+        }
+        self.path.pop();
+
+        let depth = deepest.map(|d| frame + d);
+        self.depths.insert(function, depth);
+        depth
+    }
+}
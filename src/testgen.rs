@@ -0,0 +1,114 @@
+use crate::asm::{generate_code, CodegenOptions};
+use crate::grade::Assertion;
+use crate::vm::{parse_sources, Source};
+
+/// One program to lock down: its sources, and optionally the RAM the
+/// course's `.cmp` file expects once it has run.
+pub struct Scenario {
+    /// Names the generated tests.
+    pub name: String,
+    pub sources: Vec<Source>,
+    pub expected_ram: Option<Vec<Assertion>>,
+}
+
+/// A Rust test file for `scenarios`, as written by `gen-tests`. Each
+/// scenario gets a test comparing the translation against the assembly
+/// produced now, and, with expected RAM, one running it in the
+/// interpreter. The tests use only the public library API.
+///
+/// ```
+/// use hack_vmtranslator::grade::parse_assertions;
+/// use hack_vmtranslator::testgen::{generate, Scenario};
+/// use hack_vmtranslator::vm::Source;
+///
+/// let scenario = Scenario {
+///     name: "Simple Add".to_string(),
+///     sources: vec![Source::new("Simple.vm", "// \"quoted\" \\ comment\npush constant 7\n")],
+///     expected_ram: Some(parse_assertions("RAM[0]=257; RAM[256]=7").unwrap()),
+/// };
+/// let tests = generate(&[scenario]).unwrap();
+///
+/// assert!(tests.contains("#[test]\nfn simple_add_asm() {"));
+/// assert!(tests.contains("#[test]\nfn simple_add_ram() {"));
+/// assert!(tests.contains(r#"Source::new("Simple.vm", "// \"quoted\" \\ comment\npush constant 7\n")"#));
+/// assert!(tests.contains(r#"parse_assertions("RAM[0]=257; RAM[256]=7")"#));
+/// assert_eq!(generate(&[]).unwrap(), generate(&[]).unwrap());
+/// ```
+pub fn generate(scenarios: &[Scenario]) -> Result<String, String> {
+    let mut out = String::from(
+        "// Generated by `hack_vmtranslator gen-tests`. Regenerate rather than edit.\n\
+         \n\
+         fn parse(sources: &[hack_vmtranslator::vm::Source]) -> Vec<hack_vmtranslator::vm::SourceCommand<'_>> {\n\
+         \x20   hack_vmtranslator::vm::parse_sources(sources).into_iter().map(|c| c.expect(\"parses\")).collect()\n\
+         }\n",
+    );
+    let mut names: Vec<String> = Vec::new();
+
+    for scenario in scenarios {
+        let mut name = identifier(&scenario.name);
+        if names.contains(&name) {
+            name = format!("{name}_{}", names.len() + 1);
+        }
+        names.push(name.clone());
+
+        let commands = parse_sources(&scenario.sources)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let asm = generate_code(commands, &CodegenOptions::default())?.join("\n");
+        let sources: String = scenario
+            .sources
+            .iter()
+            .map(|s| format!("        hack_vmtranslator::vm::Source::new({:?}, {:?}),\n", s.path.display().to_string(), s.text))
+            .collect();
+
+        out.push_str(&format!(
+            "\n#[test]\n\
+             fn {name}_asm() {{\n\
+             \x20   let sources = [\n{sources}    ];\n\
+             \x20   let options = hack_vmtranslator::asm::CodegenOptions::default();\n\
+             \x20   let asm = hack_vmtranslator::asm::generate_code(parse(&sources), &options).unwrap().join(\"\\n\");\n\
+             \x20   assert_eq!(asm, {asm:?});\n\
+             }}\n"
+        ));
+
+        if let Some(expected) = &scenario.expected_ram {
+            let assertions: Vec<String> = expected.iter().map(Assertion::to_string).collect();
+            out.push_str(&format!(
+                "\n#[test]\n\
+                 fn {name}_ram() {{\n\
+                 \x20   let sources = [\n{sources}    ];\n\
+                 \x20   let program = parse(&sources);\n\
+                 \x20   let mut vm = hack_vmtranslator::interp::Vm::new(&program, Default::default());\n\
+                 \x20   vm.run(&Default::default()).unwrap();\n\
+                 \x20   for assertion in hack_vmtranslator::grade::parse_assertions({:?}).unwrap() {{\n\
+                 \x20       assert!(assertion.failures(vm.ram()).is_empty(), \"{{assertion}}\");\n\
+                 \x20   }}\n\
+                 }}\n",
+                assertions.join("; ")
+            ));
+        }
+    }
+    Ok(out)
+}
+
+/// `name` as a snake_case Rust identifier.
+fn identifier(name: &str) -> String {
+    let mut ident = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            if c.is_ascii_uppercase() && ident.chars().last().is_some_and(|p| p.is_ascii_lowercase()) {
+                ident.push('_');
+            }
+            ident.push(c.to_ascii_lowercase());
+        } else if !ident.ends_with('_') {
+            ident.push('_');
+        }
+    }
+    let ident = ident.trim_matches('_').to_string();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("scenario_{ident}")
+    } else {
+        ident
+    }
+}
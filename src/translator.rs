@@ -0,0 +1,230 @@
+//! Translation a file at a time, for callers that produce VM code as they
+//! go, such as a compiler emitting one class after another. Each file is
+//! parsed and checked as it is added; the program-wide work waits for
+//! `finish`.
+
+use crate::asm::{self, CodegenOptions, Manifest};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::lint;
+use crate::vm::{Command, Source, SourceCommand};
+use std::path::PathBuf;
+
+/// The files added so far. A `Translator` is `Send` and `Sync`, and all
+/// its state is its own, so separate programs can be translated on
+/// separate threads at once.
+///
+/// ```
+/// use hack_vmtranslator::asm::CodegenOptions;
+/// use hack_vmtranslator::fuzz::Generator;
+/// use hack_vmtranslator::translator::Translator;
+///
+/// let translate = |seed: u64| {
+///     let mut translator = Translator::new();
+///     for source in Generator::new(seed).sources() {
+///         translator.add_source(source.path, source.text).unwrap();
+///     }
+///     translator.finish(&CodegenOptions::default()).unwrap().asm
+/// };
+/// let alone: Vec<String> = (0..16).map(translate).collect();
+/// let threads: Vec<_> = (0..16).map(|seed| std::thread::spawn(move || translate(seed))).collect();
+/// let together: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+/// assert_eq!(together, alone);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Translator {
+    sources: Vec<Source>,
+    /// For sources added from now on, if not `vm::MAX_LINE_LENGTH`.
+    max_line_length: Option<usize>,
+}
+
+/// What `add_source` found in one file.
+#[derive(Debug, Clone)]
+pub struct FileSummary {
+    /// The file's static prefix; see `vm::file_base`.
+    pub name: String,
+    pub commands: usize,
+    /// The functions the file defines, in order.
+    pub functions: Vec<String>,
+    /// How many distinct static indices it uses.
+    pub statics: usize,
+    /// Warnings and notes; errors make `add_source` fail instead.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A translated program.
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// The assembly, without the metadata block.
+    pub asm: String,
+    /// Describes `asm`; the command line appends it as a comment block.
+    pub manifest: Manifest,
+}
+
+impl Translator {
+    pub fn new() -> Translator {
+        Translator::default()
+    }
+
+    /// Parses the file at `path` and runs the checks that need only it.
+    /// A file that doesn't parse is still kept, so that `finish` reports
+    /// it too and callers that carry on past errors can; a second file
+    /// with the same static prefix is rejected and not kept.
+    ///
+    /// ```
+    /// use hack_vmtranslator::translator::Translator;
+    ///
+    /// let mut translator = Translator::new();
+    /// let summary = translator
+    ///     .add_source("Main.vm", "function Main.main 0\npush static 0\npop static 2\nlabel END\ngoto END\n")
+    ///     .unwrap();
+    /// assert_eq!((summary.name.as_str(), summary.commands, summary.statics), ("Main", 5, 2));
+    /// assert_eq!(summary.functions, ["Main.main"]);
+    /// assert_eq!(summary.diagnostics.len(), 1);
+    ///
+    /// let errors = translator.add_source("lib/Main.vm", "push constant 1\n").unwrap_err();
+    /// assert_eq!(errors[0].message, "Main.vm and lib/Main.vm would share the static prefix 'Main'");
+    /// let errors = translator.add_source("Bad.vm", "push nowhere 1\n").unwrap_err();
+    /// assert_eq!(errors[0].line, Some(0));
+    /// assert_eq!(translator.sources().len(), 2);
+    /// ```
+    pub fn add_source(&mut self, path: impl Into<PathBuf>, text: impl Into<String>) -> Result<FileSummary, Vec<Diagnostic>> {
+        let mut source = Source::new(path, text);
+        if let Some(max) = self.max_line_length {
+            source = source.with_max_line_length(max);
+        }
+        if let Some(other) = self.sources.iter().find(|s| s.name == source.name) {
+            return Err(vec![Diagnostic::error(
+                "Error",
+                format!(
+                    "{} and {} would share the static prefix '{}'",
+                    other.path.display(),
+                    source.path.display(),
+                    source.name
+                ),
+            )]);
+        }
+
+        let mut errors = Vec::new();
+        let mut commands = Vec::new();
+        for result in source.parse() {
+            match result {
+                Ok(sc) => commands.push(sc),
+                Err(e) => errors.push(Diagnostic::from(&e)),
+            }
+        }
+        let summary = FileSummary {
+            name: source.name.clone(),
+            commands: commands.len(),
+            functions: commands
+                .iter()
+                .filter_map(|sc| match sc.command() {
+                    Command::Function { name, .. } => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            statics: lint::static_usage(&commands).iter().map(|file| file.indices.len()).sum(),
+            diagnostics: lint::check_spin_loops(&commands),
+        };
+
+        // Kept in path order, as a directory is read, so the output
+        // doesn't depend on the order files were added in.
+        let position = self.sources.partition_point(|s| s.path < source.path);
+        self.sources.insert(position, source);
+        if errors.is_empty() {
+            Ok(summary)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Allows lines of code up to `max` bytes long in the sources added
+    /// after this.
+    pub fn set_max_line_length(&mut self, max: usize) {
+        self.max_line_length = Some(max);
+    }
+
+    /// The files added so far, in the order `finish` translates them.
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    pub fn into_sources(self) -> Vec<Source> {
+        self.sources
+    }
+
+    /// Translates everything added: reports any file that didn't parse
+    /// and functions defined in more than one place, decides on the
+    /// bootstrap, and generates the code.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::CodegenOptions;
+    /// use hack_vmtranslator::translator::Translator;
+    ///
+    /// let files = [
+    ///     ("Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n"),
+    ///     ("Main.vm", "function Main.main 0\npush constant 1\npush static 0\neq\nreturn\n"),
+    ///     ("Math.vm", "function Math.f 0\npush static 0\nreturn\n"),
+    /// ];
+    /// let translate = |order: [usize; 3]| {
+    ///     let mut translator = Translator::new();
+    ///     for i in order {
+    ///         translator.add_source(files[i].0, files[i].1).unwrap();
+    ///     }
+    ///     translator.finish(&CodegenOptions::default()).unwrap()
+    /// };
+    /// let output = translate([0, 1, 2]);
+    /// assert!(output.manifest.bootstrap);
+    /// assert_eq!(output.manifest.functions, ["Main.main", "Math.f", "Sys.init"]);
+    /// for order in [[2, 1, 0], [1, 0, 2]] {
+    ///     assert_eq!(translate(order).asm, output.asm);
+    /// }
+    ///
+    /// let mut translator = Translator::new();
+    /// translator.add_source("A.vm", "function Main.f 0\nreturn\n").unwrap();
+    /// translator.add_source("B.vm", "function Main.f 0\nreturn\n").unwrap();
+    /// let errors = translator.finish(&CodegenOptions::default()).unwrap_err();
+    /// assert_eq!(errors[0].message, "Main.f is already defined at A.vm line 1");
+    /// ```
+    pub fn finish(&self, options: &CodegenOptions) -> Result<Output, Vec<Diagnostic>> {
+        let mut errors = Vec::new();
+        let mut commands: Vec<SourceCommand> = Vec::new();
+        for result in self.sources.iter().flat_map(Source::parse) {
+            match result {
+                Ok(sc) => commands.push(sc),
+                Err(e) => errors.push(Diagnostic::from(&e)),
+            }
+        }
+        errors.extend(lint::check_extensions(&commands, false));
+        errors.extend(duplicate_functions(&commands));
+        errors.retain(|d| d.severity == Severity::Error);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let manifest = Manifest::new(&commands, options);
+        let asm = asm::generate_code(commands, options).map_err(|e| vec![Diagnostic::error("Error", e)])?;
+        Ok(Output {
+            asm: asm.join("\n"),
+            manifest,
+        })
+    }
+}
+
+fn duplicate_functions(commands: &[SourceCommand]) -> Vec<Diagnostic> {
+    let mut defined: Vec<(&str, &SourceCommand)> = Vec::new();
+    let mut diagnostics = Vec::new();
+    for sc in commands {
+        let Command::Function { name, .. } = sc.command() else { continue };
+        match defined.iter().find(|(n, _)| n == name) {
+            Some((_, first)) => diagnostics.push(
+                Diagnostic::error(
+                    "Error",
+                    format!("{name} is already defined at {} line {}", first.path().display(), first.line() + 1),
+                )
+                .at(sc),
+            ),
+            None => defined.push((name, sc)),
+        }
+    }
+    diagnostics
+}
@@ -1,6 +1,11 @@
+use crate::diagnostic::{excerpt, Diagnostic};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-#[derive(Debug)]
+pub mod cfg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Segment {
     Argument,
     Constant,
@@ -10,8 +15,17 @@ pub enum Segment {
     Temp,
     That,
     This,
+    /// `reg n` names the register Rn directly. An extension, accepted only
+    /// with `--extensions`.
+    Reg,
 }
 
+/// The highest register `reg` can name.
+pub const MAX_REG: u16 = 15;
+
+/// The largest constant a single A-instruction can load.
+pub const MAX_CONSTANT: u16 = 32767;
+
 impl FromStr for Segment {
     type Err = String;
 
@@ -25,12 +39,51 @@ impl FromStr for Segment {
             "temp" => Ok(Segment::Temp),
             "that" => Ok(Segment::That),
             "this" => Ok(Segment::This),
+            "reg" => Ok(Segment::Reg),
             _ => Err(format!("Unknown segment name: '{}'", s)),
         }
     }
 }
 
-#[derive(Debug)]
+impl Segment {
+    /// The name the segment is written with in VM code.
+    ///
+    /// ```
+    /// use hack_vmtranslator::vm::Segment;
+    ///
+    /// let all = [
+    ///     Segment::Argument, Segment::Constant, Segment::Local, Segment::Pointer, Segment::Static,
+    ///     Segment::Temp, Segment::That, Segment::This, Segment::Reg,
+    /// ];
+    /// for segment in all {
+    ///     assert_eq!(segment.as_str().parse::<Segment>(), Ok(segment));
+    ///     assert_eq!(segment.to_string(), segment.as_str());
+    /// }
+    /// assert_eq!(Segment::That.as_str(), "that");
+    /// assert!("That".parse::<Segment>().is_err());
+    /// ```
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Segment::Argument => "argument",
+            Segment::Constant => "constant",
+            Segment::Local => "local",
+            Segment::Pointer => "pointer",
+            Segment::Static => "static",
+            Segment::Temp => "temp",
+            Segment::That => "that",
+            Segment::This => "this",
+            Segment::Reg => "reg",
+        }
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command<'a> {
     Push { segment: Segment, index: u16 },
     Pop { segment: Segment, index: u16 },
@@ -49,6 +102,21 @@ pub enum Command<'a> {
     Call {name: &'a str, nargs: u16 },
     Function { name: &'a str, nvars: u16 },
     Return,
+    /// `asm { @SCREEN / M=-1 }`: Hack instructions, separated by `/`,
+    /// copied into the output where the command stands. An extension,
+    /// accepted only with `--extensions`; see `raw_asm_lines`.
+    RawAsm(&'a str),
+    /// `include "macros/util.vm"`: the commands of another file, relative
+    /// to this one, spliced in by `splice_includes`. An extension,
+    /// accepted only with `--extensions`.
+    Include(&'a str),
+    /// `debug.print`: reports the value on top of the stack, without
+    /// popping it, to the interpreter's hooks. An extension, accepted only
+    /// with `--extensions`, and interpreter-only: it translates to nothing.
+    DebugPrint,
+    /// Stands in for a line that failed to parse when translating with
+    /// `--keep-going`. Never produced by the parser.
+    Trap,
 }
 
 impl<'a> Command<'a> {
@@ -67,6 +135,10 @@ impl<'a> Command<'a> {
             Command::parse_function(s)
         } else if let Some(s) = line.strip_prefix("call") {
             Command::parse_call(s)
+        } else if let Some(s) = line.strip_prefix("include") {
+            Command::parse_include(s)
+        } else if let Some(s) = line.strip_prefix("asm") {
+            Command::parse_raw_asm(s)
         } else if line == "add" {
             Ok(Command::Add)
         } else if line == "sub" {
@@ -87,46 +159,62 @@ impl<'a> Command<'a> {
             Ok(Command::Not)
         } else if line == "return" {
             Ok(Command::Return)
+        } else if line == "debug.print" {
+            Ok(Command::DebugPrint)
         } else {
             Err(format!("Parser not implemented for '{}'", line))
         }
     }
 
-    fn parse_label(s: &str) -> Result<Command, String> {
+    fn parse_raw_asm(s: &str) -> Result<Command<'_>, String> {
+        match s.trim().strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(body) => Ok(Command::RawAsm(body.trim())),
+            None => Err("expected format 'asm { <instruction> / <instruction> ... }'".to_string()),
+        }
+    }
+
+    fn parse_include(s: &str) -> Result<Command<'_>, String> {
+        match s.trim().strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(path) if !path.is_empty() => Ok(Command::Include(path)),
+            _ => Err("expected format 'include \"<file.vm>\"'".to_string()),
+        }
+    }
+
+    fn parse_label(s: &str) -> Result<Command<'_>, String> {
         match Self::parse_label_name(s) {
             Ok(name) => Ok(Command::Label(name)),
             Err(e) => Err(e),
         }
     }
 
-    fn parse_if_goto(s: &str) -> Result<Command, String> {
+    fn parse_if_goto(s: &str) -> Result<Command<'_>, String> {
         match Self::parse_label_name(s) {
             Ok(name) => Ok(Command::IfGoto(name)),
             Err(e) => Err(e),
         }
     }
 
-    fn parse_goto(s: &str) -> Result<Command, String> {
+    fn parse_goto(s: &str) -> Result<Command<'_>, String> {
         match Self::parse_label_name(s) {
             Ok(name) => Ok(Command::Goto(name)),
             Err(e) => Err(e),
         }
     }
 
-    fn parse_call(s: &str) -> Result<Command, String> {
+    fn parse_call(s: &str) -> Result<Command<'_>, String> {
         match Self::parse_label_and_n(s) {
             Ok((name, n)) => Ok(Command::Call {
-                name: name,
+                name,
                 nargs: n
             }),
             Err(e) => Err(e)
         }
     }
 
-    fn parse_function(s: &str) -> Result<Command, String> {
+    fn parse_function(s: &str) -> Result<Command<'_>, String> {
         match Self::parse_label_and_n(s) {
             Ok((name, n)) => Ok(Command::Function {
-                name: name,
+                name,
                 nvars: n
             }),
             Err(e) => Err(e)
@@ -136,27 +224,27 @@ impl<'a> Command<'a> {
     fn parse_label_name(s: &str) -> Result<&str, String> {
         let s = s.trim();
         if s.is_empty() {
-            Err(format!("Label must have a name"))
+            Err("Label must have a name".to_string())
         } else {
             Ok(s)
         }
     }
 
-    fn parse_pop(s: &str) -> Result<Command, String> {
+    fn parse_pop(s: &str) -> Result<Command<'_>, String> {
         match Command::parse_stack_arguments(s) {
             Ok((segment, index)) => Ok(Command::Pop {
-                segment: segment,
-                index: index,
+                segment,
+                index,
             }),
             Err(e) => Err(e),
         }
     }
 
-    fn parse_push(s: &str) -> Result<Command, String> {
+    fn parse_push(s: &str) -> Result<Command<'_>, String> {
         match Command::parse_stack_arguments(s) {
             Ok((segment, index)) => Ok(Command::Push {
-                segment: segment,
-                index: index, // TODO validate index based on segment
+                segment,
+                index,
             }),
             Err(e) => Err(e),
         }
@@ -165,12 +253,15 @@ impl<'a> Command<'a> {
     fn parse_stack_arguments(s: &str) -> Result<(Segment, u16), String> {
         match Self::parse_label_and_n(s) {
             Ok((label, n)) => {
-                let segment  = label.parse::<Segment>();
-
-                if segment.is_err() {
-                    Err(segment.unwrap_err())
-                } else {
-                    Ok((segment.unwrap(), n))
+                match label.parse::<Segment>() {
+                    Ok(Segment::Reg) if n > MAX_REG => {
+                        Err(format!("reg index must be 0-{MAX_REG}, got {n}"))
+                    }
+                    Ok(Segment::Constant) if n > MAX_CONSTANT => {
+                        Err(format!("constant index must be 0-{MAX_CONSTANT}, got {n}"))
+                    }
+                    Ok(segment) => Ok((segment, n)),
+                    Err(e) => Err(e),
                 }
             },
             Err(e) => Err(e)
@@ -182,34 +273,300 @@ impl<'a> Command<'a> {
 
         if parts.len() == 2 {
             let name = parts[0];
-            let index = parts[1].parse::<u16>();
-
-            if index.is_err() {
-                Err(format!("Error parsing index: {}", index.unwrap_err()))
-            } else {
-                Ok((name, index.unwrap()))
+            match parts[1].parse::<u16>() {
+                Ok(index) => Ok((name, index)),
+                Err(e) => Err(format!("Error parsing index: {}", e)),
             }
         } else {
-            Err(format!("expected format '<string> <int>'"))
+            Err("expected format '<string> <int>'".to_string())
         }
     }
 }
 
-#[derive(Debug)]
+/// Renders the command in its canonical form: lowercase keywords
+/// separated by single spaces.
+impl<'a> fmt::Display for Command<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Push { segment, index } => write!(f, "push {segment} {index}"),
+            Command::Pop { segment, index } => write!(f, "pop {segment} {index}"),
+            Command::Add => write!(f, "add"),
+            Command::Sub => write!(f, "sub"),
+            Command::Neg => write!(f, "neg"),
+            Command::Eq => write!(f, "eq"),
+            Command::Gt => write!(f, "gt"),
+            Command::Lt => write!(f, "lt"),
+            Command::And => write!(f, "and"),
+            Command::Or => write!(f, "or"),
+            Command::Not => write!(f, "not"),
+            Command::Goto(label) => write!(f, "goto {label}"),
+            Command::IfGoto(label) => write!(f, "if-goto {label}"),
+            Command::Label(label) => write!(f, "label {label}"),
+            Command::Call { name, nargs } => write!(f, "call {name} {nargs}"),
+            Command::Function { name, nvars } => write!(f, "function {name} {nvars}"),
+            Command::Return => write!(f, "return"),
+            Command::RawAsm(body) => write!(f, "asm {{ {body} }}"),
+            Command::Include(path) => write!(f, "include \"{path}\""),
+            Command::DebugPrint => write!(f, "debug.print"),
+            Command::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+impl Command<'_> {
+    /// Whether the command only means something to the interpreter.
+    /// Such commands generate no code at all, so the translated program
+    /// behaves as if they weren't there.
+    ///
+    #[cfg_attr(feature = "interp", doc = "```")]
+    #[cfg_attr(not(feature = "interp"), doc = "```ignore")]
+    /// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+    /// use hack_vmtranslator::interp::{Config, Event, Limits, Vm};
+    /// use hack_vmtranslator::vm::{parse_sources, Source};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let text = "function Sys.init 0\npush constant 6\ndebug.print\npush constant 7\nadd\ndebug.print\nreturn\n";
+    /// let sources = [Source::new("Main.vm", text)];
+    /// let program: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// assert!(program[2].command().is_interpreter_only());
+    ///
+    /// let printed = Arc::new(Mutex::new(Vec::new()));
+    /// let log = Arc::clone(&printed);
+    /// let mut vm = Vm::new(&program, Config::default());
+    /// vm.on_event(move |event| {
+    ///     if let Event::DebugPrint { function, command, value } = event {
+    ///         log.lock().unwrap().push(format!("{} line {}: {value}", function.unwrap_or("-"), command.line() + 1));
+    ///     }
+    /// });
+    /// vm.run(&Limits::default()).unwrap();
+    /// drop(vm);
+    /// assert_eq!(*printed.lock().unwrap(), ["Sys.init line 3: 6", "Sys.init line 6: 13"]);
+    ///
+    /// let edited = [Source::new("Main.vm", text.replace("debug.print\n", ""))];
+    /// let without = parse_sources(&edited).into_iter().map(Result::unwrap).collect();
+    /// let code = |asm: Vec<String>| asm.join("\n").lines().filter(|l| !l.starts_with("//")).collect::<Vec<_>>().join("\n");
+    /// let asm = generate_code(program, &CodegenOptions::default()).unwrap();
+    /// assert!(!asm.join("\n").contains("debug"));
+    /// assert_eq!(code(asm), code(generate_code(without, &CodegenOptions::default()).unwrap()));
+    /// ```
+    pub fn is_interpreter_only(&self) -> bool {
+        matches!(self, Command::DebugPrint)
+    }
+}
+
+/// The instructions in the body of an `asm { ... }` command.
+///
+/// ```
+/// use hack_vmtranslator::vm::{parse_sources, raw_asm_lines, Command, Source};
+///
+/// let sources = [Source::new("Main.vm", "asm { @SCREEN / M=-1 / 0;JMP }\n")];
+/// let commands = parse_sources(&sources);
+/// let command = commands[0].as_ref().unwrap().command();
+/// assert_eq!(*command, Command::RawAsm("@SCREEN / M=-1 / 0;JMP"));
+/// assert_eq!(raw_asm_lines("@SCREEN / M=-1 / 0;JMP").collect::<Vec<_>>(), ["@SCREEN", "M=-1", "0;JMP"]);
+/// assert_eq!(commands[0].as_ref().unwrap().command().to_string(), "asm { @SCREEN / M=-1 / 0;JMP }");
+/// ```
+pub fn raw_asm_lines(body: &str) -> impl Iterator<Item = &str> {
+    body.split('/').map(str::trim).filter(|line| !line.is_empty())
+}
+
+/// The file `include "<name>"` in `sc` refers to: `name` relative to the
+/// including file.
+pub fn include_path(sc: &SourceCommand, name: &str) -> PathBuf {
+    normalize(&sc.path().parent().unwrap_or(Path::new("")).join(name))
+}
+
+// Drops `.` and folds `dir/..`, without touching the file system, so
+// one file reached along different relative paths compares equal.
+fn normalize(path: &Path) -> PathBuf {
+    use std::path::Component;
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if matches!(normal.components().next_back(), Some(Component::Normal(_))) => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+/// Replaces each `include` in `commands` with the commands of the file it
+/// names, taken from `included`, recursively. Spliced commands keep their
+/// own file, so their statics and diagnostics belong to it. A cycle is an
+/// error naming the chain of includes; so is a file included twice,
+/// unless `once` is set, when later includes of it are dropped.
+///
+/// ```
+/// use hack_vmtranslator::vm::{parse_sources, splice_includes, Source};
+///
+/// let sources = [Source::new("game/Main.vm", "include \"lib/Stack.vm\"\npush static 0\n")];
+/// let included = [
+///     Source::new("game/lib/Stack.vm", "include \"Util.vm\"\npush static 1\n"),
+///     Source::new("game/lib/Util.vm", "pop static 2\n"),
+/// ];
+/// let commands = splice_includes(parse_sources(&sources), &included, false).unwrap();
+/// let spliced: Vec<String> = commands
+///     .iter()
+///     .map(|c| c.as_ref().unwrap())
+///     .map(|sc| format!("{} {}", sc.file_base(), sc.command()))
+///     .collect();
+/// assert_eq!(spliced, ["Util pop static 2", "Stack push static 1", "Main push static 0"]);
+///
+/// let looping = [Source::new("game/lib/Stack.vm", "include \"../Main.vm\"\n")];
+/// let error = splice_includes(parse_sources(&sources), &looping, false).unwrap_err();
+/// assert_eq!(error.message, "Include cycle: game/Main.vm -> game/lib/Stack.vm -> game/Main.vm");
+/// assert_eq!((error.file.as_deref(), error.line), (Some("game/lib/Stack.vm"), Some(0)));
+///
+/// let twice = [Source::new("game/Main.vm", "include \"lib/Util.vm\"\ninclude \"lib/Util.vm\"\n")];
+/// assert!(splice_includes(parse_sources(&twice), &included, false).is_err());
+/// assert_eq!(splice_includes(parse_sources(&twice), &included, true).unwrap().len(), 1);
+/// ```
+pub fn splice_includes<'a>(
+    commands: Vec<Result<SourceCommand<'a>, ParseError<'a>>>,
+    included: &'a [Source],
+    once: bool,
+) -> Result<Vec<Result<SourceCommand<'a>, ParseError<'a>>>, Box<Diagnostic>> {
+    let mut spliced = Vec::new();
+    let mut seen: Vec<PathBuf> = Vec::new();
+    splice(commands, included, once, &[], &mut seen, &mut spliced)?;
+    Ok(spliced)
+}
+
+fn splice<'a>(
+    commands: Vec<Result<SourceCommand<'a>, ParseError<'a>>>,
+    included: &'a [Source],
+    once: bool,
+    chain: &[PathBuf],
+    seen: &mut Vec<PathBuf>,
+    spliced: &mut Vec<Result<SourceCommand<'a>, ParseError<'a>>>,
+) -> Result<(), Box<Diagnostic>> {
+    for result in commands {
+        let include = result.as_ref().ok().and_then(|sc| match sc.command() {
+            Command::Include(name) => Some(*name),
+            _ => None,
+        });
+        let Some(name) = include else {
+            spliced.push(result);
+            continue;
+        };
+        let sc = result.as_ref().unwrap();
+        let target = include_path(sc, name);
+        let chain = match chain {
+            [] => vec![normalize(sc.path())],
+            chain => chain.to_vec(),
+        };
+
+        if chain.contains(&target) {
+            let names: Vec<String> = chain.iter().chain([&target]).map(|p| p.display().to_string()).collect();
+            return Err(Diagnostic::error("Include error", format!("Include cycle: {}", names.join(" -> "))).at(sc).into());
+        }
+        if seen.contains(&target) {
+            if once {
+                continue;
+            }
+            return Err(Diagnostic::error(
+                "Include error",
+                format!("{} is included more than once; pass --include-once to allow it", target.display()),
+            )
+            .at(sc)
+            .into());
+        }
+        let Some(source) = included.iter().find(|s| normalize(&s.path) == target) else {
+            return Err(Diagnostic::error("Include error", format!("{} wasn't loaded", target.display())).at(sc).into());
+        };
+
+        seen.push(target.clone());
+        let chain: Vec<PathBuf> = chain.into_iter().chain([target]).collect();
+        splice(source.parse(), included, once, &chain, seen, spliced)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SourceCommand<'a> {
     line: usize,
     command: Command<'a>,
     source: &'a str,
     file_base: &'a str,
+    path: &'a Path,
 }
 
 impl<'a> SourceCommand<'a> {
-    pub fn bootstrap(command: Command) -> SourceCommand {
+    /// A command that wasn't parsed from text, as from a compiler that
+    /// generates commands directly. `source` stands in for the line it
+    /// would have been read from, and the path is `file_base` itself; see
+    /// `builder` to set it. The code generator treats it exactly as a
+    /// parsed command.
+    ///
+    /// ```
+    /// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+    /// use hack_vmtranslator::vm::{parse_sources, Command, Segment, Source, SourceCommand};
+    ///
+    /// let built = vec![
+    ///     SourceCommand::new("Main", 0, "function Main.main 0", Command::Function { name: "Main.main", nvars: 0 }),
+    ///     SourceCommand::new("Main", 1, "push constant 7", Command::Push { segment: Segment::Constant, index: 7 }),
+    ///     SourceCommand::new("Main", 2, "pop static 0", Command::Pop { segment: Segment::Static, index: 0 }),
+    ///     SourceCommand::new("Main", 3, "push static 0", Command::Push { segment: Segment::Static, index: 0 }),
+    ///     SourceCommand::new("Main", 4, "lt", Command::Lt),
+    ///     SourceCommand::new("Main", 5, "return", Command::Return),
+    /// ];
+    ///
+    /// let text = "function Main.main 0\npush constant 7\npop static 0\npush static 0\nlt\nreturn\n";
+    /// let sources = [Source::new("Main", text)];
+    /// let parsed: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// assert_eq!(built, parsed);
+    ///
+    /// let options = CodegenOptions::default();
+    /// assert_eq!(generate_code(built, &options), generate_code(parsed, &options));
+    /// ```
+    pub fn new(file_base: &'a str, line: usize, source: &'a str, command: Command<'a>) -> SourceCommand<'a> {
+        SourceCommand {
+            line,
+            command,
+            source,
+            file_base,
+            path: Path::new(file_base),
+        }
+    }
+
+    /// A `SourceCommand` for `command` with each part set only if needed:
+    /// the file base, line and source text default to `"Main"`, 0 and the
+    /// empty string, and the path to the file base.
+    ///
+    /// ```
+    /// use hack_vmtranslator::vm::{Command, SourceCommand};
+    /// use std::path::Path;
+    ///
+    /// let sc = SourceCommand::builder(Command::Add)
+    ///     .file_base("Math")
+    ///     .line(12)
+    ///     .source("add // sum")
+    ///     .path(Path::new("lib/Math.vm"))
+    ///     .build();
+    /// assert_eq!((sc.file_base(), sc.line(), sc.source()), ("Math", 12, "add // sum"));
+    /// assert_eq!(sc.path(), Path::new("lib/Math.vm"));
+    ///
+    /// let sc = SourceCommand::builder(Command::Add).file_base("Math").build();
+    /// assert_eq!(sc, SourceCommand::new("Math", 0, "", Command::Add));
+    /// ```
+    pub fn builder(command: Command<'a>) -> SourceCommandBuilder<'a> {
+        SourceCommandBuilder {
+            command: SourceCommand::new("Main", 0, "", command),
+            path: None,
+        }
+    }
+
+    /// A placeholder for the command that failed to parse with `error`.
+    pub fn trap(error: &ParseError<'a>) -> SourceCommand<'a> {
         SourceCommand {
-            line: 0,
-            command: command,
-            source: "Bootstrap",
-            file_base: "Bootstrap"
+            line: error.line,
+            command: Command::Trap,
+            source: error.source,
+            file_base: error.file_base,
+            path: error.path,
         }
     }
 
@@ -217,31 +574,470 @@ impl<'a> SourceCommand<'a> {
         self.line
     }
 
-    pub fn source(&self) -> &str {
+    pub fn source(&self) -> &'a str {
         self.source
     }
 
-    pub fn command(&self) -> &Command {
+    pub fn command(&self) -> &Command<'a> {
         &self.command
     }
 
+    pub fn into_command(self) -> Command<'a> {
+        self.command
+    }
+
+    /// The file name without its extension, which names the file's
+    /// statics.
+    pub fn file_base(&self) -> &'a str {
+        self.file_base
+    }
+
+    /// The path the command was read from, for reporting.
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+}
+
+/// Sets the parts of a synthetic `SourceCommand`; see
+/// `SourceCommand::builder`.
+pub struct SourceCommandBuilder<'a> {
+    command: SourceCommand<'a>,
+    path: Option<&'a Path>,
+}
+
+impl<'a> SourceCommandBuilder<'a> {
+    pub fn file_base(mut self, file_base: &'a str) -> Self {
+        self.command.file_base = file_base;
+        self
+    }
+
+    pub fn line(mut self, line: usize) -> Self {
+        self.command.line = line;
+        self
+    }
+
+    pub fn source(mut self, source: &'a str) -> Self {
+        self.command.source = source;
+        self
+    }
+
+    pub fn path(mut self, path: &'a Path) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    pub fn build(self) -> SourceCommand<'a> {
+        let path = self.path.unwrap_or(Path::new(self.command.file_base));
+        SourceCommand { path, ..self.command }
+    }
+}
+
+/// The function `wrap_snippet` puts a snippet in.
+pub const SNIPPET_FUNCTION: &str = "Eval.main";
+
+/// Makes a runnable program of a few loose commands parsed from `source`:
+/// they become the body of `SNIPPET_FUNCTION`, followed by a halt loop.
+/// No `Sys.init` is defined, so code generation adds no bootstrap and the
+/// interpreter starts at the top. Line numbers stay those of the snippet.
+///
+#[cfg_attr(feature = "interp", doc = "```")]
+#[cfg_attr(not(feature = "interp"), doc = "```ignore")]
+/// use hack_vmtranslator::interp::{Config, Limits, Vm};
+/// use hack_vmtranslator::vm::{wrap_snippet, Source};
+///
+/// let source = Source::new("eval", "push constant 2\npush constant 3\nadd\n");
+/// let commands = source.parse().into_iter().map(Result::unwrap).collect();
+/// let program = wrap_snippet(&source, commands);
+/// assert_eq!(program[0].command().to_string(), "function Eval.main 0");
+/// assert_eq!(program[1].line(), 0);
+///
+/// let mut vm = Vm::new(&program, Config::default());
+/// vm.run(&Limits::default()).unwrap();
+/// assert_eq!((vm.sp(), vm.ram()[256]), (257, 5));
+/// ```
+pub fn wrap_snippet<'a>(source: &'a Source, commands: Vec<SourceCommand<'a>>) -> Vec<SourceCommand<'a>> {
+    let synthetic = |line: usize, command: Command<'a>, text: &'a str| {
+        SourceCommand::builder(command)
+            .file_base(&source.name)
+            .line(line)
+            .source(text)
+            .path(&source.path)
+            .build()
+    };
+    let end = source.text.lines().count();
+
+    let mut program = vec![synthetic(
+        0,
+        Command::Function { name: SNIPPET_FUNCTION, nvars: 0 },
+        "function Eval.main 0",
+    )];
+    program.extend(commands);
+    program.push(synthetic(end, Command::Label("EVAL_END"), "label EVAL_END"));
+    program.push(synthetic(end, Command::Goto("EVAL_END"), "goto EVAL_END"));
+    program
+}
+
+#[derive(Debug)]
+pub struct ParseError<'a> {
+    file_base: &'a str,
+    path: &'a Path,
+    line: usize,
+    column: usize,
+    source: &'a str,
+    message: String,
+}
+
+impl<'a> ParseError<'a> {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Where the command starts in its line, counting from zero.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn source(&self) -> &str {
+        self.source
+    }
+
     pub fn file_base(&self) -> &str {
-        &self.file_base
+        self.file_base
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl<'a> fmt::Display for ParseError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Parse error at line {}:{} ({}): {}",
+            self.path.display(), self.line + 1, excerpt(self.source), self.message
+        )
+    }
+}
+
+/// Whether `path` names a VM source: its final extension is `vm`, in
+/// any case.
+///
+/// ```
+/// use hack_vmtranslator::vm::is_vm_source;
+/// use std::path::{Path, PathBuf};
+///
+/// assert!(is_vm_source(Path::new("Main.v2.vm")));
+/// assert!(is_vm_source(Path::new("MAIN.VM")));
+/// assert!(!is_vm_source(Path::new("archive.vm.bak")));
+/// ```
+pub fn is_vm_source(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vm"))
+}
+
+/// The VM sources `path` names: the file itself, or the `.vm` files in a
+/// directory sorted by name, so the output doesn't depend on the order the
+/// file system lists them in.
+///
+/// ```
+/// use hack_vmtranslator::asm::{generate_code, CodegenOptions};
+/// use hack_vmtranslator::vm::{discover_sources, parse_sources, Source};
+/// use std::fs;
+///
+/// let translate = |files: &[&str]| {
+///     let dir = std::env::temp_dir().join(format!("discover_sources_{}", std::process::id()));
+///     let _ = fs::remove_dir_all(&dir);
+///     fs::create_dir_all(&dir).unwrap();
+///     for name in files {
+///         let text = format!("function {}.f 0\npush static 0\nreturn\n", &name[..name.len() - 3]);
+///         fs::write(dir.join(name), text).unwrap();
+///     }
+///     let sources: Vec<Source> = discover_sources(&dir)
+///         .unwrap()
+///         .into_iter()
+///         .map(|path| Source::new(&path, fs::read_to_string(&path).unwrap()))
+///         .collect();
+///     let commands = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+///     let asm = generate_code(commands, &CodegenOptions::default()).unwrap().join("\n");
+///     fs::remove_dir_all(&dir).unwrap();
+///     asm
+/// };
+///
+/// assert_eq!(translate(&["Sys.vm", "Main.vm", "Array.vm"]), translate(&["Array.vm", "Main.vm", "Sys.vm"]));
+/// ```
+pub fn discover_sources(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(if path.is_file() { vec![path.to_path_buf()] } else { Vec::new() });
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if is_vm_source(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The name a source file's statics are prefixed with: the file name
+/// minus its final extension, with any character that can't appear in a
+/// Hack symbol replaced by `_`. Inner dots are kept.
+///
+/// ```
+/// use hack_vmtranslator::vm::file_base;
+/// use std::path::{Path, PathBuf};
+///
+/// assert_eq!(file_base(Path::new("dir/Main.v2.vm")), "Main.v2");
+/// assert_eq!(file_base(Path::new("MAIN.VM")), "MAIN");
+/// assert_eq!(file_base(Path::new("my game.vm")), "my_game");
+/// assert_eq!(file_base(Path::new("2048.vm")), "_2048");
+/// ```
+pub fn file_base(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    let mut base: String = stem
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '$' | ':' => c,
+            _ => '_',
+        })
+        .collect();
+    if base.is_empty() || base.starts_with(|c: char| c.is_ascii_digit()) {
+        base.insert(0, '_');
+    }
+    base
+}
+
+/// The longest a line's code may be by default, in bytes, leaving out any
+/// comment and surrounding space. No real command comes close; a longer
+/// line is an error, as it's corrupt, and is only ever echoed back as an
+/// `excerpt`.
+///
+/// ```
+/// use hack_vmtranslator::diagnostic::{Diagnostic, EXCERPT_WIDTH};
+/// use hack_vmtranslator::vm::{Source, MAX_LINE_LENGTH};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// // Counts the bytes allocated, to show the error path copies none of
+/// // the line.
+/// struct Counting;
+/// static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// unsafe impl GlobalAlloc for Counting {
+///     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+///         ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+///         unsafe { System.alloc(layout) }
+///     }
+///     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+///         unsafe { System.dealloc(ptr, layout) }
+///     }
+/// }
+/// #[global_allocator]
+/// static GLOBAL: Counting = Counting;
+///
+/// let line = format!("push constant {}", "1".repeat(4 << 20));
+/// let source = Source::new("Main.vm", format!("push constant 1\n{line} // a comment\nadd\n"));
+/// let before = ALLOCATED.load(Ordering::Relaxed);
+/// let parsed = source.parse();
+/// let e = parsed[1].as_ref().unwrap_err();
+/// let diagnostic = Diagnostic::from(e);
+/// let rendered = (e.to_string(), diagnostic.to_string());
+/// assert!(ALLOCATED.load(Ordering::Relaxed) - before < 4096);
+///
+/// assert!(parsed[0].is_ok() && parsed[2].is_ok());
+/// assert_eq!(e.message(), format!("The line is {} bytes long, more than the {MAX_LINE_LENGTH} allowed", line.len()));
+/// assert_eq!(diagnostic.source.unwrap().len(), EXCERPT_WIDTH + 3);
+/// assert!(rendered.0.len() < 300 && rendered.1.len() < 300);
+/// assert!(rendered.1.starts_with("Parse error at line Main.vm:2 (push constant 111"));
+///
+/// // Comments don't count, and the limit can be raised.
+/// let long_comment = Source::new("Main.vm", format!("add // {line}\n"));
+/// assert!(long_comment.parse()[0].is_ok());
+/// assert!(source.clone().with_max_line_length(line.len()).parse()[1].as_ref().unwrap_err().message().starts_with("Error parsing index"));
+/// ```
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+/// A VM source file held in memory. Parsed commands borrow from it rather
+/// than copying, so keep it alive for as long as they are used.
+///
+/// Library callers that used to hand `parse_source` a `(name, text)` pair
+/// per file can build these with `Source::new` and parse them all at once
+/// with `parse_sources`.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    /// Names the file's statics; see `file_base`.
+    pub name: String,
+    pub text: String,
+    /// The longest a line's code may be, in bytes; see `MAX_LINE_LENGTH`.
+    pub max_line_length: usize,
+}
+
+impl Source {
+    /// ```
+    /// use hack_vmtranslator::vm::{parse_sources, Command, Segment, Source};
+    ///
+    /// let sources = [Source::new("Main.vm", "push constant 7\npop static 0\n")];
+    /// let commands = parse_sources(&sources);
+    /// let parsed: Vec<Command> = commands.iter().map(|c| c.as_ref().unwrap().command().clone()).collect();
+    /// assert_eq!(
+    ///     parsed,
+    ///     [
+    ///         Command::Push { segment: Segment::Constant, index: 7 },
+    ///         Command::Pop { segment: Segment::Static, index: 0 },
+    ///     ]
+    /// );
+    /// assert_eq!(commands[1].as_ref().unwrap().file_base(), "Main");
+    /// ```
+    pub fn new(path: impl Into<PathBuf>, text: impl Into<String>) -> Source {
+        let path = path.into();
+        Source {
+            name: file_base(&path),
+            path,
+            text: text.into(),
+            max_line_length: MAX_LINE_LENGTH,
+        }
+    }
+
+    pub fn with_max_line_length(self, max_line_length: usize) -> Source {
+        Source { max_line_length, ..self }
+    }
+
+    pub fn parse(&self) -> Vec<Result<SourceCommand<'_>, ParseError<'_>>> {
+        source_commands(&self.name, &self.path, &self.text, self.max_line_length).collect()
+    }
+
+    /// The first line that doesn't parse, without parsing any further.
+    pub fn first_error(&self) -> Option<ParseError<'_>> {
+        source_commands(&self.name, &self.path, &self.text, self.max_line_length).find_map(Result::err)
     }
 }
 
+/// Parses several sources into one command sequence, in order.
+pub fn parse_sources<'a>(
+    sources: impl IntoIterator<Item = &'a Source>,
+) -> Vec<Result<SourceCommand<'a>, ParseError<'a>>> {
+    sources.into_iter().flat_map(Source::parse).collect()
+}
+
+/// Parses the VM code read from `path`. Statics are named after
+/// `file_base`, normally the file name without its extension.
 pub fn parse_source<'a>(
     file_base: &'a str,
+    path: &'a Path,
     source: &'a str,
-) -> Vec<Result<SourceCommand<'a>, String>> {
-    source
-        .lines()
-        .enumerate()
-        .filter_map(|(i, line)| match strip_comments(line) {
-            Some(s) => Some(parse_source_command(file_base, i, s)),
-            None => None,
-        })
-        .collect()
+) -> Vec<Result<SourceCommand<'a>, ParseError<'a>>> {
+    source_commands(file_base, path, source, MAX_LINE_LENGTH).collect()
+}
+
+/// Parses `text` as the one command on `line` of a file named
+/// `file_base`, for tools that handle a command at a time, such as `eval`
+/// or an editor explaining the line under the cursor. A trailing comment
+/// is allowed; a line with no command on it is an error.
+///
+/// ```
+/// use hack_vmtranslator::vm::{parse_command, Command, Segment};
+///
+/// let sc = parse_command("Main", 4, "  pop static 3 // x").unwrap();
+/// assert_eq!(sc.command(), &Command::Pop { segment: Segment::Static, index: 3 });
+/// assert_eq!((sc.file_base(), sc.line(), sc.source()), ("Main", 4, "pop static 3"));
+///
+/// let e = parse_command("Main", 4, "  push nowhere 1").unwrap_err();
+/// assert_eq!((e.line, e.column, e.source.as_deref()), (Some(4), Some(2), Some("push nowhere 1")));
+/// assert_eq!(parse_command("Main", 0, "// only a comment").unwrap_err().message, "expected a command");
+/// assert!(parse_command("Main", 0, "push constant 1 push constant 2").is_err());
+/// ```
+// A `Diagnostic`, large as it is, because that's what these tools show.
+#[allow(clippy::result_large_err)]
+pub fn parse_command<'a>(file_base: &'a str, line: usize, text: &'a str) -> Result<SourceCommand<'a>, Diagnostic> {
+    let path = Path::new(file_base);
+    let result = match strip_comments(text) {
+        Some(code) => parse_source_command(file_base, path, line, column(text, code), code),
+        None => Err(ParseError {
+            file_base,
+            path,
+            line,
+            column: 0,
+            source: text.trim(),
+            message: "expected a command".to_string(),
+        }),
+    };
+    result.map_err(|e| Diagnostic::from(&e))
+}
+
+fn source_commands<'a>(
+    file_base: &'a str,
+    path: &'a Path,
+    source: &'a str,
+    max_line_length: usize,
+) -> impl Iterator<Item = Result<SourceCommand<'a>, ParseError<'a>>> {
+    source.lines().enumerate().filter_map(move |(i, line)| {
+        let code = strip_comments(line)?;
+        if code.len() > max_line_length {
+            return Some(Err(ParseError {
+                file_base,
+                path,
+                line: i,
+                column: column(line, code),
+                source: code,
+                message: format!("The line is {} bytes long, more than the {max_line_length} allowed", code.len()),
+            }));
+        }
+        Some(parse_source_command(file_base, path, i, column(line, code), code))
+    })
+}
+
+/// Source text that carries no code: comments and blank lines. Line
+/// numbers count from zero like `SourceCommand::line`. A comment with the
+/// same line number as a command trails that command.
+#[derive(Debug)]
+pub enum Trivia<'a> {
+    /// The text following `//`.
+    Comment(usize, &'a str),
+    Blank(usize),
+}
+
+#[derive(Debug)]
+pub enum SourceItem<'a> {
+    Command(Result<SourceCommand<'a>, ParseError<'a>>),
+    Trivia(Trivia<'a>),
+}
+
+/// Like `parse_source`, but keeps comments and blank lines in position
+/// for tools that need to reproduce the original file. Translation should
+/// use `parse_source`, which doesn't pay for this.
+pub fn parse_source_with_trivia<'a>(
+    file_base: &'a str,
+    path: &'a Path,
+    source: &'a str,
+) -> Vec<SourceItem<'a>> {
+    let mut items: Vec<SourceItem> = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let (code, comment) = match line.find("//") {
+            None => (line, None),
+            Some(c) => (&line[..c], Some(&line[c + 2..])),
+        };
+        let code = code.trim();
+
+        if !code.is_empty() {
+            items.push(SourceItem::Command(parse_source_command(file_base, path, i, column(line, code), code)));
+        }
+        match comment {
+            Some(text) => items.push(SourceItem::Trivia(Trivia::Comment(i, text))),
+            None if code.is_empty() => items.push(SourceItem::Trivia(Trivia::Blank(i))),
+            None => (),
+        }
+    }
+
+    items
 }
 
 fn strip_comments(line: &str) -> Option<&str> {
@@ -261,20 +1057,33 @@ fn strip_comments(line: &str) -> Option<&str> {
     }
 }
 
+// The offset of `code` within `line`, which it must be a slice of.
+fn column(line: &str, code: &str) -> usize {
+    code.as_ptr() as usize - line.as_ptr() as usize
+}
+
 fn parse_source_command<'a>(
     file_base: &'a str,
+    path: &'a Path,
     i: usize,
+    column: usize,
     source: &'a str,
-) -> Result<SourceCommand<'a>, String> {
+) -> Result<SourceCommand<'a>, ParseError<'a>> {
     match Command::from_str(source) {
         Ok(command) => Ok(SourceCommand {
-            file_base: file_base,
+            file_base,
+            path,
+            line: i,
+            command,
+            source,
+        }),
+        Err(message) => Err(ParseError {
+            file_base,
+            path,
             line: i,
-            command: command,
-            source: source,
+            column,
+            source,
+            message,
         }),
-        Err(e) => Err(format!(
-            "Parse error at line {file_base}:{i} ({source}): {e}"
-        )),
     }
 }
@@ -0,0 +1,162 @@
+//! Basic blocks and the control flow between them, one function at a
+//! time.
+//!
+//! A block starts at a function's first command, at each `label`, and
+//! after each `goto`, `if-goto` and `return`; calls don't end a block,
+//! since control comes back to the next command.
+
+use super::{Command, SourceCommand};
+use crate::callgraph::function_bodies;
+
+/// How control gets from one block to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Running off the end of the block.
+    Fallthrough,
+    /// A `goto`, or an `if-goto` whose condition holds.
+    Jump,
+    /// An `if-goto` whose condition is false.
+    NotTaken,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The block's commands are `commands[start..end]` of the function.
+    pub start: usize,
+    pub end: usize,
+    /// Successor block indexes.
+    pub edges: Vec<(usize, EdgeKind)>,
+}
+
+/// The control-flow graph of one function.
+pub struct Cfg<'c, 'a> {
+    pub function: &'a str,
+    /// The function's commands, `function` first.
+    pub commands: Vec<&'c SourceCommand<'a>>,
+    pub blocks: Vec<Block>,
+}
+
+/// A graph for each function in `commands`, in source order. Code
+/// outside any function is left out.
+pub fn functions<'c, 'a>(commands: &'c [SourceCommand<'a>]) -> Vec<Cfg<'c, 'a>> {
+    let mut graphs: Vec<Cfg> = Vec::new();
+    for (function, sc) in function_bodies(commands) {
+        let Some(function) = function else { continue };
+        if matches!(sc.command(), Command::Function { .. }) {
+            graphs.push(Cfg {
+                function,
+                commands: Vec::new(),
+                blocks: Vec::new(),
+            });
+        }
+        graphs.last_mut().unwrap().commands.push(sc);
+    }
+    for graph in &mut graphs {
+        graph.blocks = blocks(&graph.commands);
+    }
+    graphs
+}
+
+fn blocks(commands: &[&SourceCommand]) -> Vec<Block> {
+    let mut starts: Vec<usize> = vec![0];
+    for (i, sc) in commands.iter().enumerate() {
+        match sc.command() {
+            Command::Label(_) => starts.push(i),
+            Command::Goto(_) | Command::IfGoto(_) | Command::Return | Command::Trap => starts.push(i + 1),
+            _ => (),
+        }
+    }
+    starts.retain(|start| *start < commands.len());
+    starts.sort();
+    starts.dedup();
+
+    let block_at = |i: usize| starts.iter().position(|start| *start == i);
+    let label_block = |name: &str| {
+        commands
+            .iter()
+            .position(|sc| matches!(sc.command(), Command::Label(l) if *l == name))
+            .and_then(block_at)
+    };
+
+    let mut blocks = Vec::new();
+    for (b, start) in starts.iter().enumerate() {
+        let end = starts.get(b + 1).copied().unwrap_or(commands.len());
+        let next = (end < commands.len()).then_some(b + 1);
+        let edges = match commands[end - 1].command() {
+            Command::Goto(label) => label_block(label).map(|t| (t, EdgeKind::Jump)).into_iter().collect(),
+            Command::IfGoto(label) => label_block(label)
+                .map(|t| (t, EdgeKind::Jump))
+                .into_iter()
+                .chain(next.map(|n| (n, EdgeKind::NotTaken)))
+                .collect(),
+            Command::Return | Command::Trap => Vec::new(),
+            _ => next.map(|n| (n, EdgeKind::Fallthrough)).into_iter().collect(),
+        };
+        blocks.push(Block {
+            start: *start,
+            end,
+            edges,
+        });
+    }
+    blocks
+}
+
+impl Cfg<'_, '_> {
+    /// The graph in Graphviz DOT. Each node shows the block's source lines
+    /// and first command; edges are solid for fallthrough and jumps and
+    /// dashed for the false branch of an `if-goto`.
+    ///
+    /// ```
+    /// use hack_vmtranslator::vm::{cfg, parse_sources, Source};
+    ///
+    /// let text = "function Main.f 0\n\
+    ///             label LOOP\npush argument 0\nif-goto ELSE\npush constant 1\ngoto JOIN\n\
+    ///             label ELSE\npush constant 2\n\
+    ///             label JOIN\npop local 0\ngoto LOOP\n";
+    /// let sources = [Source::new("Main.vm", text)];
+    /// let commands: Vec<_> = parse_sources(&sources).into_iter().map(Result::unwrap).collect();
+    /// let graphs = cfg::functions(&commands);
+    ///
+    /// assert_eq!(
+    ///     graphs[0].to_dot(),
+    ///     "digraph \"Main.f\" {\n\
+    ///     \x20   node [shape=box, fontname=monospace];\n\
+    ///     \x20   b0 [label=\"line 1\\nfunction Main.f 0\"];\n\
+    ///     \x20   b1 [label=\"lines 2-4\\nlabel LOOP\"];\n\
+    ///     \x20   b2 [label=\"lines 5-6\\npush constant 1\"];\n\
+    ///     \x20   b3 [label=\"lines 7-8\\nlabel ELSE\"];\n\
+    ///     \x20   b4 [label=\"lines 9-11\\nlabel JOIN\"];\n\
+    ///     \x20   b0 -> b1;\n\
+    ///     \x20   b1 -> b3;\n\
+    ///     \x20   b1 -> b2 [style=dashed];\n\
+    ///     \x20   b2 -> b4;\n\
+    ///     \x20   b3 -> b4;\n\
+    ///     \x20   b4 -> b1;\n\
+    ///     }\n"
+    /// );
+    /// ```
+    pub fn to_dot(&self) -> String {
+        let mut out = format!("digraph \"{}\" {{\n    node [shape=box, fontname=monospace];\n", self.function);
+        for (b, block) in self.blocks.iter().enumerate() {
+            let first = self.commands[block.start];
+            let last = self.commands[block.end - 1];
+            let lines = if first.line() == last.line() {
+                format!("line {}", first.line() + 1)
+            } else {
+                format!("lines {}-{}", first.line() + 1, last.line() + 1)
+            };
+            let command = first.command().to_string().replace('\\', "\\\\").replace('"', "\\\"");
+            out.push_str(&format!("    b{b} [label=\"{lines}\\n{command}\"];\n"));
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (to, kind) in &block.edges {
+                match kind {
+                    EdgeKind::NotTaken => out.push_str(&format!("    b{b} -> b{to} [style=dashed];\n")),
+                    EdgeKind::Fallthrough | EdgeKind::Jump => out.push_str(&format!("    b{b} -> b{to};\n")),
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
@@ -0,0 +1,829 @@
+//! The command-line translator, run as a user would run it: each test
+//! writes a project to a directory of its own and checks what the binary
+//! prints, writes and exits with.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A fresh directory for the test `name`.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hack_cli_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write(dir: &Path, name: &str, text: &str) {
+    let path = dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, text).unwrap();
+}
+
+/// Runs the translator in `dir`.
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hack_vmtranslator")).args(args).current_dir(dir).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+const MAIN: &str = "push constant 7\npush constant 8\nadd\n";
+
+#[test]
+fn output_never_overwrites_an_input() {
+    let dir = scratch("output_input");
+    write(&dir, "Main.vm", MAIN);
+
+    let output = run(&dir, &["-o", "Main.vm", "Main.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Refusing to overwrite input file Main.vm"), "{}", stderr(&output));
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("Main.vm", dir.join("link.asm")).unwrap();
+        let output = run(&dir, &["-o", "link.asm", "Main.vm"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(stderr(&output).contains("Refusing to overwrite input file Main.vm"), "{}", stderr(&output));
+    }
+    let output = run(&dir, &["-o", "./sub/../Main.vm", "Main.vm"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout(&output));
+    assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(), MAIN);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn an_unknown_flag_is_a_usage_error() {
+    let dir = scratch("unknown_flag");
+    write(&dir, "Main.vm", MAIN);
+    for (args, message) in [
+        (&["--bogus", "Main.vm"][..], "Argument Error: Unknown flag: '--bogus'"),
+        (&["Main.vm", "--emit-sim", "x"][..], "Argument Error: Unknown flag: '--emit-sim'"),
+    ] {
+        let output = run(&dir, args);
+        assert_eq!(output.status.code(), Some(2), "{args:?}");
+        let printed = stdout(&output);
+        let mut lines = printed.lines();
+        assert_eq!(lines.next(), Some(message));
+        assert!(lines.next().is_some_and(|usage| usage.starts_with("Usage: hack_vmtranslator ")));
+    }
+    assert!(!dir.join("Main.asm").exists());
+    // After `--`, it's a file name.
+    let output = run(&dir, &["--", "--bogus"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Error reading --bogus"), "{}", stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_prelude_or_epilogue_is_never_overwritten() {
+    let dir = scratch("prelude_input");
+    write(&dir, "Main.vm", MAIN);
+    let prelude = "@7\nD=A\n";
+    write(&dir, "pre.asm", prelude);
+    write(&dir, "post.asm", prelude);
+    for args in [
+        &["--prelude", "pre.asm", "-o", "pre.asm", "Main.vm"][..],
+        &["--epilogue", "post.asm", "-o", "./post.asm", "Main.vm"][..],
+        &["--prelude", "pre.asm", "--emit-sym", "pre.asm", "Main.vm"][..],
+        &["--prelude", "pre.asm", "--report-json", "pre.asm", "Main.vm"][..],
+    ] {
+        let output = run(&dir, args);
+        assert_eq!(output.status.code(), Some(1), "{args:?}: {}", stdout(&output));
+        assert!(stderr(&output).contains("Refusing to overwrite input file"), "{args:?}: {}", stderr(&output));
+    }
+    assert_eq!(fs::read_to_string(dir.join("pre.asm")).unwrap(), prelude);
+    assert_eq!(fs::read_to_string(dir.join("post.asm")).unwrap(), prelude);
+
+    let output = run(&dir, &["--prelude", "pre.asm", "-o", "out.asm", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("out.asm")).unwrap().starts_with(prelude));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn an_asm_module_is_never_overwritten() {
+    let dir = scratch("module_input");
+    let blit = "(Blit.copy)\n@SP\nM=M+1\n";
+    write(&dir, "Proj/Main.vm", MAIN);
+    write(&dir, "Proj/Blit.asm", blit);
+    let output = run(&dir, &["--allow-asm-modules", "-o", "Proj/Blit.asm", "Proj"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout(&output));
+    assert!(stderr(&output).contains("Refusing to overwrite input file Proj/Blit.asm"), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(dir.join("Proj/Blit.asm")).unwrap(), blit);
+
+    // Without the flag it isn't read, so isn't an input.
+    let output = run(&dir, &["-o", "Proj/Blit.asm", "Proj"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn an_included_file_is_never_overwritten() {
+    let dir = scratch("include_input");
+    let util = "push constant 1\nadd\n";
+    write(&dir, "P/Main.vm", "push constant 2\ninclude \"util.vmi\"\n");
+    write(&dir, "P/util.vmi", util);
+    let output = run(&dir, &["--extensions", "-o", "P/util.vmi", "P"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout(&output));
+    assert!(stderr(&output).contains("Refusing to overwrite input file P/util.vmi"), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(dir.join("P/util.vmi")).unwrap(), util);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_library_fragment_is_never_overwritten() {
+    let dir = scratch("library_input");
+    write(&dir, "libsrc/Os.vm", "function Os.f 0\npush constant 0\nreturn\n");
+    let output = run(&dir, &["-o", "lib/Os.asm", "--emit-sym", "lib/Os.sym", "libsrc/Os.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let fragment = fs::read_to_string(dir.join("lib/Os.asm")).unwrap();
+    let symbols = fs::read_to_string(dir.join("lib/Os.sym")).unwrap();
+    write(&dir, "Sys.vm", "function Sys.init 0\ncall Os.f 0\nlabel END\ngoto END\n");
+
+    for target in ["lib/Os.asm", "lib/Os.sym"] {
+        let output = run(&dir, &["--lib", "lib", "-o", target, "Sys.vm"]);
+        assert_eq!(output.status.code(), Some(1), "{target}: {}", stdout(&output));
+        assert!(stderr(&output).contains("Refusing to overwrite input file"), "{target}: {}", stderr(&output));
+    }
+    assert_eq!(fs::read_to_string(dir.join("lib/Os.asm")).unwrap(), fragment);
+    assert_eq!(fs::read_to_string(dir.join("lib/Os.sym")).unwrap(), symbols);
+
+    let output = run(&dir, &["--lib", "lib", "-o", "Prog.asm", "Sys.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("Prog.asm")).unwrap().contains("(Os.f)"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pointer_and_temp_indices_follow_the_memory_map() {
+    let dir = scratch("segment_sizes");
+    write(&dir, "Main.vm", "push constant 1\npop pointer 5\n");
+    write(&dir, "wide.toml", "pointer_size = 8\ntemp_base = 16\nstatic_base = 24\n");
+
+    let output = run(&dir, &["Main.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("(pop pointer 5): pointer index must be 0-1, got 5"), "{}", stderr(&output));
+    assert!(!dir.join("Main.asm").exists());
+
+    let output = run(&dir, &["--target", "file:wide.toml", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("Main.asm")).unwrap().contains("@8\nM=D"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fmt_rejects_unknown_flags_and_missing_paths() {
+    let dir = scratch("fmt_arguments");
+    write(&dir, "Main.vm", "push   constant 7\n");
+
+    let output = run(&dir, &["fmt", "--bogus", "Main.vm"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).starts_with("Argument Error: Unknown flag: '--bogus'\nUsage: hack_vmtranslator fmt "));
+    for args in [&["fmt", "missing.vm"][..], &["fmt", "--check", "missing.vm"][..], &["fmt", "missing.vm", "Main.vm"][..]] {
+        let output = run(&dir, args);
+        assert_eq!(output.status.code(), Some(1), "{args:?}");
+        assert!(stderr(&output).contains("Error reading missing.vm: no such file or directory"), "{args:?}: {}", stderr(&output));
+    }
+    // Nothing is formatted when one of the paths is wrong.
+    assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(), "push   constant 7\n");
+
+    let output = run(&dir, &["fmt", "--check", "Main.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "Would reformat Main.vm\n");
+    let output = run(&dir, &["fmt", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(dir.join("Main.vm")).unwrap(), "push constant 7\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bootstrap_flags_override_the_sys_init_heuristic() {
+    let dir = scratch("bootstrap_flags");
+    write(&dir, "P/Sys.vm", "function Sys.init 0\nlabel END\ngoto END\n");
+    write(&dir, "Q/Main.vm", "function Main.f 0\npush constant 0\nreturn\n");
+
+    let output = run(&dir, &["--bootstrap", "Q"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains(
+            "--bootstrap calls Sys.init, but no source defines it; define it, or pass --entry-extern if a linked library does"
+        ),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("Q/Q.asm").exists());
+    let output = run(&dir, &["--bootstrap", "--entry-extern", "Q"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("Q/Q.asm")).unwrap().starts_with("@256\n"));
+
+    let output = run(&dir, &["--no-bootstrap", "P"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Sys.init is defined, but --no-bootstrap leaves out the bootstrap, so nothing calls it"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(fs::read_to_string(dir.join("P/P.asm")).unwrap().starts_with("// Sys[0]: function Sys.init 0\n"));
+    let output = run(&dir, &["P"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("P/P.asm")).unwrap().starts_with("@256\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn only_and_skip_choose_the_functions_emitted() {
+    let dir = scratch("only_skip");
+    write(&dir, "P/Main.vm", "function Main.main 0\ncall Math.double 1\nreturn\nfunction Main.unused 0\nreturn\n");
+    write(&dir, "P/Math.vm", "function Math.double 0\ncall Math.add 2\nreturn\nfunction Math.add 0\nadd\nreturn\n");
+    let emitted = |args: &[&str]| -> Vec<String> {
+        let output = run(&dir, &[args, &["-o", "out.asm", "P"]].concat());
+        assert!(output.status.success(), "{args:?}: {}", stderr(&output));
+        let asm = fs::read_to_string(dir.join("out.asm")).unwrap();
+        asm.lines()
+            .filter_map(|line| line.strip_prefix('(').and_then(|l| l.strip_suffix(')')))
+            .filter(|label| !label.contains('$'))
+            .map(str::to_string)
+            .collect()
+    };
+
+    assert_eq!(emitted(&["--only", "Main.main"]), ["Main.main"]);
+    assert_eq!(emitted(&["--only", "Main.main", "--only-closure"]), ["Main.main", "Math.double", "Math.add"]);
+    assert_eq!(emitted(&["--only", "Main.main", "--only-closure", "--skip", "Math.add"]), ["Main.main", "Math.double"]);
+    assert_eq!(emitted(&["--skip", "Main.*"]), ["Math.double", "Math.add"]);
+
+    let output = run(&dir, &["--only", "Maths.*", "P"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("No function matches 'Maths.*'"), "{}", stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn response_files_are_spliced_before_parsing() {
+    let dir = scratch("response_files");
+    write(&dir, "My Dir/Main.vm", MAIN);
+    write(&dir, "args.txt", "# the flags for CI\n-o out.asm\n\"My Dir\"\n");
+
+    let output = run(&dir, &["@args.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("out.asm").exists());
+
+    let output = run(&dir, &["--", "@args.txt"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Error reading @args.txt"), "{}", stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn print_config_shows_each_setting_and_its_source() {
+    let dir = scratch("print_config");
+    write(&dir, "P/Main.vm", MAIN);
+    write(&dir, "P/hackvm.toml", "[output]\npath = \"out.asm\"\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_hack_vmtranslator"))
+        .args(["--release", "--only", "Main.*", "--print-config", "--emit=asm,hack", "P"])
+        .env("HACK_VM_FLAGS", "--function-order alpha -W static-gaps")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = stdout(&output);
+    let setting = |name: &str| -> Vec<String> {
+        let line = printed.lines().find(|line| line.split_whitespace().next() == Some(name)).unwrap();
+        line.split_whitespace().skip(1).map(str::to_string).collect()
+    };
+    assert_eq!(setting("--keep-going"), ["false", "(default)"]);
+    assert_eq!(setting("--only"), ["Main.*", "(command", "line)"]);
+    assert_eq!(setting("--function-order"), ["alpha", "(HACK_VM_FLAGS)"]);
+    assert_eq!(setting("-W"), ["static-gaps", "(HACK_VM_FLAGS)"]);
+    assert_eq!(setting("--output"), ["P/out.asm", "(P/hackvm.toml)"]);
+    assert_eq!(setting("--emit"), ["asm,hack", "(command", "line)"]);
+    assert_eq!(setting("--max-rom"), ["32768", "(--release", "from", "command", "line)"]);
+
+    // A later layer replaces a setting, except for the lists it adds to.
+    let output = Command::new(env!("CARGO_BIN_EXE_hack_vmtranslator"))
+        .args(["--function-order", "source", "--only", "Main.*", "--print-config", "P"])
+        .env("HACK_VM_FLAGS", "--function-order alpha --only Sys.*")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    let printed = stdout(&output);
+    assert!(printed.contains("--function-order       source  (command line)\n"), "{printed}");
+    assert!(printed.contains("--only                 Sys.*,Main.*  (HACK_VM_FLAGS, command line)\n"), "{printed}");
+
+    // Every flag that changes the translation has a line.
+    let meta = ["-o", "--no-bootstrap", "--no-stack-checks", "--debug", "--release", "--print-config", "--config"];
+    for (flag, _) in hack_vmtranslator::completions::FLAGS.iter().filter(|(flag, _)| !meta.contains(flag)) {
+        assert!(printed.lines().any(|line| line.starts_with(&format!("{flag} "))), "{flag}");
+    }
+    assert!(!printed.contains("origins") && !dir.join("P/out.asm").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn presets_write_what_their_options_say_and_flags_override_them() {
+    let dir = scratch("presets");
+    write(&dir, "Main.vm", "function Main.f 0\npush constant 1\nreturn\n");
+
+    let output = run(&dir, &["--debug", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = fs::read_to_string(dir.join("Main.asm")).unwrap();
+    assert!(asm.contains("// Main[1]: push constant 1 // Main.f, stack 1"), "{asm}");
+    assert!(asm.contains("(STACK_OVERFLOW)") && asm.contains("// 0x0000"), "{asm}");
+    assert!(dir.join("Main.spans.json").exists());
+    assert_eq!(dir.join("Main.lst").exists(), cfg!(feature = "assembler"));
+
+    let output = run(&dir, &["--release", "-o", "release.asm", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = fs::read_to_string(dir.join("release.asm")).unwrap();
+    assert!(!asm.contains("// Main[") && !asm.contains("STACK_OVERFLOW"), "{asm}");
+
+    let output = run(&dir, &["--debug", "--comments", "headers", "--no-stack-checks", "--emit", "asm", "-o", "plain.asm", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = fs::read_to_string(dir.join("plain.asm")).unwrap();
+    assert!(asm.contains("// Main[1]: push constant 1\n") && !asm.contains("STACK_OVERFLOW"), "{asm}");
+    assert!(!dir.join("plain.spans.json").exists());
+
+    let output = run(&dir, &["--release", "--emit-debug", "Main.hvd", "Main.vm"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(stdout(&output).contains("--comments off leaves out"), "{}", stdout(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn subcommands_only_take_the_flags_they_complete() {
+    let dir = scratch("subcommand_flags");
+    write(&dir, "Main.vm", MAIN);
+    for (subcommand, _) in hack_vmtranslator::completions::SUBCOMMANDS {
+        if subcommand == "completions" {
+            continue;
+        }
+        let output = run(&dir, &[subcommand, "--bogus"]);
+        assert!(!output.status.success(), "{subcommand}");
+        let printed = stdout(&output) + &stderr(&output);
+        assert!(printed.contains("Unknown flag: '--bogus'"), "{subcommand}: {printed}");
+    }
+    let completion = run(&dir, &["completions", "bash"]);
+    assert!(stdout(&completion).contains("--max-steps --timeout"));
+    let output = run(&dir, &["run", "--max-steps", "1000", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fail_fast_stops_at_the_first_error() {
+    let dir = scratch("fail_fast");
+    write(&dir, "P/A.vm", "push constant 1\nbogus\nalso bad\n");
+    write(&dir, "P/B.vm", "push constant 2\n");
+    write(&dir, "P/C.vm", "push constant 3\nnope\n");
+
+    let output = run(&dir, &["--fail-fast", "P"]);
+    assert_eq!(output.status.code(), Some(1));
+    let (printed, errors) = (stdout(&output), stderr(&output));
+    assert!(printed.contains("Reading file P/A.vm"), "{printed}");
+    // The later files aren't even read.
+    assert!(!printed.contains("P/B.vm") && !printed.contains("P/C.vm"), "{printed}");
+    assert!(errors.contains("(bogus): Parser not implemented for 'bogus'"), "{errors}");
+    assert!(!errors.contains("also bad") && errors.contains("Stopped at the first parse error (--fail-fast)"), "{errors}");
+    assert!(!dir.join("P/P.asm").exists());
+
+    // By default every error is reported.
+    let output = run(&dir, &["P"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("also bad") && stderr(&output).contains("nope"), "{}", stderr(&output));
+
+    let output = run(&dir, &["--keep-going", "--fail-fast", "P"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).starts_with("Argument Error: --keep-going and --fail-fast can't be used together\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_same_file_is_read_once_and_stems_must_differ() {
+    let dir = scratch("duplicate_inputs");
+    write(&dir, "P/Main.vm", "push constant 1\n");
+    write(&dir, "Q/my game.vm", "push constant 3\n");
+    write(&dir, "Q/my_game.vm", "push constant 4\n");
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("Main.vm", dir.join("P/Alias.vm")).unwrap();
+        let output = run(&dir, &["P"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let printed = stdout(&output);
+        assert!(printed.contains("Skipping P/Main.vm (same file as P/Alias.vm)"), "{printed}");
+        assert_eq!(printed.matches("Reading file").count(), 1, "{printed}");
+        let asm = fs::read_to_string(dir.join("P/P.asm")).unwrap();
+        assert_eq!(asm.matches("push constant 1").count(), 1);
+    }
+
+    let output = run(&dir, &["Q"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Q/my game.vm and Q/my_game.vm would share the static prefix 'my_game'"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("Q/Q.asm").exists());
+
+    // An input that can't be read stops the run rather than being left out.
+    fs::create_dir_all(dir.join("R")).unwrap();
+    fs::write(dir.join("R/Bad.vm"), [0xff, 0xfe, b'\n']).unwrap();
+    write(&dir, "R/Good.vm", MAIN);
+    let output = run(&dir, &["R"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Error reading file in R/Bad.vm: stream did not contain valid UTF-8"), "{}", stderr(&output));
+    assert!(!dir.join("R/R.asm").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_reg_segment_needs_extensions() {
+    let dir = scratch("reg_segment");
+    write(&dir, "Main.vm", "push constant 5\npop reg 7\npush reg 7\npush reg 7\nadd\npop reg 0\n");
+    write(&dir, "Wide.vm", "push reg 16\n");
+
+    let output = run(&dir, &["Main.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("(pop reg 7): The reg segment is an extension; enable it with --extensions"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("Main.asm").exists());
+
+    let output = run(&dir, &["--extensions", "Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Warning at line Main.vm:6 (pop reg 0): R0 is the stack pointer SP"), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("reg 7"), "{}", stderr(&output));
+    let asm = fs::read_to_string(dir.join("Main.asm")).unwrap();
+    assert!(asm.contains("@R7\nM=D") && asm.contains("@R7\nD=M"), "{asm}");
+
+    let output = run(&dir, &["--extensions", "Wide.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("reg index must be 0-15, got 16"), "{}", stderr(&output));
+
+    // The interpreter runs it too.
+    write(&dir, "Main.vm", "push constant 5\npop reg 7\npush reg 7\npush reg 7\nadd\n");
+    let output = run(&dir, &["run", "--grade", "RAM[7]=5;RAM[256]=10", "Main.vm"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), stderr(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn summary_prints_one_line() {
+    let dir = scratch("summary");
+    write(&dir, "P/Sys.vm", "function Sys.init 0\nlabel END\ngoto END\n");
+
+    let args = ["--summary", "--no-bootstrap", "-o", "build/Prog.asm", "--report-json", "report.json", "P"];
+    let output = run(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "ok: 1 file, 3 commands, 2 instructions, 1 warning, wrote build/Prog.asm\n");
+    // The warning itself still goes to stderr, and the numbers are the report's.
+    assert!(stderr(&output).contains("--no-bootstrap leaves out the bootstrap"), "{}", stderr(&output));
+    let report = fs::read_to_string(dir.join("report.json")).unwrap();
+    assert!(report.contains("\"rom_size\": 2,"), "{report}");
+    assert!(dir.join("build/Prog.asm").exists());
+
+    write(&dir, "P/Bad.vm", "bogus\n");
+    write(&dir, "P/Worse.vm", "nope\nworse\n");
+    let output = run(&dir, &["--summary", "P"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "error: 3 errors in 2 files\n");
+    assert!(stderr(&output).contains("Parser not implemented for 'worse'"), "{}", stderr(&output));
+
+    let output = run(&dir, &["--summary", "--stack-report", "P"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).starts_with("Argument Error: --summary keeps stdout to one line; it can't be combined with --stack-report\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Runs the translator in `dir` with `HACK_VM_FLAGS` set to `flags`.
+fn run_with_flags(dir: &Path, flags: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hack_vmtranslator"))
+        .args(args)
+        .env("HACK_VM_FLAGS", flags)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn hack_vm_flags_come_before_the_command_line() {
+    let dir = scratch("hack_vm_flags");
+    write(&dir, "My Dir/Main.vm", MAIN);
+
+    // Quoted like a response file, and applied as if typed first.
+    let output = run_with_flags(&dir, "-o 'build/my out.asm' --annotate-addresses", &["My Dir"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(dir.join("build/my out.asm")).unwrap().contains("// 0x0000\n"));
+    let output = run_with_flags(&dir, "-o 'build/my out.asm'", &["-o", "build/cli.asm", "My Dir"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("build/cli.asm").exists());
+
+    let output = run_with_flags(&dir, "-o env.asm --keep-going", &["--print-config", "My Dir"]);
+    let printed = stdout(&output);
+    assert!(printed.contains("--output               env.asm  (HACK_VM_FLAGS)\n"), "{printed}");
+    assert!(printed.contains("--keep-going           true  (HACK_VM_FLAGS)\n"), "{printed}");
+
+    for (flags, message) in [
+        ("--bogus", "Argument Error: Unknown flag: '--bogus' (from HACK_VM_FLAGS)"),
+        ("--function-order sideways", "(from HACK_VM_FLAGS)"),
+        ("'unterminated", "Argument Error: unterminated ' quote in HACK_VM_FLAGS"),
+    ] {
+        let output = run_with_flags(&dir, flags, &["My Dir"]);
+        assert_eq!(output.status.code(), Some(2), "{flags}");
+        assert!(stdout(&output).lines().next().unwrap().contains(message), "{flags}: {}", stdout(&output));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn output_goes_to_a_file_or_into_a_directory() {
+    let dir = scratch("output_paths");
+    write(&dir, "P/Main.vm", MAIN);
+    fs::create_dir_all(dir.join("out")).unwrap();
+
+    for (args, written) in [
+        (&["P"][..], "P/P.asm"),
+        (&["-o", "out", "P"][..], "out/P.asm"),
+        (&["-o", "out", "P/Main.vm"][..], "out/Main.asm"),
+        (&["--output", "deep/nested/x.asm", "P"][..], "deep/nested/x.asm"),
+        (&["-o", "new/", "P"][..], "new/P.asm"),
+    ] {
+        let output = run(&dir, args);
+        assert!(output.status.success(), "{args:?}: {}", stderr(&output));
+        assert!(stdout(&output).contains(&format!("output file = {written}\n")), "{args:?}: {}", stdout(&output));
+        assert!(fs::read_to_string(dir.join(written)).unwrap().contains("@7"), "{written}");
+    }
+
+    // A file standing where a parent directory has to go.
+    write(&dir, "blocker", "");
+    let output = run(&dir, &["-o", "blocker/x.asm", "P"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Error creating blocker: "), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(dir.join("blocker")).unwrap(), "");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn link_joins_fragments() {
+    let dir = scratch("link");
+    write(&dir, "Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n");
+    write(&dir, "Main.vm", "function Main.main 0\npush constant 7\nreturn\n");
+    for file in ["Sys.vm", "Main.vm"] {
+        let output = run(&dir, &[file]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    let output = run(&dir, &["link", "Main.asm", "Sys.asm", "-o", "Prog.asm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let program = fs::read_to_string(dir.join("Prog.asm")).unwrap();
+    assert!(program.starts_with(&fs::read_to_string(dir.join("Sys.asm")).unwrap()));
+
+    let output = run(&dir, &["link", "Main.asm", "Main.asm", "-o", "Twice.asm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Label 'Main.main' is defined in both Main.asm and Main.asm"), "{}", stdout(&output));
+    assert!(!dir.join("Twice.asm").exists());
+
+    let output = run(&dir, &["link", "Main.asm"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).contains("\nUsage: hack_vmtranslator link "), "{}", stdout(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `asm` without its comment lines.
+fn code(asm: &str) -> String {
+    asm.lines().filter(|line| !line.starts_with("//")).map(|line| format!("{line}\n")).collect()
+}
+
+#[test]
+fn keep_going_traps_a_bad_line_and_keeps_the_rest() {
+    let dir = scratch("keep_going");
+    let before = "push constant 1\npush constant 2\neq\n";
+    let after = "push constant 3\nlt\npop temp 0\n";
+    write(&dir, "good/Main.vm", &format!("{before}{after}"));
+    write(&dir, "bad/Main.vm", &format!("{before}bogus\n{after}"));
+
+    let output = run(&dir, &["good/Main.vm"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run(&dir, &["--keep-going", "bad/Main.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("(bogus): Parser not implemented for 'bogus'"), "{}", stderr(&output));
+
+    // The bad line becomes a jump to the trap, which halts; everything
+    // around it is what the file gives without the line.
+    let good = fs::read_to_string(dir.join("good/Main.asm")).unwrap();
+    let bad = fs::read_to_string(dir.join("bad/Main.asm")).unwrap();
+    let split = good.find("// Main[3]: push constant 3").unwrap();
+    assert!(bad.contains("// Main[3]: bogus\n@PARSE_ERROR_TRAP\n0;JMP\n// Main[4]: push constant 3\n"), "{bad}");
+    assert_eq!(
+        code(&bad),
+        format!(
+            "{}@PARSE_ERROR_TRAP\n0;JMP\n{}(PARSE_ERROR_TRAP)\n@PARSE_ERROR_TRAP\n0;JMP\n",
+            code(&good[..split]),
+            code(&good[split..])
+        )
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn report_json_reads_back() {
+    use hack_vmtranslator::json::Json;
+    use hack_vmtranslator::sha256::hex_digest;
+
+    let dir = scratch("report_json");
+    let main = "function Main.main 0\npush constant 1\nreturn\n";
+    let sys = "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n";
+    write(&dir, "P/Main.vm", main);
+    write(&dir, "P/Sys.vm", sys);
+
+    let output = run(&dir, &["--report-json", "report.json", "P"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = Json::parse(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    let field = |json: &Json, key: &str| json.get(key).unwrap().clone();
+    let text = |json: &Json, key: &str| json.get(key).and_then(Json::as_str).unwrap().to_string();
+    assert_eq!(field(&report, "schema_version").to_string(), "1");
+    assert_eq!(text(&report, "status"), "ok");
+    assert!(report.get("error").is_none());
+
+    let inputs = field(&report, "inputs");
+    let inputs: Vec<(String, String)> = inputs.as_array().unwrap().iter().map(|i| (text(i, "path"), text(i, "sha256"))).collect();
+    assert_eq!(
+        inputs,
+        [
+            ("P/Main.vm".to_string(), hex_digest(main.as_bytes())),
+            ("P/Sys.vm".to_string(), hex_digest(sys.as_bytes())),
+        ]
+    );
+    let functions = field(&report, "functions");
+    let names: Vec<String> = functions.as_array().unwrap().iter().map(|f| text(f, "name")).collect();
+    assert_eq!(names, ["Main.main", "Sys.init"]);
+    assert_eq!(field(&report, "bootstrap").to_string(), "true");
+
+    let asm = fs::read(dir.join("P/P.asm")).unwrap();
+    let artifacts = field(&report, "artifacts");
+    let [artifact] = artifacts.as_array().unwrap() else { panic!("{artifacts}") };
+    assert_eq!(text(artifact, "path"), "P/P.asm");
+    assert_eq!(text(artifact, "sha256"), hex_digest(&asm));
+    assert_eq!(field(artifact, "bytes").to_string(), asm.len().to_string());
+
+    // A failed run still writes one, saying why.
+    write(&dir, "P/Bad.vm", "bogus\n");
+    let output = run(&dir, &["--report-json", "failed.json", "P"]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = Json::parse(&fs::read_to_string(dir.join("failed.json")).unwrap()).unwrap();
+    assert_eq!(text(&report, "status"), "failed");
+    assert_eq!(text(&report, "error"), "Parse errors found: 1");
+    assert_eq!(field(&report, "inputs").as_array().unwrap().len(), 3);
+    let errors = field(&report, "errors");
+    let [error] = errors.as_array().unwrap() else { panic!("{errors}") };
+    assert!(error.as_str().unwrap().ends_with("(bogus): Parser not implemented for 'bogus'"), "{error}");
+    assert_eq!(field(&report, "artifacts").to_string(), "[]");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Splits a `--error-format=short` line, `<file>:<line>:<column>:
+/// <severity>: <message>`, into its parts.
+fn short_diagnostic(line: &str) -> Option<(&str, usize, usize, &str, &str)> {
+    let mut parts = line.splitn(4, ':');
+    let (file, number, column, rest) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let number = number.parse().ok().filter(|n| *n > 0 && number.bytes().all(|b| b.is_ascii_digit()))?;
+    let column = column.parse().ok().filter(|n| *n > 0 && column.bytes().all(|b| b.is_ascii_digit()))?;
+    let (severity, message) = rest.strip_prefix(' ')?.split_once(": ")?;
+    ["error", "warning", "note"].contains(&severity).then_some((file, number, column, severity, message))
+}
+
+#[test]
+fn short_errors_are_one_located_line_each() {
+    let dir = scratch("short_errors");
+    write(&dir, "P/Sys.vm", "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n");
+    let cases = [
+        // A parse error and a temp index out of range.
+        (
+            "function Main.main 0\npush constant 99999\npop temp 9\nreturn\n",
+            &[][..],
+            &[("P/Main.vm", 2, 1, "error"), ("P/Main.vm", 3, 1, "error")][..],
+        ),
+        // A call to a function nothing defines.
+        (
+            "function Main.main 0\ncall Nowhere.f 0\nreturn\n",
+            &[],
+            &[("P/Sys.vm", 4, 1, "note"), ("P/Main.vm", 2, 1, "warning")][..],
+        ),
+        // An entry nothing calls.
+        (
+            "function Main.main 0\nreturn\n",
+            &["--no-bootstrap"][..],
+            &[("P/Sys.vm", 1, 1, "warning"), ("P/Sys.vm", 4, 1, "note")][..],
+        ),
+    ];
+    for (main, flags, expected) in cases {
+        write(&dir, "P/Main.vm", main);
+        let output = run(&dir, &[&["--error-format=short"], flags, &["P"]].concat());
+        let printed = stderr(&output);
+        let lines: Vec<_> = printed.lines().map(|line| short_diagnostic(line).unwrap_or_else(|| panic!("{line}"))).collect();
+        let located: Vec<_> = lines.iter().map(|(file, line, column, severity, _)| (*file, *line, *column, *severity)).collect();
+        assert_eq!(located, expected, "{printed}");
+        let failed = expected.iter().any(|(.., severity)| *severity == "error");
+        assert_eq!(output.status.code(), Some(if failed { 1 } else { 0 }), "{printed}");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn timings_and_the_report_count_what_the_library_counts() {
+    use hack_vmtranslator::json::Json;
+
+    let dir = scratch("metrics");
+    let main = "// Adds two numbers.\nfunction Main.main 0\npush constant 7\npush constant 8\nadd\nreturn\n";
+    let sys = "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n";
+    write(&dir, "P/Main.vm", main);
+    write(&dir, "P/Sys.vm", sys);
+    let sources = [("P/Main.vm".to_string(), main.to_string()), ("P/Sys.vm".to_string(), sys.to_string())];
+    let (_, metrics) = hack_vmtranslator::translate_with_metrics(&sources).unwrap();
+
+    let output = run(&dir, &["--timings", "--report-json", "report.json", "P"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let counts = format!("{} lines, {} commands, {} instructions, ", metrics.input_lines, metrics.commands, metrics.instructions);
+    assert!(stderr(&output).lines().last().unwrap().starts_with(&counts), "{}", stderr(&output));
+
+    let report = Json::parse(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    let reported = report.get("metrics").unwrap();
+    let count = |key: &str| reported.get(key).unwrap().to_string();
+    assert_eq!(
+        [count("input_lines"), count("commands"), count("instructions")],
+        [metrics.input_lines, metrics.commands, metrics.instructions].map(|n| n.to_string())
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The library shares the code generator with the command line but not
+/// the rest of the pipeline, so this keeps the two from drifting apart.
+#[test]
+fn the_library_writes_what_the_command_line_writes() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat/programs");
+    for program in ["Fib", "Simple", "Statics"] {
+        let dir = scratch(&format!("library_{program}"));
+        let mut sources = Vec::new();
+        let mut files: Vec<_> = fs::read_dir(programs.join(program)).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        for file in files {
+            let name = format!("{program}/{}", file.file_name().unwrap().to_str().unwrap());
+            let text = fs::read_to_string(&file).unwrap();
+            write(&dir, &name, &text);
+            sources.push((name, text));
+        }
+
+        let output = run(&dir, &[program]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let written = fs::read_to_string(dir.join(program).join(format!("{program}.asm"))).unwrap();
+        assert_eq!(written, hack_vmtranslator::translate(&sources).unwrap(), "{program}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn a_directory_that_cant_be_listed_is_an_error() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch("unlistable");
+    write(&dir, "P/Main.vm", MAIN);
+    fs::set_permissions(dir.join("P"), fs::Permissions::from_mode(0o000)).unwrap();
+    // Permissions don't stop root.
+    if fs::read_dir(dir.join("P")).is_err() {
+        for args in [&["P"][..], &["fmt", "P"], &["run", "P"]] {
+            let output = run(&dir, args);
+            assert_eq!(output.status.code(), Some(1), "{args:?}");
+            assert!(stderr(&output).contains("Error reading directory in P: "), "{args:?}: {}", stderr(&output));
+            assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+        }
+    }
+    fs::set_permissions(dir.join("P"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn refused_commands_are_reported_with_parse_errors() {
+    let dir = scratch("refused");
+    write(&dir, "Main.vm", "push constant 99999\npop temp 9\npush constant 1\npop pointer 2\n");
+
+    for flags in [&[][..], &["--keep-going"]] {
+        let output = run(&dir, &[flags, &["Main.vm"]].concat());
+        assert_eq!(output.status.code(), Some(1), "{flags:?}");
+        let printed = stderr(&output);
+        assert!(printed.starts_with("Main.vm: 3 errors, 0 warnings\n"), "{printed}");
+        assert!(printed.contains("Parse error at line Main.vm:1 (push constant 99999)"), "{printed}");
+        assert!(printed.contains("Error at line Main.vm:2 (pop temp 9): temp index must be 0-7, got 9"), "{printed}");
+        assert!(printed.contains("Error at line Main.vm:4 (pop pointer 2): pointer index must be 0-1, got 2"), "{printed}");
+        assert!(printed.ends_with("Error: Parse errors found: 3\n"), "{printed}");
+        assert!(!dir.join("Main.asm").exists());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,459 @@
+@256
+D=A
+@SP
+M=D
+@LCL
+M=-1
+@2
+D=-A
+@ARG
+M=D
+@3
+D=-A
+@THIS
+M=D
+@4
+D=-A
+@THAT
+M=D
+@Bootstrap$ret.0
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@5
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Sys.init
+0;JMP
+(Bootstrap$ret.0)
+// Main[3]: function Main.fibonacci 0
+(Main.fibonacci)
+// Main[4]: push argument 0
+@0
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[5]: push constant 2
+@2
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[6]: lt
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@COMP_TRUE_Main.6
+D;JLT
+@0
+D=A
+@COMP_END_Main.6
+0;JMP
+(COMP_TRUE_Main.6)
+@1
+D=-A
+(COMP_END_Main.6)
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[7]: if-goto IF_TRUE
+@SP
+AM=M-1
+D=M
+@Main.fibonacci$IF_TRUE
+D;JNE
+// Main[8]: goto IF_FALSE
+@Main.fibonacci$IF_FALSE
+0;JMP
+// Main[9]: label IF_TRUE
+(Main.fibonacci$IF_TRUE)
+// Main[10]: push argument 0
+@0
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[11]: return
+@LCL
+D=M
+@frame
+M=D
+@frame
+D=M
+@5
+A=D-A
+D=M
+@retaddr
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+@frame
+AM=M-1
+D=M
+@THAT
+M=D
+@frame
+AM=M-1
+D=M
+@THIS
+M=D
+@frame
+AM=M-1
+D=M
+@ARG
+M=D
+@frame
+AM=M-1
+D=M
+@LCL
+M=D
+@retaddr
+A=M
+0;JMP
+// Main[12]: label IF_FALSE
+(Main.fibonacci$IF_FALSE)
+// Main[13]: push argument 0
+@0
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[14]: push constant 2
+@2
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[15]: sub
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[16]: call Main.fibonacci 1
+@Main.fibonacci$ret.16
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@6
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Main.fibonacci
+0;JMP
+(Main.fibonacci$ret.16)
+// Main[17]: push argument 0
+@0
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[18]: push constant 1
+@1
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[19]: sub
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[20]: call Main.fibonacci 1
+@Main.fibonacci$ret.20
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@6
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Main.fibonacci
+0;JMP
+(Main.fibonacci$ret.20)
+// Main[21]: add
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=D+M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Main[22]: return
+@LCL
+D=M
+@frame
+M=D
+@frame
+D=M
+@5
+A=D-A
+D=M
+@retaddr
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+@frame
+AM=M-1
+D=M
+@THAT
+M=D
+@frame
+AM=M-1
+D=M
+@THIS
+M=D
+@frame
+AM=M-1
+D=M
+@ARG
+M=D
+@frame
+AM=M-1
+D=M
+@LCL
+M=D
+@retaddr
+A=M
+0;JMP
+// Sys[2]: function Sys.init 0
+(Sys.init)
+// Sys[3]: push constant 4
+@4
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Sys[4]: call Main.fibonacci 1
+@Sys.init$ret.4
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@6
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Main.fibonacci
+0;JMP
+(Sys.init$ret.4)
+// Sys[5]: label WHILE
+(Sys.init$WHILE)
+// Sys[6]: goto WHILE
+@Sys.init$WHILE
+0;JMP
\ No newline at end of file
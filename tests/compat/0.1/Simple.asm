@@ -0,0 +1,83 @@
+// Simple[1]: push constant 7
+@7
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[2]: push constant 8
+@8
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[3]: add
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=D+M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[4]: push constant 3
+@3
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[5]: eq
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@COMP_TRUE_Simple.5
+D;JEQ
+@0
+D=A
+@COMP_END_Simple.5
+0;JMP
+(COMP_TRUE_Simple.5)
+@1
+D=-A
+(COMP_END_Simple.5)
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[6]: pop temp 0
+@SP
+AM=M-1
+D=M
+@5
+M=D
+// Simple[7]: push static 2
+@Simple.2
+D=M
+
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[8]: pop local 1
+@LCL
+D=M
+@1
+D=D+A
+@SP
+AM=M-1
+D=D+M
+A=D-M
+M=D-A
\ No newline at end of file
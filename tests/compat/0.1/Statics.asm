@@ -0,0 +1,359 @@
+@256
+D=A
+@SP
+M=D
+@LCL
+M=-1
+@2
+D=-A
+@ARG
+M=D
+@3
+D=-A
+@THIS
+M=D
+@4
+D=-A
+@THAT
+M=D
+@Bootstrap$ret.0
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@5
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Sys.init
+0;JMP
+(Bootstrap$ret.0)
+// Class1[0]: function Class1.set 0
+(Class1.set)
+// Class1[1]: push argument 0
+@0
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[2]: pop static 0
+@SP
+AM=M-1
+D=M
+@Class1.0
+M=D
+// Class1[3]: push argument 1
+@1
+D=A
+@ARG
+A=D+M
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[4]: pop static 1
+@SP
+AM=M-1
+D=M
+@Class1.1
+M=D
+// Class1[5]: push constant 0
+@0
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[6]: return
+@LCL
+D=M
+@frame
+M=D
+@frame
+D=M
+@5
+A=D-A
+D=M
+@retaddr
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+@frame
+AM=M-1
+D=M
+@THAT
+M=D
+@frame
+AM=M-1
+D=M
+@THIS
+M=D
+@frame
+AM=M-1
+D=M
+@ARG
+M=D
+@frame
+AM=M-1
+D=M
+@LCL
+M=D
+@retaddr
+A=M
+0;JMP
+// Class1[7]: function Class1.get 0
+(Class1.get)
+// Class1[8]: push static 0
+@Class1.0
+D=M
+
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[9]: push static 1
+@Class1.1
+D=M
+
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[10]: sub
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Class1[11]: return
+@LCL
+D=M
+@frame
+M=D
+@frame
+D=M
+@5
+A=D-A
+D=M
+@retaddr
+M=D
+@SP
+AM=M-1
+D=M
+@ARG
+A=M
+M=D
+@ARG
+D=M+1
+@SP
+M=D
+@frame
+AM=M-1
+D=M
+@THAT
+M=D
+@frame
+AM=M-1
+D=M
+@THIS
+M=D
+@frame
+AM=M-1
+D=M
+@ARG
+M=D
+@frame
+AM=M-1
+D=M
+@LCL
+M=D
+@retaddr
+A=M
+0;JMP
+// Sys[0]: function Sys.init 0
+(Sys.init)
+// Sys[1]: push constant 6
+@6
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Sys[2]: push constant 8
+@8
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Sys[3]: call Class1.set 2
+@Sys.init$ret.3
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@7
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Class1.set
+0;JMP
+(Sys.init$ret.3)
+// Sys[4]: pop temp 0
+@SP
+AM=M-1
+D=M
+@5
+M=D
+// Sys[5]: call Class1.get 0
+@Sys.init$ret.5
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@LCL
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@ARG
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THIS
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@THAT
+D=M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+@SP
+D=M
+@5
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@Class1.get
+0;JMP
+(Sys.init$ret.5)
+// Sys[6]: label WHILE
+(Sys.init$WHILE)
+// Sys[7]: goto WHILE
+@Sys.init$WHILE
+0;JMP
\ No newline at end of file
@@ -0,0 +1,87 @@
+// Simple[1]: push constant 7
+@7
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[2]: push constant 8
+@8
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[3]: add
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=D+M
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[4]: push constant 3
+@3
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[5]: eq
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+D=M-D
+@Simple$cmp.0
+D;JEQ
+@0
+D=A
+@Simple$cmp.0.end
+0;JMP
+(Simple$cmp.0)
+@1
+D=-A
+(Simple$cmp.0.end)
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[6]: pop temp 0
+@SP
+AM=M-1
+D=M
+@5
+M=D
+// Simple[7]: push static 2
+@Simple.2
+D=M
+
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// Simple[8]: pop local 1
+@LCL
+D=M
+@1
+D=D+A
+@SP
+AM=M-1
+D=D+M
+A=D-M
+M=D-A
+// == hack_vm_translator metadata ==
+// bootstrap no
+// static Simple.2 2 Simple.vm
+// == end metadata ==
\ No newline at end of file